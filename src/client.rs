@@ -1,35 +1,458 @@
+#[cfg(feature = "net")]
+mod pool;
+#[cfg(feature = "net")]
+mod socks4;
+mod stream;
+#[cfg(feature = "tracing")]
+mod trace;
+#[cfg(feature = "udp")]
+mod udp;
+
+#[cfg(feature = "net")]
+pub use pool::{EndpointStats, ProxyEndpoint, Selection, Socks5Pool};
+pub use stream::Socks5Stream;
+#[cfg(feature = "udp")]
+pub use udp::Socks5UdpFramed;
+
 use crate::utils::*;
+#[cfg(feature = "net")]
+use crate::{socket, SocketOptions};
 
 use std::{
+    convert::{Infallible, TryFrom, TryInto},
     io,
     net::SocketAddr,
     ops::{Deref, DerefMut},
+    time::Duration,
 };
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+#[cfg(feature = "net")]
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt, Result},
-    net::{TcpStream, ToSocketAddrs},
+    net::{lookup_host, TcpStream, ToSocketAddrs},
+    task::JoinSet,
 };
 
-pub async fn new(
+type Result<T> = std::result::Result<T, Socks5ClientError>;
+
+/// Awaits one phase of a connect; with the `tracing` feature, in a span
+/// named `$name` that records its duration and outcome.
+macro_rules! phase {
+    ($name:literal, $phase:expr) => {{
+        #[cfg(feature = "tracing")]
+        let done = trace::phase(tracing::info_span!($name), $phase).await;
+        #[cfg(not(feature = "tracing"))]
+        let done = $phase.await;
+        done
+    }};
+}
+
+#[derive(Debug, Error)]
+pub enum Socks5ClientError {
+    #[error("unrecognized protocol")]
+    UnknowProtocol,
+    #[error("server replied with version {0:#04X}")]
+    UnexpectedVersion(u8),
+    #[error("no acceptable authenticate method, server selected 0xFF")]
+    NoAcceptableAuth,
+    #[error("server selected authenticate method {selected:#04X}, but offered {offered:02X?}")]
+    UnexpectedMethod { offered: Vec<u8>, selected: u8 },
+    #[error("authenticate method {0:#04X} not implemented")]
+    UnsupportAuth(u8),
+    #[error("server replied {0:#04X}: {1}")]
+    Rejected(u8, SocksError),
+    #[error("unknow bound address type {0:#04X}")]
+    UnknowAddrType(u8),
+    #[error("bad pattern in hostname:port")]
+    BadHostnamePort,
+    #[error("hostname too long")]
+    HostnameTooLong,
+    #[error("invalid hostname: {0}")]
+    InvalidHostname(HostnameError),
+    #[error("username too long")]
+    UsernameTooLong,
+    #[error("SOCKS4 can't reach IPv6 destinations")]
+    Socks4Ipv6,
+    #[error("SOCKS4 server replied {0:#04X}")]
+    Socks4Rejected(u8),
+    #[error("no proxy endpoint available")]
+    NoEndpoint,
+    #[error(transparent)]
+    IOError(#[from] io::Error),
+}
+
+impl From<Socks5ClientError> for io::Error {
+    fn from(e: Socks5ClientError) -> io::Error {
+        use Socks5ClientError::*;
+        match e {
+            IOError(e) => e,
+            NoAcceptableAuth | Rejected(..) | Socks4Rejected(_) => {
+                io::Error::new(io::ErrorKind::ConnectionRefused, e)
+            }
+            BadHostnamePort | HostnameTooLong | InvalidHostname(_) | UsernameTooLong
+            | Socks4Ipv6 => io::Error::new(io::ErrorKind::InvalidInput, e),
+            _ => io::Error::new(io::ErrorKind::ConnectionAborted, e),
+        }
+    }
+}
+
+impl From<HostnameError> for Socks5ClientError {
+    fn from(e: HostnameError) -> Socks5ClientError {
+        match e {
+            HostnameError::TooLong => Socks5ClientError::HostnameTooLong,
+            e => Socks5ClientError::InvalidHostname(e),
+        }
+    }
+}
+
+impl From<Infallible> for Socks5ClientError {
+    fn from(e: Infallible) -> Socks5ClientError {
+        match e {}
+    }
+}
+
+impl Socks5ClientError {
+    /// Whether the greeting failed the way it does against a server that
+    /// only speaks SOCKS4: an immediate close, or a SOCKS4 style reply.
+    #[cfg(feature = "net")]
+    fn suggests_socks4(&self) -> bool {
+        match self {
+            Socks5ClientError::UnexpectedVersion(ver) => matches!(ver, 0x00 | 0x5A..=0x5D),
+            Socks5ClientError::IOError(e) => matches!(
+                e.kind(),
+                io::ErrorKind::UnexpectedEof
+                    | io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::ConnectionAborted
+            ),
+            _ => false,
+        }
+    }
+}
+
+/// The protocol version a tunnel was negotiated with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    Socks5,
+    /// SOCKS4, with the 4a extension for hostname destinations.
+    Socks4,
+}
+
+/// Connects to `dest` through the proxy at `server`. Destinations can be
+/// given as an [`Addr`], a `SocketAddr`, an `(ip, port)` or
+/// `(hostname, port)` tuple, or a `"host:port"` string.
+#[cfg(feature = "net")]
+pub async fn new<D>(
     server: impl ToSocketAddrs,
-    dest: &Addr,
+    dest: D,
+    auth: Option<AuthMethod>,
+) -> Result<Socks5Stream>
+where
+    D: TryInto<Addr>,
+    D::Error: Into<Socks5ClientError>,
+{
+    let mut builder = Builder::new();
+    if let Some(auth) = auth {
+        builder = builder.auth(auth);
+    }
+    builder.connect(server, dest).await
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Builder {
     auth: Option<AuthMethod>,
-) -> Result<TcpStream> {
-    let conn = TcpStream::connect(server).await?;
-    let auth = auth.unwrap_or(AuthMethod::NoAuth);
+    fallback_socks4: bool,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+    optimistic: Option<usize>,
+    #[cfg(feature = "net")]
+    socket: SocketOptions,
+}
+
+impl Builder {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn auth(mut self, auth: AuthMethod) -> Self {
+        self.auth = Some(auth);
+        self
+    }
+
+    /// Retries with SOCKS4a when the proxy turns out not to speak SOCKS5.
+    /// Failures reported by a SOCKS5 server never trigger the fallback.
+    pub fn fallback_socks4(mut self, enabled: bool) -> Self {
+        self.fallback_socks4 = enabled;
+        self
+    }
+
+    /// Fails any single read on the returned stream that makes no progress
+    /// for `timeout` with [`io::ErrorKind::TimedOut`].
+    pub fn read_timeout(mut self, timeout: Duration) -> Self {
+        self.read_timeout = Some(timeout);
+        self
+    }
+
+    /// Fails any single write, flush or shutdown on the returned stream
+    /// that makes no progress for `timeout` with
+    /// [`io::ErrorKind::TimedOut`].
+    pub fn write_timeout(mut self, timeout: Duration) -> Self {
+        self.write_timeout = Some(timeout);
+        self
+    }
+
+    /// Returns from connecting as soon as the CONNECT request is written,
+    /// saving the round trip to the reply. Up to `limit` bytes written
+    /// before the reply arrives are held on the stream and sent once it
+    /// tells of success; writes past that wait for it. The reply is read
+    /// by the first read, or by the write or flush that has to wait. If it
+    /// is a failure, that read or write fails with it, and so does
+    /// everything after; the bytes held are discarded. Splitting the
+    /// stream or taking its inner transport waits for the reply first. Not
+    /// used when falling back to SOCKS4.
+    pub fn optimistic(mut self, limit: usize) -> Self {
+        self.optimistic = Some(limit);
+        self
+    }
+
+    /// Sets `options` on the connection to the proxy before it connects.
+    #[cfg(feature = "net")]
+    pub fn socket_options(mut self, options: SocketOptions) -> Self {
+        self.socket = options;
+        self
+    }
+
+    /// With the `tracing` feature, each call runs in a `socks5_connect`
+    /// span with a child span for every phase of the negotiation.
+    ///
+    /// `dest` takes the same forms as in [`new`].
+    #[cfg(feature = "net")]
+    pub async fn connect<D>(&self, server: impl ToSocketAddrs, dest: D) -> Result<Socks5Stream>
+    where
+        D: TryInto<Addr>,
+        D::Error: Into<Socks5ClientError>,
+    {
+        let dest = dest.try_into().map_err(Into::into)?;
+        let negotiated = self.negotiate(server, &dest);
+        #[cfg(feature = "tracing")]
+        let negotiated = trace::connect(negotiated, &dest);
+        let stream = negotiated.await?;
+        Ok(stream.with_timeouts(self.read_timeout, self.write_timeout))
+    }
+
+    /// Connects to `dest` through a proxy listening on vsock `port` of the
+    /// VM or host `cid`, e.g. from a guest to a proxy on its host. There is
+    /// no SOCKS4 fallback over vsock.
+    #[cfg(all(feature = "vsock", target_os = "linux"))]
+    pub async fn connect_vsock<D>(
+        &self,
+        cid: u32,
+        port: u32,
+        dest: D,
+    ) -> Result<Socks5Stream<tokio_vsock::VsockStream>>
+    where
+        D: TryInto<Addr>,
+        D::Error: Into<Socks5ClientError>,
+    {
+        use tokio_vsock::{VsockAddr, VsockStream};
+
+        let dest = dest.try_into().map_err(Into::into)?;
+        let negotiated = async {
+            let proxy = VsockAddr::new(cid, port);
+            let conn = phase!("vsock_connect", VsockStream::connect(proxy))?;
+            self.establish(conn, &dest).await
+        };
+        #[cfg(feature = "tracing")]
+        let negotiated = trace::connect(negotiated, &dest);
+        let stream = negotiated.await?;
+        Ok(stream.with_timeouts(self.read_timeout, self.write_timeout))
+    }
+
+    /// Connects to `dest` through `conn`, an already connected socket to
+    /// the proxy, e.g. one made by code outside Tokio. The socket is put
+    /// in nonblocking mode. There is no SOCKS4 fallback, as that takes a
+    /// new connection.
+    #[cfg(feature = "net")]
+    pub async fn from_std<D>(&self, conn: std::net::TcpStream, dest: D) -> Result<Socks5Stream>
+    where
+        D: TryInto<Addr>,
+        D::Error: Into<Socks5ClientError>,
+    {
+        let dest = dest.try_into().map_err(Into::into)?;
+        conn.set_nonblocking(true)?;
+        let conn = TcpStream::from_std(conn)?;
+        let negotiated = async {
+            #[cfg(feature = "tracing")]
+            trace::proxy(conn.peer_addr()?);
+            self.establish(conn, &dest).await
+        };
+        #[cfg(feature = "tracing")]
+        let negotiated = trace::connect(negotiated, &dest);
+        let stream = negotiated.await?;
+        Ok(stream.with_timeouts(self.read_timeout, self.write_timeout))
+    }
+
+    /// Connects to `dest` over `conn`, a transport to the proxy opened by
+    /// the caller: a TLS session, a WebSocket, an in-memory pipe. This is
+    /// all there is to the client without the `net` feature. There is no
+    /// SOCKS4 fallback.
+    pub async fn connect_with_stream<S, D>(&self, conn: S, dest: D) -> Result<Socks5Stream<S>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+        D: TryInto<Addr>,
+        D::Error: Into<Socks5ClientError>,
+    {
+        let dest = dest.try_into().map_err(Into::into)?;
+        let negotiated = self.establish(conn, &dest);
+        #[cfg(feature = "tracing")]
+        let negotiated = trace::connect(negotiated, &dest);
+        let stream = negotiated.await?;
+        Ok(stream.with_timeouts(self.read_timeout, self.write_timeout))
+    }
+
+    /// Negotiates a SOCKS5 tunnel to `dest` over `conn`, a fresh connection
+    /// to the proxy.
+    async fn establish<S>(&self, conn: S, dest: &Addr) -> Result<Socks5Stream<S>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let auth = self.auth.clone().unwrap_or(AuthMethod::NoAuth);
+        let client = phase!(
+            "method_negotiation",
+            PendingHandshake(conn).handshake(&auth)
+        )?;
+        let client = phase!("auth", client.authenticate(&auth))?;
+        self.request_connect(client, dest).await
+    }
+
+    /// Sends the CONNECT request, and unless the builder is optimistic,
+    /// waits for the reply.
+    async fn request_connect<S>(
+        &self,
+        client: PendingConnect<S>,
+        dest: &Addr,
+    ) -> Result<Socks5Stream<S>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        match self.optimistic {
+            Some(limit) => {
+                let conn = phase!("connect_request", client.connect_optimistic(dest))?;
+                Ok(Socks5Stream::optimistic(conn, limit))
+            }
+            None => {
+                let conn = phase!("connect_reply", client.connect(dest))?;
+                Ok(Socks5Stream::new(conn, Protocol::Socks5))
+            }
+        }
+    }
+
+    /// Opens a UDP association through the proxy. It lasts as long as the
+    /// returned value.
+    #[cfg(feature = "udp")]
+    pub async fn udp_associate(&self, server: impl ToSocketAddrs) -> Result<Socks5UdpFramed> {
+        let servers: Vec<SocketAddr> = lookup_host(server).await?.collect();
+        let conn = connect_racing(&servers, &self.socket).await?;
+        let auth = self.auth.clone().unwrap_or(AuthMethod::NoAuth);
+        let control = PendingHandshake(conn)
+            .handshake(&auth)
+            .await?
+            .authenticate(&auth)
+            .await?;
+        udp::associate(control).await
+    }
 
-    let client = PendingHandshake(conn);
-    let client = client.handshake(&auth).await?;
-    let client = client.authenticate(&auth).await?;
-    let client = client.connect(dest).await?;
+    #[cfg(feature = "net")]
+    async fn negotiate(&self, server: impl ToSocketAddrs, dest: &Addr) -> Result<Socks5Stream> {
+        let servers: Vec<SocketAddr> = lookup_host(server).await?.collect();
+        let conn = phase!("tcp_connect", connect_racing(&servers, &self.socket))?;
+        #[cfg(feature = "tracing")]
+        trace::proxy(conn.peer_addr()?);
+        let auth = self.auth.clone().unwrap_or(AuthMethod::NoAuth);
 
-    Ok(client)
+        let client = PendingHandshake(conn);
+        let client = match phase!("method_negotiation", client.handshake(&auth)) {
+            Ok(client) => client,
+            Err(e) if self.fallback_socks4 && e.suggests_socks4() => {
+                let conn = phase!("tcp_connect", connect_racing(&servers, &self.socket))?;
+                let user = match &auth {
+                    AuthMethod::UserPass(Some((user, _))) => user.as_str(),
+                    _ => "",
+                };
+                let client = phase!("socks4_connect", socks4::connect(conn, dest, user))?;
+                return Ok(Socks5Stream::new(client, Protocol::Socks4));
+            }
+            Err(e) => return Err(e),
+        };
+        let client = phase!("auth", client.authenticate(&auth))?;
+        self.request_connect(client, dest).await
+    }
+}
+
+/// How long a connection attempt to the proxy gets before the next
+/// address is tried alongside it (RFC 8305).
+#[cfg(feature = "net")]
+const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Connects to whichever of `addrs` answers first, alternating address
+/// families and starting a new attempt every [`ATTEMPT_DELAY`] or as soon
+/// as one fails. The losing attempts are cancelled.
+#[cfg(feature = "net")]
+async fn connect_racing(addrs: &[SocketAddr], options: &SocketOptions) -> io::Result<TcpStream> {
+    let connect = |addr| {
+        let options = options.clone();
+        async move { socket::connect(addr, None, &options).await }
+    };
+    let mut pending = interleave_families(addrs).into_iter();
+    let mut attempts = JoinSet::new();
+    let mut last = io::Error::new(io::ErrorKind::NotFound, "no address to connect to");
+    loop {
+        if attempts.is_empty() {
+            match pending.next() {
+                Some(addr) => attempts.spawn(connect(addr)),
+                None => return Err(last),
+            };
+        }
+        tokio::select! {
+            Some(done) = attempts.join_next() => match done.map_err(io::Error::other)? {
+                Ok(conn) => return Ok(conn),
+                Err(e) => {
+                    last = e;
+                    if let Some(addr) = pending.next() {
+                        attempts.spawn(connect(addr));
+                    }
+                }
+            },
+            _ = tokio::time::sleep(ATTEMPT_DELAY), if pending.len() > 0 => {
+                if let Some(addr) = pending.next() {
+                    attempts.spawn(connect(addr));
+                }
+            }
+        }
+    }
+}
+
+/// Reorders `addrs` to alternate between families, starting with the
+/// family of the first one.
+#[cfg(feature = "net")]
+fn interleave_families(addrs: &[SocketAddr]) -> Vec<SocketAddr> {
+    let first_v6 = addrs.first().is_some_and(SocketAddr::is_ipv6);
+    let (mut preferred, mut other): (Vec<_>, Vec<_>) =
+        addrs.iter().partition(|addr| addr.is_ipv6() == first_v6);
+    let mut ordered = Vec::with_capacity(addrs.len());
+    preferred.reverse();
+    other.reverse();
+    while let Some(addr) = preferred.pop() {
+        ordered.push(addr);
+        ordered.extend(other.pop());
+    }
+    ordered.extend(other.into_iter().rev());
+    ordered
 }
 
-impl_deref!(PendingHandshake, TcpStream);
-impl PendingHandshake {
+impl_deref!(PendingHandshake<S>, S);
+impl<S: AsyncRead + AsyncWrite + Unpin> PendingHandshake<S> {
     #[inline]
-    async fn handshake(mut self, method: &AuthMethod) -> Result<PendingAuthenticate> {
+    async fn handshake(mut self, method: &AuthMethod) -> Result<PendingAuthenticate<S>> {
         let msg: &[u8] = &[SOCKS_VER, 0x01, method.to_code()];
         self.write_all(msg).await?;
         self.flush().await?;
@@ -38,93 +461,111 @@ impl PendingHandshake {
         self.read_exact(&mut buffer).await?;
 
         if buffer[0] != SOCKS_VER {
-            return Err(io::Error::new(
-                io::ErrorKind::ConnectionAborted,
-                "unsupported protocol",
-            ));
+            return Err(Socks5ClientError::UnexpectedVersion(buffer[0]));
         }
 
-        let auth = AuthMethod::from_code(buffer[1])?;
-
-        if let AuthMethod::NoAvailable = auth {
-            Err(io::Error::new(
-                io::ErrorKind::ConnectionRefused,
-                "no supported authenticate method available",
-            ))
-        } else if auth.to_code() != method.to_code() {
-            Err(io::Error::new(
-                io::ErrorKind::ConnectionAborted,
-                "unsupported protocol",
-            ))
+        let selected = buffer[1];
+        if selected == AuthMethod::NoAvailable.to_code() {
+            Err(Socks5ClientError::NoAcceptableAuth)
+        } else if selected != method.to_code() {
+            Err(Socks5ClientError::UnexpectedMethod {
+                offered: vec![method.to_code()],
+                selected,
+            })
         } else {
             Ok(PendingAuthenticate(self.0))
         }
     }
 }
 
-impl_deref!(PendingAuthenticate, TcpStream);
-impl PendingAuthenticate {
+impl_deref!(PendingAuthenticate<S>, S);
+impl<S: AsyncRead + AsyncWrite + Unpin> PendingAuthenticate<S> {
     #[inline]
-    async fn authenticate(self, auth: &AuthMethod) -> Result<PendingConnect> {
+    async fn authenticate(self, auth: &AuthMethod) -> Result<PendingConnect<S>> {
         match auth {
             AuthMethod::NoAuth => Ok(PendingConnect(self.0)),
-            _ => Err(io::Error::new(
-                io::ErrorKind::Other,
-                format!("authenticate method {:?} not implemented", &auth),
-            )),
+            _ => Err(Socks5ClientError::UnsupportAuth(auth.to_code())),
         }
     }
 }
 
-impl_deref!(PendingConnect, TcpStream);
-impl PendingConnect {
+impl_deref!(PendingConnect<S>, S);
+impl<S: AsyncRead + AsyncWrite + Unpin> PendingConnect<S> {
     #[inline]
-    async fn connect(mut self, dest: &Addr) -> Result<TcpStream> {
+    async fn connect(self, dest: &Addr) -> Result<S> {
+        let (conn, _) = self.request(SOCKS_COMMAND_CONNECT, dest).await?;
+        Ok(conn)
+    }
+
+    /// Sends the CONNECT request and returns the connection without
+    /// waiting for the reply.
+    async fn connect_optimistic(mut self, dest: &Addr) -> Result<S> {
+        self.send(SOCKS_COMMAND_CONNECT, dest).await?;
+        Ok(self.0)
+    }
+
+    async fn send(&mut self, command: u8, dest: &Addr) -> Result<()> {
         let mut buffer = [0u8; 4 + 255 + 2];
         let mut request = Buffer::from(&mut buffer);
-        request.extend(&[SOCKS_RSV, SOCKS_COMMAND_CONNECT, SOCKS_RSV]);
+        request.extend(&[SOCKS_VER, command, SOCKS_RSV]);
 
         parse_dest(&mut request, dest)?;
 
         self.write_all(request.content()).await?;
         self.flush().await?;
+        Ok(())
+    }
+
+    /// Sends a request and returns the connection with the bound address
+    /// from the reply.
+    async fn request(mut self, command: u8, dest: &Addr) -> Result<(S, Addr)> {
+        self.send(command, dest).await?;
 
+        let mut buffer = [0u8; 4 + 255 + 2];
         let header: &mut [u8] = &mut buffer[..4];
 
         self.read_exact(header).await?;
 
-        if header[0] != SOCKS_VER || header[02] != SOCKS_RSV {
-            return Err(io::Error::new(
-                io::ErrorKind::ConnectionAborted,
-                "unsupported protocol",
-            ));
+        if header[0] != SOCKS_VER || header[2] != SOCKS_RSV {
+            return Err(Socks5ClientError::UnknowProtocol);
         }
         if header[1] != SocksError::SUCCESS as u8 {
-            return Err(SocksError::from(header[1]).into());
+            return Err(Socks5ClientError::Rejected(
+                header[1],
+                SocksError::from(header[1]),
+            ));
         }
 
-        self.extract_address(header[3], &mut buffer).await?;
+        let bound = self.extract_address(header[3], &mut buffer).await?;
 
-        Ok(self.0)
+        Ok((self.0, bound))
     }
 
-    async fn extract_address(&mut self, addr_type: u8, buffer: &mut [u8]) -> Result<()> {
-        match addr_type {
-            SOCKS_ADDR_IPV4 => self.read_exact(&mut buffer[..4 + 2]).await?,
-            SOCKS_ADDR_IPV6 => self.read_exact(&mut buffer[..16 + 2]).await?,
+    async fn extract_address(&mut self, addr_type: u8, buffer: &mut [u8]) -> Result<Addr> {
+        let bound = match addr_type {
+            SOCKS_ADDR_IPV4 => {
+                self.read_exact(&mut buffer[..4 + 2]).await?;
+                let ip: [u8; 4] = buffer[..4].try_into().unwrap();
+                let port = u16::from_be_bytes([buffer[4], buffer[5]]);
+                Addr::SocketAddr(SocketAddr::new(ip.into(), port))
+            }
+            SOCKS_ADDR_IPV6 => {
+                self.read_exact(&mut buffer[..16 + 2]).await?;
+                let ip: [u8; 16] = buffer[..16].try_into().unwrap();
+                let port = u16::from_be_bytes([buffer[16], buffer[17]]);
+                Addr::SocketAddr(SocketAddr::new(ip.into(), port))
+            }
             SOCKS_ADDR_DOMAINNAME => {
                 self.read_exact(&mut buffer[..1]).await?;
                 let len = buffer[0] as usize;
-                self.read_exact(&mut buffer[..(len + 2)]).await?
-            }
-            _ => {
-                return Err(io::Error::new(
-                    io::ErrorKind::ConnectionAborted,
-                    "unsupported address type",
-                ))
+                self.read_exact(&mut buffer[..(len + 2)]).await?;
+                let host = String::from_utf8_lossy(&buffer[..len]);
+                let port = u16::from_be_bytes([buffer[len], buffer[len + 1]]);
+                Addr::HostnamePort(format!("{}:{}", host, port))
             }
+            _ => return Err(Socks5ClientError::UnknowAddrType(addr_type)),
         };
-        Ok(())
+        Ok(bound)
     }
 }
 
@@ -137,7 +578,7 @@ macro_rules! write_addr_binary {
 }
 
 #[inline]
-fn parse_dest(request: &mut Buffer, dest: &Addr) -> Result<()> {
+pub(crate) fn parse_dest(request: &mut Buffer, dest: &Addr) -> Result<()> {
     match dest {
         Addr::SocketAddr(addr) => {
             match addr {
@@ -146,30 +587,139 @@ fn parse_dest(request: &mut Buffer, dest: &Addr) -> Result<()> {
             };
         }
         Addr::HostnamePort(hostname_port) => {
+            let (hostname, port) = split_hostname_port(hostname_port)?;
+            let hostname = hostname.as_str().as_bytes();
             request.push(SOCKS_ADDR_DOMAINNAME);
-            let mut hostname_port = hostname_port.split(":");
-            let parse_err =
-                io::Error::new(io::ErrorKind::InvalidInput, "bad pattern in hostname:port");
-            let hostname = hostname_port.next();
-            let port = hostname_port.next();
-            let none = hostname_port.next();
-
-            if let (Some(hostname), Some(port), None) = (hostname, port, none) {
-                let hostname = hostname.as_bytes();
-                if hostname.len() > u8::MAX as usize {
-                    return Err(io::Error::new(
-                        io::ErrorKind::InvalidInput,
-                        "hostname too long",
-                    ));
-                }
-                request.push(hostname.len() as u8);
-                request.extend(hostname);
-                let port = port.parse::<u16>().map_err(|_| parse_err)?;
-                request.extend(&port.to_be_bytes());
-            } else {
-                return Err(parse_err);
-            }
+            request.push(hostname.len() as u8);
+            request.extend(hostname);
+            request.extend(&port.to_be_bytes());
         }
     }
     Ok(())
 }
+
+/// Parses `"ip:port"`, `"[ipv6]:port"` or `"hostname:port"`.
+impl TryFrom<&str> for Addr {
+    type Error = Socks5ClientError;
+
+    fn try_from(addr: &str) -> Result<Addr> {
+        if let Ok(addr) = addr.parse() {
+            return Ok(Addr::SocketAddr(addr));
+        }
+        split_hostname_port(addr)?;
+        Ok(Addr::HostnamePort(addr.to_owned()))
+    }
+}
+
+impl TryFrom<String> for Addr {
+    type Error = Socks5ClientError;
+
+    fn try_from(addr: String) -> Result<Addr> {
+        Addr::try_from(addr.as_str())
+    }
+}
+
+fn split_hostname_port(hostname_port: &str) -> Result<(Hostname, u16)> {
+    let mut hostname_port = hostname_port.split(':');
+    let hostname = hostname_port.next();
+    let port = hostname_port.next();
+    let none = hostname_port.next();
+
+    if let (Some(hostname), Some(port), None) = (hostname, port, none) {
+        let hostname = Hostname::new(hostname)?;
+        let port = port
+            .parse::<u16>()
+            .map_err(|_| Socks5ClientError::BadHostnamePort)?;
+        Ok((hostname, port))
+    } else {
+        Err(Socks5ClientError::BadHostnamePort)
+    }
+}
+
+#[cfg(all(test, feature = "net"))]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn reads_the_address_bound_for_a_connect() {
+        let server = crate::server::new("127.0.0.1:0".parse().unwrap(), None).unwrap();
+        let proxy = server.local_addr().unwrap();
+        tokio::spawn(server.run());
+        tokio::task::yield_now().await;
+
+        for dest in ["127.0.0.1:0", "[::1]:0"] {
+            let dest = TcpListener::bind(dest).await.unwrap();
+            let conn = TcpStream::connect(proxy).await.unwrap();
+            let auth = AuthMethod::NoAuth;
+            let client = PendingHandshake(conn).handshake(&auth).await.unwrap();
+            let client = client.authenticate(&auth).await.unwrap();
+            let dest_addr = Addr::SocketAddr(dest.local_addr().unwrap());
+            let (_conn, bound) = client
+                .request(SOCKS_COMMAND_CONNECT, &dest_addr)
+                .await
+                .unwrap();
+            let (_upstream, outbound) = dest.accept().await.unwrap();
+            assert_eq!(bound, Addr::SocketAddr(outbound));
+        }
+    }
+
+    #[tokio::test]
+    async fn connects_over_a_std_socket() {
+        let server = crate::server::new("127.0.0.1:0".parse().unwrap(), None).unwrap();
+        let proxy = server.local_addr().unwrap();
+        tokio::spawn(server.run());
+        tokio::task::yield_now().await;
+
+        let dest = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let conn = std::net::TcpStream::connect(proxy).unwrap();
+        let mut stream = Builder::new()
+            .from_std(conn, dest.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (mut upstream, _) = dest.accept().await.unwrap();
+        stream.write_all(b"ping").await.unwrap();
+        let mut buf = [0u8; 4];
+        upstream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+        upstream.write_all(b"pong").await.unwrap();
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"pong");
+    }
+
+    #[tokio::test]
+    async fn connects_to_destinations_given_as_strings() {
+        let server = crate::server::new("127.0.0.1:0".parse().unwrap(), None).unwrap();
+        let proxy = server.local_addr().unwrap();
+        tokio::spawn(server.run());
+        tokio::task::yield_now().await;
+
+        let dest = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = dest.local_addr().unwrap().port();
+        let given = format!("127.0.0.1:{}", port);
+        new(proxy, given.as_str(), None).await.unwrap();
+        new(proxy, given, None).await.unwrap();
+        for _ in 0..2 {
+            dest.accept().await.unwrap();
+        }
+
+        for malformed in [
+            "127.0.0.1",
+            "example.com",
+            "example.com:http",
+            "a:1:2",
+            ":80",
+        ] {
+            let e = new(proxy, malformed, None).await.unwrap_err();
+            assert!(
+                matches!(
+                    e,
+                    Socks5ClientError::BadHostnamePort | Socks5ClientError::InvalidHostname(_)
+                ),
+                "{}: {:?}",
+                malformed,
+                e
+            );
+        }
+    }
+}
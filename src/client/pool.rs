@@ -0,0 +1,218 @@
+use super::{Builder, Result, Socks5ClientError, Socks5Stream};
+use crate::utils::*;
+use log::warn;
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// A proxy server in a [`Socks5Pool`].
+#[derive(Debug, Clone)]
+pub struct ProxyEndpoint {
+    server: String,
+    weight: u32,
+}
+
+impl ProxyEndpoint {
+    /// `server` is written as `host:port`. Endpoints with a higher `weight`
+    /// are picked proportionally more often by weighted selection.
+    pub fn new(server: impl Into<String>, weight: u32) -> Self {
+        ProxyEndpoint {
+            server: server.into(),
+            weight,
+        }
+    }
+}
+
+/// How a pool picks the endpoint to try first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Selection {
+    /// At random, in proportion to the endpoint weights.
+    WeightedRandom,
+    /// In turn, ignoring weights.
+    RoundRobin,
+}
+
+/// Counters and health of one endpoint, as reported by
+/// [`Socks5Pool::endpoints`].
+#[derive(Debug, Clone)]
+pub struct EndpointStats {
+    pub server: String,
+    pub successes: u64,
+    pub failures: u64,
+    /// Whether the endpoint is out of its cooldown.
+    pub healthy: bool,
+}
+
+#[derive(Debug, Default)]
+struct Health {
+    successes: u64,
+    failures: u64,
+    failures_in_row: u32,
+    cooldown_until: Option<Instant>,
+}
+
+struct Inner {
+    endpoints: Vec<ProxyEndpoint>,
+    health: Mutex<Vec<Health>>,
+    builder: Builder,
+    selection: Selection,
+    cooldown: Duration,
+    max_cooldown: Duration,
+    next: AtomicUsize,
+}
+
+/// A long-lived, shared set of proxy endpoints that learns which of them
+/// work.
+///
+/// An endpoint that fails to negotiate is put in a cooldown, which doubles
+/// with every further failure in a row, and the connect is retried on
+/// another endpoint. Once the cooldown ends, the endpoint is tried again;
+/// a success clears its record. A proxy rejecting the request itself, e.g.
+/// because the destination is unreachable, does not count against it.
+#[derive(Clone)]
+pub struct Socks5Pool {
+    inner: Arc<Inner>,
+}
+
+impl Socks5Pool {
+    /// Negotiates with `builder` settings, picks weighted at random, and
+    /// cools failing endpoints down from 1s up to 5 minutes.
+    pub fn new(endpoints: Vec<ProxyEndpoint>, builder: Builder) -> Self {
+        let health = endpoints.iter().map(|_| Health::default()).collect();
+        Socks5Pool {
+            inner: Arc::new(Inner {
+                endpoints,
+                health: Mutex::new(health),
+                builder,
+                selection: Selection::WeightedRandom,
+                cooldown: Duration::from_secs(1),
+                max_cooldown: Duration::from_secs(300),
+                next: AtomicUsize::new(0),
+            }),
+        }
+    }
+
+    fn inner_mut(&mut self) -> &mut Inner {
+        Arc::get_mut(&mut self.inner).expect("pool configured after being shared")
+    }
+
+    pub fn selection(mut self, selection: Selection) -> Self {
+        self.inner_mut().selection = selection;
+        self
+    }
+
+    /// The cooldown after a first failure, and the most it may grow to.
+    pub fn cooldown(mut self, first: Duration, max: Duration) -> Self {
+        let inner = self.inner_mut();
+        inner.cooldown = first;
+        inner.max_cooldown = max;
+        self
+    }
+
+    /// Connects to `dest` through the pool, trying healthy endpoints first
+    /// and cooling ones only as a last resort.
+    pub async fn connect(&self, dest: &Addr) -> Result<Socks5Stream> {
+        let mut last = Socks5ClientError::NoEndpoint;
+        for i in self.candidates() {
+            let server = self.inner.endpoints[i].server.as_str();
+            match self.inner.builder.connect(server, dest).await {
+                Ok(stream) => {
+                    self.record(i, true);
+                    return Ok(stream);
+                }
+                Err(e @ Socks5ClientError::Rejected(..)) => {
+                    self.record(i, true);
+                    return Err(e);
+                }
+                Err(e) => {
+                    warn!("proxy {} failed: {}", server, e);
+                    self.record(i, false);
+                    last = e;
+                }
+            }
+        }
+        Err(last)
+    }
+
+    pub fn endpoints(&self) -> Vec<EndpointStats> {
+        let now = Instant::now();
+        let health = self.inner.health.lock().unwrap();
+        self.inner
+            .endpoints
+            .iter()
+            .zip(health.iter())
+            .map(|(endpoint, health)| EndpointStats {
+                server: endpoint.server.clone(),
+                successes: health.successes,
+                failures: health.failures,
+                healthy: health.cooldown_until.is_none_or(|until| until <= now),
+            })
+            .collect()
+    }
+
+    /// Endpoint indices in the order they should be tried.
+    fn candidates(&self) -> Vec<usize> {
+        let now = Instant::now();
+        let health = self.inner.health.lock().unwrap();
+        let (mut healthy, mut cooling): (Vec<usize>, Vec<usize>) = (0..health.len())
+            .partition(|&i| health[i].cooldown_until.is_none_or(|until| until <= now));
+        cooling.sort_by_key(|&i| health[i].cooldown_until);
+
+        match self.inner.selection {
+            Selection::RoundRobin if !healthy.is_empty() => {
+                let start = self.inner.next.fetch_add(1, Ordering::Relaxed) % healthy.len();
+                healthy.rotate_left(start);
+            }
+            Selection::RoundRobin => {}
+            Selection::WeightedRandom => {
+                // Draw the remaining endpoints one by one, each weighted.
+                let mut ordered = Vec::with_capacity(healthy.len());
+                while !healthy.is_empty() {
+                    let weight = |i: usize| self.inner.endpoints[i].weight as u64;
+                    let total: u64 = healthy.iter().map(|&i| weight(i)).sum();
+                    let at = match total {
+                        0 => 0,
+                        total => {
+                            let mut pick = random_u64() % total;
+                            healthy
+                                .iter()
+                                .position(|&i| {
+                                    let hit = pick < weight(i);
+                                    pick = pick.saturating_sub(weight(i));
+                                    hit
+                                })
+                                .unwrap_or(0)
+                        }
+                    };
+                    ordered.push(healthy.remove(at));
+                }
+                healthy = ordered;
+            }
+        }
+        healthy.extend(cooling);
+        healthy
+    }
+
+    fn record(&self, i: usize, success: bool) {
+        let mut health = self.inner.health.lock().unwrap();
+        let health = &mut health[i];
+        if success {
+            health.successes += 1;
+            health.failures_in_row = 0;
+            health.cooldown_until = None;
+        } else {
+            health.failures += 1;
+            let cooldown = self
+                .inner
+                .cooldown
+                .saturating_mul(1 << health.failures_in_row.min(16))
+                .min(self.inner.max_cooldown);
+            health.failures_in_row += 1;
+            health.cooldown_until = Some(Instant::now() + cooldown);
+        }
+    }
+}
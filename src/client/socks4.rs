@@ -0,0 +1,54 @@
+use super::{split_hostname_port, Result, Socks5ClientError};
+use crate::utils::*;
+use std::net::SocketAddr;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+const SOCKS4_VER: u8 = 0x04;
+const SOCKS4_GRANTED: u8 = 0x5A;
+
+/// Runs a SOCKS4 CONNECT, using the 4a extension for hostnames.
+pub(crate) async fn connect(mut conn: TcpStream, dest: &Addr, user: &str) -> Result<TcpStream> {
+    let mut buffer = [0u8; 8 + 255 + 1 + 255 + 1];
+    let mut request = Buffer::from(&mut buffer);
+    request.extend(&[SOCKS4_VER, SOCKS_COMMAND_CONNECT]);
+
+    let user = user.as_bytes();
+    if user.len() > u8::MAX as usize {
+        return Err(Socks5ClientError::UsernameTooLong);
+    }
+    match dest {
+        Addr::SocketAddr(SocketAddr::V4(v4)) => {
+            request.extend(&v4.port().to_be_bytes());
+            request.extend(&v4.ip().octets());
+            request.extend(user);
+            request.push(0);
+        }
+        Addr::SocketAddr(SocketAddr::V6(_)) => return Err(Socks5ClientError::Socks4Ipv6),
+        Addr::HostnamePort(hostname_port) => {
+            let (hostname, port) = split_hostname_port(hostname_port)?;
+            // 0.0.0.x with a non-zero x tells the server a hostname follows.
+            request.extend(&port.to_be_bytes());
+            request.extend(&[0, 0, 0, 1]);
+            request.extend(user);
+            request.push(0);
+            request.extend(hostname.as_str().as_bytes());
+            request.push(0);
+        }
+    }
+
+    conn.write_all(request.content()).await?;
+    conn.flush().await?;
+
+    let mut reply = [0u8; 8];
+    conn.read_exact(&mut reply).await?;
+    if reply[0] != 0x00 {
+        return Err(Socks5ClientError::UnknowProtocol);
+    }
+    if reply[1] != SOCKS4_GRANTED {
+        return Err(Socks5ClientError::Socks4Rejected(reply[1]));
+    }
+    Ok(conn)
+}
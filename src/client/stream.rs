@@ -0,0 +1,573 @@
+use super::{Protocol, Socks5ClientError};
+use crate::utils::*;
+#[cfg(feature = "net")]
+use std::net::SocketAddr;
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    task::{ready, Context, Poll},
+    time::{Duration, Instant},
+};
+#[cfg(feature = "net")]
+use tokio::net::{
+    tcp::{OwnedReadHalf, OwnedWriteHalf, ReadHalf, WriteHalf},
+    TcpStream,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::Sleep,
+};
+
+/// A tunnel through a SOCKS5 proxy, ready to carry application data.
+///
+/// The stream counts the bytes it carries once the negotiation is done;
+/// the negotiation itself and traffic through [`split`](Self::split) or
+/// [`into_split`](Self::into_split) halves are not counted.
+#[derive(Debug)]
+pub struct Socks5Stream<#[cfg(feature = "net")] S = TcpStream, #[cfg(not(feature = "net"))] S> {
+    inner: S,
+    protocol: Protocol,
+    negotiated_at: Instant,
+    bytes_read: u64,
+    bytes_written: u64,
+    read_timeout: Option<Timeout>,
+    write_timeout: Option<Timeout>,
+    reply: Option<Reply>,
+    /// Bytes written before the reply, sent once it tells of success.
+    held: Vec<u8>,
+}
+
+/// The CONNECT reply of an optimistic tunnel, until it is read.
+#[derive(Debug)]
+enum Reply {
+    /// Not read in full yet; up to `limit` bytes may be held until it is.
+    Awaiting {
+        head: Box<[u8; 4 + 1 + 255 + 2]>,
+        filled: usize,
+        limit: usize,
+    },
+    /// The proxy refused the request, or the reply was broken.
+    Failed(io::ErrorKind, String),
+}
+
+/// The length of the reply starting with `head`, as far as `head` tells,
+/// or why it is a failure.
+fn reply_len(head: &[u8]) -> Result<usize, Socks5ClientError> {
+    if head.len() >= 2 {
+        if head[0] != SOCKS_VER {
+            return Err(Socks5ClientError::UnknowProtocol);
+        }
+        if head[1] != SocksError::SUCCESS as u8 {
+            return Err(Socks5ClientError::Rejected(
+                head[1],
+                SocksError::from(head[1]),
+            ));
+        }
+    }
+    if head.len() < 4 {
+        return Ok(4);
+    }
+    if head[2] != SOCKS_RSV {
+        return Err(Socks5ClientError::UnknowProtocol);
+    }
+    match head[3] {
+        SOCKS_ADDR_IPV4 => Ok(4 + 4 + 2),
+        SOCKS_ADDR_IPV6 => Ok(4 + 16 + 2),
+        SOCKS_ADDR_DOMAINNAME => match head.get(4) {
+            Some(len) => Ok(4 + 1 + *len as usize + 2),
+            None => Ok(5),
+        },
+        addr_type => Err(Socks5ClientError::UnknowAddrType(addr_type)),
+    }
+}
+
+/// Fails an operation that makes no progress for `duration`. The timer is
+/// armed when the operation first returns pending and disarmed on progress.
+#[derive(Debug)]
+struct Timeout {
+    duration: Duration,
+    sleep: Pin<Box<Sleep>>,
+    armed: bool,
+}
+
+impl Timeout {
+    fn new(duration: Duration) -> Self {
+        Timeout {
+            duration,
+            sleep: Box::pin(tokio::time::sleep(duration)),
+            armed: false,
+        }
+    }
+
+    fn poll<T>(&mut self, cx: &mut Context<'_>, poll: Poll<io::Result<T>>) -> Poll<io::Result<T>> {
+        if poll.is_ready() {
+            self.armed = false;
+            return poll;
+        }
+        if !self.armed {
+            let deadline = tokio::time::Instant::now() + self.duration;
+            self.sleep.as_mut().reset(deadline);
+            self.armed = true;
+        }
+        match self.sleep.as_mut().poll(cx) {
+            Poll::Ready(()) => {
+                self.armed = false;
+                Poll::Ready(Err(io::ErrorKind::TimedOut.into()))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+fn poll_timeout<T>(
+    timeout: &mut Option<Timeout>,
+    cx: &mut Context<'_>,
+    poll: Poll<io::Result<T>>,
+) -> Poll<io::Result<T>> {
+    match timeout {
+        Some(timeout) => timeout.poll(cx, poll),
+        None => poll,
+    }
+}
+
+impl<S> Socks5Stream<S> {
+    pub(crate) fn new(inner: S, protocol: Protocol) -> Self {
+        Socks5Stream {
+            inner,
+            protocol,
+            negotiated_at: Instant::now(),
+            bytes_read: 0,
+            bytes_written: 0,
+            read_timeout: None,
+            write_timeout: None,
+            reply: None,
+            held: Vec::new(),
+        }
+    }
+
+    /// A tunnel whose CONNECT request is sent but whose reply is still to
+    /// be read, holding up to `limit` bytes written before it.
+    pub(crate) fn optimistic(inner: S, limit: usize) -> Self {
+        let mut stream = Socks5Stream::new(inner, Protocol::Socks5);
+        stream.reply = Some(Reply::Awaiting {
+            head: Box::new([0; 4 + 1 + 255 + 2]),
+            filled: 0,
+            limit,
+        });
+        stream
+    }
+
+    pub(crate) fn with_timeouts(mut self, read: Option<Duration>, write: Option<Duration>) -> Self {
+        self.read_timeout = read.map(Timeout::new);
+        self.write_timeout = write.map(Timeout::new);
+        self
+    }
+
+    /// Bytes read from the tunnel since the negotiation.
+    pub fn bytes_read(&self) -> u64 {
+        self.bytes_read
+    }
+
+    /// Bytes written to the tunnel since the negotiation.
+    pub fn bytes_written(&self) -> u64 {
+        self.bytes_written
+    }
+
+    /// When the negotiation finished and the tunnel became usable.
+    pub fn negotiated_at(&self) -> Instant {
+        self.negotiated_at
+    }
+
+    /// The protocol version the tunnel was actually negotiated with.
+    pub fn protocol(&self) -> Protocol {
+        self.protocol
+    }
+}
+
+#[cfg(feature = "net")]
+impl Socks5Stream<TcpStream> {
+    /// Address of the proxy this stream is connected to, not the one of
+    /// the tunneled destination.
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.peer_addr()
+    }
+
+    /// Local address of the connection to the proxy.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    /// Splits the stream into borrowed read and write halves, once the
+    /// reply of an optimistic stream is confirmed and the bytes held for
+    /// it are sent.
+    pub async fn split(&mut self) -> io::Result<(ReadHalf<'_>, WriteHalf<'_>)> {
+        self.settle().await?;
+        Ok(self.inner.split())
+    }
+
+    /// Splits the stream into owned read and write halves, e.g. to drive
+    /// them from separate tasks, once settled as for [`split`](Self::split).
+    pub async fn into_split(mut self) -> io::Result<(OwnedReadHalf, OwnedWriteHalf)> {
+        self.settle().await?;
+        Ok(self.inner.into_split())
+    }
+
+    /// Deregisters the tunnel from the runtime and returns it as a std
+    /// socket, e.g. for blocking code or to pass on its raw descriptor,
+    /// once settled as for [`split`](Self::split). It stays in the
+    /// nonblocking mode Tokio uses, so blocking reads and writes need
+    /// `set_nonblocking(false)` first. Timeouts set on the builder don't
+    /// apply to it.
+    pub async fn into_std(mut self) -> io::Result<std::net::TcpStream> {
+        self.settle().await?;
+        self.inner.into_std()
+    }
+}
+
+impl<S> AsRef<S> for Socks5Stream<S> {
+    fn as_ref(&self) -> &S {
+        &self.inner
+    }
+}
+
+impl<S> AsMut<S> for Socks5Stream<S> {
+    fn as_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+}
+
+impl<S: AsyncRead + Unpin> Socks5Stream<S> {
+    /// Waits for the CONNECT reply of a stream from an
+    /// [`optimistic`](super::Builder::optimistic) builder, failing if the
+    /// proxy refused. Returns right away once the reply has been read, and
+    /// for every other stream. Bytes held for the reply go out with the
+    /// next write or flush.
+    pub async fn confirm(&mut self) -> io::Result<()> {
+        std::future::poll_fn(|cx| self.poll_reply(cx)).await
+    }
+
+    /// Reads as much of the pending reply as is available.
+    fn poll_reply(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        loop {
+            let (head, filled) = match &mut self.reply {
+                None => return Poll::Ready(Ok(())),
+                Some(Reply::Failed(kind, reason)) => {
+                    return Poll::Ready(Err(io::Error::new(*kind, reason.clone())))
+                }
+                Some(Reply::Awaiting { head, filled, .. }) => (head, filled),
+            };
+            let len = match reply_len(&head[..*filled]) {
+                Ok(len) if len == *filled => {
+                    self.reply = None;
+                    return Poll::Ready(Ok(()));
+                }
+                Ok(len) => len,
+                Err(e) => {
+                    let e = io::Error::from(e);
+                    self.reply = Some(Reply::Failed(e.kind(), e.to_string()));
+                    self.held = Vec::new();
+                    return Poll::Ready(Err(e));
+                }
+            };
+            let mut buf = ReadBuf::new(&mut head[*filled..len]);
+            ready!(Pin::new(&mut self.inner).poll_read(cx, &mut buf))?;
+            match buf.filled().len() {
+                0 => return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into())),
+                n => *filled += n,
+            }
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Socks5Stream<S> {
+    /// Returns the underlying transport to the proxy, once settled as for
+    /// [`split`](Self::split).
+    pub async fn into_inner(mut self) -> io::Result<S> {
+        self.settle().await?;
+        Ok(self.inner)
+    }
+
+    /// Waits for the reply, then sends the bytes held for it.
+    async fn settle(&mut self) -> io::Result<()> {
+        self.confirm().await?;
+        std::future::poll_fn(|cx| self.poll_release(cx)).await
+    }
+
+    /// Sends the bytes held for the reply, once it told of success.
+    fn poll_release(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.held.is_empty() {
+            match ready!(Pin::new(&mut self.inner).poll_write(cx, &self.held))? {
+                0 => return Poll::Ready(Err(io::ErrorKind::WriteZero.into())),
+                n => drop(self.held.drain(..n)),
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    /// Writes what it can of `buf`: held while the reply is pending, room
+    /// permitting, and once it succeeded, right behind the bytes held.
+    fn poll_send(&mut self, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.poll_reply(cx) {
+            Poll::Ready(Ok(())) => {
+                ready!(self.poll_release(cx))?;
+                Pin::new(&mut self.inner).poll_write(cx, buf)
+            }
+            Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+            Poll::Pending => match &self.reply {
+                Some(Reply::Awaiting { limit, .. }) if self.held.len() < *limit => {
+                    let n = buf.len().min(limit - self.held.len());
+                    self.held.extend_from_slice(&buf[..n]);
+                    Poll::Ready(Ok(n))
+                }
+                _ => Poll::Pending,
+            },
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for Socks5Stream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        let filled = buf.filled().len();
+        // What the destination answers may wait on the bytes held.
+        let poll = match this.poll_reply(cx) {
+            Poll::Ready(Ok(())) => match this.poll_release(cx) {
+                Poll::Ready(Ok(())) => Pin::new(&mut this.inner).poll_read(cx, buf),
+                poll => poll,
+            },
+            poll => poll,
+        };
+        let poll = poll_timeout(&mut this.read_timeout, cx, poll);
+        if let Poll::Ready(Ok(())) = poll {
+            this.bytes_read += (buf.filled().len() - filled) as u64;
+        }
+        poll
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for Socks5Stream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        let poll = this.poll_send(cx, buf);
+        let poll = poll_timeout(&mut this.write_timeout, cx, poll);
+        if let Poll::Ready(Ok(n)) = poll {
+            this.bytes_written += n as u64;
+        }
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        let poll = match this.poll_reply(cx) {
+            Poll::Ready(Ok(())) => match this.poll_release(cx) {
+                Poll::Ready(Ok(())) => Pin::new(&mut this.inner).poll_flush(cx),
+                poll => poll,
+            },
+            poll => poll,
+        };
+        poll_timeout(&mut this.write_timeout, cx, poll)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        // Held bytes go out before the shutdown, but only if the reply
+        // tells of success.
+        let poll = match this.poll_reply(cx) {
+            Poll::Ready(Ok(())) => match this.poll_release(cx) {
+                Poll::Ready(Ok(())) => Pin::new(&mut this.inner).poll_shutdown(cx),
+                poll => poll,
+            },
+            Poll::Ready(Err(_)) => Pin::new(&mut this.inner).poll_shutdown(cx),
+            Poll::Pending => Poll::Pending,
+        };
+        poll_timeout(&mut this.write_timeout, cx, poll)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        if self.reply.is_some() || !self.held.is_empty() {
+            let buf = bufs.iter().find(|buf| !buf.is_empty());
+            return self.poll_write(cx, buf.map_or(&[][..], |buf| buf));
+        }
+        let poll = Pin::new(&mut self.inner).poll_write_vectored(cx, bufs);
+        let poll = poll_timeout(&mut self.write_timeout, cx, poll);
+        if let Poll::Ready(Ok(n)) = poll {
+            self.bytes_written += n as u64;
+        }
+        poll
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+}
+
+#[cfg(all(test, feature = "net"))]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    const SUCCESS: [u8; 10] = [SOCKS_VER, 0, SOCKS_RSV, SOCKS_ADDR_IPV4, 0, 0, 0, 0, 0, 0];
+
+    #[tokio::test]
+    async fn holds_writes_until_the_reply() {
+        let (conn, mut proxy) = tokio::io::duplex(64);
+        let mut stream = Socks5Stream::optimistic(conn, 4);
+        assert_eq!(stream.write(b"hello").await.unwrap(), 4);
+        let mut buf = [0u8; 4];
+        let early = tokio::time::timeout(Duration::from_millis(50), proxy.read(&mut buf)).await;
+        assert!(early.is_err(), "nothing goes out before the reply");
+
+        proxy.write_all(&SUCCESS).await.unwrap();
+        stream.flush().await.unwrap();
+        proxy.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hell");
+        assert_eq!(stream.bytes_written(), 4);
+    }
+
+    #[tokio::test]
+    async fn discards_writes_on_a_failure() {
+        let (conn, mut proxy) = tokio::io::duplex(64);
+        let mut stream = Socks5Stream::optimistic(conn, 16);
+        stream.write_all(b"hello").await.unwrap();
+        let mut refused = SUCCESS;
+        refused[1] = SocksError::DENY as u8;
+        proxy.write_all(&refused).await.unwrap();
+
+        assert!(stream.flush().await.is_err());
+        assert!(stream.write(b"again").await.is_err());
+        assert!(stream.read(&mut [0u8; 4]).await.is_err());
+        drop(stream);
+        let mut sent = Vec::new();
+        proxy.read_to_end(&mut sent).await.unwrap();
+        assert!(sent.is_empty());
+    }
+
+    #[tokio::test]
+    async fn into_inner_waits_for_the_reply() {
+        let (conn, mut proxy) = tokio::io::duplex(64);
+        let mut stream = Socks5Stream::optimistic(conn, 16);
+        stream.write_all(b"ping").await.unwrap();
+        let reply = tokio::spawn(async move {
+            proxy.write_all(&SUCCESS).await.unwrap();
+            proxy.write_all(b"pong").await.unwrap();
+            proxy
+        });
+        let mut conn = stream.into_inner().await.unwrap();
+        let mut buf = [0u8; 4];
+        conn.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"pong", "the reply is not left to the caller");
+        let mut proxy = reply.await.unwrap();
+        proxy.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+    }
+
+    /// A tunnel through a server started in-process to a listener of the
+    /// test's own, with the destination side of it.
+    async fn tunnel() -> (Socks5Stream, TcpStream, SocketAddr) {
+        let server = crate::server::new("127.0.0.1:0".parse().unwrap(), None).unwrap();
+        let proxy = server.local_addr().unwrap();
+        tokio::spawn(server.run());
+        tokio::task::yield_now().await;
+        let dest = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stream = crate::client::new(proxy, dest.local_addr().unwrap(), None)
+            .await
+            .unwrap();
+        let (upstream, _) = dest.accept().await.unwrap();
+        (stream, upstream, proxy)
+    }
+
+    #[tokio::test]
+    async fn reports_the_proxy_as_its_peer() {
+        let (stream, _upstream, proxy) = tunnel().await;
+        assert_eq!(stream.peer_addr().unwrap(), proxy);
+    }
+
+    #[tokio::test]
+    async fn reports_the_local_end_of_the_connection_to_the_proxy() {
+        let (stream, _upstream, _) = tunnel().await;
+        let local = stream.local_addr().unwrap();
+        assert_eq!(local, stream.as_ref().local_addr().unwrap());
+        assert!(local.ip().is_loopback() && local.port() != 0);
+    }
+
+    #[tokio::test]
+    async fn splits_into_borrowed_halves() {
+        let (mut stream, mut upstream, _) = tunnel().await;
+        let (mut read, mut write) = stream.split().await.unwrap();
+        write.write_all(b"ping").await.unwrap();
+        upstream.write_all(b"pong").await.unwrap();
+        let mut buf = [0u8; 4];
+        read.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"pong");
+        upstream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+    }
+
+    #[tokio::test]
+    async fn splits_into_halves_for_separate_tasks() {
+        let (stream, mut upstream, _) = tunnel().await;
+        let (mut read, mut write) = stream.into_split().await.unwrap();
+        let writer = tokio::spawn(async move { write.write_all(b"ping").await.unwrap() });
+        let reader = tokio::spawn(async move {
+            let mut buf = [0u8; 4];
+            read.read_exact(&mut buf).await.unwrap();
+            buf
+        });
+        upstream.write_all(b"pong").await.unwrap();
+        let mut buf = [0u8; 4];
+        upstream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+        writer.await.unwrap();
+        assert_eq!(&reader.await.unwrap(), b"pong");
+    }
+
+    #[tokio::test]
+    async fn lends_out_the_connection_to_the_proxy() {
+        let (mut stream, mut upstream, proxy) = tunnel().await;
+        assert_eq!(stream.as_ref().peer_addr().unwrap(), proxy);
+        stream.as_mut().set_nodelay(true).unwrap();
+        assert!(stream.as_ref().nodelay().unwrap());
+        // Bypassing the stream, the bytes go uncounted.
+        stream.as_mut().write_all(b"ping").await.unwrap();
+        let mut buf = [0u8; 4];
+        upstream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+        assert_eq!(stream.bytes_written(), 0);
+    }
+
+    #[tokio::test]
+    async fn hands_over_a_std_socket_for_blocking_code() {
+        let (stream, mut upstream, _) = tunnel().await;
+        let conn = stream.into_std().await.unwrap();
+        conn.set_nonblocking(false).unwrap();
+        let conn = tokio::task::spawn_blocking(move || {
+            use std::io::Write;
+            (&conn).write_all(b"ping").unwrap();
+            conn
+        })
+        .await
+        .unwrap();
+        let mut buf = [0u8; 4];
+        upstream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+
+        conn.set_nonblocking(true).unwrap();
+        let mut conn = TcpStream::from_std(conn).unwrap();
+        upstream.write_all(b"pong").await.unwrap();
+        conn.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"pong");
+    }
+}
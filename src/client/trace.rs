@@ -0,0 +1,50 @@
+use super::{Result, Socks5ClientError};
+use crate::utils::Addr;
+use std::{fmt, future::Future, time::Instant};
+use tracing::{debug, error, field, info_span, warn, Instrument, Span};
+
+/// Runs a whole connect in a `socks5_connect` span, a child of whatever
+/// span the caller is in, and reports how it failed.
+pub(super) fn connect<T>(
+    negotiated: impl Future<Output = Result<T>>,
+    dest: &Addr,
+) -> impl Future<Output = Result<T>> {
+    let span = info_span!("socks5_connect", dest = %dest, proxy = field::Empty);
+    async move {
+        let negotiated = negotiated.instrument(span.clone()).await;
+        if let Err(e) = &negotiated {
+            span.in_scope(|| match e {
+                Socks5ClientError::Rejected(rep, reason) => {
+                    error!(rep, %reason, "proxy rejected the request")
+                }
+                Socks5ClientError::Socks4Rejected(rep) => {
+                    error!(rep, "proxy rejected the request")
+                }
+                e => error!(error = %e, "connect failed"),
+            });
+        }
+        negotiated
+    }
+}
+
+/// Records the proxy address on the connect span the caller is in.
+#[cfg(feature = "net")]
+pub(super) fn proxy(addr: std::net::SocketAddr) {
+    Span::current().record("proxy", field::display(addr));
+}
+
+/// Awaits one phase of a connect in `span`, recording its duration and
+/// outcome as an event.
+pub(super) async fn phase<T, E: fmt::Display>(
+    span: Span,
+    phase: impl Future<Output = std::result::Result<T, E>>,
+) -> std::result::Result<T, E> {
+    let start = Instant::now();
+    let done = phase.instrument(span.clone()).await;
+    let elapsed_ms = start.elapsed().as_secs_f64() * 1000.0;
+    span.in_scope(|| match &done {
+        Ok(_) => debug!(elapsed_ms, outcome = "ok"),
+        Err(e) => warn!(elapsed_ms, outcome = "failed", error = %e),
+    });
+    done
+}
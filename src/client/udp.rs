@@ -0,0 +1,229 @@
+use super::{parse_dest, PendingConnect, Result};
+use crate::utils::*;
+use bytes::Bytes;
+use futures_core::Stream;
+use futures_sink::Sink;
+use log::debug;
+use std::{
+    convert::TryInto,
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+use tokio::{
+    io::{AsyncRead, ReadBuf},
+    net::{lookup_host, TcpStream, UdpSocket},
+};
+
+const MAX_DATAGRAM: usize = 65535;
+
+/// A UDP association through a SOCKS5 proxy, framed as datagrams paired
+/// with their remote address.
+///
+/// Owns both the UDP socket and the TCP control connection; dropping it
+/// closes the association. The stream ends once the proxy closes the
+/// control connection.
+pub struct Socks5UdpFramed {
+    socket: UdpSocket,
+    control: TcpStream,
+    relay: SocketAddr,
+    recv: Vec<u8>,
+    send: Option<Vec<u8>>,
+}
+
+pub(super) async fn associate(control: PendingConnect<TcpStream>) -> Result<Socks5UdpFramed> {
+    let socket = UdpSocket::bind(SocketAddr::new(control.local_addr()?.ip(), 0)).await?;
+    let from = Addr::SocketAddr(socket.local_addr()?);
+    let (control, bound) = control.request(SOCKS_COMMAND_UDP_ASSOCIATE, &from).await?;
+
+    let mut relay = match bound {
+        Addr::SocketAddr(addr) => addr,
+        Addr::HostnamePort(host) => lookup_host(host)
+            .await?
+            .next()
+            .ok_or_else(|| io::Error::from(io::ErrorKind::AddrNotAvailable))?,
+    };
+    if relay.ip().is_unspecified() {
+        relay.set_ip(control.peer_addr()?.ip());
+    }
+    socket.connect(relay).await?;
+
+    Ok(Socks5UdpFramed {
+        socket,
+        control,
+        relay,
+        recv: vec![0; MAX_DATAGRAM],
+        send: None,
+    })
+}
+
+impl Socks5UdpFramed {
+    /// The proxy's relay address datagrams are sent to.
+    #[inline]
+    pub fn relay_addr(&self) -> SocketAddr {
+        self.relay
+    }
+
+    /// The local address of the UDP socket.
+    #[inline]
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    fn poll_control_closed(&mut self, cx: &mut Context<'_>) -> bool {
+        let mut byte = [0u8; 1];
+        let mut buf = ReadBuf::new(&mut byte);
+        match Pin::new(&mut self.control).poll_read(cx, &mut buf) {
+            Poll::Ready(Ok(())) => buf.filled().is_empty(),
+            Poll::Ready(Err(_)) => true,
+            Poll::Pending => false,
+        }
+    }
+
+    fn poll_send_pending(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if let Some(datagram) = &self.send {
+            ready!(self.socket.poll_send(cx, datagram))?;
+            self.send = None;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Stream for Socks5UdpFramed {
+    type Item = io::Result<(Bytes, Addr)>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            let mut buf = ReadBuf::new(&mut this.recv);
+            match this.socket.poll_recv(cx, &mut buf) {
+                Poll::Ready(Ok(())) => {}
+                Poll::Ready(Err(e)) => return Poll::Ready(Some(Err(e))),
+                Poll::Pending if this.poll_control_closed(cx) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            }
+            match decode(buf.filled()) {
+                Some((addr, payload)) => {
+                    return Poll::Ready(Some(Ok((Bytes::copy_from_slice(payload), addr))))
+                }
+                None => debug!("dropping malformed or fragmented datagram from relay"),
+            }
+        }
+    }
+}
+
+impl Sink<(Bytes, Addr)> for Socks5UdpFramed {
+    type Error = io::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().poll_send_pending(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, (payload, dest): (Bytes, Addr)) -> io::Result<()> {
+        let mut header = [0u8; 3 + 1 + 255 + 2];
+        let mut request = Buffer::from(&mut header);
+        request.extend(&[SOCKS_RSV, SOCKS_RSV, 0x00]);
+        parse_dest(&mut request, &dest)?;
+        let header = request.content();
+
+        let mut datagram = Vec::with_capacity(header.len() + payload.len());
+        datagram.extend_from_slice(header);
+        datagram.extend_from_slice(&payload);
+        self.get_mut().send = Some(datagram);
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().poll_send_pending(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().poll_send_pending(cx)
+    }
+}
+
+/// Splits a relayed datagram into its source address and payload.
+/// Fragments are not supported and are treated as malformed.
+fn decode(datagram: &[u8]) -> Option<(Addr, &[u8])> {
+    let header = datagram.get(..4)?;
+    let rest = &datagram[4..];
+    if header[..3] != [SOCKS_RSV, SOCKS_RSV, 0x00] {
+        return None;
+    }
+    let (addr, len) = match header[3] {
+        SOCKS_ADDR_IPV4 => {
+            let ip: [u8; 4] = rest.get(..4)?.try_into().ok()?;
+            (
+                Addr::SocketAddr(SocketAddr::new(ip.into(), port_at(rest, 4)?)),
+                4,
+            )
+        }
+        SOCKS_ADDR_IPV6 => {
+            let ip: [u8; 16] = rest.get(..16)?.try_into().ok()?;
+            (
+                Addr::SocketAddr(SocketAddr::new(ip.into(), port_at(rest, 16)?)),
+                16,
+            )
+        }
+        SOCKS_ADDR_DOMAINNAME => {
+            let len = *rest.first()? as usize;
+            let host = std::str::from_utf8(rest.get(1..1 + len)?).ok()?;
+            let port = port_at(rest, 1 + len)?;
+            (Addr::HostnamePort(format!("{}:{}", host, port)), 1 + len)
+        }
+        _ => return None,
+    };
+    Some((addr, &rest[len + 2..]))
+}
+
+#[inline]
+fn port_at(buf: &[u8], at: usize) -> Option<u16> {
+    let port = buf.get(at..at + 2)?;
+    Some(u16::from_be_bytes([port[0], port[1]]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::Builder;
+    use std::{future::poll_fn, time::Duration};
+
+    #[tokio::test]
+    async fn round_trips_through_the_relay() {
+        let server = crate::server::new("127.0.0.1:0".parse().unwrap(), None)
+            .unwrap()
+            .bind_and_udp(true);
+        let (proxy, stats) = (server.local_addr().unwrap(), server.stats());
+        tokio::spawn(server.run());
+        let echo = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let dest = echo.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            while let Ok((len, from)) = echo.recv_from(&mut buf).await {
+                let _ = echo.send_to(&buf[..len], from).await;
+            }
+        });
+
+        let mut framed = Builder::new().udp_associate(proxy).await.unwrap();
+        let mut sink = Pin::new(&mut framed);
+        poll_fn(|cx| sink.as_mut().poll_ready(cx)).await.unwrap();
+        let datagram = (Bytes::from_static(b"ping"), Addr::SocketAddr(dest));
+        sink.as_mut().start_send(datagram).unwrap();
+        poll_fn(|cx| sink.as_mut().poll_flush(cx)).await.unwrap();
+        let received = poll_fn(|cx| Pin::new(&mut framed).poll_next(cx));
+        let (payload, from) = received.await.unwrap().unwrap();
+        assert_eq!(&payload[..], b"ping");
+        assert_eq!(from.to_string(), dest.to_string());
+
+        assert_eq!(stats.relaying(), 1);
+        drop(framed);
+        for _ in 0..100 {
+            if stats.relaying() == 0 {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("the association outlived the framed object");
+    }
+}
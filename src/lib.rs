@@ -1,8 +1,24 @@
 #[forbid(unsafe_code)]
 #[macro_use]
+#[cfg_attr(not(feature = "client-core"), allow(dead_code, unused_macros))]
 mod utils;
+#[cfg(feature = "client-core")]
 pub mod client;
+#[cfg(feature = "net")]
 pub mod server;
+#[cfg(feature = "net")]
+mod socket;
 
+#[cfg(feature = "net")]
+pub use socket::SocketOptions;
 pub use utils::Addr;
 pub use utils::AuthMethod;
+pub use utils::Hostname;
+pub use utils::HostnameError;
+pub use utils::SocksError;
+
+#[cfg(feature = "tls")]
+pub use tokio_rustls::rustls;
+
+#[cfg(feature = "otel")]
+pub use opentelemetry;
@@ -1,265 +1,3862 @@
+mod accept_limit;
+mod accounting;
+mod advertised;
+mod audit;
+#[cfg(feature = "audit")]
+mod audit_file;
+mod auth_handler;
+mod authenticator;
+mod bans;
+mod bind;
+mod blocklist;
+mod client_conn;
+#[cfg(feature = "config")]
+mod config_file;
+mod dest_limit;
+mod dns_cache;
+mod egress;
+#[cfg(feature = "chaos")]
+mod faults;
+#[cfg(feature = "file-auth")]
+mod file_auth;
+mod handle;
+mod happy_eyeballs;
+mod host_overrides;
+mod http_connect;
+mod intercept;
+mod listener;
+mod login_throttle;
+mod marking;
+#[cfg(feature = "otel")]
+mod otel;
+mod policy;
+mod privacy;
+mod private_dest;
+mod quota;
+mod relay;
+mod resolver;
+mod retry;
+mod rewrite;
+mod rules;
+mod shedding;
+mod socks4;
+mod source_limit;
+mod stats;
+mod strictness;
+mod tarpit;
+#[cfg(feature = "tls")]
+mod tls;
+mod top;
+mod udp;
+
+pub use accounting::{Accounting, CommitFuture, MemoryAccounting};
+pub use audit::{AuditEntry, AuditSink};
+#[cfg(feature = "audit")]
+pub use audit_file::JsonlAudit;
+pub use auth_handler::{AuthContext, AuthFuture, AuthHandler};
+pub use authenticator::{AuthDecision, Authenticator, VerifyFuture};
+pub use bans::AutoBan;
+pub use blocklist::Blocklist;
+pub use client_conn::ClientConn;
+#[cfg(feature = "config")]
+pub use config_file::{
+    AcceptRateConfig, AclConfig, AuthConfig, AuthMethodConfig, ConnectRetryConfig,
+    DestinationLimitConfig, DnsConfig, EgressConfig, LimitsConfig, LoggingConfig, ReloadReport,
+    RewriteConfig, RuleConfig, ServerConfig, SheddingConfig, TarpitConfig, TimeoutsConfig,
+};
+pub use dest_limit::{AtCapacity, DestinationKey};
+pub use dns_cache::DnsCache;
+pub use egress::{EgressFamily, UnboundFamily};
+#[cfg(feature = "chaos")]
+pub use faults::{Faults, Trigger};
+#[cfg(feature = "file-auth")]
+pub use file_auth::{CredentialsError, FileAuthenticator};
+pub use handle::{Drained, Handle, TunnelLimits};
+pub use happy_eyeballs::AddressFamily;
+pub use intercept::IncomingRequest;
+pub use listener::Listener;
+pub use login_throttle::LoginThrottle;
+pub use policy::{ConnectContext, Policy, PolicyFuture, Verdict};
+pub use privacy::LogPrivacy;
+pub use quota::{QuotaAction, Quotas};
+pub use relay::{CloseReason, TunnelSummary};
+pub use resolver::{ResolveFuture, Resolver, SystemResolver};
+pub use retry::ConnectRetry;
+pub use rewrite::Rewrite;
+pub use rules::{InvalidNet, IpNet, Rule, RuleAction, RuleSet};
+pub use shedding::{AtConnectionLimit, ShedMode};
+pub use stats::Stats;
+pub use strictness::ProtocolStrictness;
+pub use tarpit::FailureClass;
+#[cfg(feature = "tls")]
+pub use tls::EgressTls;
+pub use top::{TopMetric, OTHER_DESTINATIONS};
+
+use crate::socket::{self, SocketOptions};
 use crate::utils::*;
-use log::{error, info};
+use dns_cache::Cached;
+use intercept::Handler;
+use log::{debug, error, info, log, warn, Level};
 use std::{
+    collections::HashMap,
     convert::TryInto,
-    net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, ToSocketAddrs},
-    ops::{Deref, DerefMut},
-    sync::Arc,
+    fmt,
+    future::Future,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
+    ops::{Deref, DerefMut, RangeInclusive},
+    sync::{atomic::Ordering, Arc},
+    task::Poll,
+    time::Duration,
 };
-use std::borrow::Borrow;
 use thiserror::Error;
-use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
-use tokio::net::{TcpSocket, TcpStream};
+use tokio::io::{
+    self, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader,
+};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc::UnboundedReceiver, OwnedSemaphorePermit, Semaphore, SemaphorePermit};
+use tokio::task::JoinSet;
+use tokio::time::Instant;
 
 type Result<T> = std::result::Result<T, Socks5ServerError>;
 
+/// How long a BIND request waits for its peer by default.
+const BIND_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// How long a DNS lookup may take by default.
+const DNS_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long connecting to a destination may take by default, retries and
+/// all.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long clients have by default to get through the handshake,
+/// authentication and the request.
+const NEGOTIATION_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often running out of descriptors is warned about while it lasts.
+const EXHAUSTION_WARNING_INTERVAL: Duration = Duration::from_secs(10);
+
 #[derive(Debug, Error)]
 pub enum Socks5ServerError {
+    #[error("client closed before sending anything")]
+    EarlyEof,
     #[error("unrecognized protocol")]
     UnknowProtocol,
     #[error("unsupport authenticate method")]
     UnsupportAuth,
+    #[error("authentication failed for user {0:?}")]
+    AuthFailed(String),
     #[error("unsupport socks5 command {0:#04X}")]
     UnsupportCommand(u8),
+    /// The length of an unknown address can't be known, so the server
+    /// discards as much of the request as the client has sent by the time
+    /// it replies REP 0x08, then closes the connection.
     #[error("unknow destination type {0:#04X}")]
     UnknowAddrType(u8),
-    #[error("invalid hostname received")]
-    InvalidHost(#[from] std::str::Utf8Error),
+    #[error("invalid hostname received: {0}")]
+    InvalidHost(#[from] HostnameError),
+    #[error("CONNECT to port 0 of {0}")]
+    ZeroPort(String),
+    #[error("malformed HTTP request: {0}")]
+    BadHttpRequest(String),
+    #[error("unsupported HTTP method {0}")]
+    HttpMethod(String),
+    #[error("refused hostname {0}, resolving hostnames is off")]
+    DomainRefused(String),
+    /// The destination, and the index of the rule denying it, or `None`
+    /// for the default action.
+    #[error(
+        "connection to {0} denied by {}",
+        .1.map_or("the default action".to_owned(), |rule| format!("rule {}", rule))
+    )]
+    Denied(String, Option<usize>),
+    #[error("policy denied {0}, replied {1}")]
+    PolicyDenied(String, SocksError),
+    #[error("invalid rewritten destination {0:?}")]
+    InvalidRewrite(String),
+    #[error("{0} is on the blocklist")]
+    Blocked(String),
+    #[error("{0} is a private destination")]
+    PrivateDestination(String),
+    #[error("user {0:?} may not connect to {1}")]
+    UserDenied(String, String),
+    #[error("user {0:?} is over quota")]
+    QuotaExceeded(String),
     #[error("DNS lookup error: {0}")]
     DNSError(String),
+    #[error("DNS lookup timed out: {0}")]
+    DNSTimeout(String),
+    #[error("server overloaded, connection shed")]
+    Overloaded,
+    #[error("too many handshakes in progress")]
+    TooManyHandshakes,
+    #[error("too many tunnels to {0}")]
+    DestinationFull(String, SocksError),
+    #[error("no egress address for any address of {0}")]
+    NoEgress(String),
+    #[error("client did not finish negotiating within {0:?}")]
+    NegotiationTimeout(Duration),
+    #[error("connecting to {0} timed out after {1:?}")]
+    ConnectTimeout(String, Duration),
+    #[error("no connection to the BIND port within {0:?}")]
+    BindTimeout(Duration),
+    #[error("BIND port connected from unexpected peer {0}")]
+    UnexpectedPeer(String),
+    #[cfg(feature = "tls")]
+    #[error("TLS handshake with {0} failed: {1}")]
+    EgressTls(String, io::Error),
+    #[cfg(feature = "chaos")]
+    #[error("injected fault, replied {0}")]
+    InjectedReply(SocksError),
+    #[error("server is no longer running")]
+    Stopped,
+    #[cfg(feature = "config")]
+    #[error("invalid configuration: {}", .0.join("; "))]
+    Config(Vec<String>),
+    #[cfg(feature = "file-auth")]
+    #[error(transparent)]
+    Credentials(#[from] CredentialsError),
     #[error(transparent)]
     IOError(#[from] io::Error),
 }
+impl Socks5ServerError {
+    /// The level a failed connection is worth logging at: clients going
+    /// away are routine, protocol violations are suspicious, and failures
+    /// on the server side need attention.
+    pub fn severity(&self) -> Level {
+        use Socks5ServerError::*;
+        match self {
+            EarlyEof => Level::Debug,
+            UnknowProtocol | UnsupportAuth | AuthFailed(_) | UnsupportCommand(_)
+            | UnknowAddrType(_) | InvalidHost(_) | ZeroPort(_) | BadHttpRequest(_)
+            | HttpMethod(_) => Level::Warn,
+            DNSError(_)
+            | Overloaded
+            | TooManyHandshakes
+            | DestinationFull(..)
+            | NoEgress(_)
+            | BindTimeout(_)
+            | NegotiationTimeout(_)
+            | ConnectTimeout(..)
+            | UnexpectedPeer(_)
+            | DomainRefused(_)
+            | Denied(..)
+            | UserDenied(..)
+            | QuotaExceeded(_)
+            | PolicyDenied(..)
+            | Blocked(_) => Level::Info,
+            DNSTimeout(_) | PrivateDestination(_) => Level::Warn,
+            #[cfg(feature = "tls")]
+            EgressTls(..) => Level::Warn,
+            #[cfg(feature = "chaos")]
+            InjectedReply(_) => Level::Debug,
+            Stopped | InvalidRewrite(_) => Level::Error,
+            #[cfg(feature = "config")]
+            Config(_) => Level::Error,
+            #[cfg(feature = "file-auth")]
+            Credentials(_) => Level::Error,
+            IOError(e) => match e.kind() {
+                io::ErrorKind::UnexpectedEof
+                | io::ErrorKind::ConnectionReset
+                | io::ErrorKind::ConnectionAborted
+                | io::ErrorKind::BrokenPipe
+                | io::ErrorKind::ConnectionRefused
+                | io::ErrorKind::TimedOut
+                | io::ErrorKind::HostUnreachable
+                | io::ErrorKind::NetworkUnreachable => Level::Info,
+                _ => Level::Error,
+            },
+        }
+    }
+
+    /// Whether the client sent something that is not valid SOCKS5, as
+    /// opposed to failing to authenticate or asking for something refused.
+    fn is_protocol_violation(&self) -> bool {
+        use Socks5ServerError::*;
+        matches!(
+            self,
+            UnknowProtocol | UnknowAddrType(_) | InvalidHost(_) | ZeroPort(_) | BadHttpRequest(_)
+        )
+    }
+}
+
 pub struct Socks5Server {
-    conn: TcpSocket,
-    auth: Arc<AuthMethod>,
+    conns: Vec<Listener>,
+    config: Arc<Config>,
+    handle: Handle,
+    control: UnboundedReceiver<handle::Control>,
 }
-pub fn new(addr: SocketAddr, auth: Option<AuthMethod>) -> Result<Socks5Server> {
-    let conn = match addr {
-        SocketAddr::V4(_) => TcpSocket::new_v4()?,
-        SocketAddr::V6(_) => TcpSocket::new_v6()?,
-    };
-    conn.bind(addr)?;
 
-    let auth = auth.unwrap_or(AuthMethod::NoAuth);
-    let auth = Arc::new(auth);
-    Ok(Socks5Server { conn, auth })
+#[derive(Clone)]
+struct Config {
+    /// The name of the listener connections are accepted on.
+    listener: Option<Arc<str>>,
+    /// The methods clients may authenticate with, most preferred first.
+    auth: Vec<AuthMethod>,
+    /// The methods in place of `auth` for clients from within each
+    /// network, the first holding the source deciding.
+    source_auth: Vec<(IpNet, Vec<AuthMethod>)>,
+    /// Verifies username/password logins in place of the credentials in
+    /// `auth`.
+    authenticator: Option<Arc<dyn Authenticator>>,
+    /// The handlers serving methods by code, in place of the built-in ones.
+    auth_handlers: HashMap<u8, Arc<dyn AuthHandler>>,
+    /// Decides on every CONNECT request first.
+    policy: Option<Arc<dyn Policy>>,
+    /// Where requests for some destinations go instead, first match first.
+    rewrites: Vec<Rewrite>,
+    /// Where clients may connect.
+    acl: Option<RuleSet>,
+    /// The domains clients may not connect to.
+    blocklist: Option<Arc<Blocklist>>,
+    /// Whether loopback and private destinations are refused.
+    block_private: bool,
+    /// Where each of the users named may connect.
+    user_rules: HashMap<String, RuleSet>,
+    /// Whether SOCKS4 and SOCKS4a clients are served too.
+    socks4: bool,
+    /// Whether HTTP CONNECT clients are served too.
+    http_connect: bool,
+    /// Whether BIND and UDP ASSOCIATE requests are served.
+    bind_and_udp: bool,
+    /// Whether IPv4-mapped IPv6 destinations are connected to over IPv4.
+    unmap_v4_mapped: bool,
+    /// Whether hostnames are resolved, or refused.
+    resolve_domains: bool,
+    strictness: ProtocolStrictness,
+    tarpit: HashMap<FailureClass, tarpit::Delay>,
+    shedding: Option<shedding::Shedding>,
+    /// Slots for the connections open at once, and what happens to more.
+    connection_limit: Option<(Arc<Semaphore>, AtConnectionLimit)>,
+    source_limit: Option<source_limit::SourceLimit>,
+    accept_limit: Option<Arc<accept_limit::AcceptLimiter>>,
+    resolver: Arc<dyn Resolver>,
+    hosts: host_overrides::HostOverrides,
+    dns_cache: Option<Arc<DnsCache>>,
+    dns_permits: Option<Arc<Semaphore>>,
+    handshake_permits: Option<(Arc<Semaphore>, Duration)>,
+    /// Slots for the connections negotiating at once, past which new ones
+    /// are closed at accept.
+    handshake_budget: Option<Arc<Semaphore>>,
+    dns_timeout: Duration,
+    dest_limit: Option<dest_limit::DestinationLimit>,
+    accounting: Option<accounting::Metering>,
+    quotas: Option<Arc<Quotas>>,
+    audit: Option<Arc<dyn AuditSink>>,
+    egress: egress::Egress,
+    outbound_socket: SocketOptions,
+    advertised: advertised::Advertised,
+    bind_timeout: Duration,
+    /// How long clients have to get through the negotiation.
+    negotiation_timeout: Duration,
+    /// How long connecting to a destination may take.
+    connect_timeout: Duration,
+    udp: udp::UdpRelay,
+    connect_retry: Option<ConnectRetry>,
+    happy_eyeballs: happy_eyeballs::HappyEyeballs,
+    autoban: Option<AutoBan>,
+    /// The networks clients may connect from; empty for everywhere.
+    allowed_sources: Vec<IpNet>,
+    /// The networks clients may not connect from, whether allowed or not.
+    denied_sources: Vec<IpNet>,
+    login_throttle: Option<LoginThrottle>,
+    stall_timeout: Option<Duration>,
+    /// How long a tunnel may go without relaying anything.
+    idle_timeout: Option<Duration>,
+    /// How long a tunnel or UDP association may last.
+    max_session: Option<Duration>,
+    /// The bytes a tunnel may relay from the client, and to it.
+    max_bytes_up: Option<u64>,
+    max_bytes_down: Option<u64>,
+    marking: marking::Marking,
+    privacy: privacy::Privacy,
+    #[cfg(feature = "tls")]
+    egress_tls: Vec<EgressTls>,
+    #[cfg(feature = "chaos")]
+    faults: Option<Faults>,
+    #[cfg(feature = "otel")]
+    metrics: Option<Arc<otel::Metrics>>,
+    stats: Arc<Stats>,
 }
 
-impl Socks5Server {
-    pub async fn run(self) -> Result<()> {
-        let conn = self.conn.listen(1024)?;
-        loop {
-            let (conn, source) = conn.accept().await?;
+impl Config {
+    /// The methods clients from `ip` may authenticate with.
+    fn auth_for(&self, ip: IpAddr) -> &[AuthMethod] {
+        self.source_auth
+            .iter()
+            .find(|(network, _)| network.contains(ip))
+            .map_or(&self.auth, |(_, methods)| methods)
+    }
 
-            let auth = self.auth.clone();
-            tokio::spawn(async move {
-                let result = handle_client(conn, auth).await;
-                if let Err(e) = result {
-                    error!("{:?}, source {}", e, source);
-                }
-            });
+    /// Whether clients may connect from `ip`.
+    fn admits_source(&self, ip: IpAddr) -> bool {
+        let within = |nets: &[IpNet]| nets.iter().any(|net| net.contains(ip));
+        (self.allowed_sources.is_empty() || within(&self.allowed_sources))
+            && !within(&self.denied_sources)
+    }
+
+    /// Holds a failing connection for the tarpit delay configured for
+    /// `class`, if any.
+    async fn tarpit(&self, class: FailureClass) {
+        if let Some(delay) = self.tarpit.get(&class) {
+            self.stats.record_tarpitted();
+            tokio::time::sleep(delay.sample()).await;
         }
     }
 }
 
-impl_deref!(PendingHandshake, TcpStream);
-impl PendingHandshake {
-    async fn handshake(mut self, auth: &Arc<AuthMethod>) -> Result<PendingAuthenticate> {
-        let mut header = [0u8; 2];
-        self.read_exact(&mut header).await?;
-        if header[0] != SOCKS_VER {
-            return Err(Socks5ServerError::UnknowProtocol);
-        }
-        let mut matched = false;
-        for _ in 0..header[1] {
-            let mut m = [0u8; 1];
-            self.read_exact(&mut m).await?;
-            if m[0] == auth.to_code() {
-                matched = true;
+/// Checks that the server can serve every one of `methods`: a method
+/// with a registered [`AuthHandler`] always can, built-in username/password
+/// only with credentials RFC 1929 can carry or an authenticator, and
+/// other codes only through a handler. Fails for no method at all.
+fn check_auth(methods: &[AuthMethod], config: &Config) -> io::Result<()> {
+    if methods.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "no authentication method",
+        ));
+    }
+    for method in methods {
+        let (user, pass) = match method {
+            AuthMethod::UserPass(Some(credentials)) => credentials,
+            _ if config.auth_handlers.contains_key(&method.to_code()) => continue,
+            AuthMethod::UserPass(None) if config.authenticator.is_some() => continue,
+            AuthMethod::UserPass(None) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "username/password authentication needs credentials or an authenticator",
+                ))
             }
+            AuthMethod::Other(code) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("no handler for authentication method {:#04X}", code),
+                ))
+            }
+            _ => continue,
+        };
+        if !(1..=255).contains(&user.len()) || !(1..=255).contains(&pass.len()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "username and password must be 1 to 255 bytes",
+            ));
         }
-        if !matched {
-            return Err(Socks5ServerError::UnsupportAuth);
+    }
+    Ok(())
+}
+
+pub fn new(addr: SocketAddr, auth: Option<AuthMethod>) -> Result<Socks5Server> {
+    with_listener(Listener::bind(addr)?, auth)
+}
+
+/// A server accepting on `listener`, bound and listening already. Its
+/// socket options and backlog are the caller's to choose, e.g. for a
+/// privileged bind made before dropping privileges.
+pub fn from_listener(listener: TcpListener, auth: Option<AuthMethod>) -> Result<Socks5Server> {
+    with_listener(Listener::from_listener(listener)?, auth)
+}
+
+fn with_listener(conn: Listener, auth: Option<AuthMethod>) -> Result<Socks5Server> {
+    let auth = vec![auth.unwrap_or(AuthMethod::NoAuth)];
+
+    let config = Config {
+        listener: None,
+        auth,
+        authenticator: None,
+        auth_handlers: HashMap::new(),
+        source_auth: Vec::new(),
+        policy: None,
+        rewrites: Vec::new(),
+        blocklist: None,
+        acl: None,
+        block_private: false,
+        user_rules: HashMap::new(),
+        socks4: false,
+        http_connect: false,
+        bind_and_udp: false,
+        unmap_v4_mapped: true,
+        resolve_domains: true,
+        strictness: ProtocolStrictness::Strict,
+        tarpit: HashMap::new(),
+        shedding: None,
+        connection_limit: None,
+        source_limit: None,
+        accept_limit: None,
+        resolver: Arc::new(SystemResolver),
+        hosts: host_overrides::HostOverrides::default(),
+        dns_cache: None,
+        dns_permits: None,
+        handshake_permits: None,
+        handshake_budget: None,
+        dns_timeout: DNS_TIMEOUT,
+        dest_limit: None,
+        accounting: None,
+        quotas: None,
+        audit: None,
+        egress: egress::Egress::default(),
+        outbound_socket: SocketOptions::default(),
+        advertised: advertised::Advertised::default(),
+        bind_timeout: BIND_TIMEOUT,
+        negotiation_timeout: NEGOTIATION_TIMEOUT,
+        connect_timeout: CONNECT_TIMEOUT,
+        udp: udp::UdpRelay::default(),
+        connect_retry: None,
+        happy_eyeballs: happy_eyeballs::HappyEyeballs::default(),
+        autoban: None,
+        allowed_sources: Vec::new(),
+        denied_sources: Vec::new(),
+        login_throttle: None,
+        stall_timeout: None,
+        idle_timeout: None,
+        max_session: None,
+        max_bytes_up: None,
+        max_bytes_down: None,
+        marking: marking::Marking::default(),
+        privacy: privacy::Privacy {
+            mode: LogPrivacy::Full,
+            key: privacy::random_key(),
+        },
+        #[cfg(feature = "tls")]
+        egress_tls: Vec::new(),
+        #[cfg(feature = "chaos")]
+        faults: None,
+        #[cfg(feature = "otel")]
+        metrics: None,
+        stats: Arc::new(Stats::default()),
+    };
+    let (handle, control) = handle::channel(config.stats.clone(), config.privacy.key.clone());
+    check_auth(&config.auth, &config)?;
+    handle.listening(vec![(conn.local_addr()?, conn.options().clone())]);
+    Ok(Socks5Server {
+        conns: vec![conn],
+        config: Arc::new(config),
+        handle,
+        control,
+    })
+}
+
+impl Socks5Server {
+    /// Accepts on `listener` as well.
+    pub fn listener(mut self, listener: Listener) -> Self {
+        self.conns.push(listener);
+        let bound = self.conns.iter().filter_map(|conn| {
+            let addr = conn.local_addr().ok()?;
+            Some((addr, conn.options().clone()))
+        });
+        self.handle.listening(bound.collect());
+        self
+    }
+
+    /// The address the server listens on, the one given to [`new`] with
+    /// the port picked if it was 0. Once running, [`Handle::local_addr`]
+    /// tells it.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.conns[0].local_addr()
+    }
+
+    fn config_mut(&mut self) -> &mut Config {
+        Arc::make_mut(&mut self.config)
+    }
+
+    /// Lets clients authenticate with any of `methods` instead of the one
+    /// given to [`new`]. Of the methods a client offers, the one earliest
+    /// in `methods` is selected. Running fails if `methods` is empty or
+    /// holds credentials RFC 1929 can't carry.
+    pub fn auth_methods(mut self, methods: Vec<AuthMethod>) -> Self {
+        self.config_mut().auth = methods;
+        self
+    }
+
+    /// Lets clients connecting from within `network` authenticate with
+    /// `methods` instead, as [`auth_methods`](Self::auth_methods) does,
+    /// whichever listener they connect to. Networks are tried in the order
+    /// given; clients from none of them get the server's or listener's
+    /// methods. Running fails as it does for `auth_methods`.
+    pub fn auth_methods_from(mut self, network: IpNet, methods: Vec<AuthMethod>) -> Self {
+        self.config_mut().source_auth.push((network, methods));
+        self
+    }
+
+    /// Also serves SOCKS4 and SOCKS4a clients, told apart by the first
+    /// byte they send. They may only CONNECT, and only while clients may
+    /// go without authentication. Off by default; connections served with
+    /// [`run_with_handler`](Self::run_with_handler) never speak SOCKS4.
+    pub fn socks4(mut self, enabled: bool) -> Self {
+        self.config_mut().socks4 = enabled;
+        self
+    }
+
+    /// Also serves clients of HTTP proxies, told apart by the method they
+    /// start with. They may only `CONNECT`, other methods getting a 405,
+    /// and like SOCKS4 clients only while clients may go without
+    /// authentication. Off by default; connections served with
+    /// [`run_with_handler`](Self::run_with_handler) never speak HTTP.
+    pub fn http_connect(mut self, enabled: bool) -> Self {
+        self.config_mut().http_connect = enabled;
+        self
+    }
+
+    /// Also serves BIND and UDP ASSOCIATE requests. Off by default, both
+    /// being refused with REP 0x07. Datagrams are vetted per destination
+    /// as CONNECT requests are, while a BIND peer only has to be the one
+    /// the request names.
+    pub fn bind_and_udp(mut self, enabled: bool) -> Self {
+        self.config_mut().bind_and_udp = enabled;
+        self
+    }
+
+    /// Sets whether destinations given as IPv4-mapped IPv6 addresses
+    /// (`::ffff:a.b.c.d`) are connected to over IPv4, as they are by
+    /// default. When off, they are connected to as given, over IPv6.
+    pub fn unmap_ipv4_mapped(mut self, unmap: bool) -> Self {
+        self.config_mut().unmap_v4_mapped = unmap;
+        self
+    }
+
+    /// Sets whether the server resolves hostnames, as it does by default.
+    /// When off, requests naming a hostname are refused with REP 0x08
+    /// before anything is looked up, and UDP datagrams to one are dropped;
+    /// clients must resolve themselves and send addresses.
+    pub fn resolve_domains(mut self, resolve: bool) -> Self {
+        self.config_mut().resolve_domains = resolve;
+        self
+    }
+
+    /// Verifies username/password logins through `authenticator`, in
+    /// place of the credentials of the [`AuthMethod::UserPass`] methods,
+    /// which may then go without: offer the method with
+    /// `auth_methods(vec![AuthMethod::UserPass(None)])`.
+    pub fn authenticator(mut self, authenticator: Arc<dyn Authenticator>) -> Self {
+        self.config_mut().authenticator = Some(authenticator);
+        self
+    }
+
+    /// Serves the authentication method `code` with `handler`, whether
+    /// GSSAPI (0x01) or a private method (0x80 to 0xFE); offer it as
+    /// [`AuthMethod::Other`] among the [`auth_methods`](Self::auth_methods),
+    /// whose order decides which of the methods a client offers is
+    /// selected. A handler for 0x00 or 0x02 replaces the built-in one.
+    pub fn auth_handler(mut self, code: u8, handler: Arc<dyn AuthHandler>) -> Self {
+        self.config_mut().auth_handlers.insert(code, handler);
+        self
+    }
+
+    /// Has `policy` decide on every CONNECT request, before anything else
+    /// is: it may let the request through, refuse it with the reply of its
+    /// choice, or have the server connect elsewhere. Rewritten
+    /// destinations are checked, resolved and connected to in place of the
+    /// requested one.
+    pub fn policy(mut self, policy: Arc<dyn Policy>) -> Self {
+        self.config_mut().policy = Some(policy);
+        self
+    }
+
+    /// Appends `rewrite` to the rewrite table, tried after those before
+    /// it. A CONNECT request goes where the first rewrite matching it
+    /// says, after the [`policy`](Self::policy) if any, and its rewritten
+    /// destination is checked, resolved and connected to in place of the
+    /// requested one.
+    pub fn rewrite(mut self, rewrite: Rewrite) -> Self {
+        self.config_mut().rewrites.push(rewrite);
+        self
+    }
+
+    /// Only lets clients CONNECT where `acl` allows, replying REP 0x02
+    /// elsewhere. Hostnames are checked as requested and, where rules
+    /// match by network, by the addresses they resolve to; addresses the
+    /// rules deny are left out.
+    pub fn acl(mut self, acl: RuleSet) -> Self {
+        self.config_mut().acl = Some(acl);
+        self
+    }
+
+    /// Refuses CONNECTs to the domains in `blocklist`, and to names under
+    /// them, with REP 0x02.
+    pub fn blocklist(mut self, blocklist: Arc<Blocklist>) -> Self {
+        self.config_mut().blocklist = Some(blocklist);
+        self
+    }
+
+    /// Refuses CONNECTs to this host and to private networks with REP
+    /// 0x02, and drops UDP datagrams to them: unspecified, loopback,
+    /// RFC 1918, shared (100.64.0.0/10), link-local, broadcast and
+    /// unique-local addresses, also in IPv4-mapped form. Hostnames are
+    /// judged by the addresses they resolve to, so names resolving to
+    /// 127.0.0.1 are refused too. Off by default.
+    pub fn block_private_destinations(mut self, enabled: bool) -> Self {
+        self.config_mut().block_private = enabled;
+        self
+    }
+
+    /// Only lets `user` CONNECT where `rules` allow, replying REP 0x02
+    /// elsewhere. Hostnames are checked as requested and, where rules
+    /// match by network, by the addresses they resolve to; addresses the
+    /// rules deny are left out. Users without rules may connect anywhere.
+    pub fn user_rules(mut self, user: &str, rules: RuleSet) -> Self {
+        self.config_mut().user_rules.insert(user.to_owned(), rules);
+        self
+    }
+
+    /// Requires username/password logins, checked against the credentials
+    /// file at `path` (see [`FileAuthenticator`]).
+    #[cfg(feature = "file-auth")]
+    pub fn credentials_file(self, path: impl AsRef<std::path::Path>) -> Result<Self> {
+        let users = FileAuthenticator::open(path)?;
+        Ok(self
+            .authenticator(Arc::new(users))
+            .auth_methods(vec![AuthMethod::UserPass(None)]))
+    }
+
+    /// Sets how requests deviating from the RFCs are treated. Strict by
+    /// default.
+    pub fn protocol_strictness(mut self, strictness: ProtocolStrictness) -> Self {
+        self.config_mut().strictness = strictness;
+        self
+    }
+
+    /// Delays the failure reply (or the close) of connections failing with
+    /// `class` by `delay` plus a random share of `jitter`.
+    pub fn tarpit(mut self, class: FailureClass, delay: Duration, jitter: Duration) -> Self {
+        self.config_mut()
+            .tarpit
+            .insert(class, tarpit::Delay { delay, jitter });
+        self
+    }
+
+    /// Sheds new connections once `high_water` connections are active,
+    /// until the count drops back to `low_water`.
+    pub fn load_shedding(mut self, high_water: usize, low_water: usize, mode: ShedMode) -> Self {
+        let shedding = self
+            .config_mut()
+            .shedding
+            .get_or_insert_with(Default::default);
+        shedding.high_water = high_water;
+        shedding.low_water = low_water;
+        shedding.mode = mode;
+        self
+    }
+
+    /// Limits how many connections may be open at the same time, from
+    /// accept until closed, across all listeners. [`Stats::open`] tells how
+    /// many are.
+    pub fn max_connections(mut self, limit: usize, mode: AtConnectionLimit) -> Self {
+        self.config_mut().connection_limit = Some((Arc::new(Semaphore::new(limit)), mode));
+        self
+    }
+
+    /// Accepts at most `rate` connections per second on average, and up to
+    /// `burst` in a row. Further connections wait in the listen backlog
+    /// until their turn rather than being dropped.
+    pub fn accept_rate_limit(mut self, rate: f64, burst: u32) -> Self {
+        let limiter = accept_limit::AcceptLimiter::new(rate, burst);
+        self.config_mut().accept_limit = Some(Arc::new(limiter));
+        self
+    }
+
+    /// Limits how many connections each source address may have open at
+    /// the same time. Connections over the limit are closed at accept.
+    pub fn max_connections_per_source(mut self, cap: usize) -> Self {
+        let v6_prefix = self
+            .config_mut()
+            .source_limit
+            .take()
+            .map_or(128, |limit| limit.v6_prefix);
+        let mut limit = source_limit::SourceLimit::new(cap);
+        limit.v6_prefix = v6_prefix;
+        self.config_mut().source_limit = Some(limit);
+        self
+    }
+
+    /// Counts IPv6 sources sharing their first `prefix` bits as one source
+    /// for [`max_connections_per_source`](Self::max_connections_per_source),
+    /// e.g. 64 to limit a whole subnet. Each address counts on its own by
+    /// default.
+    pub fn source_limit_ipv6_prefix(mut self, prefix: u8) -> Self {
+        if let Some(limit) = &mut self.config_mut().source_limit {
+            limit.v6_prefix = prefix.min(128);
         }
+        self
+    }
 
-        self.write_all(&[SOCKS_VER, auth.to_code()]).await?;
-        self.flush().await?;
+    /// Also sheds new connections while the accept rate (a moving average,
+    /// per second) is above `high`, until it drops back to `low`.
+    pub fn shed_on_accept_rate(mut self, high: f64, low: f64) -> Self {
+        let shedding = self
+            .config_mut()
+            .shedding
+            .get_or_insert_with(Default::default);
+        shedding.accept_rate = Some((high, low));
+        self
+    }
 
-        Ok(PendingAuthenticate(self.0))
+    /// Resolves hostnames through `resolver` instead of the system.
+    pub fn resolver(mut self, resolver: Arc<dyn Resolver>) -> Self {
+        self.config_mut().resolver = resolver;
+        self
     }
-}
 
-impl_deref!(PendingAuthenticate, TcpStream);
-impl PendingAuthenticate {
-    async fn authenticate(mut self, auth: &Arc<AuthMethod>) -> Result<PendingCommand> {
-        match auth.borrow() {
-            AuthMethod::NoAuth => Ok(PendingCommand(self.0)),
-            AuthMethod::UserPass(user_auth) => {
-                //read data
-                let mut header = [0u8; 2];
-                self.read_exact(&mut header).await?;
-
-                let name_lenth = header[1];
-                let pass_lenth;
-                let mut one_byte = [0u8; 1];
-                let mut name_vec: Vec<u8> = Vec::new();
-                let mut pass_vec: Vec<u8> = Vec::new();
-
-                for _i in 0..name_lenth {
-                    self.read_exact(&mut one_byte).await?;
-                    name_vec.push(one_byte[0]);
-                }
+    /// Resolves `host` to `ip` without asking the resolver, on the port the
+    /// client requested. Matching ignores case, and a `*.` prefix makes the
+    /// entry match every name under it, as `*.corp` does `db.corp`.
+    pub fn override_host(mut self, host: &str, ip: IpAddr) -> Self {
+        self.config_mut().hosts.insert(host, ip);
+        self
+    }
 
-                self.read_exact(&mut one_byte).await?;
-                pass_lenth = one_byte[0];
+    /// Answers repeated lookups of a hostname from `cache` while they are
+    /// fresh, shared by all connections.
+    pub fn dns_cache(mut self, cache: DnsCache) -> Self {
+        self.config_mut().dns_cache = Some(Arc::new(cache));
+        self
+    }
 
-                for _i in 0..pass_lenth {
-                    self.read_exact(&mut one_byte).await?;
-                    pass_vec.push(one_byte[0]);
-                }
+    /// Limits how many DNS lookups may run at the same time; further
+    /// requests wait for a free slot.
+    pub fn dns_concurrency(mut self, permits: usize) -> Self {
+        self.config_mut().dns_permits = Some(Arc::new(Semaphore::new(permits)));
+        self
+    }
 
-                let user_name = String::from_utf8_lossy(&name_vec).to_string();
-                let user_pwd = String::from_utf8_lossy(&pass_vec).to_string();
-
-                let x = user_auth.as_ref().unwrap();
-                if x.0 == user_name && x.1 == user_pwd {
-                    //Authentication succeeded
-                    self.write_all(&[SOCKS_VER, SocksError::SUCCESS as u8]).await?;
-                    self.flush().await?;
-                    Ok(PendingCommand(self.0))
-                } else {
-                    //Authentication fail
-                    self.write_all(&[SOCKS_VER, SocksError::FAIL as u8]).await?;
-                    self.flush().await?;
-                    Err(Socks5ServerError::UnsupportAuth)
-                }
-            }
-            _ => Err(Socks5ServerError::UnsupportAuth),
+    /// Limits how many connections may be negotiating at the same time,
+    /// short of relaying. A connection over the limit waits up to `wait`
+    /// for a slot and is closed if none frees up.
+    pub fn handshake_limit(mut self, permits: usize, wait: Duration) -> Self {
+        self.config_mut().handshake_permits = Some((Arc::new(Semaphore::new(permits)), wait));
+        self
+    }
+
+    /// Closes new connections at accept while `cap` connections are still
+    /// negotiating, short of relaying. Unlike the
+    /// [handshake limit](Self::handshake_limit), nobody waits: a flood of
+    /// clients that never finish the greeting is turned away without
+    /// holding up established tunnels. [`Stats::handshaking`] and
+    /// [`Stats::relaying`] tell the two apart.
+    pub fn max_handshaking(mut self, cap: usize) -> Self {
+        self.config_mut().handshake_budget = Some(Arc::new(Semaphore::new(cap)));
+        self
+    }
+
+    /// Fails a DNS lookup with REP 0x04 if it takes longer than `timeout`,
+    /// waiting for a [lookup slot](Self::dns_concurrency) included. Five
+    /// seconds by default.
+    pub fn dns_timeout(mut self, timeout: Duration) -> Self {
+        self.config_mut().dns_timeout = timeout;
+        self
+    }
+
+    /// Caps the number of simultaneous tunnels to any single destination,
+    /// identified as `key` says.
+    pub fn destination_limit(
+        mut self,
+        cap: usize,
+        key: DestinationKey,
+        at_capacity: AtCapacity,
+    ) -> Self {
+        let overrides = self
+            .config_mut()
+            .dest_limit
+            .take()
+            .map(|limit| limit.overrides)
+            .unwrap_or_default();
+        let mut limit = dest_limit::DestinationLimit::new(cap, key, at_capacity);
+        limit.overrides = overrides;
+        self.config_mut().dest_limit = Some(limit);
+        self
+    }
+
+    /// Overrides the per-destination cap for `dest`, written the way the
+    /// configured [`DestinationKey`] identifies it (e.g. `10.0.0.5:443` or
+    /// `example.com:443`). Only effective together with
+    /// [`destination_limit`](Self::destination_limit).
+    pub fn destination_limit_override(mut self, dest: &str, cap: usize) -> Self {
+        if let Some(limit) = &mut self.config_mut().dest_limit {
+            limit.overrides.insert(dest_limit::key(dest), cap);
         }
+        self
     }
-}
 
-impl_deref!(PendingCommand, TcpStream);
-impl PendingCommand {
-    async fn handle_command(&mut self) -> Result<SocketAddr> {
-        let mut header = [0u8; 4];
-        self.read_exact(&mut header).await?;
-        if header[0] != SOCKS_VER || header[2] != SOCKS_RSV {
-            return Err(Socks5ServerError::UnknowProtocol);
-        } else if header[1] != SOCKS_COMMAND_CONNECT {
-            return Err(Socks5ServerError::UnsupportCommand(header[1]));
+    /// Connects to IPv4 destinations from `ip`.
+    pub fn outbound_bind_v4(mut self, ip: Ipv4Addr) -> Self {
+        self.config_mut().egress.v4 = Some(ip);
+        self
+    }
+
+    /// Connects to IPv6 destinations from `ip`.
+    pub fn outbound_bind_v6(mut self, ip: Ipv6Addr) -> Self {
+        self.config_mut().egress.v6 = Some(ip);
+        self
+    }
+
+    /// Sets what happens to destinations of the family without an
+    /// outbound bind, when only one family has one. Skipped by default.
+    pub fn unbound_family(mut self, unbound: UnboundFamily) -> Self {
+        self.config_mut().egress.unbound = unbound;
+        self
+    }
+
+    /// Restricts outbound connections to one address family. Addresses of
+    /// the other are left out of what hostnames resolve to, and requests
+    /// for one get REP 0x03 right away. Both families are allowed by
+    /// default.
+    pub fn egress_family(mut self, family: EgressFamily) -> Self {
+        self.config_mut().egress.family = family;
+        self
+    }
+
+    /// Sets `options` on outbound connections before they connect. A TOS
+    /// picked by [`outbound_tos`](Self::outbound_tos) or
+    /// [`tos_by_destination`](Self::tos_by_destination) takes precedence over one set here.
+    pub fn outbound_socket_options(mut self, options: SocketOptions) -> Self {
+        self.config_mut().outbound_socket = options;
+        self
+    }
+
+    /// Tells BIND and UDP ASSOCIATE clients to reach the server's sockets
+    /// at `ip` rather than at the address they are bound to, for servers
+    /// behind NAT that forward ports one to one. Set once for each family.
+    pub fn advertised_address(mut self, ip: IpAddr) -> Self {
+        let advertised = &mut self.config_mut().advertised;
+        match ip {
+            IpAddr::V4(ip) => advertised.v4 = Some(ip),
+            IpAddr::V6(ip) => advertised.v6 = Some(ip),
         }
+        self
+    }
 
-        match header[3] {
-            SOCKS_ADDR_IPV4 => {
-                let mut buffer = [0u8; 4 + 2];
-                self.read_exact(&mut buffer).await?;
-                let ip: [u8; 4] = buffer[..4].try_into().unwrap();
-                let ip: Ipv4Addr = Ipv4Addr::from(ip);
-                let port = u16::from_be_bytes([buffer[4], buffer[5]]);
-                let addr = SocketAddr::V4(SocketAddrV4::new(ip, port));
-                info!("connecting to {}", addr);
-                Ok(addr)
-            }
-            SOCKS_ADDR_IPV6 => {
-                let mut buffer = [0u8; 16 + 2];
-                self.read_exact(&mut buffer).await?;
-                let ip: [u8; 16] = buffer[..16].try_into().unwrap();
-                let ip = Ipv6Addr::from(ip);
-                let port = u16::from_be_bytes([buffer[16], buffer[17]]);
-                let addr = SocketAddr::V6(SocketAddrV6::new(ip, port, 0, 0));
-                info!("connecting to {}", addr);
-                Ok(addr)
+    /// Has `rule` pick the address BIND and UDP ASSOCIATE replies tell
+    /// each client to reach the server's socket at, given the client's
+    /// address and the one the socket is bound to, for NAT setups a fixed
+    /// address doesn't fit. Where it returns `None`, those set with
+    /// [`advertised_address`](Self::advertised_address) apply; the port is
+    /// always the one bound.
+    pub fn advertise_with(
+        mut self,
+        rule: impl Fn(SocketAddr, SocketAddr) -> Option<IpAddr> + Send + Sync + 'static,
+    ) -> Self {
+        self.config_mut().advertised.rule = Some(Arc::new(rule));
+        self
+    }
+
+    /// Closes connections whose client hasn't got through the handshake,
+    /// authentication and the request within `timeout`, without a reply.
+    /// Tarpit and login throttling delays count towards it. Ten seconds by
+    /// default.
+    pub fn negotiation_timeout(mut self, timeout: Duration) -> Self {
+        self.config_mut().negotiation_timeout = timeout;
+        self
+    }
+
+    /// Gives up on connecting to a destination after `timeout`, replying
+    /// TTL expired (REP 0x06). The timeout covers every address tried and
+    /// every [retry](Self::connect_retry). 30 seconds by default.
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.config_mut().connect_timeout = timeout;
+        self
+    }
+
+    /// Gives up on a BIND request whose peer hasn't connected within
+    /// `timeout`, replying TTL expired. Two minutes by default.
+    pub fn bind_timeout(mut self, timeout: Duration) -> Self {
+        self.config_mut().bind_timeout = timeout;
+        self
+    }
+
+    /// Binds the relay sockets of UDP associations to `ip`, rather than to
+    /// the unspecified address of the family clients connect over.
+    pub fn udp_bind_address(mut self, ip: IpAddr) -> Self {
+        self.config_mut().udp.bind = Some(ip);
+        self
+    }
+
+    /// Binds the relay sockets of UDP associations to ports in `ports`
+    /// only. With every port taken, associate requests fail with REP 0x01.
+    pub fn udp_port_range(mut self, ports: RangeInclusive<u16>) -> Self {
+        self.config_mut().udp.ports = Some(ports);
+        self
+    }
+
+    /// Sets `options` on the relay sockets of UDP associations, e.g.
+    /// larger buffers for high-rate streams. Options only TCP has fail
+    /// the associate request.
+    pub fn udp_socket_options(mut self, options: SocketOptions) -> Self {
+        self.config_mut().udp.options = options;
+        self
+    }
+
+    /// Retries failed outbound connects as `retry` says before replying
+    /// failure to the client.
+    pub fn connect_retry(mut self, retry: ConnectRetry) -> Self {
+        self.config_mut().connect_retry = Some(retry);
+        self
+    }
+
+    /// Sets the family whose addresses are tried first when a destination
+    /// has both. IPv6 by default.
+    pub fn prefer_family(mut self, family: AddressFamily) -> Self {
+        self.config_mut().happy_eyeballs.prefer = family;
+        self
+    }
+
+    /// Sets how long a connect to one address of a destination gets before
+    /// a connect to the next starts alongside it. 250 milliseconds by
+    /// default.
+    pub fn connection_attempt_delay(mut self, delay: Duration) -> Self {
+        self.config_mut().happy_eyeballs.delay = delay;
+        self
+    }
+
+    /// Bans sources that keep violating the protocol, as `autoban` says.
+    /// Banned sources are closed right after accept; see also
+    /// [`Handle::ban`].
+    pub fn autoban(mut self, autoban: AutoBan) -> Self {
+        self.config_mut().autoban = Some(autoban);
+        self
+    }
+
+    /// Only serves clients connecting from within `networks`, IPv4 or
+    /// IPv6. Connections from elsewhere are closed right after accept,
+    /// before a byte is read. Every source is allowed by default.
+    pub fn allow_sources(mut self, networks: Vec<IpNet>) -> Self {
+        self.config_mut().allowed_sources = networks;
+        self
+    }
+
+    /// Closes connections from within `networks` right after accept, even
+    /// if their source is [allowed](Self::allow_sources).
+    pub fn deny_sources(mut self, networks: Vec<IpNet>) -> Self {
+        self.config_mut().denied_sources = networks;
+        self
+    }
+
+    /// Slows down, and optionally locks out, sources that keep failing
+    /// username/password logins, as `throttle` says.
+    pub fn login_throttle(mut self, throttle: LoginThrottle) -> Self {
+        self.config_mut().login_throttle = Some(throttle);
+        self
+    }
+
+    /// Ends a tunnel when a write to either side makes no progress for
+    /// `timeout` while the other side has data for it.
+    pub fn stall_timeout(mut self, timeout: Duration) -> Self {
+        self.config_mut().stall_timeout = Some(timeout);
+        self
+    }
+
+    /// Ends a tunnel once neither side has sent anything for `timeout`,
+    /// closing both. Tunnels may idle as long as they like by default.
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.config_mut().idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Cuts tunnels, and UDP associations, `duration` after the success
+    /// reply, however busy they are. Sessions last as long as they like by
+    /// default.
+    pub fn max_session_duration(mut self, duration: Duration) -> Self {
+        self.config_mut().max_session = Some(duration);
+        self
+    }
+
+    /// Cuts a tunnel once it has relayed `limit` bytes from the client,
+    /// shutting down the destination's side when the last of them is
+    /// written. Unlimited by default.
+    pub fn max_bytes_up(mut self, limit: u64) -> Self {
+        self.config_mut().max_bytes_up = Some(limit);
+        self
+    }
+
+    /// Cuts a tunnel once it has relayed `limit` bytes to the client,
+    /// shutting down the client's side when the last of them is written.
+    /// Unlimited by default.
+    pub fn max_bytes_down(mut self, limit: u64) -> Self {
+        self.config_mut().max_bytes_down = Some(limit);
+        self
+    }
+
+    /// Aggregates connections and bytes per destination host over a
+    /// sliding window of `buckets` buckets of `bucket` each, for
+    /// [`Handle::top_destinations`]. Each bucket keeps at most `max_keys`
+    /// destinations.
+    pub fn top_destinations(self, bucket: Duration, buckets: usize, max_keys: usize) -> Self {
+        self.config.stats.top.configure(bucket, buckets, max_keys);
+        self
+    }
+
+    /// Commits the traffic of every tunnel to `accounting` when it closes
+    /// and, with an `interim` interval, periodically while it is open.
+    pub fn accounting(
+        mut self,
+        accounting: Arc<dyn Accounting>,
+        interim: Option<Duration>,
+    ) -> Self {
+        self.config_mut().accounting = Some(accounting::Metering::new(accounting, interim));
+        self
+    }
+
+    /// Caps the bytes each authenticated user may relay at `quotas`, which
+    /// the caller may keep to read and reset usage.
+    pub fn quotas(mut self, quotas: Arc<Quotas>) -> Self {
+        self.config_mut().quotas = Some(quotas);
+        self
+    }
+
+    /// Marks outbound connections with the IP TOS (IPv6 traffic class)
+    /// `tos`, e.g. a DSCP code point shifted left by two.
+    pub fn outbound_tos(mut self, tos: u8) -> Self {
+        self.config_mut().marking.outbound = Some(tos);
+        self
+    }
+
+    /// Marks the connections of clients, which carry the downstream side of
+    /// tunnels, with `tos`.
+    pub fn client_tos(mut self, tos: u8) -> Self {
+        self.config_mut().marking.client = Some(tos);
+        self
+    }
+
+    /// Marks both connections of each tunnel with whatever `rule` returns
+    /// for its destination host, given as a normalized [`Hostname`] or a
+    /// canonical IP literal; where it returns `None`, the markings set
+    /// with [`outbound_tos`](Self::outbound_tos) and
+    /// [`client_tos`](Self::client_tos) apply.
+    pub fn tos_by_destination(
+        mut self,
+        rule: impl Fn(&str) -> Option<u8> + Send + Sync + 'static,
+    ) -> Self {
+        self.config_mut().marking.by_destination = Some(Arc::new(rule));
+        self
+    }
+
+    /// Sets what logs, errors, audit entries and top destinations reveal of
+    /// destinations. Everything by default.
+    pub fn log_privacy(mut self, privacy: LogPrivacy) -> Self {
+        self.config_mut().privacy.mode = privacy;
+        self
+    }
+
+    /// Sets the key destinations are hashed with under
+    /// [`LogPrivacy::HashedDestinations`], random by default. Change it
+    /// later with [`Handle::rotate_privacy_key`].
+    pub fn privacy_key(self, key: [u8; 16]) -> Self {
+        self.handle.rotate_privacy_key(key);
+        self
+    }
+
+    /// Records every connection in `audit` once it ends.
+    pub fn audit(mut self, audit: Arc<dyn AuditSink>) -> Self {
+        self.config_mut().audit = Some(audit);
+        self
+    }
+
+    /// Records metrics through instruments made from `meter`: negotiation,
+    /// connect and tunnel durations, bytes relayed, replies by REP code, and
+    /// the live [`Stats`] as observable instruments. Installing a meter
+    /// provider and exporter is left to the caller.
+    #[cfg(feature = "otel")]
+    pub fn otel_metrics(mut self, meter: &opentelemetry::metrics::Meter) -> Self {
+        let metrics = otel::Metrics::new(meter, &self.config.stats);
+        self.config_mut().metrics = Some(Arc::new(metrics));
+        self
+    }
+
+    /// Connects to destinations matching `tls` over TLS, so plaintext
+    /// clients reach them encrypted. The first matching entry wins.
+    #[cfg(feature = "tls")]
+    pub fn egress_tls(mut self, tls: EgressTls) -> Self {
+        self.config_mut().egress_tls.push(tls);
+        self
+    }
+
+    /// Injects `faults` into tunnels. Only meant for testing.
+    #[cfg(feature = "chaos")]
+    pub fn faults(mut self, faults: Faults) -> Self {
+        self.config_mut().faults = Some(faults);
+        self
+    }
+
+    pub fn stats(&self) -> Arc<Stats> {
+        self.config.stats.clone()
+    }
+
+    /// A handle to control the server once it runs.
+    pub fn handle(&self) -> Handle {
+        self.handle.clone()
+    }
+
+    pub async fn run(self) -> Result<()> {
+        self.accept_loop(None).await
+    }
+
+    /// Runs the server like [`run`](Self::run), but hands every request
+    /// that passed the handshake, authentication and the request read to
+    /// `handler` instead of connecting to the destination. The handler
+    /// fulfils the request however it likes and answers it through the
+    /// [`IncomingRequest`]; dropping the request unanswered closes the
+    /// connection.
+    pub async fn run_with_handler<H, F>(self, handler: H) -> Result<()>
+    where
+        H: Fn(IncomingRequest) -> F + Send + Sync + 'static,
+        F: Future<Output = ()> + Send + 'static,
+    {
+        self.accept_loop(Some(Arc::new(move |request| Box::pin(handler(request)))))
+            .await
+    }
+
+    async fn accept_loop(mut self, handler: Option<Handler>) -> Result<()> {
+        let mut base = self.config;
+        check_auth(&base.auth, &base)?;
+        for (_, methods) in &base.source_auth {
+            check_auth(methods, &base)?;
+        }
+        let mut listeners = self
+            .conns
+            .into_iter()
+            .map(|conn| conn.listen(&base))
+            .collect::<io::Result<Vec<_>>>()?;
+        let mut tasks = Connections::default();
+        loop {
+            let (conn, source, config, slot) = tokio::select! {
+                Some(done) = tasks.0.join_next() => {
+                    if let Err(e) = done {
+                        warn!("connection task failed: {}", e);
+                    }
+                    continue;
+                }
+                Some(control) = self.control.recv() => {
+                    match control {
+                        handle::Control::Rebind(rebound) => {
+                            match listener::move_all(&listeners, rebound, &base) {
+                                Ok(moved) => {
+                                    listeners = moved;
+                                    let bound = listeners.iter().filter_map(|bound| {
+                                        let addr = bound.listener.local_addr().ok()?;
+                                        info!("listening on {}", addr);
+                                        Some((addr, bound.options.clone()))
+                                    });
+                                    self.handle.listening(bound.collect());
+                                }
+                                Err(e) => error!("rebinding failed, listening as before: {}", e),
+                            }
+                        }
+                        handle::Control::Shutdown => {
+                            self.handle.listening(Vec::new());
+                            drop(listeners);
+                            let drained = tasks.stop(Duration::ZERO).await;
+                            if let Some(metering) = &base.accounting {
+                                metering.flush().await;
+                            }
+                            info!("shut down, {} connections cut", drained.cut);
+                            return Ok(());
+                        }
+                        handle::Control::Drain(deadline, report) => {
+                            self.handle.listening(Vec::new());
+                            drop(listeners);
+                            info!("draining {} connections", tasks.0.len());
+                            let drained = tasks.stop(deadline).await;
+                            if let Some(metering) = &base.accounting {
+                                metering.flush().await;
+                            }
+                            info!(
+                                "drained {} connections, cut {}",
+                                drained.drained, drained.cut
+                            );
+                            let _ = report.send(drained);
+                            return Ok(());
+                        }
+                        handle::Control::Update(update, applied) => {
+                            let mut fresh = (*base).clone();
+                            match update.apply(&mut fresh) {
+                                Ok(()) => {
+                                    base = Arc::new(fresh);
+                                    for bound in &mut listeners {
+                                        bound.configure(&base);
+                                    }
+                                    info!("configuration updated");
+                                    let _ = applied.send(Ok(()));
+                                }
+                                Err(e) => {
+                                    warn!("configuration not updated: {}", e);
+                                    let _ = applied.send(Err(e));
+                                }
+                            }
+                        }
+                        #[cfg(feature = "config")]
+                        handle::Control::Reload(config, resources) => {
+                            base = Arc::new(config_file::reconfigure(&base, &config, &resources));
+                            for bound in &mut listeners {
+                                bound.configure(&base);
+                            }
+                            info!("configuration reloaded");
+                        }
+                    }
+                    continue;
+                }
+                accepted = accept_within(&listeners, &base) => accepted?,
+            };
+            let source = canonical_addr(source);
+            if !config.admits_source(source.ip()) {
+                config.stats.record_source_refused();
+                debug!("refused source {}, not allowed", source);
+                continue;
             }
-            SOCKS_ADDR_DOMAINNAME => {
-                let mut buffer = [0u8; 255];
-                self.read_exact(&mut buffer[..1]).await?;
-                let len = buffer[0];
-                self.read_exact(&mut buffer[..len as usize]).await?;
-                let mut port = [0u8; 2];
-                self.read_exact(&mut port).await?;
-                let port = u16::from_be_bytes(port);
-                let host = std::str::from_utf8(&buffer[..len as usize])?;
-                let sock = (host, port).to_socket_addrs()?.next();
-                if let None = sock {
-                    return Err(Socks5ServerError::DNSError(host.into()));
+            if config.stats.bans.check(source.ip()) {
+                debug!("refused banned source {}", source);
+                if config.tarpit.contains_key(&FailureClass::Denied) {
+                    tasks.0.spawn(async move {
+                        config.tarpit(FailureClass::Denied).await;
+                        drop(conn);
+                    });
                 }
-                let addr = sock.unwrap();
-                info!("connecting to {}:{}", host, port);
-                Ok(addr)
+                continue;
             }
-            _ => Err(Socks5ServerError::UnknowAddrType(header[3])),
+            let source_slot = match &config.source_limit {
+                Some(limit) => match limit.acquire(source.ip()) {
+                    Some(slot) => Some(slot),
+                    None => {
+                        config.stats.record_source_limited();
+                        debug!("closed connection from {}, too many open from it", source);
+                        continue;
+                    }
+                },
+                None => None,
+            };
+            let budget = match &config.handshake_budget {
+                Some(budget) => match budget.clone().try_acquire_owned() {
+                    Ok(slot) => Some(slot),
+                    Err(_) => {
+                        config.stats.record_handshake_refused();
+                        debug!("closed connection from {}, too many negotiating", source);
+                        continue;
+                    }
+                },
+                None => None,
+            };
+            let handler = handler.clone();
+
+            #[cfg(feature = "otel")]
+            let span = tracing::info_span!(
+                "socks5_serve",
+                net.peer.ip = %source.ip(),
+                net.peer.port = source.port(),
+                server.address = config.listener.as_deref().unwrap_or_default(),
+                socks.rep = tracing::field::Empty,
+            );
+            let stats = config.stats.clone();
+            let conn = ClientConn::tcp(conn, source);
+            let task = async move {
+                let _slots = (slot, source_slot);
+                let _open = config.stats.track_open();
+                let listener = config.listener.as_deref().unwrap_or_default();
+                let served = match handler {
+                    Some(handler) => intercept(conn, &config, &handler, budget)
+                        .await
+                        .map(|()| None),
+                    None => serve(conn, &config, budget).await.map(Some),
+                };
+                match served {
+                    Ok(Some(summary)) => {
+                        info!("{:?}, source {}, listener {}", summary, source, listener)
+                    }
+                    Ok(None) => debug!("intercepted, source {}, listener {}", source, listener),
+                    Err(e) => {
+                        if let (true, Some(autoban)) = (e.is_protocol_violation(), &config.autoban)
+                        {
+                            config.stats.bans.violation(source.ip(), autoban);
+                        }
+                        log!(
+                            e.severity(),
+                            "{:?}, source {}, listener {}",
+                            e,
+                            source,
+                            listener
+                        )
+                    }
+                }
+            };
+            #[cfg(feature = "otel")]
+            let task = tracing::Instrument::instrument(task, span);
+            tasks.0.spawn(isolated(task, source, stats));
         }
     }
-    async fn reply(mut self, content: &[u8]) -> Result<TcpStream> {
-        self.write_all(&content).await?;
-        self.flush().await?;
-        Ok(self.0)
+
+    /// Serves a single connection accepted elsewhere, as the accept loop of
+    /// [`run`](Self::run) does, driving the tunnel to completion. The
+    /// summary tells where the client connected, how much was relayed and
+    /// why the tunnel ended. The connection limits at accept are left to
+    /// the caller.
+    ///
+    /// `conn` may be of any transport, its client at `peer`; BIND and UDP
+    /// ASSOCIATE are only served to a [`TcpStream`].
+    pub async fn serve_connection<S>(&self, conn: S, peer: SocketAddr) -> Result<TunnelSummary>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        serve(ClientConn::new(conn, peer), &self.config, None).await
     }
 }
-async fn handle_client(conn: TcpStream, auth: Arc<AuthMethod>) -> Result<()> {
-    let mut conn = PendingHandshake(conn)
-        .handshake(&auth)
-        .await?
-        .authenticate(&auth)
-        .await?;
-    let addr = conn.handle_command().await;
-    let mut rep = [
-        SOCKS_VER,
-        SocksError::SUCCESS as u8,
-        SOCKS_RSV,
-        SOCKS_ADDR_IPV4,
-        0,
-        0,
-        0,
-        0,
-        0,
-        0,
-    ];
-    let addr = match addr {
-        Ok(c) => c,
-        Err(e) => {
-            rep[1] = match e {
-                Socks5ServerError::DNSError(_) => SocksError::HOST,
-                Socks5ServerError::UnsupportCommand(_) => SocksError::COMMAND,
-                Socks5ServerError::UnknowAddrType(_) => SocksError::ADDRESS,
-                _ => SocksError::FAIL,
-            } as u8;
-            conn.reply(&rep).await?;
-            return Err(e);
-        }
-    };
-
-    // --------------------------------
-    let delegate = TcpStream::connect(addr).await;
-    let delegate = match delegate {
-        Ok(c) => c,
-        Err(e) => {
-            rep[1] = SocksError::NETWORK as u8;
-            conn.reply(&rep).await?;
-            return Err(e.into());
-        }
-    };
-
-    let conn = conn.reply(&rep).await?;
 
-    let (conn_r, conn_w) = conn.into_split();
-    let (delegate_r, delegate_w) = delegate.into_split();
+/// The tasks serving the connections accepted, each reaped once it ends.
+/// Those still running when the accept loop ends are left running.
+#[derive(Default)]
+struct Connections(JoinSet<()>);
 
-    tokio::spawn(async move {
-        copy(conn_r, delegate_w).await;
-    });
+impl Connections {
+    /// Waits up to `deadline` for the connections to end, then cuts the
+    /// rest.
+    async fn stop(&mut self, deadline: Duration) -> Drained {
+        let deadline = Instant::now() + deadline;
+        let mut drained = 0;
+        while let Ok(Some(_)) = tokio::time::timeout_at(deadline, self.0.join_next()).await {
+            drained += 1;
+        }
+        let cut = self.0.len();
+        self.0.abort_all();
+        while self.0.join_next().await.is_some() {}
+        Drained { drained, cut }
+    }
+}
 
-    tokio::spawn(async move {
-        copy(delegate_r, conn_w).await;
-    });
+impl Drop for Connections {
+    fn drop(&mut self) {
+        self.0.detach_all();
+    }
+}
 
-    Ok(())
+/// Drives the `task` serving a connection from `source`, logging and
+/// counting a panic in it rather than letting the connection die silently.
+async fn isolated(task: impl Future<Output = ()>, source: SocketAddr, stats: Arc<Stats>) {
+    tokio::pin!(task);
+    let caught = std::future::poll_fn(|cx| {
+        match std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| task.as_mut().poll(cx))) {
+            Ok(Poll::Ready(())) => Poll::Ready(Ok(())),
+            Ok(Poll::Pending) => Poll::Pending,
+            Err(panic) => Poll::Ready(Err(panic)),
+        }
+    })
+    .await;
+    if let Err(panic) = caught {
+        stats.record_panic();
+        let message = match (panic.downcast_ref::<&str>(), panic.downcast_ref::<String>()) {
+            (Some(message), _) => message,
+            (_, Some(message)) => message.as_str(),
+            (None, None) => "unknown cause",
+        };
+        error!("connection panicked: {}, source {}", message, source);
+    }
 }
 
-async fn copy(mut r: impl AsyncRead + Unpin, mut w: impl AsyncWrite + Unpin) {
-    tokio::io::copy(&mut r, &mut w).await.unwrap_or(0);
+/// Accepts the next connection that fits within the connection limit,
+/// with its slot. Pausing, no connection is accepted until a slot frees
+/// up; otherwise those over the limit are closed right away.
+async fn accept_within(
+    listeners: &[listener::Bound],
+    base: &Config,
+) -> io::Result<(
+    TcpStream,
+    SocketAddr,
+    Arc<Config>,
+    Option<OwnedSemaphorePermit>,
+)> {
+    let (slots, mode) = match &base.connection_limit {
+        Some((slots, mode)) => (slots, mode),
+        None => {
+            let (conn, source, config) = accept(listeners, base).await?;
+            return Ok((conn, source, config, None));
+        }
+    };
+    if *mode == AtConnectionLimit::Pause {
+        let slot = slots
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("semaphore never closed");
+        let (conn, source, config) = accept(listeners, base).await?;
+        return Ok((conn, source, config, Some(slot)));
+    }
+    loop {
+        let (conn, source, config) = accept(listeners, base).await?;
+        match slots.clone().try_acquire_owned() {
+            Ok(slot) => return Ok((conn, source, config, Some(slot))),
+            Err(_) => {
+                config.stats.record_over_limit();
+                debug!("closed connection from {}, too many open", source);
+            }
+        }
+    }
+}
+
+/// Accepts from whichever listener has a connection ready, once the
+/// accept rate limit allows.
+async fn accept(
+    listeners: &[listener::Bound],
+    base: &Config,
+) -> io::Result<(TcpStream, SocketAddr, Arc<Config>)> {
+    if let Some(limiter) = &base.accept_limit {
+        let mut delayed = false;
+        while let Err(wait) = limiter.ready() {
+            if !delayed {
+                delayed = true;
+                base.stats.record_accept_delayed();
+            }
+            tokio::time::sleep(wait).await;
+        }
+    }
+    let mut failures = 0;
+    let mut warned: Option<Instant> = None;
+    let accepted = loop {
+        let e = match poll_accept(listeners).await {
+            Err(e) if listener::is_exhaustion(&e) => e,
+            accepted => break accepted?,
+        };
+        let backoff = listener::exhaustion_backoff(failures);
+        failures += 1;
+        if warned.is_none_or(|warned| warned.elapsed() >= EXHAUSTION_WARNING_INTERVAL) {
+            warned = Some(Instant::now());
+            warn!(
+                "cannot accept connections: {}, {} failures in a row, retrying in {:?}",
+                e, failures, backoff
+            );
+        }
+        tokio::time::sleep(backoff).await;
+    };
+    if let Some(limiter) = &base.accept_limit {
+        limiter.take();
+    }
+    Ok(accepted)
+}
+
+/// Polls every listener for a connection, skipping past transient errors.
+async fn poll_accept(
+    listeners: &[listener::Bound],
+) -> io::Result<(TcpStream, SocketAddr, Arc<Config>)> {
+    std::future::poll_fn(|cx| {
+        for bound in listeners {
+            loop {
+                match bound.listener.poll_accept(cx) {
+                    Poll::Ready(Ok((conn, source))) => {
+                        return Poll::Ready(Ok((conn, source, bound.config.clone())))
+                    }
+                    Poll::Ready(Err(e)) if listener::is_transient(&e) => debug!(
+                        "accept failed on listener {}, going on: {}",
+                        bound.config.listener.as_deref().unwrap_or_default(),
+                        e
+                    ),
+                    Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                    Poll::Pending => break,
+                }
+            }
+        }
+        Poll::Pending
+    })
+    .await
+}
+
+impl_deref!(PendingHandshake, BufReader<ClientConn>);
+impl PendingHandshake {
+    /// Selects the most preferred method that the client offers.
+    async fn handshake(mut self, config: &Config) -> Result<(PendingAuthenticate, &AuthMethod)> {
+        let mut header = [0u8; 2];
+        if self.read(&mut header[..1]).await? == 0 {
+            return Err(Socks5ServerError::EarlyEof);
+        }
+        self.read_exact(&mut header[1..]).await?;
+        if header[0] != SOCKS_VER {
+            let _conn = self.0.into_inner();
+            config.tarpit(FailureClass::Protocol).await;
+            return Err(Socks5ServerError::UnknowProtocol);
+        }
+        let mut offered = vec![0u8; header[1] as usize];
+        self.read_exact(&mut offered).await?;
+        let source = canonical_ip(self.get_ref().peer_addr()?.ip());
+        // Sources locked out of logins may still use the other methods.
+        let locked = match &config.login_throttle {
+            Some(_) => config.stats.logins.locked(source),
+            None => false,
+        };
+        let method = match config.auth_for(source).iter().find(|method| {
+            offered.contains(&method.to_code())
+                && !(locked && matches!(method, AuthMethod::UserPass(_)))
+        }) {
+            Some(method) => method,
+            None => {
+                let mut conn = self.0.into_inner();
+                config.tarpit(FailureClass::Auth).await;
+                conn.write_all(&[SOCKS_VER, AuthMethod::NoAvailable.to_code()])
+                    .await?;
+                conn.flush().await?;
+                return Err(Socks5ServerError::UnsupportAuth);
+            }
+        };
+        debug!(
+            "{} offered methods {:02X?}, selected {:#04X}",
+            self.get_ref().peer_addr()?,
+            offered,
+            method.to_code()
+        );
+
+        self.write_all(&[SOCKS_VER, method.to_code()]).await?;
+        self.flush().await?;
+
+        Ok((PendingAuthenticate(self.0), method))
+    }
+}
+
+impl_deref!(PendingAuthenticate, BufReader<ClientConn>);
+impl PendingAuthenticate {
+    /// Runs the subnegotiation of `method`, through its registered handler
+    /// or the built-in one. Returns the connection and the user the client
+    /// authenticated as, if any.
+    async fn authenticate(
+        mut self,
+        method: &AuthMethod,
+        config: &Config,
+    ) -> Result<(PendingCommand, Option<String>)> {
+        let handler: &dyn AuthHandler = match config.auth_handlers.get(&method.to_code()) {
+            Some(handler) => handler.as_ref(),
+            None => match method {
+                AuthMethod::NoAuth => &auth_handler::NoAuth,
+                AuthMethod::UserPass(_) => &auth_handler::UserPass,
+                _ => return Err(Socks5ServerError::UnsupportAuth),
+            },
+        };
+        let source = canonical_addr(self.get_ref().peer_addr()?);
+        let ctx = AuthContext {
+            conn: &mut self.0,
+            source,
+            method,
+            config,
+        };
+        let user = handler.authenticate(ctx).await?;
+        Ok((PendingCommand(self.0), user))
+    }
+}
+
+/// The protocol a client speaks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Dialect {
+    Socks5,
+    Socks4,
+    Http,
+}
+
+impl Dialect {
+    /// Encodes a reply with code `rep` and the bound address `bound`, as
+    /// far as the protocol carries them.
+    fn reply(self, rep: SocksError, bound: Option<SocketAddr>) -> Vec<u8> {
+        match self {
+            Dialect::Socks5 => intercept::encode_reply(rep, bound),
+            Dialect::Socks4 => socks4::encode_reply(rep, bound),
+            Dialect::Http => http_connect::encode_reply(rep),
+        }
+    }
+}
+
+/// What the client asks the server to do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Command {
+    Connect,
+    Bind,
+    UdpAssociate,
+}
+
+/// A destination as requested by the client, before resolution.
+pub(crate) enum Target {
+    Ip(SocketAddr),
+    Domain(Hostname, u16),
+}
+
+impl From<&Target> for Addr {
+    fn from(target: &Target) -> Self {
+        match target {
+            Target::Ip(addr) => Addr::SocketAddr(canonical_addr(*addr)),
+            Target::Domain(..) => Addr::HostnamePort(target.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Target::Ip(addr) => write!(f, "{}", canonical_addr(*addr)),
+            Target::Domain(host, port) => write!(f, "{}:{}", host, port),
+        }
+    }
+}
+
+impl Target {
+    fn port(&self) -> u16 {
+        match self {
+            Target::Ip(addr) => addr.port(),
+            Target::Domain(_, port) => *port,
+        }
+    }
+
+    /// The host part, without the port.
+    fn host(&self) -> String {
+        match self {
+            Target::Ip(addr) => canonical_ip(addr.ip()).to_string(),
+            Target::Domain(host, _) => host.to_string(),
+        }
+    }
+
+    async fn resolve(&self, config: &Config) -> Result<Vec<SocketAddr>> {
+        let addrs = match self {
+            Target::Ip(addr) => vec![*addr],
+            Target::Domain(host, port) => lookup(host.as_str(), *port, config).await?,
+        };
+        Ok(addrs)
+    }
+}
+
+/// The addresses a request may be connected to.
+///
+/// They come from exactly one resolution of the target and have passed
+/// every admission check. The dialer connects to nothing else, so a
+/// hostname is never resolved again between the checks and the connect,
+/// where a rebinding DNS server could swap in a different answer.
+struct Admitted {
+    target: Target,
+    /// The target requested, if rewritten to `target`.
+    requested: Option<Target>,
+    addrs: Vec<SocketAddr>,
+    /// The slot under the per-destination cap, taken once it is known
+    /// which address is connected to.
+    permit: Option<dest_limit::DestinationPermit>,
+    /// The address connected to, once connected.
+    connected: Option<SocketAddr>,
+    /// The user the client authenticated as, if any.
+    user: Option<String>,
+    outbound: SocketOptions,
+    client_tos: Option<u8>,
+    #[cfg(feature = "chaos")]
+    faults: faults::Plan,
+}
+
+impl Admitted {
+    /// Connects to the destination, retrying if so configured. Returns
+    /// the connection and the number of connects it took.
+    async fn dial(&self, config: &Config) -> io::Result<(TcpStream, u32)> {
+        let shown = config.privacy.show(&self.target.to_string());
+        match &self.requested {
+            Some(requested) => info!(
+                "connecting to {} (requested {})",
+                shown,
+                config.privacy.show(&requested.to_string())
+            ),
+            None => info!("connecting to {}", shown),
+        }
+        let mut connects = 0;
+        let retry = match &config.connect_retry {
+            Some(retry) => retry,
+            None => {
+                let conn = self.connect(&shown, &mut connects, config).await?;
+                return Ok((conn, connects));
+            }
+        };
+
+        let deadline = retry.budget.map(|budget| Instant::now() + budget);
+        let mut attempt = 1;
+        loop {
+            let connect = self.connect(&shown, &mut connects, config);
+            let result = match deadline {
+                Some(deadline) => tokio::time::timeout_at(deadline, connect)
+                    .await
+                    .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into())),
+                None => connect.await,
+            };
+            let e = match result {
+                Ok(conn) => return Ok((conn, connects)),
+                Err(e) => e,
+            };
+
+            let delay = retry.delay.sample();
+            let out_of_budget = deadline.is_some_and(|deadline| Instant::now() + delay >= deadline);
+            if attempt >= retry.attempts || out_of_budget || !retry.retry_on.contains(&e.kind()) {
+                info!("connect to {} failed after {} attempts", shown, attempt);
+                return Err(e);
+            }
+            debug!("connect to {} failed ({}), retrying", shown, e);
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
+    }
+
+    /// Races connects over the addresses as Happy Eyeballs does, failing
+    /// with the error of the last one.
+    async fn connect(
+        &self,
+        shown: &str,
+        connects: &mut u32,
+        config: &Config,
+    ) -> io::Result<TcpStream> {
+        let (egress, privacy) = (config.egress, config.privacy.clone());
+        let connect = |addr: SocketAddr| {
+            let (options, privacy) = (self.outbound.clone(), privacy.clone());
+            let shown = shown.to_owned();
+            async move {
+                let conn = egress.connect(addr, &options).await;
+                if let Err(e) = &conn {
+                    let at = privacy.show(&addr.to_string());
+                    debug!("connect to {} at {} failed: {}", shown, at, e);
+                }
+                conn
+            }
+        };
+        let (conn, addr, started) = match config.happy_eyeballs.connect(&self.addrs, connect).await
+        {
+            Ok(connected) => connected,
+            Err(e) => {
+                *connects += self.addrs.len() as u32;
+                return Err(e);
+            }
+        };
+        *connects += started;
+        let at = config.privacy.show(&addr.to_string());
+        info!("connected to {} at {}", shown, at);
+        Ok(conn)
+    }
+}
+
+/// Puts the target through the policy and the rewrites, resolves it once,
+/// vets the answers and takes the tunnel slot, if destinations are capped.
+async fn admit(
+    target: Target,
+    source: SocketAddr,
+    user: Option<String>,
+    config: &Config,
+) -> Result<Admitted> {
+    let (target, requested) = screen(target, source, user.as_deref(), config).await?;
+    let addrs = vet(&target, user.as_deref(), config).await?;
+    let permit = match &config.dest_limit {
+        // With several addresses to try, the one connected to decides.
+        Some(limit) if limit.key == DestinationKey::Resolved && addrs.len() > 1 => None,
+        Some(limit) => Some(limit.acquire(&target, addrs[0]).await?),
+        None => None,
+    };
+    let dest = admitted(target, addrs, permit, user, config);
+    Ok(Admitted { requested, ..dest })
+}
+
+/// The target a request from `source` for `target` is served with, as the
+/// policy and the rewrites have it, and the target requested if that was
+/// rewritten.
+async fn screen(
+    target: Target,
+    source: SocketAddr,
+    user: Option<&str>,
+    config: &Config,
+) -> Result<(Target, Option<Target>)> {
+    let target = match &config.policy {
+        Some(policy) => policy::evaluate(policy.as_ref(), target, source, user, config).await?,
+        None => target,
+    };
+    rewrite::apply(&config.rewrites, target)
+}
+
+/// Resolves `target` once and keeps the addresses the quotas, the
+/// blocklist, the rule sets, the guard on private destinations and the
+/// egress families let `user` reach.
+async fn vet(target: &Target, user: Option<&str>, config: &Config) -> Result<Vec<SocketAddr>> {
+    if let (Some(user), Some(quotas)) = (user, &config.quotas) {
+        if quotas.exceeded(user) {
+            return Err(Socks5ServerError::QuotaExceeded(user.to_owned()));
+        }
+    }
+    if let (Target::Domain(host, _), Some(blocklist)) = (target, &config.blocklist) {
+        if blocklist.blocks(host) {
+            return Err(Socks5ServerError::Blocked(target.to_string()));
+        }
+    }
+    let denied =
+        |decision: rules::Decision| Socks5ServerError::Denied(target.to_string(), decision.rule);
+    let user_denied =
+        |user: &str| Socks5ServerError::UserDenied(user.to_owned(), target.to_string());
+    // The rule sets left to decide by the resolved addresses.
+    let acl = match &config.acl {
+        Some(acl) => match acl.check_target(target) {
+            Some(decision) if decision.action == RuleAction::Deny => return Err(denied(decision)),
+            Some(_) => None,
+            None => Some(acl),
+        },
+        None => None,
+    };
+    let user_rules = match user.and_then(|user| Some((user, config.user_rules.get(user)?))) {
+        Some((user, rules)) => match rules.check_target(target) {
+            Some(decision) if decision.action == RuleAction::Deny => return Err(user_denied(user)),
+            Some(_) => None,
+            None => Some((user, rules)),
+        },
+        None => None,
+    };
+    let mut addrs = target.resolve(config).await?;
+    if let Some(acl) = acl {
+        acl.filter(target, &mut addrs).map_err(denied)?;
+    }
+    if let Some((user, rules)) = user_rules {
+        rules
+            .filter(target, &mut addrs)
+            .map_err(|_| user_denied(user))?;
+    }
+    if config.block_private {
+        addrs.retain(|addr| !private_dest::is_private(addr.ip()));
+        if addrs.is_empty() {
+            return Err(Socks5ServerError::PrivateDestination(target.to_string()));
+        }
+    }
+    addrs.retain(|addr| config.egress.allows(addr));
+    if addrs.is_empty() {
+        return Err(Socks5ServerError::NoEgress(target.to_string()));
+    }
+    Ok(addrs)
+}
+
+/// Settles the markings and faults of a tunnel to `target`, at `addrs`.
+fn admitted(
+    target: Target,
+    addrs: Vec<SocketAddr>,
+    permit: Option<dest_limit::DestinationPermit>,
+    user: Option<String>,
+    config: &Config,
+) -> Admitted {
+    let (outbound_tos, client_tos) = config.marking.for_destination(&target);
+    let outbound = match outbound_tos {
+        Some(tos) => config.outbound_socket.clone().tos(tos),
+        None => config.outbound_socket.clone(),
+    };
+    Admitted {
+        #[cfg(feature = "chaos")]
+        faults: config
+            .faults
+            .as_ref()
+            .map(|faults| faults.plan(&target))
+            .unwrap_or_default(),
+        target,
+        requested: None,
+        addrs,
+        permit,
+        connected: None,
+        user,
+        outbound,
+        client_tos,
+    }
+}
+
+async fn lookup(host: &str, port: u16, config: &Config) -> Result<Vec<SocketAddr>> {
+    if let Some(ip) = config.hosts.get(host) {
+        debug!("{} overridden to {}", config.privacy.show(host), ip);
+        return Ok(vec![SocketAddr::new(ip, port)]);
+    }
+    let cache = match &config.dns_cache {
+        Some(cache) => cache,
+        None => return resolve(host, port, config).await,
+    };
+    match cache.get(host, port) {
+        Some(Cached::Addrs(addrs)) => return Ok(addrs),
+        Some(Cached::Failed) => return Err(Socks5ServerError::DNSError(host.into())),
+        None => {}
+    }
+    let resolved = resolve(host, port, config).await;
+    match &resolved {
+        Ok(addrs) => cache.insert(host, addrs),
+        Err(Socks5ServerError::DNSError(_)) => cache.insert_failed(host),
+        Err(_) => {}
+    }
+    resolved
+}
+
+async fn resolve(host: &str, port: u16, config: &Config) -> Result<Vec<SocketAddr>> {
+    let deadline = Instant::now() + config.dns_timeout;
+    let timed_out = |_| Socks5ServerError::DNSTimeout(host.into());
+    // The permit moves into the lookup task, so a lookup abandoned on
+    // timeout keeps its slot until the resolver actually returns.
+    let permit = match &config.dns_permits {
+        Some(permits) => Some(
+            tokio::time::timeout_at(deadline, permits.clone().acquire_owned())
+                .await
+                .map_err(timed_out)?
+                .expect("semaphore never closed"),
+        ),
+        None => None,
+    };
+    let _in_flight = config.stats.track_dns();
+
+    let (query, resolver) = (host.to_owned(), config.resolver.clone());
+    let lookup = tokio::spawn(async move {
+        let _permit = permit;
+        resolver.resolve(&query, port).await
+    });
+    let result = tokio::time::timeout_at(deadline, lookup)
+        .await
+        .map_err(timed_out)?;
+    let addrs = match result.map_err(io::Error::from)? {
+        Ok(addrs) => addrs,
+        Err(e) => {
+            debug!("looking up {}: {}", config.privacy.show(host), e);
+            return Err(Socks5ServerError::DNSError(host.into()));
+        }
+    };
+    if addrs.is_empty() {
+        return Err(Socks5ServerError::DNSError(host.into()));
+    }
+    Ok(addrs)
+}
+
+impl_deref!(PendingCommand, BufReader<ClientConn>);
+impl PendingCommand {
+    async fn read_request(&mut self, config: &Config) -> Result<(Command, Target)> {
+        let mut header = [0u8; 4];
+        self.read_exact(&mut header).await?;
+        if header[0] != SOCKS_VER
+            || header[2] != SOCKS_RSV && !config.strictness.tolerate("RSV", &header[2..3])
+        {
+            return Err(Socks5ServerError::UnknowProtocol);
+        }
+        let command = match header[1] {
+            SOCKS_COMMAND_CONNECT => Command::Connect,
+            SOCKS_COMMAND_BIND => Command::Bind,
+            SOCKS_COMMAND_UDP_ASSOCIATE => Command::UdpAssociate,
+            command => return Err(Socks5ServerError::UnsupportCommand(command)),
+        };
+
+        let target = match header[3] {
+            SOCKS_ADDR_IPV4 => {
+                let mut buffer = [0u8; 4 + 2];
+                self.read_exact(&mut buffer).await?;
+                let ip: [u8; 4] = buffer[..4].try_into().unwrap();
+                let ip: Ipv4Addr = Ipv4Addr::from(ip);
+                let port = u16::from_be_bytes([buffer[4], buffer[5]]);
+                Target::Ip(SocketAddr::V4(SocketAddrV4::new(ip, port)))
+            }
+            SOCKS_ADDR_IPV6 => {
+                let mut buffer = [0u8; 16 + 2];
+                self.read_exact(&mut buffer).await?;
+                let ip: [u8; 16] = buffer[..16].try_into().unwrap();
+                let ip = Ipv6Addr::from(ip);
+                let port = u16::from_be_bytes([buffer[16], buffer[17]]);
+                match ip.to_ipv4_mapped() {
+                    Some(ip) if config.unmap_v4_mapped => {
+                        Target::Ip(SocketAddr::V4(SocketAddrV4::new(ip, port)))
+                    }
+                    _ => Target::Ip(SocketAddr::V6(SocketAddrV6::new(ip, port, 0, 0))),
+                }
+            }
+            SOCKS_ADDR_DOMAINNAME => {
+                let mut buffer = [0u8; 255];
+                self.read_exact(&mut buffer[..1]).await?;
+                let len = buffer[0];
+                self.read_exact(&mut buffer[..len as usize]).await?;
+                let mut port = [0u8; 2];
+                self.read_exact(&mut port).await?;
+                let port = u16::from_be_bytes(port);
+                let host =
+                    std::str::from_utf8(&buffer[..len as usize]).map_err(HostnameError::from)?;
+                Target::Domain(Hostname::new(host)?, port)
+            }
+            _ => return Err(Socks5ServerError::UnknowAddrType(header[3])),
+        };
+        // A zero port is a wildcard in BIND and UDP ASSOCIATE requests, but
+        // nothing to connect to.
+        if command == Command::Connect && target.port() == 0 {
+            return Err(Socks5ServerError::ZeroPort(target.host()));
+        }
+        Ok((command, target))
+    }
+    async fn reply(mut self, content: &[u8]) -> Result<BufReader<ClientConn>> {
+        self.write_all(content).await?;
+        self.flush().await?;
+        Ok(self.0)
+    }
+}
+async fn serve(
+    conn: ClientConn,
+    config: &Config,
+    budget: Option<OwnedSemaphorePermit>,
+) -> Result<TunnelSummary> {
+    let start = Instant::now();
+    let source = conn.peer_addr().map(canonical_addr);
+    let (mut requested, mut authenticated) = (None, None);
+    let served = match accepted(config, budget).await {
+        Ok(accepted) => {
+            handle_client(conn, config, accepted, &mut requested, &mut authenticated).await
+        }
+        Err(e) => Err(e),
+    };
+    let dest = requested
+        .filter(|_| config.privacy.reveals())
+        .map(|dest| config.privacy.show(&dest.to_string()));
+    let served = match served {
+        Ok(summary) => Ok(TunnelSummary {
+            destination: dest.clone(),
+            ..summary
+        }),
+        Err(e) => Err(config.privacy.scrub(e)),
+    };
+    if let (Some(audit), Ok(source)) = (&config.audit, source) {
+        audit.record(&audit::AuditEntry::new(
+            config.listener.as_deref(),
+            source,
+            authenticated.as_deref(),
+            dest,
+            &served,
+            start.elapsed(),
+        ));
+    }
+    served
+}
+
+/// Serves a connection with `handler` once its request is read.
+async fn intercept(
+    conn: ClientConn,
+    config: &Config,
+    handler: &Handler,
+    budget: Option<OwnedSemaphorePermit>,
+) -> Result<()> {
+    let accepted = accepted(config, budget).await?;
+    let source = canonical_addr(conn.peer_addr()?);
+    let negotiation = async {
+        let (conn, method) = PendingHandshake(BufReader::new(conn))
+            .handshake(config)
+            .await?;
+        let (mut conn, user) = conn.authenticate(method, config).await?;
+        let request = conn.read_request(config).await;
+        Ok((conn, user, request))
+    };
+    let (conn, user, request) = negotiate(negotiation, config).await?;
+    let target = match request {
+        Ok(_) if accepted.overloaded => Err(Socks5ServerError::Overloaded),
+        Ok((Command::Connect, target)) => Ok(target),
+        Ok((Command::Bind, _)) => Err(Socks5ServerError::UnsupportCommand(SOCKS_COMMAND_BIND)),
+        Ok((Command::UdpAssociate, _)) => Err(Socks5ServerError::UnsupportCommand(
+            SOCKS_COMMAND_UDP_ASSOCIATE,
+        )),
+        Err(e) => Err(e),
+    };
+    let target = match target {
+        Ok(target) => target,
+        Err(e) => return Err(refuse(conn, Dialect::Socks5, config, e).await),
+    };
+    drop(accepted.negotiating);
+
+    let request = IncomingRequest::new(conn, Addr::from(&target), source, user);
+    handler(request).await;
+    Ok(())
+}
+
+/// Runs `negotiation` for as long as clients have to negotiate.
+async fn negotiate<T>(negotiation: impl Future<Output = Result<T>>, config: &Config) -> Result<T> {
+    let timeout = config.negotiation_timeout;
+    tokio::time::timeout(timeout, negotiation)
+        .await
+        .map_err(|_| Socks5ServerError::NegotiationTimeout(timeout))?
+}
+
+/// Sends the failure reply for `e`, the way its class calls for, and hands
+/// `e` back for the caller to return.
+async fn refuse(
+    conn: PendingCommand,
+    dialect: Dialect,
+    config: &Config,
+    e: Socks5ServerError,
+) -> Socks5ServerError {
+    let rep = match e {
+        Socks5ServerError::DNSError(_) | Socks5ServerError::DNSTimeout(_) => SocksError::HOST,
+        Socks5ServerError::UnsupportCommand(_) | Socks5ServerError::HttpMethod(_) => {
+            SocksError::COMMAND
+        }
+        Socks5ServerError::UnknowAddrType(_)
+        | Socks5ServerError::InvalidHost(_)
+        | Socks5ServerError::ZeroPort(_)
+        | Socks5ServerError::BadHttpRequest(_)
+        | Socks5ServerError::DomainRefused(_) => SocksError::ADDRESS,
+        // Only SOCKS4 and HTTP clients get here, those that can't
+        // authenticate where they must.
+        Socks5ServerError::UnsupportAuth => SocksError::DENY,
+        Socks5ServerError::Denied(..)
+        | Socks5ServerError::Blocked(_)
+        | Socks5ServerError::PrivateDestination(_)
+        | Socks5ServerError::UserDenied(..)
+        | Socks5ServerError::QuotaExceeded(_) => SocksError::DENY,
+        Socks5ServerError::DestinationFull(_, rep) | Socks5ServerError::PolicyDenied(_, rep) => rep,
+        Socks5ServerError::NoEgress(_) => SocksError::NETWORK,
+        _ => SocksError::FAIL,
+    };
+    replied(config, rep);
+    let rep = dialect.reply(rep, None);
+    let sent = match e {
+        Socks5ServerError::DNSError(_)
+        | Socks5ServerError::DNSTimeout(_)
+        | Socks5ServerError::Overloaded
+        | Socks5ServerError::DestinationFull(..)
+        | Socks5ServerError::NoEgress(_)
+        | Socks5ServerError::DomainRefused(_)
+        | Socks5ServerError::InvalidRewrite(_)
+        | Socks5ServerError::QuotaExceeded(_)
+        | Socks5ServerError::IOError(_) => conn.reply(&rep).await.map(drop),
+        Socks5ServerError::Denied(..)
+        | Socks5ServerError::Blocked(_)
+        | Socks5ServerError::PolicyDenied(..)
+        | Socks5ServerError::PrivateDestination(_)
+        | Socks5ServerError::UserDenied(..) => {
+            config.tarpit(FailureClass::Denied).await;
+            conn.reply(&rep).await.map(drop)
+        }
+        _ => {
+            let mut conn = conn.0.into_inner();
+            config.tarpit(FailureClass::Protocol).await;
+            if let Socks5ServerError::UnknowAddrType(_) = e {
+                discard_unread(&conn);
+            }
+            async {
+                conn.write_all(&rep).await?;
+                conn.shutdown().await
+            }
+            .await
+            .map_err(Into::into)
+        }
+    };
+    match sent {
+        Ok(()) => e,
+        Err(sent) => sent,
+    }
+}
+
+/// Drops whatever the client has sent that was not read yet. Closing a
+/// socket with unread data resets the connection, which may discard the
+/// reply before the client reads it.
+fn discard_unread(conn: &ClientConn) {
+    let mut buf = [0u8; 512];
+    if let Some(conn) = conn.as_tcp() {
+        while let Ok(1..) = conn.try_read(&mut buf) {}
+    }
+}
+
+/// A connection let in past load shedding and the handshake limit.
+struct Accepted<'a> {
+    _active: stats::GaugeGuard<'a>,
+    negotiating: Negotiating<'a>,
+    /// Whether the connection is to be shed with a failure reply.
+    overloaded: bool,
+}
+
+/// `budget` is the connection's slot under the cap on connections
+/// negotiating, if any.
+async fn accepted(config: &Config, budget: Option<OwnedSemaphorePermit>) -> Result<Accepted<'_>> {
+    config.stats.record_accept(config.listener.as_ref());
+    let shed = config
+        .shedding
+        .as_ref()
+        .and_then(|shedding| shedding.check(&config.stats));
+    if shed.is_some() {
+        config.stats.record_shed();
+    }
+    if shed == Some(ShedMode::Close) {
+        return Err(Socks5ServerError::Overloaded);
+    }
+
+    let _active = config.stats.track_active();
+    let permit = match &config.handshake_permits {
+        Some((permits, wait)) => Some(
+            tokio::time::timeout(*wait, permits.acquire())
+                .await
+                .map_err(|_| Socks5ServerError::TooManyHandshakes)?
+                .expect("semaphore never closed"),
+        ),
+        None => None,
+    };
+    let negotiating = Negotiating {
+        _gauge: config.stats.track_handshaking(),
+        _permit: permit,
+        _budget: budget,
+        #[cfg(feature = "otel")]
+        since: config
+            .metrics
+            .as_deref()
+            .map(|metrics| (metrics, std::time::Instant::now())),
+    };
+    Ok(Accepted {
+        _active,
+        negotiating,
+        overloaded: shed.is_some(),
+    })
+}
+
+/// Marks a connection as negotiating until it starts relaying.
+struct Negotiating<'a> {
+    _gauge: stats::GaugeGuard<'a>,
+    _permit: Option<SemaphorePermit<'a>>,
+    _budget: Option<OwnedSemaphorePermit>,
+    #[cfg(feature = "otel")]
+    since: Option<(&'a otel::Metrics, std::time::Instant)>,
+}
+
+#[cfg(feature = "otel")]
+impl Drop for Negotiating<'_> {
+    fn drop(&mut self) {
+        if let Some((metrics, since)) = self.since {
+            metrics.negotiated(since.elapsed());
+        }
+    }
+}
+
+/// Notes the REP code a request was answered with.
+fn replied(config: &Config, rep: SocksError) {
+    #[cfg(feature = "otel")]
+    {
+        if let Some(metrics) = &config.metrics {
+            metrics.replied(rep);
+        }
+        tracing::Span::current().record("socks.rep", rep as u8);
+    }
+    #[cfg(not(feature = "otel"))]
+    let _ = (config, rep);
+}
+
+async fn handle_client(
+    conn: ClientConn,
+    config: &Config,
+    accepted: Accepted<'_>,
+    requested: &mut Option<Addr>,
+    authenticated: &mut Option<String>,
+) -> Result<TunnelSummary> {
+    let negotiation = async {
+        let mut conn = BufReader::new(conn);
+        let dialect = match conn.fill_buf().await?.first() {
+            Some(&socks4::SOCKS4_VER) if config.socks4 => Dialect::Socks4,
+            Some(&first) if config.http_connect && http_connect::sniff(first) => Dialect::Http,
+            _ => Dialect::Socks5,
+        };
+        let (conn, request) = match dialect {
+            Dialect::Socks5 => {
+                let (conn, method) = PendingHandshake(conn).handshake(config).await?;
+                let (mut conn, user) = conn.authenticate(method, config).await?;
+                *authenticated = user;
+                let request = conn.read_request(config).await;
+                (conn, request)
+            }
+            Dialect::Socks4 => {
+                let mut conn = PendingCommand(conn);
+                let request = socks4::read_request(&mut conn, config).await;
+                (conn, request)
+            }
+            Dialect::Http => {
+                let mut conn = PendingCommand(conn);
+                let request = http_connect::read_request(&mut conn, config).await;
+                (conn, request)
+            }
+        };
+        Ok((dialect, conn, request))
+    };
+    let (dialect, conn, request) = negotiate(negotiation, config).await?;
+    let request = match request {
+        Ok((command, target)) => {
+            *requested = Some(Addr::from(&target));
+            match &target {
+                // Shed connections still get a well-formed failure reply.
+                _ if accepted.overloaded => Err(Socks5ServerError::Overloaded),
+                Target::Domain(host, _) if !config.resolve_domains => {
+                    Err(Socks5ServerError::DomainRefused(host.to_string()))
+                }
+                _ => Ok((command, target)),
+            }
+        }
+        Err(e) => Err(e),
+    };
+    let served = config.bind_and_udp && conn.get_ref().as_tcp().is_some();
+    let dest = match request {
+        Ok((Command::Bind, _)) if !served => {
+            Err(Socks5ServerError::UnsupportCommand(SOCKS_COMMAND_BIND))
+        }
+        Ok((Command::UdpAssociate, _)) if !served => Err(Socks5ServerError::UnsupportCommand(
+            SOCKS_COMMAND_UDP_ASSOCIATE,
+        )),
+        Ok((Command::Bind, target)) => {
+            let user = authenticated.clone();
+            return bind::serve(conn, target, user, config, accepted.negotiating).await;
+        }
+        Ok((Command::UdpAssociate, target)) => {
+            let user = authenticated.clone();
+            return udp::serve(conn, target, user, config, accepted.negotiating).await;
+        }
+        Ok((Command::Connect, target)) => {
+            let source = canonical_addr(conn.get_ref().peer_addr()?);
+            admit(target, source, authenticated.clone(), config).await
+        }
+        Err(e) => Err(e),
+    };
+    let mut dest = match dest {
+        Ok(dest) => dest,
+        Err(e) => return Err(refuse(conn, dialect, config, e).await),
+    };
+    if let (Some(tos), Some(client)) = (dest.client_tos, conn.get_ref().as_tcp()) {
+        let v6 = client.local_addr()?.is_ipv6();
+        socket::mark(socket2::SockRef::from(client), v6, tos);
+    }
+    let negotiating = accepted.negotiating;
+    #[cfg(feature = "chaos")]
+    if let Some(injected) = dest.faults.reply {
+        if let Some(latency) = dest.faults.latency {
+            tokio::time::sleep(latency).await;
+        }
+        replied(config, injected);
+        conn.reply(&dialect.reply(injected, None)).await?;
+        return Err(Socks5ServerError::InjectedReply(injected));
+    }
+
+    // --------------------------------
+    #[cfg(feature = "otel")]
+    let dialing = std::time::Instant::now();
+    let timeout = config.connect_timeout;
+    let delegate = match tokio::time::timeout(timeout, dest.dial(config)).await {
+        Ok(delegate) => delegate.map_err(Socks5ServerError::from),
+        Err(_) => Err(Socks5ServerError::ConnectTimeout(
+            dest.target.to_string(),
+            timeout,
+        )),
+    };
+    #[cfg(feature = "otel")]
+    if let Some(metrics) = &config.metrics {
+        metrics.connected(dialing.elapsed(), delegate.is_ok());
+    }
+    let (delegate, attempts) = match delegate {
+        Ok(c) => c,
+        Err(e) => {
+            let code = match &e {
+                Socks5ServerError::IOError(e) => SocksError::from(e),
+                _ => SocksError::TTL,
+            };
+            replied(config, code);
+            conn.reply(&dialect.reply(code, None)).await?;
+            return Err(e);
+        }
+    };
+    dest.connected = Some(delegate.peer_addr()?);
+    if let (Some(limit), None) = (&config.dest_limit, &dest.permit) {
+        match limit.acquire(&dest.target, delegate.peer_addr()?).await {
+            Ok(permit) => dest.permit = Some(permit),
+            Err(e) => return Err(refuse(conn, dialect, config, e).await),
+        }
+    }
+    // BND.ADDR and BND.PORT of the reply: where the server connects from.
+    let bound = canonical_addr(delegate.local_addr()?);
+    let rep = dialect.reply(SocksError::SUCCESS, Some(bound));
 
-    w.shutdown().await.unwrap_or(());
+    #[cfg(feature = "tls")]
+    if let Some(tls) = config
+        .egress_tls
+        .iter()
+        .find(|tls| tls.matches(&dest.target))
+    {
+        let delegate = match tls.connect(&dest.target, delegate).await {
+            Ok(c) => c,
+            Err(e) => {
+                let code = tls::reply_code(&e);
+                replied(config, code);
+                conn.reply(&dialect.reply(code, None)).await?;
+                return Err(Socks5ServerError::EgressTls(dest.target.to_string(), e));
+            }
+        };
+        return established(conn, &rep, &dest, delegate, attempts, config, negotiating).await;
+    }
+
+    established(conn, &rep, &dest, delegate, attempts, config, negotiating).await
+}
+
+/// Sends the success reply and relays until the tunnel closes.
+async fn established<D>(
+    conn: PendingCommand,
+    rep: &[u8],
+    dest: &Admitted,
+    delegate: D,
+    attempts: u32,
+    config: &Config,
+    negotiating: Negotiating<'_>,
+) -> Result<TunnelSummary>
+where
+    D: AsyncRead + AsyncWrite + Unpin,
+{
+    #[cfg(feature = "chaos")]
+    if let Some(latency) = dest.faults.latency {
+        tokio::time::sleep(latency).await;
+    }
+    let conn = conn.reply(rep).await?;
+    replied(config, SocksError::SUCCESS);
+    drop(negotiating);
+    tunnel(conn, dest, delegate, attempts, config).await
+}
+
+/// Relays between the client and `delegate` until the tunnel closes.
+async fn tunnel<D>(
+    conn: BufReader<ClientConn>,
+    dest: &Admitted,
+    delegate: D,
+    attempts: u32,
+    config: &Config,
+) -> Result<TunnelSummary>
+where
+    D: AsyncRead + AsyncWrite + Unpin,
+{
+    let _relaying = config.stats.track_relaying();
+
+    // Clients may pipeline data right behind the request without waiting
+    // for the reply; whatever the negotiation reads buffered past the
+    // request belongs to the destination and must be relayed first.
+    let pending = conn.buffer().to_vec();
+    let conn = conn.into_inner();
+
+    #[cfg(feature = "chaos")]
+    let delegate = {
+        if dest.faults.reset.is_some() {
+            // A zero linger makes dropping the socket send an RST.
+            if let Some(conn) = conn.as_tcp() {
+                #[allow(deprecated)]
+                conn.set_linger(Some(Duration::ZERO))?;
+            }
+        }
+        faults::Faulty::new(delegate, &dest.faults)
+    };
+
+    let usage = match &config.accounting {
+        Some(_) => Some(accounting::Usage {
+            user: dest.user.clone(),
+            source: canonical_ip(conn.peer_addr()?.ip()),
+            dest: Addr::from(&dest.target),
+        }),
+        None => None,
+    };
+    let progress = relay::Progress::default();
+    let limits = relay::Limits {
+        stall: config.stall_timeout,
+        idle: config.idle_timeout,
+        up: config.max_bytes_up,
+        down: config.max_bytes_down,
+    };
+    let tunnel = relay::relay(conn, delegate, &pending, &progress, limits);
+    let tunnel = async {
+        match config.max_session {
+            Some(limit) => relay::limit(tunnel, limit, &progress).await,
+            None => tunnel.await,
+        }
+    };
+    let tunnel = async {
+        match (&dest.user, &config.quotas) {
+            (Some(user), Some(quotas)) => quotas.enforce(user, tunnel, &progress).await,
+            _ => tunnel.await,
+        }
+    };
+    let summary = match (&config.accounting, usage) {
+        (Some(metering), Some(usage)) => metering.meter(tunnel, &progress, usage).await,
+        _ => tunnel.await,
+    };
+    if config.privacy.reveals() {
+        config.stats.top.record(
+            &config.privacy.show(&dest.target.host()),
+            progress.up.load(Ordering::Relaxed) + progress.down.load(Ordering::Relaxed),
+        );
+    }
+    let mut summary = summary?;
+    summary.connect_attempts = attempts;
+    summary.connected = dest
+        .connected
+        .filter(|_| config.privacy.reveals())
+        .map(|addr| config.privacy.show(&canonical_addr(addr).to_string()));
+    #[cfg(feature = "otel")]
+    if let Some(metrics) = &config.metrics {
+        metrics.closed(&summary);
+    }
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+    use tokio::task::JoinHandle;
+
+    /// A CONNECT request for the IPv4 `addr`.
+    pub(super) fn connect_request(addr: SocketAddr) -> Vec<u8> {
+        let ip = match addr.ip() {
+            IpAddr::V4(ip) => ip.octets(),
+            IpAddr::V6(_) => unreachable!("IPv4 only"),
+        };
+        let mut request = vec![SOCKS_VER, SOCKS_COMMAND_CONNECT, SOCKS_RSV, SOCKS_ADDR_IPV4];
+        request.extend_from_slice(&ip);
+        request.extend_from_slice(&addr.port().to_be_bytes());
+        request
+    }
+
+    /// A CONNECT request for `host`.
+    pub(super) fn domain_request(host: &str, port: u16) -> Vec<u8> {
+        let mut request = vec![SOCKS_VER, SOCKS_COMMAND_CONNECT, SOCKS_RSV];
+        request.extend_from_slice(&[SOCKS_ADDR_DOMAINNAME, host.len() as u8]);
+        request.extend_from_slice(host.as_bytes());
+        request.extend_from_slice(&port.to_be_bytes());
+        request
+    }
+
+    /// Serves one TCP connection of `server`, handing back its client side.
+    pub(super) async fn serve(
+        server: Socks5Server,
+    ) -> (TcpStream, JoinHandle<Result<TunnelSummary>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap());
+        let (client, accepted) = tokio::join!(client, listener.accept());
+        let (conn, peer) = accepted.unwrap();
+        let served = tokio::spawn(async move { server.serve_connection(conn, peer).await });
+        (client.unwrap(), served)
+    }
+
+    #[tokio::test]
+    async fn keeps_data_pipelined_after_connect() {
+        // Greeting, request and payload in a single segment.
+        let server = new("127.0.0.1:0".parse().unwrap(), None).unwrap();
+        let dest = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (mut client, _served) = serve(server).await;
+        client.set_nodelay(true).unwrap();
+        let payload: Vec<u8> = (0..1024).map(|i| i as u8).collect();
+        let mut segment = vec![SOCKS_VER, 1, 0];
+        segment.extend_from_slice(&connect_request(dest.local_addr().unwrap()));
+        segment.extend_from_slice(&payload);
+        client.write_all(&segment).await.unwrap();
+
+        let mut replies = [0u8; 12];
+        client.read_exact(&mut replies).await.unwrap();
+        assert_eq!(replies[3], SocksError::SUCCESS as u8);
+        let (mut upstream, _) = dest.accept().await.unwrap();
+        let mut relayed = vec![0u8; payload.len()];
+        upstream.read_exact(&mut relayed).await.unwrap();
+        assert_eq!(relayed, payload);
+    }
+
+    #[tokio::test]
+    async fn serves_any_stream() {
+        let server = new("127.0.0.1:0".parse().unwrap(), None).unwrap();
+        let dest = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let dest_addr = dest.local_addr().unwrap();
+        let (mut client, conn) = io::duplex(4096);
+        let peer = "192.0.2.1:5000".parse().unwrap();
+        let served = tokio::spawn(async move { server.serve_connection(conn, peer).await });
+
+        client.write_all(&[SOCKS_VER, 1, 0]).await.unwrap();
+        let mut selected = [0u8; 2];
+        client.read_exact(&mut selected).await.unwrap();
+        assert_eq!(selected, [SOCKS_VER, 0]);
+        client.write_all(&connect_request(dest_addr)).await.unwrap();
+        let mut reply = [0u8; 10];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[1], SocksError::SUCCESS as u8);
+
+        let (mut upstream, _) = dest.accept().await.unwrap();
+        client.write_all(b"ping").await.unwrap();
+        client.shutdown().await.unwrap();
+        let mut relayed = Vec::new();
+        upstream.read_to_end(&mut relayed).await.unwrap();
+        assert_eq!(relayed, b"ping");
+        drop(upstream);
+
+        let summary = served.await.unwrap().unwrap();
+        assert_eq!(summary.bytes_up, 4);
+        assert_eq!(summary.connected, Some(dest_addr.to_string()));
+    }
+
+    #[test]
+    fn denies_mapped_sources_by_v4_network() {
+        let server = new("127.0.0.1:0".parse().unwrap(), None)
+            .unwrap()
+            .deny_sources(vec!["203.0.113.0/24".parse().unwrap()]);
+        let mapped: IpAddr = "::ffff:203.0.113.7".parse().unwrap();
+        assert!(!server.config.admits_source(mapped));
+        assert!(!server.config.admits_source(canonical_ip(mapped)));
+        assert!(server
+            .config
+            .admits_source("::ffff:198.51.100.7".parse().unwrap()));
+    }
+
+    /// Answers with each of its answers in turn, the last one from then on.
+    struct Fickle {
+        answers: Vec<SocketAddr>,
+        calls: AtomicUsize,
+    }
+
+    impl Resolver for Fickle {
+        fn resolve<'a>(&'a self, _: &'a str, _: u16) -> ResolveFuture<'a> {
+            let call = self.calls.fetch_add(1, Ordering::SeqCst);
+            let answer = self.answers[call.min(self.answers.len() - 1)];
+            Box::pin(async move { Ok(vec![answer]) })
+        }
+    }
+
+    #[tokio::test]
+    async fn connects_only_where_policy_looked() {
+        let (checked, swapped) = (
+            TcpListener::bind("127.0.0.1:0").await.unwrap(),
+            TcpListener::bind("127.0.0.1:0").await.unwrap(),
+        );
+        let swapped_port = swapped.local_addr().unwrap().port();
+        let resolver = Arc::new(Fickle {
+            answers: vec![checked.local_addr().unwrap(), swapped.local_addr().unwrap()],
+            calls: AtomicUsize::new(0),
+        });
+        let rule = Rule::deny()
+            .network(IpNet::from(Ipv4Addr::LOCALHOST))
+            .ports(swapped_port..=swapped_port);
+        let server = new("127.0.0.1:0".parse().unwrap(), None)
+            .unwrap()
+            .resolver(resolver.clone())
+            .acl(RuleSet::new(RuleAction::Allow).rule(rule));
+        let (mut client, _served) = serve(server).await;
+
+        client.write_all(&[SOCKS_VER, 1, 0]).await.unwrap();
+        client
+            .write_all(&domain_request("example.com", 80))
+            .await
+            .unwrap();
+        let mut replies = [0u8; 12];
+        client.read_exact(&mut replies).await.unwrap();
+        assert_eq!(replies[3], SocksError::SUCCESS as u8);
+
+        checked.accept().await.unwrap();
+        let contacted = tokio::time::timeout(Duration::from_millis(100), swapped.accept());
+        assert!(contacted.await.is_err());
+        assert_eq!(resolver.calls.load(Ordering::SeqCst), 1);
+    }
+
+    /// Whether a server of `strictness` goes along with a client sending
+    /// `deviation`: a request with a non-zero RSV byte, or a login of
+    /// another subnegotiation version.
+    async fn goes_along(strictness: ProtocolStrictness, deviation: &str) -> bool {
+        let dest = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server = new(
+            "127.0.0.1:0".parse().unwrap(),
+            Some(("user", "pass").into()),
+        )
+        .unwrap()
+        .protocol_strictness(strictness);
+        let (mut client, _served) = serve(server).await;
+        let mut login = vec![SOCKS_AUTH_USERPASS_VER, 4];
+        login.extend_from_slice(b"user\x04pass");
+        let mut request = connect_request(dest.local_addr().unwrap());
+        match deviation {
+            "RSV" => request[2] = 0x01,
+            "subnegotiation version" => login[0] = 0x05,
+            _ => unreachable!("no such deviation"),
+        }
+
+        client.write_all(&[SOCKS_VER, 1, 2]).await.unwrap();
+        client.write_all(&login).await.unwrap();
+        let mut reply = [0u8; 4];
+        if client.read_exact(&mut reply).await.is_err() || reply[3] != 0 {
+            return false;
+        }
+        client.write_all(&request).await.unwrap();
+        let mut reply = [0u8; 2];
+        client.read_exact(&mut reply).await.is_ok() && reply[1] == SocksError::SUCCESS as u8
+    }
+
+    #[tokio::test]
+    async fn tolerates_deviations_only_when_lenient() {
+        for deviation in ["RSV", "subnegotiation version"] {
+            let strict = goes_along(ProtocolStrictness::Strict, deviation).await;
+            assert!(!strict, "{} rejected when strict", deviation);
+            let lenient = goes_along(ProtocolStrictness::Lenient, deviation).await;
+            assert!(lenient, "{} tolerated when lenient", deviation);
+        }
+    }
+
+    /// Keeps every record logged, for tests of the levels failures are
+    /// logged at and of what they reveal.
+    pub(super) struct Capture(pub(super) std::sync::Mutex<Vec<(Level, String)>>);
+
+    impl log::Log for Capture {
+        fn enabled(&self, _: &log::Metadata<'_>) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record<'_>) {
+            let message = record.args().to_string();
+            self.0.lock().unwrap().push((record.level(), message));
+        }
+
+        fn flush(&self) {}
+    }
+
+    pub(super) static CAPTURED: Capture = Capture(std::sync::Mutex::new(Vec::new()));
+
+    /// The level the failure of a client sending `bytes` and going away is
+    /// logged at by a server running at `proxy`.
+    async fn logged_at(proxy: SocketAddr, bytes: &[u8]) -> Level {
+        let mut client = TcpStream::connect(proxy).await.unwrap();
+        let source = format!("source {},", client.local_addr().unwrap());
+        client.write_all(bytes).await.unwrap();
+        client.shutdown().await.unwrap();
+        let _ = client.read_to_end(&mut Vec::new()).await;
+        for _ in 0..100 {
+            let level = CAPTURED
+                .0
+                .lock()
+                .unwrap()
+                .iter()
+                .find(|(_, m)| m.contains(&source))
+                .map(|(level, _)| *level);
+            if let Some(level) = level {
+                return level;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("nothing logged for {}", source);
+    }
+
+    #[tokio::test]
+    async fn logs_failures_by_severity() {
+        let _ = log::set_logger(&CAPTURED);
+        log::set_max_level(log::LevelFilter::Trace);
+        let server = new("127.0.0.1:0".parse().unwrap(), None).unwrap();
+        let proxy = server.local_addr().unwrap();
+        tokio::spawn(server.run());
+        // Let it start listening.
+        tokio::task::yield_now().await;
+
+        let cases: [(&[u8], Level); 4] = [
+            (b"", Level::Debug),
+            (&[SOCKS_VER], Level::Info),
+            (
+                &[SOCKS_VER, 1, 0, SOCKS_VER, SOCKS_COMMAND_CONNECT],
+                Level::Info,
+            ),
+            (b"\x16\x03\x01\x00", Level::Warn),
+        ];
+        for (bytes, level) in cases {
+            assert_eq!(logged_at(proxy, bytes).await, level, "after {:02X?}", bytes);
+        }
+    }
+
+    #[test]
+    fn rates_server_side_failures_as_errors() {
+        let exhausted = io::Error::from_raw_os_error(24);
+        assert_eq!(
+            Socks5ServerError::IOError(exhausted).severity(),
+            Level::Error
+        );
+        assert_eq!(Socks5ServerError::Stopped.severity(), Level::Error);
+        let reset = io::Error::from(io::ErrorKind::ConnectionReset);
+        assert_eq!(Socks5ServerError::IOError(reset).severity(), Level::Info);
+    }
+
+    /// Offers username and password over `client` and logs in as `login`
+    /// with the subnegotiation `version`, returning the status byte, or
+    /// `None` if the server closed the connection.
+    pub(super) async fn log_in(
+        client: &mut TcpStream,
+        version: u8,
+        login: (&str, &str),
+    ) -> Option<u8> {
+        client.write_all(&[SOCKS_VER, 1, 2]).await.unwrap();
+        let mut request = vec![version, login.0.len() as u8];
+        request.extend_from_slice(login.0.as_bytes());
+        request.push(login.1.len() as u8);
+        request.extend_from_slice(login.1.as_bytes());
+        client.write_all(&request).await.unwrap();
+        let mut reply = [0u8; 4];
+        client.read_exact(&mut reply).await.ok().map(|_| reply[3])
+    }
+
+    #[tokio::test]
+    async fn authenticates_with_username_and_password() {
+        let cases = [
+            (SOCKS_AUTH_USERPASS_VER, "pass", SocksError::SUCCESS),
+            (SOCKS_AUTH_USERPASS_VER, "guess", SocksError::FAIL),
+            (0x05, "pass", SocksError::FAIL),
+        ];
+        let mut ended = Vec::new();
+        for (version, pass, status) in cases {
+            let server = new(
+                "127.0.0.1:0".parse().unwrap(),
+                Some(("user", "pass").into()),
+            )
+            .unwrap();
+            let (mut client, served) = serve(server).await;
+            let login = log_in(&mut client, version, ("user", pass)).await;
+            assert_eq!(login, Some(status as u8), "{:#04X} {}", version, pass);
+            drop(client);
+            ended.push(served.await.unwrap());
+        }
+        assert!(matches!(&ended[1], Err(Socks5ServerError::AuthFailed(user)) if user == "user"));
+        assert!(matches!(ended[2], Err(Socks5ServerError::UnknowProtocol)));
+    }
+
+    #[test]
+    fn refuses_credentials_longer_than_a_field() {
+        let long = "x".repeat(256);
+        let addr = "127.0.0.1:0".parse().unwrap();
+        assert!(new(addr, Some((&*long, "pass").into())).is_err());
+        assert!(new(addr, Some(("user", &*long).into())).is_err());
+    }
+
+    #[tokio::test]
+    async fn answers_no_acceptable_methods_before_closing() {
+        let server = new("127.0.0.1:0".parse().unwrap(), None).unwrap();
+        let (mut client, served) = serve(server).await;
+        // GSSAPI only.
+        client.write_all(&[SOCKS_VER, 1, 0x01]).await.unwrap();
+        let mut selected = Vec::new();
+        client.read_to_end(&mut selected).await.unwrap();
+        assert_eq!(selected, [SOCKS_VER, 0xFF]);
+        let served = served.await.unwrap();
+        assert!(matches!(served, Err(Socks5ServerError::UnsupportAuth)));
+    }
+
+    #[tokio::test]
+    async fn replies_connection_refused_for_a_closed_port() {
+        let closed = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let closed_addr = closed.local_addr().unwrap();
+        drop(closed);
+        let server = new("127.0.0.1:0".parse().unwrap(), None).unwrap();
+        let (mut client, _served) = serve(server).await;
+        client.write_all(&[SOCKS_VER, 1, 0]).await.unwrap();
+        client
+            .write_all(&connect_request(closed_addr))
+            .await
+            .unwrap();
+        let mut replies = [0u8; 12];
+        client.read_exact(&mut replies).await.unwrap();
+        assert_eq!(replies[3], SocksError::CONNECTION as u8);
+    }
+
+    #[tokio::test]
+    async fn resolves_localhost() {
+        let dest = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = dest.local_addr().unwrap().port();
+        let server = new("127.0.0.1:0".parse().unwrap(), None).unwrap();
+        let (mut client, _served) = serve(server).await;
+        client.write_all(&[SOCKS_VER, 1, 0]).await.unwrap();
+        client
+            .write_all(&domain_request("localhost", port))
+            .await
+            .unwrap();
+        let mut replies = [0u8; 4];
+        client.read_exact(&mut replies).await.unwrap();
+        assert_eq!(replies[3], SocksError::SUCCESS as u8);
+        dest.accept().await.unwrap();
+    }
+
+    /// Answers every lookup with the same addresses.
+    struct Answers(Vec<SocketAddr>);
+
+    impl Resolver for Answers {
+        fn resolve<'a>(&'a self, _: &'a str, _: u16) -> ResolveFuture<'a> {
+            Box::pin(async move { Ok(self.0.clone()) })
+        }
+    }
+
+    #[tokio::test]
+    async fn tries_every_address_resolved() {
+        let dead = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let dead_addr = dead.local_addr().unwrap();
+        drop(dead);
+        let live = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let live_addr = live.local_addr().unwrap();
+        let server = new("127.0.0.1:0".parse().unwrap(), None)
+            .unwrap()
+            .resolver(Arc::new(Answers(vec![dead_addr, live_addr])));
+        let (mut client, served) = serve(server).await;
+        client.write_all(&[SOCKS_VER, 1, 0]).await.unwrap();
+        client
+            .write_all(&domain_request("example.com", 80))
+            .await
+            .unwrap();
+        let mut replies = [0u8; 12];
+        client.read_exact(&mut replies).await.unwrap();
+        assert_eq!(replies[3], SocksError::SUCCESS as u8);
+
+        let (upstream, _) = live.accept().await.unwrap();
+        drop((client, upstream));
+        let summary = served.await.unwrap().unwrap();
+        assert_eq!(summary.connect_attempts, 2);
+        assert_eq!(summary.connected, Some(live_addr.to_string()));
+    }
+
+    /// Fails every lookup, noting what was looked up.
+    #[derive(Default)]
+    pub(super) struct Failing(std::sync::Mutex<Vec<(String, u16)>>);
+
+    impl Resolver for Failing {
+        fn resolve<'a>(&'a self, host: &'a str, port: u16) -> ResolveFuture<'a> {
+            self.0.lock().unwrap().push((host.to_owned(), port));
+            Box::pin(async { Err(io::Error::other("no such host")) })
+        }
+    }
+
+    #[tokio::test]
+    async fn resolves_through_the_resolver_given() {
+        let resolver = Arc::new(Failing::default());
+        let server = new("127.0.0.1:0".parse().unwrap(), None)
+            .unwrap()
+            .resolver(resolver.clone());
+        let (mut client, served) = serve(server).await;
+        client.write_all(&[SOCKS_VER, 1, 0]).await.unwrap();
+        client
+            .write_all(&domain_request("Example.COM", 80))
+            .await
+            .unwrap();
+        let mut replies = [0u8; 4];
+        client.read_exact(&mut replies).await.unwrap();
+        assert_eq!(replies[3], SocksError::HOST as u8);
+
+        let served = served.await.unwrap();
+        assert!(matches!(served, Err(Socks5ServerError::DNSError(_))));
+        let queries = resolver.0.lock().unwrap();
+        assert_eq!(*queries, [("example.com".to_owned(), 80)]);
+    }
+
+    /// Sends `request` over an in-memory stream, returning the REP code
+    /// and how serving it ended.
+    async fn in_memory(request: &[u8]) -> (u8, Result<TunnelSummary>) {
+        let server = new("127.0.0.1:0".parse().unwrap(), None).unwrap();
+        let (mut client, conn) = io::duplex(4096);
+        let peer = "192.0.2.1:5000".parse().unwrap();
+        let served = tokio::spawn(async move { server.serve_connection(conn, peer).await });
+        client.write_all(&[SOCKS_VER, 1, 0]).await.unwrap();
+        client.write_all(request).await.unwrap();
+        let mut replies = [0u8; 4];
+        client.read_exact(&mut replies).await.unwrap();
+        (replies[3], served.await.unwrap())
+    }
+
+    #[tokio::test]
+    async fn rejects_malformed_requests() {
+        use HostnameError::*;
+        type Check = fn(&Socks5ServerError) -> bool;
+        let cases: [(Vec<u8>, Check); 5] = [
+            (domain_request("", 80), |e| {
+                matches!(e, Socks5ServerError::InvalidHost(Empty))
+            }),
+            (domain_request("exa\0mple.com", 80), |e| {
+                matches!(e, Socks5ServerError::InvalidHost(ForbiddenChar))
+            }),
+            (domain_request("exa mple.com", 80), |e| {
+                matches!(e, Socks5ServerError::InvalidHost(ForbiddenChar))
+            }),
+            (domain_request("example.com", 0), |e| {
+                matches!(e, Socks5ServerError::ZeroPort(_))
+            }),
+            (connect_request("192.0.2.1:0".parse().unwrap()), |e| {
+                matches!(e, Socks5ServerError::ZeroPort(_))
+            }),
+        ];
+        for (request, check) in cases {
+            let (rep, served) = in_memory(&request).await;
+            assert_eq!(rep, SocksError::ADDRESS as u8, "{:02X?}", request);
+            let e = served.unwrap_err();
+            assert!(check(&e), "{:02X?}: {:?}", request, e);
+        }
+    }
+
+    #[tokio::test]
+    async fn drains_an_unknown_address_type_before_replying() {
+        let server = new("127.0.0.1:0".parse().unwrap(), None).unwrap();
+        let (mut client, served) = serve(server).await;
+        let mut request = vec![
+            SOCKS_VER,
+            1,
+            0,
+            SOCKS_VER,
+            SOCKS_COMMAND_CONNECT,
+            SOCKS_RSV,
+            0x02,
+        ];
+        request.extend_from_slice(&[0xAB; 18]);
+        client.write_all(&request).await.unwrap();
+
+        let mut replies = Vec::new();
+        client.read_to_end(&mut replies).await.unwrap();
+        assert_eq!(
+            replies[..4],
+            [SOCKS_VER, 0, SOCKS_VER, SocksError::ADDRESS as u8]
+        );
+        assert_eq!(replies.len(), 2 + 10, "the reply, then a clean close");
+        let served = served.await.unwrap();
+        assert!(matches!(
+            served,
+            Err(Socks5ServerError::UnknowAddrType(0x02))
+        ));
+    }
+
+    #[tokio::test]
+    async fn unmaps_ipv4_mapped_destinations_unless_told_not_to() {
+        let dest = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = dest.local_addr().unwrap().port();
+        let mut request = vec![SOCKS_VER, SOCKS_COMMAND_CONNECT, SOCKS_RSV, SOCKS_ADDR_IPV6];
+        request.extend_from_slice(&Ipv4Addr::LOCALHOST.to_ipv6_mapped().octets());
+        request.extend_from_slice(&port.to_be_bytes());
+        // Only IPv4 goes out, so the mapped form can't be connected to as
+        // given.
+        for (unmap, rep) in [(true, SocksError::SUCCESS), (false, SocksError::NETWORK)] {
+            let server = new("127.0.0.1:0".parse().unwrap(), None)
+                .unwrap()
+                .egress_family(EgressFamily::V4Only)
+                .unmap_ipv4_mapped(unmap);
+            let (mut client, _served) = serve(server).await;
+            client.write_all(&[SOCKS_VER, 1, 0]).await.unwrap();
+            client.write_all(&request).await.unwrap();
+            let mut replies = [0u8; 4];
+            client.read_exact(&mut replies).await.unwrap();
+            assert_eq!(replies[3], rep as u8, "unmap {}", unmap);
+        }
+        dest.accept().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn keeps_to_the_egress_family() {
+        let v4 = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let v6 = TcpListener::bind("[::1]:0").await.unwrap();
+        let (v4_addr, v6_addr) = (v4.local_addr().unwrap(), v6.local_addr().unwrap());
+        let mut v6_request = vec![SOCKS_VER, SOCKS_COMMAND_CONNECT, SOCKS_RSV, SOCKS_ADDR_IPV6];
+        v6_request.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        v6_request.extend_from_slice(&v6_addr.port().to_be_bytes());
+        let server = |family| {
+            new("127.0.0.1:0".parse().unwrap(), None)
+                .unwrap()
+                .egress_family(family)
+                .resolver(Arc::new(Answers(vec![v6_addr, v4_addr])))
+        };
+        let cases = [
+            (
+                EgressFamily::V4Only,
+                v6_request.clone(),
+                SocksError::NETWORK,
+            ),
+            (
+                EgressFamily::V6Only,
+                connect_request(v4_addr),
+                SocksError::NETWORK,
+            ),
+            (EgressFamily::V6Only, v6_request, SocksError::SUCCESS),
+            (
+                EgressFamily::V4Only,
+                domain_request("example.com", 80),
+                SocksError::SUCCESS,
+            ),
+        ];
+        for (family, request, rep) in cases {
+            let (mut client, _served) = serve(server(family)).await;
+            client.write_all(&[SOCKS_VER, 1, 0]).await.unwrap();
+            client.write_all(&request).await.unwrap();
+            let mut replies = [0u8; 4];
+            client.read_exact(&mut replies).await.unwrap();
+            assert_eq!(replies[3], rep as u8, "{:?}, {:02X?}", family, request);
+        }
+
+        // The domain resolved to both; only the IPv4 address was tried.
+        let (_, from) = v4.accept().await.unwrap();
+        assert!(from.is_ipv4());
+        v6.accept().await.unwrap();
+        let contacted = tokio::time::timeout(Duration::from_millis(50), v6.accept());
+        assert!(contacted.await.is_err());
+    }
+
+    #[tokio::test]
+    async fn denies_what_the_acl_denies() {
+        let rules = RuleSet::new(RuleAction::Allow)
+            .rule(Rule::allow().host("ok.internal"))
+            .rule(Rule::deny().host("*.internal"));
+        let server = new("127.0.0.1:0".parse().unwrap(), None)
+            .unwrap()
+            .acl(rules);
+        let (mut client, served) = serve(server).await;
+        client.write_all(&[SOCKS_VER, 1, 0]).await.unwrap();
+        client
+            .write_all(&domain_request("db.internal", 80))
+            .await
+            .unwrap();
+        let mut replies = [0u8; 4];
+        client.read_exact(&mut replies).await.unwrap();
+        assert_eq!(replies[3], SocksError::DENY as u8);
+        let served = served.await.unwrap();
+        assert!(matches!(served, Err(Socks5ServerError::Denied(_, Some(1)))));
+    }
+
+    #[tokio::test]
+    async fn reports_both_the_requested_and_the_rewritten_destination() {
+        let dest = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let dest_addr = dest.local_addr().unwrap();
+        let rewrite = Rewrite::new()
+            .host("old.example")
+            .to_host("127.0.0.1")
+            .to_port(dest_addr.port());
+        let server = new("127.0.0.1:0".parse().unwrap(), None)
+            .unwrap()
+            .rewrite(rewrite);
+        let (mut client, served) = serve(server).await;
+        client.write_all(&[SOCKS_VER, 1, 0]).await.unwrap();
+        client
+            .write_all(&domain_request("old.example", 80))
+            .await
+            .unwrap();
+        let mut replies = [0u8; 12];
+        client.read_exact(&mut replies).await.unwrap();
+        assert_eq!(replies[3], SocksError::SUCCESS as u8);
+
+        let (upstream, outbound) = dest.accept().await.unwrap();
+        let bound = u16::from_be_bytes([replies[10], replies[11]]);
+        assert_eq!(
+            bound,
+            outbound.port(),
+            "the reply tells the outbound address"
+        );
+        drop((client, upstream));
+        let summary = served.await.unwrap().unwrap();
+        assert_eq!(summary.destination.as_deref(), Some("old.example:80"));
+        assert_eq!(summary.connected, Some(dest_addr.to_string()));
+    }
+
+    /// What a server `limited` so relays of `sent` bytes from the client
+    /// and `returned` from the destination: the bytes the destination got,
+    /// those the client got and the tunnel's summary.
+    async fn relayed(
+        limited: Socks5Server,
+        sent: usize,
+        returned: usize,
+    ) -> (usize, usize, TunnelSummary) {
+        let dest = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (mut client, served) = serve(limited).await;
+        client.write_all(&[SOCKS_VER, 1, 0]).await.unwrap();
+        client
+            .write_all(&connect_request(dest.local_addr().unwrap()))
+            .await
+            .unwrap();
+        let mut replies = [0u8; 12];
+        client.read_exact(&mut replies).await.unwrap();
+        assert_eq!(replies[3], SocksError::SUCCESS as u8);
+
+        let (mut upstream, _) = dest.accept().await.unwrap();
+        let destination = tokio::spawn(async move {
+            upstream.write_all(&vec![1u8; returned]).await.unwrap();
+            let mut got = Vec::new();
+            let _ = upstream.read_to_end(&mut got).await;
+            got.len()
+        });
+        let _ = client.write_all(&vec![2u8; sent]).await;
+        let _ = client.shutdown().await;
+        let mut got = Vec::new();
+        let _ = client.read_to_end(&mut got).await;
+        let summary = served.await.unwrap().unwrap();
+        (destination.await.unwrap(), got.len(), summary)
+    }
+
+    #[tokio::test]
+    async fn cuts_a_tunnel_at_either_byte_limit() {
+        let server = || new("127.0.0.1:0".parse().unwrap(), None).unwrap();
+        let (up, _, summary) = relayed(server().max_bytes_up(1000), 1500, 0).await;
+        assert_eq!(up, 1000, "truncated at the limit");
+        assert_eq!(summary.bytes_up, 1000);
+        assert_eq!(summary.close_reason, CloseReason::ByteLimit);
+
+        let (_, down, summary) = relayed(server().max_bytes_down(600), 0, 2000).await;
+        assert_eq!(down, 600);
+        assert_eq!(summary.bytes_down, 600);
+        assert_eq!(summary.close_reason, CloseReason::ByteLimit);
+
+        let limited = server().max_bytes_up(1000).max_bytes_down(1000);
+        let (up, down, summary) = relayed(limited, 900, 900).await;
+        assert_eq!((up, down), (900, 900), "each direction counted alone");
+        assert_eq!(summary.close_reason, CloseReason::ClientClosed);
+    }
+
+    #[tokio::test]
+    async fn closes_clients_that_do_not_negotiate_in_time() {
+        let timeout = Duration::from_millis(200);
+        for sent in [&[][..], &[SOCKS_VER, 1, 0][..]] {
+            let server = new("127.0.0.1:0".parse().unwrap(), None)
+                .unwrap()
+                .negotiation_timeout(timeout);
+            let (mut client, served) = serve(server).await;
+            client.write_all(sent).await.unwrap();
+            let start = Instant::now();
+            let _ = client.read_to_end(&mut Vec::new()).await;
+            assert!(
+                start.elapsed() < timeout * 5,
+                "closed after {:?}",
+                start.elapsed()
+            );
+            let served = served.await.unwrap();
+            assert!(
+                matches!(served, Err(Socks5ServerError::NegotiationTimeout(t)) if t == timeout),
+                "{:?}",
+                served
+            );
+        }
+    }
+
+    /// A local address connects to it hang on, its listener's queue full,
+    /// with what keeps the queue full.
+    async fn blackhole() -> (SocketAddr, Vec<TcpStream>, tokio::net::TcpListener) {
+        let socket = tokio::net::TcpSocket::new_v4().unwrap();
+        socket.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let listener = socket.listen(1).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut queued = Vec::new();
+        while let Ok(Ok(conn)) =
+            tokio::time::timeout(Duration::from_millis(100), TcpStream::connect(addr)).await
+        {
+            queued.push(conn);
+        }
+        (addr, queued, listener)
+    }
+
+    #[tokio::test]
+    async fn replies_ttl_expired_when_connecting_takes_too_long() {
+        let (dead, _queued, _listener) = blackhole().await;
+        let timeout = Duration::from_millis(300);
+        let server = new("127.0.0.1:0".parse().unwrap(), None)
+            .unwrap()
+            .connect_timeout(timeout)
+            .resolver(Arc::new(Answers(vec![dead, dead, dead])));
+        let (mut client, served) = serve(server).await;
+        let start = Instant::now();
+        client.write_all(&[SOCKS_VER, 1, 0]).await.unwrap();
+        client
+            .write_all(&domain_request("slow.example", dead.port()))
+            .await
+            .unwrap();
+        let mut replies = [0u8; 4];
+        client.read_exact(&mut replies).await.unwrap();
+        assert_eq!(replies[3], SocksError::TTL as u8);
+        assert!(
+            start.elapsed() < timeout * 2,
+            "every address within the budget, not {:?}",
+            start.elapsed()
+        );
+        let served = served.await.unwrap();
+        assert!(
+            matches!(served, Err(Socks5ServerError::ConnectTimeout(_, t)) if t == timeout),
+            "{:?}",
+            served
+        );
+    }
+
+    /// Takes `.0` to answer any lookup.
+    struct Sleepy(Duration);
+
+    impl Resolver for Sleepy {
+        fn resolve<'a>(&'a self, _: &'a str, port: u16) -> ResolveFuture<'a> {
+            Box::pin(async move {
+                tokio::time::sleep(self.0).await;
+                Ok(vec![SocketAddr::from(([127, 0, 0, 1], port))])
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn replies_host_unreachable_when_resolving_takes_too_long() {
+        let timeout = Duration::from_millis(200);
+        for cached in [false, true] {
+            let mut server = new("127.0.0.1:0".parse().unwrap(), None)
+                .unwrap()
+                .dns_timeout(timeout)
+                .resolver(Arc::new(Sleepy(Duration::from_secs(10))));
+            if cached {
+                server = server.dns_cache(DnsCache::new(16, Duration::from_secs(60)));
+            }
+            let (mut client, served) = serve(server).await;
+            let start = Instant::now();
+            client.write_all(&[SOCKS_VER, 1, 0]).await.unwrap();
+            client
+                .write_all(&domain_request("wedged.example", 80))
+                .await
+                .unwrap();
+            let mut replies = [0u8; 4];
+            client.read_exact(&mut replies).await.unwrap();
+            assert_eq!(replies[3], SocksError::HOST as u8);
+            assert!(start.elapsed() < timeout * 5, "cached: {}", cached);
+            let served = served.await.unwrap();
+            assert!(
+                matches!(&served, Err(Socks5ServerError::DNSTimeout(host)) if host == "wedged.example"),
+                "{:?}",
+                served
+            );
+        }
+    }
+
+    /// Whether a greeting sent to `client` is answered within a while.
+    async fn greeted(client: &mut TcpStream) -> bool {
+        let mut selected = [0u8; 2];
+        let read = client.read_exact(&mut selected);
+        matches!(
+            tokio::time::timeout(Duration::from_millis(200), read).await,
+            Ok(Ok(_))
+        )
+    }
+
+    #[tokio::test]
+    async fn holds_off_clients_over_the_connection_limit() {
+        let server = new("127.0.0.1:0".parse().unwrap(), None)
+            .unwrap()
+            .max_connections(2, AtConnectionLimit::Pause);
+        let (proxy, stats) = (server.local_addr().unwrap(), server.stats());
+        tokio::spawn(server.run());
+        tokio::task::yield_now().await;
+
+        let mut clients = Vec::new();
+        for _ in 0..3 {
+            let mut client = TcpStream::connect(proxy).await.unwrap();
+            client.write_all(&[SOCKS_VER, 1, 0]).await.unwrap();
+            clients.push(client);
+        }
+        assert!(greeted(&mut clients[0]).await);
+        assert!(greeted(&mut clients[1]).await);
+        assert!(!greeted(&mut clients[2]).await, "held in the backlog");
+        assert_eq!(stats.open(), 2);
+
+        drop(clients.remove(0));
+        assert!(greeted(&mut clients[1]).await, "let in once one closed");
+    }
+
+    #[tokio::test]
+    async fn closes_clients_over_the_connection_limit_when_told_to() {
+        let server = new("127.0.0.1:0".parse().unwrap(), None)
+            .unwrap()
+            .max_connections(1, AtConnectionLimit::Close);
+        let (proxy, stats) = (server.local_addr().unwrap(), server.stats());
+        tokio::spawn(server.run());
+        tokio::task::yield_now().await;
+
+        let mut first = TcpStream::connect(proxy).await.unwrap();
+        first.write_all(&[SOCKS_VER, 1, 0]).await.unwrap();
+        assert!(greeted(&mut first).await);
+        let mut second = TcpStream::connect(proxy).await.unwrap();
+        let mut read = Vec::new();
+        second.read_to_end(&mut read).await.unwrap();
+        assert!(read.is_empty());
+        assert_eq!(stats.over_limit(), 1);
+    }
+
+    #[tokio::test]
+    async fn closes_connections_over_the_source_limit() {
+        let server = new("127.0.0.1:0".parse().unwrap(), None)
+            .unwrap()
+            .max_connections_per_source(1);
+        let (proxy, stats) = (server.local_addr().unwrap(), server.stats());
+        tokio::spawn(server.run());
+        tokio::task::yield_now().await;
+
+        let mut first = TcpStream::connect(proxy).await.unwrap();
+        first.write_all(&[SOCKS_VER, 1, 0]).await.unwrap();
+        assert!(greeted(&mut first).await);
+        let mut second = TcpStream::connect(proxy).await.unwrap();
+        let mut read = Vec::new();
+        second.read_to_end(&mut read).await.unwrap();
+        assert!(read.is_empty());
+        assert_eq!(stats.source_limited(), 1);
+
+        drop(first);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let mut third = TcpStream::connect(proxy).await.unwrap();
+        third.write_all(&[SOCKS_VER, 1, 0]).await.unwrap();
+        assert!(greeted(&mut third).await, "the slot given back");
+    }
+
+    #[tokio::test]
+    async fn paces_accepts_past_the_burst() {
+        let server = new("127.0.0.1:0".parse().unwrap(), None)
+            .unwrap()
+            .accept_rate_limit(20.0, 2);
+        let (proxy, stats) = (server.local_addr().unwrap(), server.stats());
+        tokio::spawn(server.run());
+        tokio::task::yield_now().await;
+
+        let start = Instant::now();
+        let mut clients = Vec::new();
+        for _ in 0..6 {
+            let mut client = TcpStream::connect(proxy).await.unwrap();
+            client.write_all(&[SOCKS_VER, 1, 0]).await.unwrap();
+            clients.push(client);
+        }
+        let mut greeted_at = Vec::new();
+        for client in &mut clients {
+            client.read_exact(&mut [0u8; 2]).await.unwrap();
+            greeted_at.push(start.elapsed());
+        }
+        assert!(
+            greeted_at[1] < Duration::from_millis(40),
+            "{:?}",
+            greeted_at
+        );
+        // Four more at 20 a second.
+        assert!(
+            greeted_at[5] >= Duration::from_millis(190),
+            "{:?}",
+            greeted_at
+        );
+        assert!(stats.accepts_delayed() > 0);
+    }
+
+    #[tokio::test]
+    async fn keeps_accepting_after_aborted_connections() {
+        let server = new("127.0.0.1:0".parse().unwrap(), None).unwrap();
+        let proxy = server.local_addr().unwrap();
+        let running = tokio::spawn(server.run());
+        tokio::task::yield_now().await;
+
+        for _ in 0..10 {
+            let aborted = TcpStream::connect(proxy).await.unwrap();
+            // A zero linger resets the connection on drop.
+            #[allow(deprecated)]
+            aborted.set_linger(Some(Duration::ZERO)).unwrap();
+        }
+        let mut client = TcpStream::connect(proxy).await.unwrap();
+        client.write_all(&[SOCKS_VER, 1, 0]).await.unwrap();
+        assert!(greeted(&mut client).await);
+        assert!(!running.is_finished());
+    }
+
+    /// Panics verifying anyone but `.0`.
+    struct Panicky(&'static str);
+
+    impl Authenticator for Panicky {
+        fn verify<'a>(&'a self, username: &'a str, _: &'a str, _: SocketAddr) -> VerifyFuture<'a> {
+            Box::pin(async move {
+                assert_eq!(username, self.0, "no such user");
+                AuthDecision::Accept(username.into())
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn survives_and_counts_panicking_connections() {
+        let server = new("127.0.0.1:0".parse().unwrap(), None)
+            .unwrap()
+            .auth_methods(vec![AuthMethod::UserPass(None)])
+            .authenticator(Arc::new(Panicky("alice")));
+        let (proxy, stats) = (server.local_addr().unwrap(), server.stats());
+        let running = tokio::spawn(server.run());
+        tokio::task::yield_now().await;
+
+        for (user, status) in [("mallory", None), ("alice", Some(0))] {
+            let mut client = TcpStream::connect(proxy).await.unwrap();
+            let login = log_in(&mut client, SOCKS_AUTH_USERPASS_VER, (user, "x")).await;
+            assert_eq!(login, status, "{}", user);
+        }
+        assert_eq!(stats.panics(), 1);
+        assert!(!running.is_finished());
+    }
+
+    /// Waits a while for `done` to hold.
+    async fn eventually(done: impl Fn() -> bool) -> bool {
+        for _ in 0..100 {
+            if done() {
+                return true;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        false
+    }
+
+    #[tokio::test]
+    async fn counts_connections_while_they_are_served() {
+        let server = new("127.0.0.1:0".parse().unwrap(), None).unwrap();
+        let (proxy, stats) = (server.local_addr().unwrap(), server.stats());
+        tokio::spawn(server.run());
+        tokio::task::yield_now().await;
+        let dest = TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+        let mut tunnels = Vec::new();
+        for _ in 0..3 {
+            let mut client = TcpStream::connect(proxy).await.unwrap();
+            client.write_all(&[SOCKS_VER, 1, 0]).await.unwrap();
+            client
+                .write_all(&connect_request(dest.local_addr().unwrap()))
+                .await
+                .unwrap();
+            client.read_exact(&mut [0u8; 12]).await.unwrap();
+            tunnels.push((client, dest.accept().await.unwrap().0));
+        }
+        let waiting = TcpStream::connect(proxy).await.unwrap();
+        assert!(eventually(|| stats.active() == 4).await);
+        assert!(eventually(|| stats.relaying() == 3 && stats.handshaking() == 1).await);
+
+        drop(waiting);
+        tunnels.truncate(1);
+        assert!(eventually(|| stats.active() == 1 && stats.relaying() == 1).await);
+        drop(tunnels);
+        assert!(eventually(|| stats.active() == 0).await);
+    }
+
+    /// A client of a server at `proxy`, tunneled to `dest`, and the
+    /// destination's end of the tunnel.
+    async fn tunnel(proxy: SocketAddr, dest: &TcpListener) -> (TcpStream, TcpStream) {
+        let mut client = TcpStream::connect(proxy).await.unwrap();
+        client.write_all(&[SOCKS_VER, 1, 0]).await.unwrap();
+        client
+            .write_all(&connect_request(dest.local_addr().unwrap()))
+            .await
+            .unwrap();
+        client.read_exact(&mut [0u8; 12]).await.unwrap();
+        (client, dest.accept().await.unwrap().0)
+    }
+
+    #[tokio::test]
+    async fn shuts_down_cutting_connections_and_freeing_the_port() {
+        let server = new("127.0.0.1:0".parse().unwrap(), None).unwrap();
+        let handle = server.handle();
+        let running = tokio::spawn(server.run());
+        tokio::task::yield_now().await;
+        let proxy = handle.local_addr().unwrap();
+        let dest = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (mut client, _upstream) = tunnel(proxy, &dest).await;
+
+        handle.shutdown().unwrap();
+        let ran = tokio::time::timeout(Duration::from_secs(1), running).await;
+        assert!(matches!(ran, Ok(Ok(Ok(())))), "{:?}", ran);
+        let cut = client.read(&mut [0u8; 1]).await;
+        assert!(matches!(cut, Ok(0) | Err(_)), "{:?}", cut);
+        assert!(handle.local_addr().is_err());
+        assert!(handle.shutdown().is_err(), "already stopped");
+        let refused = TcpStream::connect(proxy).await.unwrap_err();
+        assert_eq!(refused.kind(), io::ErrorKind::ConnectionRefused);
+    }
+
+    #[tokio::test]
+    async fn drains_transfers_in_progress_and_cuts_the_rest() {
+        let server = new("127.0.0.1:0".parse().unwrap(), None).unwrap();
+        let handle = server.handle();
+        let running = tokio::spawn(server.run());
+        tokio::task::yield_now().await;
+        let proxy = handle.local_addr().unwrap();
+        let dest = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (mut downloading, mut upstream) = tunnel(proxy, &dest).await;
+        let (_idle, _idle_upstream) = tunnel(proxy, &dest).await;
+
+        let drain = handle.clone();
+        let drained = tokio::spawn(async move { drain.drain(Duration::from_millis(500)).await });
+        let mut refused = false;
+        for _ in 0..100 {
+            if TcpStream::connect(proxy).await.is_err() {
+                refused = true;
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        assert!(refused, "new connections refused while draining");
+
+        let transfer = tokio::spawn(async move {
+            for chunk in 0..10u8 {
+                upstream.write_all(&[chunk; 1000]).await.unwrap();
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        });
+        let mut downloaded = vec![0u8; 10_000];
+        downloading.read_exact(&mut downloaded).await.unwrap();
+        assert_eq!(downloaded[9_999], 9, "the transfer completed");
+        transfer.await.unwrap();
+        drop(downloading);
+
+        let drained = drained.await.unwrap().unwrap();
+        assert_eq!(drained, Drained { drained: 1, cut: 1 });
+        assert!(running.await.unwrap().is_ok());
+    }
+
+    /// A client logged in to a server at `proxy` as `login` and tunneled
+    /// to `dest`, with the destination's end, or `None` if the login
+    /// failed.
+    async fn logged_in_tunnel(
+        proxy: SocketAddr,
+        dest: &TcpListener,
+        login: (&str, &str),
+    ) -> Option<(TcpStream, TcpStream)> {
+        let mut client = TcpStream::connect(proxy).await.unwrap();
+        if log_in(&mut client, SOCKS_AUTH_USERPASS_VER, login).await != Some(0) {
+            return None;
+        }
+        client
+            .write_all(&connect_request(dest.local_addr().unwrap()))
+            .await
+            .unwrap();
+        client.read_exact(&mut [0u8; 10]).await.unwrap();
+        Some((client, dest.accept().await.unwrap().0))
+    }
+
+    #[tokio::test]
+    async fn applies_new_credentials_to_new_connections_only() {
+        let server = new("127.0.0.1:0".parse().unwrap(), Some(("user", "old").into())).unwrap();
+        let handle = server.handle();
+        tokio::spawn(server.run());
+        tokio::task::yield_now().await;
+        let proxy = handle.local_addr().unwrap();
+        let dest = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (mut client, mut upstream) = logged_in_tunnel(proxy, &dest, ("user", "old"))
+            .await
+            .unwrap();
+
+        handle.set_auth(vec![("user", "new").into()]).await.unwrap();
+        assert!(
+            logged_in_tunnel(proxy, &dest, ("user", "old"))
+                .await
+                .is_none(),
+            "the old password refused"
+        );
+        assert!(logged_in_tunnel(proxy, &dest, ("user", "new"))
+            .await
+            .is_some());
+
+        client.write_all(b"still").await.unwrap();
+        let mut relayed = [0u8; 5];
+        upstream.read_exact(&mut relayed).await.unwrap();
+        assert_eq!(&relayed, b"still", "the old session kept");
+    }
+
+    #[tokio::test]
+    async fn refuses_auth_the_server_cannot_serve() {
+        let server = new("127.0.0.1:0".parse().unwrap(), Some(("user", "old").into())).unwrap();
+        let handle = server.handle();
+        tokio::spawn(server.run());
+        tokio::task::yield_now().await;
+        let proxy = handle.local_addr().unwrap();
+
+        let unservable = [
+            Vec::new(),
+            vec![AuthMethod::UserPass(None)],
+            vec![AuthMethod::Other(0x80)],
+        ];
+        for methods in unservable {
+            let refused = handle.set_auth(methods.clone()).await;
+            assert!(
+                matches!(refused, Err(Socks5ServerError::IOError(_))),
+                "{:?}",
+                methods
+            );
+        }
+        let mut client = TcpStream::connect(proxy).await.unwrap();
+        let login = log_in(&mut client, SOCKS_AUTH_USERPASS_VER, ("user", "old")).await;
+        assert_eq!(login, Some(0), "the old credentials kept");
+    }
+
+    #[tokio::test]
+    async fn tells_the_port_picked_before_and_after_running() {
+        let server = new("127.0.0.1:0".parse().unwrap(), None).unwrap();
+        let picked = server.local_addr().unwrap();
+        assert_ne!(picked.port(), 0);
+        let handle = server.handle();
+        assert_eq!(handle.local_addr().unwrap(), picked);
+        tokio::spawn(server.run());
+        tokio::task::yield_now().await;
+        assert_eq!(handle.local_addr().unwrap(), picked);
+        let mut client = TcpStream::connect(picked).await.unwrap();
+        client.write_all(&[SOCKS_VER, 1, 0]).await.unwrap();
+        assert!(greeted(&mut client).await);
+    }
+
+    #[tokio::test]
+    async fn serves_on_a_listener_bound_by_the_caller() {
+        let socket = tokio::net::TcpSocket::new_v4().unwrap();
+        socket.set_reuseaddr(true).unwrap();
+        socket.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let listener = socket.listen(16).unwrap();
+        let bound = listener.local_addr().unwrap();
+        let server = from_listener(listener, None).unwrap();
+        assert_eq!(server.local_addr().unwrap(), bound);
+        tokio::spawn(server.run());
+
+        let dest = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (mut client, mut upstream) = tunnel(bound, &dest).await;
+        client.write_all(b"ping").await.unwrap();
+        let mut relayed = [0u8; 4];
+        upstream.read_exact(&mut relayed).await.unwrap();
+        assert_eq!(&relayed, b"ping");
+    }
+
+    #[tokio::test]
+    async fn checks_requests_against_the_rules_of_their_listener() {
+        let internal = Listener::bind("127.0.0.1:0".parse().unwrap())
+            .unwrap()
+            .rules(RuleSet::new(RuleAction::Allow));
+        let internal_addr = internal.local_addr().unwrap();
+        let server = new("127.0.0.1:0".parse().unwrap(), None)
+            .unwrap()
+            .acl(RuleSet::new(RuleAction::Deny))
+            .listener(internal);
+        let external_addr = server.local_addr().unwrap();
+        tokio::spawn(server.run());
+        tokio::task::yield_now().await;
+        let dest = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let dest = dest.local_addr().unwrap();
+
+        let reply = |proxy| async move {
+            let mut client = TcpStream::connect(proxy).await.unwrap();
+            client.write_all(&[SOCKS_VER, 1, 0]).await.unwrap();
+            client.write_all(&connect_request(dest)).await.unwrap();
+            let mut replies = [0u8; 4];
+            client.read_exact(&mut replies).await.unwrap();
+            replies[3]
+        };
+        assert_eq!(reply(external_addr).await, SocksError::DENY as u8);
+        assert_eq!(reply(internal_addr).await, SocksError::SUCCESS as u8);
+    }
+
+    #[tokio::test]
+    async fn rebinds_keeping_names() {
+        let extra = Listener::bind("127.0.0.1:0".parse().unwrap())
+            .unwrap()
+            .name("extra");
+        let server = new("127.0.0.1:0".parse().unwrap(), None)
+            .unwrap()
+            .listener(extra);
+        let (stats, handle) = (server.stats(), server.handle());
+        let old = handle.local_addr().unwrap();
+        tokio::spawn(server.run());
+        tokio::task::yield_now().await;
+
+        let any = "127.0.0.1:0".parse().unwrap();
+        handle.rebind(vec![any, any]).unwrap();
+        let mut moved = false;
+        for _ in 0..100 {
+            if TcpStream::connect(old).await.is_err() {
+                moved = true;
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        assert!(moved, "the old listener closed");
+        let new = handle.local_addr().unwrap();
+        assert_ne!(new, old);
+        let mut client = TcpStream::connect(new).await.unwrap();
+        client.write_all(&[SOCKS_VER, 1, 0]).await.unwrap();
+        assert!(greeted(&mut client).await);
+        assert!(stats.accepted_by_listener().contains_key(&new.to_string()));
+        assert!(handle.rebind(Vec::new()).is_err());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn rebinds_with_the_socket_options_bound_with() {
+        let sharing = SocketOptions::new().reuse_port(true);
+        let extra = Listener::bind_with("127.0.0.1:0".parse().unwrap(), &sharing).unwrap();
+        let server = new("127.0.0.1:0".parse().unwrap(), None)
+            .unwrap()
+            .listener(extra);
+        let handle = server.handle();
+        tokio::spawn(server.run());
+        tokio::task::yield_now().await;
+
+        // Only a socket sharing the port too may bind it alongside.
+        let shared = socket::bind("127.0.0.1:0".parse().unwrap(), &sharing).unwrap();
+        let port = shared.local_addr().unwrap();
+        let any = "127.0.0.1:0".parse().unwrap();
+        handle.rebind(vec![any, port]).unwrap();
+        assert!(
+            handle.rebind(vec![port]).is_err(),
+            "the first without options"
+        );
+
+        let mut greeted_there = false;
+        for _ in 0..100 {
+            if let Ok(mut client) = TcpStream::connect(port).await {
+                client.write_all(&[SOCKS_VER, 1, 0]).await.unwrap();
+                if greeted(&mut client).await {
+                    greeted_there = true;
+                    break;
+                }
+            }
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        assert!(greeted_there);
+    }
 }
@@ -0,0 +1,76 @@
+use std::{sync::Mutex, time::Duration};
+use tokio::time::Instant;
+
+/// A token bucket pacing accepts: `rate` per second on average, up to
+/// `burst` in a row.
+#[derive(Debug)]
+pub(crate) struct AcceptLimiter {
+    rate: f64,
+    burst: f64,
+    bucket: Mutex<(f64, Instant)>,
+}
+
+impl AcceptLimiter {
+    pub fn new(rate: f64, burst: u32) -> Self {
+        let burst = f64::from(burst.max(1));
+        AcceptLimiter {
+            rate,
+            burst,
+            bucket: Mutex::new((burst, Instant::now())),
+        }
+    }
+
+    /// Whether there is a token for another accept, or how long until
+    /// there is one.
+    pub fn ready(&self) -> Result<(), Duration> {
+        let tokens = self.refill();
+        if tokens >= 1.0 {
+            return Ok(());
+        }
+        Err(Duration::from_secs_f64((1.0 - tokens) / self.rate))
+    }
+
+    /// Takes the token of a connection accepted. Acceptors that were ready
+    /// at the same time may leave the bucket in debt, which the wait of
+    /// the next ones pays off.
+    pub fn take(&self) {
+        self.refill();
+        self.bucket.lock().unwrap().0 -= 1.0;
+    }
+
+    fn refill(&self) -> f64 {
+        let mut bucket = self.bucket.lock().unwrap();
+        let (tokens, last) = &mut *bucket;
+        let now = Instant::now();
+        *tokens = (*tokens + now.duration_since(*last).as_secs_f64() * self.rate).min(self.burst);
+        *last = now;
+        *tokens
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lets_a_burst_through_then_paces() {
+        let limiter = AcceptLimiter::new(10.0, 3);
+        for _ in 0..3 {
+            assert_eq!(limiter.ready(), Ok(()));
+            limiter.take();
+        }
+        let wait = limiter.ready().unwrap_err();
+        assert!(wait > Duration::from_millis(90) && wait <= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn makes_acceptors_that_overdrew_wait_longer() {
+        let limiter = AcceptLimiter::new(10.0, 1);
+        assert_eq!(limiter.ready(), Ok(()));
+        // Two acceptors saw the same token.
+        limiter.take();
+        limiter.take();
+        let wait = limiter.ready().unwrap_err();
+        assert!(wait > Duration::from_millis(190), "{:?}", wait);
+    }
+}
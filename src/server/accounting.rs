@@ -0,0 +1,282 @@
+use super::relay::Progress;
+use crate::utils::Addr;
+use log::warn;
+use std::{
+    collections::HashMap,
+    future::Future,
+    io,
+    net::IpAddr,
+    pin::Pin,
+    sync::{atomic::Ordering, Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    sync::{mpsc, oneshot},
+    time::Instant,
+};
+
+/// Commits waiting for the committer before interim ones are put off.
+const QUEUED_COMMITS: usize = 1024;
+
+/// The future returned by [`Accounting::commit`].
+pub type CommitFuture<'a> = Pin<Box<dyn Future<Output = io::Result<()>> + Send + 'a>>;
+
+/// Persists traffic totals, e.g. for billing.
+///
+/// A tunnel commits its bytes once it closes and, if an interval is
+/// configured, periodically while it is open. Every commit carries only
+/// the bytes relayed since the previous commit of the same tunnel, so
+/// summing them gives the totals. Commits run off the relay path, one at a
+/// time and in order: a slow commit never holds up a tunnel, and a failed
+/// one is logged and dropped. While commits are backed up, interim ones are
+/// put off and their bytes go with the tunnel's next commit. Shutting the
+/// server down or draining it waits for the commits queued.
+pub trait Accounting: Send + Sync {
+    fn commit<'a>(
+        &'a self,
+        user: Option<&'a str>,
+        source: IpAddr,
+        dest: &'a Addr,
+        bytes_up: u64,
+        bytes_down: u64,
+    ) -> CommitFuture<'a>;
+}
+
+/// Keeps per-user totals in memory.
+#[derive(Debug, Default)]
+pub struct MemoryAccounting {
+    totals: Mutex<HashMap<Option<String>, (u64, u64)>>,
+}
+
+impl MemoryAccounting {
+    /// Bytes up and down committed so far for `user`, or for
+    /// unauthenticated clients if `None`.
+    pub fn usage(&self, user: Option<&str>) -> (u64, u64) {
+        let totals = self.totals.lock().unwrap();
+        totals
+            .get(&user.map(str::to_owned))
+            .copied()
+            .unwrap_or_default()
+    }
+}
+
+impl Accounting for MemoryAccounting {
+    fn commit<'a>(
+        &'a self,
+        user: Option<&'a str>,
+        _source: IpAddr,
+        _dest: &'a Addr,
+        bytes_up: u64,
+        bytes_down: u64,
+    ) -> CommitFuture<'a> {
+        Box::pin(async move {
+            let mut totals = self.totals.lock().unwrap();
+            let total = totals.entry(user.map(str::to_owned)).or_default();
+            total.0 += bytes_up;
+            total.1 += bytes_down;
+            Ok(())
+        })
+    }
+}
+
+/// Where and how often tunnels commit their traffic, and the queue of the
+/// server's committer.
+#[derive(Clone)]
+pub(crate) struct Metering {
+    pub interval: Option<Duration>,
+    sink: Arc<dyn Accounting>,
+    queue: mpsc::Sender<Job>,
+    /// The end of the queue, until the committer is started with the
+    /// first tunnel.
+    pending: Arc<Mutex<Option<mpsc::Receiver<Job>>>>,
+}
+
+enum Job {
+    Commit(Arc<Usage>, u64, u64),
+    /// Answered once every commit queued before it is done.
+    Flush(oneshot::Sender<()>),
+}
+
+/// Whose traffic a tunnel carries.
+pub(crate) struct Usage {
+    pub user: Option<String>,
+    pub source: IpAddr,
+    pub dest: Addr,
+}
+
+impl Metering {
+    pub fn new(sink: Arc<dyn Accounting>, interval: Option<Duration>) -> Self {
+        let (queue, pending) = mpsc::channel(QUEUED_COMMITS);
+        Metering {
+            interval,
+            sink,
+            queue,
+            pending: Arc::new(Mutex::new(Some(pending))),
+        }
+    }
+
+    /// Waits for the commits queued so far.
+    pub async fn flush(&self) {
+        self.start();
+        let (done, flushed) = oneshot::channel();
+        if self.queue.send(Job::Flush(done)).await.is_ok() {
+            let _ = flushed.await;
+        }
+    }
+
+    /// Starts the committer, unless it runs already. It ends with the
+    /// last clone of the queue.
+    fn start(&self) {
+        let jobs = match self.pending.lock().unwrap().take() {
+            Some(jobs) => jobs,
+            None => return,
+        };
+        tokio::spawn(commit_all(self.sink.clone(), jobs));
+    }
+
+    /// Drives `tunnel` to completion, committing what `progress` counts
+    /// every interval and once more at the end.
+    pub async fn meter<F: Future>(
+        &self,
+        tunnel: F,
+        progress: &Progress,
+        usage: Usage,
+    ) -> F::Output {
+        self.start();
+        let usage = Arc::new(usage);
+        let mut committed = (0, 0);
+        let output = match self.interval {
+            Some(interval) => {
+                tokio::pin!(tunnel);
+                let mut ticks = tokio::time::interval_at(Instant::now() + interval, interval);
+                loop {
+                    tokio::select! {
+                        output = &mut tunnel => break output,
+                        _ = ticks.tick() => {
+                            let total = total(progress);
+                            if let Some(job) = since(&usage, total, committed) {
+                                // Backed up, the bytes go with the next commit.
+                                if self.queue.try_send(job).is_ok() {
+                                    committed = total;
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+            None => tunnel.await,
+        };
+        // The tunnel is over; only its task waits for room in the queue.
+        if let Some(job) = since(&usage, total(progress), committed) {
+            let _ = self.queue.send(job).await;
+        }
+        output
+    }
+}
+
+fn total(progress: &Progress) -> (u64, u64) {
+    (
+        progress.up.load(Ordering::Relaxed),
+        progress.down.load(Ordering::Relaxed),
+    )
+}
+
+/// The commit of the bytes `total` counts past `committed`, if any.
+fn since(usage: &Arc<Usage>, total: (u64, u64), committed: (u64, u64)) -> Option<Job> {
+    let (up, down) = (total.0 - committed.0, total.1 - committed.1);
+    (up != 0 || down != 0).then(|| Job::Commit(usage.clone(), up, down))
+}
+
+/// Runs the commits of `jobs` one at a time until the queue is closed.
+async fn commit_all(sink: Arc<dyn Accounting>, mut jobs: mpsc::Receiver<Job>) {
+    while let Some(job) = jobs.recv().await {
+        match job {
+            Job::Commit(usage, up, down) => {
+                let commit =
+                    sink.commit(usage.user.as_deref(), usage.source, &usage.dest, up, down);
+                if let Err(e) = commit.await {
+                    warn!("accounting commit for {} failed: {}", usage.source, e);
+                }
+            }
+            Job::Flush(done) => {
+                let _ = done.send(());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn usage(user: &str) -> Usage {
+        Usage {
+            user: Some(user.to_owned()),
+            source: "192.0.2.1".parse().unwrap(),
+            dest: Addr::SocketAddr("192.0.2.2:80".parse().unwrap()),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn commits_in_the_interim_and_at_the_end() {
+        let accounting = Arc::new(MemoryAccounting::default());
+        let metering = Metering::new(accounting.clone(), Some(Duration::from_secs(10)));
+        let progress = Progress::default();
+        let tunnel = async {
+            progress.up.store(100, Ordering::Relaxed);
+            tokio::time::sleep(Duration::from_secs(15)).await;
+            metering.flush().await;
+            let interim = accounting.usage(Some("alice"));
+            progress.up.store(150, Ordering::Relaxed);
+            progress.down.store(20, Ordering::Relaxed);
+            interim
+        };
+        let interim = metering.meter(tunnel, &progress, usage("alice")).await;
+        assert_eq!(interim, (100, 0));
+        metering.flush().await;
+        assert_eq!(accounting.usage(Some("alice")), (150, 20));
+        assert_eq!(accounting.usage(None), (0, 0));
+    }
+
+    /// Takes `delay` to commit each time, into `inner`.
+    struct Slow {
+        inner: MemoryAccounting,
+        delay: Duration,
+    }
+
+    impl Accounting for Slow {
+        fn commit<'a>(
+            &'a self,
+            user: Option<&'a str>,
+            source: IpAddr,
+            dest: &'a Addr,
+            bytes_up: u64,
+            bytes_down: u64,
+        ) -> CommitFuture<'a> {
+            Box::pin(async move {
+                tokio::time::sleep(self.delay).await;
+                let commit = self.inner.commit(user, source, dest, bytes_up, bytes_down);
+                commit.await
+            })
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn flushes_once_the_commits_queued_are_done() {
+        let slow = Arc::new(Slow {
+            inner: MemoryAccounting::default(),
+            delay: Duration::from_secs(1),
+        });
+        let metering = Metering::new(slow.clone(), None);
+        for user in ["alice", "bob"] {
+            let progress = Progress::default();
+            progress.down.store(7, Ordering::Relaxed);
+            metering.meter(async {}, &progress, usage(user)).await;
+        }
+        let start = Instant::now();
+        metering.flush().await;
+        assert_eq!(start.elapsed(), Duration::from_secs(2), "one at a time");
+        assert_eq!(slow.inner.usage(Some("alice")), (0, 7));
+        assert_eq!(slow.inner.usage(Some("bob")), (0, 7));
+    }
+}
@@ -0,0 +1,87 @@
+use std::{
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+};
+
+/// Picks the address to advertise for a socket bound to the second
+/// address, to the client at the first.
+pub(crate) type AdvertiseRule = Arc<dyn Fn(SocketAddr, SocketAddr) -> Option<IpAddr> + Send + Sync>;
+
+/// The addresses replies tell clients to reach the server's own sockets
+/// at, for servers behind NAT. Ports are always those actually bound.
+#[derive(Clone, Default)]
+pub(crate) struct Advertised {
+    pub v4: Option<Ipv4Addr>,
+    pub v6: Option<Ipv6Addr>,
+    /// Decides per request, ahead of `v4` and `v6`.
+    pub rule: Option<AdvertiseRule>,
+}
+
+impl Advertised {
+    /// Where `client` is to reach a socket bound to `bound`. Sockets bound
+    /// to the unspecified address are reached like the server itself,
+    /// at `local`, the server end of the client's connection.
+    pub fn address(&self, bound: SocketAddr, local: SocketAddr, client: SocketAddr) -> SocketAddr {
+        if let Some(ip) = self.rule.as_ref().and_then(|rule| rule(client, bound)) {
+            return SocketAddr::new(ip, bound.port());
+        }
+        let ip = match bound.ip() {
+            IpAddr::V4(_) if self.v4.is_some() => self.v4.map(IpAddr::V4),
+            IpAddr::V6(_) if self.v6.is_some() => self.v6.map(IpAddr::V6),
+            ip if !ip.is_unspecified() => Some(ip),
+            _ if local.is_ipv6() == bound.is_ipv6() => Some(local.ip()),
+            _ => None,
+        };
+        SocketAddr::new(ip.unwrap_or(bound.ip()), bound.port())
+    }
+}
+
+impl fmt::Debug for Advertised {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Advertised")
+            .field("v4", &self.v4)
+            .field("v6", &self.v6)
+            .field("rule", &self.rule.is_some())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_the_port_bound() {
+        let bound: SocketAddr = "0.0.0.0:4000".parse().unwrap();
+        let local = "10.0.0.2:1080".parse().unwrap();
+        let client = "198.51.100.7:5000".parse().unwrap();
+        let mut advertised = Advertised::default();
+        assert_eq!(
+            advertised.address(bound, local, client).to_string(),
+            "10.0.0.2:4000"
+        );
+
+        advertised.v4 = Some(Ipv4Addr::new(203, 0, 113, 1));
+        assert_eq!(
+            advertised.address(bound, local, client).to_string(),
+            "203.0.113.1:4000"
+        );
+
+        advertised.rule = Some(Arc::new(|client: SocketAddr, _| {
+            client
+                .ip()
+                .is_loopback()
+                .then(|| Ipv4Addr::LOCALHOST.into())
+        }));
+        assert_eq!(
+            advertised.address(bound, local, client).to_string(),
+            "203.0.113.1:4000"
+        );
+        let client = "127.0.0.1:5000".parse().unwrap();
+        assert_eq!(
+            advertised.address(bound, local, client).to_string(),
+            "127.0.0.1:4000"
+        );
+    }
+}
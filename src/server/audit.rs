@@ -0,0 +1,61 @@
+use super::{CloseReason, Result, TunnelSummary};
+use std::{
+    net::SocketAddr,
+    time::{Duration, SystemTime},
+};
+
+/// Receives an entry for every connection once it ends.
+///
+/// Called on the connection's task, so recording must not block for long;
+/// a sink that persists entries should buffer them.
+pub trait AuditSink: Send + Sync {
+    fn record(&self, entry: &AuditEntry);
+}
+
+/// How a connection went, for the audit trail.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "audit", derive(serde::Serialize))]
+pub struct AuditEntry {
+    /// When the connection ended, in milliseconds since the Unix epoch.
+    pub timestamp_ms: u64,
+    pub listener: Option<String>,
+    pub source: SocketAddr,
+    pub user: Option<String>,
+    /// The destination requested, if the request got that far.
+    pub dest: Option<String>,
+    /// Why the connection failed, if it did.
+    pub error: Option<String>,
+    /// How the tunnel ended, if one was established.
+    pub close_reason: Option<CloseReason>,
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+    pub duration_ms: u64,
+}
+
+impl AuditEntry {
+    pub(super) fn new(
+        listener: Option<&str>,
+        source: SocketAddr,
+        user: Option<&str>,
+        dest: Option<String>,
+        served: &Result<TunnelSummary>,
+        duration: Duration,
+    ) -> AuditEntry {
+        let summary = served.as_ref().ok();
+        AuditEntry {
+            timestamp_ms: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64,
+            listener: listener.map(str::to_owned),
+            source,
+            user: user.map(str::to_owned),
+            dest,
+            error: served.as_ref().err().map(ToString::to_string),
+            close_reason: summary.map(|summary| summary.close_reason),
+            bytes_up: summary.map_or(0, |summary| summary.bytes_up),
+            bytes_down: summary.map_or(0, |summary| summary.bytes_down),
+            duration_ms: duration.as_millis() as u64,
+        }
+    }
+}
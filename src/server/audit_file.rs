@@ -0,0 +1,254 @@
+use super::{AuditEntry, AuditSink};
+use log::warn;
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+/// Appends audit entries to a file as JSON lines, rotating it by size.
+///
+/// Lines are buffered and written out every flush interval, whenever the
+/// buffer fills, and when the writer is dropped. An entry that can't be
+/// written is counted and dropped rather than holding up its connection;
+/// the file is reopened for the next one.
+pub struct JsonlAudit {
+    file: Mutex<Rotating>,
+    dropped: AtomicU64,
+}
+
+struct Rotating {
+    path: PathBuf,
+    max_size: u64,
+    keep: usize,
+    writer: Option<BufWriter<File>>,
+    /// Bytes in the current file, including those still buffered.
+    size: u64,
+}
+
+impl JsonlAudit {
+    /// Appends to `path`, rotating it before it would grow past `max_size`
+    /// bytes. The `keep` most recent rotated files are kept as `path.1`
+    /// (the newest) to `path.{keep}`. Must be called within a Tokio
+    /// runtime, which runs the periodic flush.
+    pub fn open(
+        path: impl Into<PathBuf>,
+        max_size: u64,
+        keep: usize,
+        flush_interval: Duration,
+    ) -> io::Result<Arc<JsonlAudit>> {
+        let mut file = Rotating {
+            path: path.into(),
+            max_size,
+            keep,
+            writer: None,
+            size: 0,
+        };
+        file.open()?;
+        let audit = Arc::new(JsonlAudit {
+            file: Mutex::new(file),
+            dropped: AtomicU64::new(0),
+        });
+
+        let weak = Arc::downgrade(&audit);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(flush_interval);
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                match weak.upgrade() {
+                    Some(audit) => {
+                        if let Err(e) = audit.flush() {
+                            warn!("flushing audit log: {}", e);
+                        }
+                    }
+                    None => break,
+                }
+            }
+        });
+        Ok(audit)
+    }
+
+    /// Writes out the buffered lines.
+    pub fn flush(&self) -> io::Result<()> {
+        let mut file = self.file.lock().unwrap();
+        let flushed = match &mut file.writer {
+            Some(writer) => writer.flush(),
+            None => Ok(()),
+        };
+        if flushed.is_err() {
+            file.writer = None;
+        }
+        flushed
+    }
+
+    /// Entries dropped because they couldn't be written.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl AuditSink for JsonlAudit {
+    fn record(&self, entry: &AuditEntry) {
+        let mut line = serde_json::to_vec(entry).expect("entries always serialize");
+        line.push(b'\n');
+        let mut file = self.file.lock().unwrap();
+        if let Err(e) = file.append(&line) {
+            // Start over with a fresh handle next time.
+            file.writer = None;
+            if self.dropped.fetch_add(1, Ordering::Relaxed) == 0 {
+                warn!("writing audit log {}: {}", file.path.display(), e);
+            }
+        }
+    }
+}
+
+impl Drop for JsonlAudit {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            warn!("flushing audit log: {}", e);
+        }
+    }
+}
+
+impl Rotating {
+    fn open(&mut self) -> io::Result<&mut BufWriter<File>> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = file.metadata()?.len();
+        Ok(self.writer.insert(BufWriter::new(file)))
+    }
+
+    fn append(&mut self, line: &[u8]) -> io::Result<()> {
+        if self.writer.is_none() {
+            self.open()?;
+        }
+        if self.size > 0 && self.size + line.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        let writer = self.writer.as_mut().expect("opened above");
+        writer.write_all(line)?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    /// Moves the current file to `path.1`, shifting the older ones along
+    /// and removing the oldest.
+    fn rotate(&mut self) -> io::Result<()> {
+        if let Some(mut writer) = self.writer.take() {
+            writer.flush()?;
+        }
+        let rotated = |n: usize| {
+            let mut path = self.path.clone().into_os_string();
+            path.push(format!(".{}", n));
+            PathBuf::from(path)
+        };
+        if self.keep == 0 {
+            remove(&self.path)?;
+        } else {
+            remove(&rotated(self.keep))?;
+            for n in (1..self.keep).rev() {
+                rename(&rotated(n), &rotated(n + 1))?;
+            }
+            rename(&self.path, &rotated(1))?;
+        }
+        self.open()?;
+        Ok(())
+    }
+}
+
+fn remove(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+fn rename(from: &Path, to: &Path) -> io::Result<()> {
+    match fs::rename(from, to) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry() -> AuditEntry {
+        AuditEntry {
+            timestamp_ms: 0,
+            listener: None,
+            source: "192.0.2.1:5000".parse().unwrap(),
+            user: None,
+            dest: Some("example.com:443".into()),
+            error: None,
+            close_reason: None,
+            bytes_up: 0,
+            bytes_down: 0,
+            duration_ms: 0,
+        }
+    }
+
+    /// A path of its own for `test` in the temporary directory, without
+    /// any rotated files left from a previous run.
+    fn scratch(test: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("{}-{}.jsonl", test, std::process::id()));
+        for n in 0..4 {
+            let mut rotated = path.clone().into_os_string();
+            if n > 0 {
+                rotated.push(format!(".{}", n));
+            }
+            remove(Path::new(&rotated)).unwrap();
+        }
+        path
+    }
+
+    fn lines(path: &Path, suffix: &str) -> Option<usize> {
+        let mut path = path.to_owned().into_os_string();
+        path.push(suffix);
+        let content = fs::read_to_string(path).ok()?;
+        Some(content.lines().count())
+    }
+
+    #[tokio::test]
+    async fn rotates_before_the_file_grows_too_large() {
+        let path = scratch("audit-rotation");
+        let line = serde_json::to_vec(&entry()).unwrap().len() as u64 + 1;
+        let audit = JsonlAudit::open(&path, 2 * line, 2, Duration::from_secs(3600)).unwrap();
+        for _ in 0..7 {
+            audit.record(&entry());
+        }
+        drop(audit);
+
+        assert_eq!(lines(&path, ""), Some(1));
+        assert_eq!(lines(&path, ".1"), Some(2));
+        assert_eq!(lines(&path, ".2"), Some(2));
+        assert_eq!(lines(&path, ".3"), None);
+        scratch("audit-rotation");
+    }
+
+    #[tokio::test]
+    async fn flushes_buffered_entries_when_dropped() {
+        let path = scratch("audit-shutdown");
+        let audit = JsonlAudit::open(&path, u64::MAX, 0, Duration::from_secs(3600)).unwrap();
+        for _ in 0..3 {
+            audit.record(&entry());
+        }
+        assert_eq!(lines(&path, ""), Some(0), "still buffered");
+        drop(audit);
+        assert_eq!(lines(&path, ""), Some(3));
+        assert_eq!(
+            fs::read_to_string(&path).unwrap().lines().last().unwrap(),
+            serde_json::to_string(&entry()).unwrap()
+        );
+        scratch("audit-shutdown");
+    }
+}
@@ -0,0 +1,165 @@
+use super::{AuthDecision, ClientConn, Config, FailureClass, Result, Socks5ServerError};
+use crate::utils::*;
+use log::debug;
+use std::{future::Future, net::SocketAddr, pin::Pin};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader};
+
+/// The future returned by [`AuthHandler::authenticate`]: the user the
+/// client authenticated as, if the method names one, or why it failed.
+pub type AuthFuture<'a> = Pin<
+    Box<dyn Future<Output = std::result::Result<Option<String>, Socks5ServerError>> + Send + 'a>,
+>;
+
+/// Serves an authentication method: runs its subnegotiation once the
+/// server has selected it, answering the client as the method says.
+///
+/// Register one with
+/// [`Socks5Server::auth_handler`](super::Socks5Server::auth_handler).
+/// The built-in methods are served the same way.
+pub trait AuthHandler: Send + Sync {
+    fn authenticate<'a>(&'a self, ctx: AuthContext<'a>) -> AuthFuture<'a>;
+}
+
+/// A connection whose client is to authenticate.
+pub struct AuthContext<'a> {
+    pub(super) conn: &'a mut BufReader<ClientConn>,
+    pub(super) source: SocketAddr,
+    pub(super) method: &'a AuthMethod,
+    pub(super) config: &'a Config,
+}
+
+impl AuthContext<'_> {
+    /// The client connection, right past the method selection. Bytes the
+    /// client sent ahead are read from the buffer first.
+    pub fn conn(&mut self) -> &mut BufReader<ClientConn> {
+        self.conn
+    }
+
+    /// The client's address.
+    pub fn source(&self) -> SocketAddr {
+        self.source
+    }
+
+    /// The method selected, as configured.
+    pub fn method(&self) -> &AuthMethod {
+        self.method
+    }
+}
+
+/// No authentication at all.
+pub(super) struct NoAuth;
+
+impl AuthHandler for NoAuth {
+    fn authenticate<'a>(&'a self, _ctx: AuthContext<'a>) -> AuthFuture<'a> {
+        Box::pin(async { Ok(None) })
+    }
+}
+
+/// Username and password, as RFC 1929 has it: checked against the
+/// method's credentials or the server's authenticator.
+pub(super) struct UserPass;
+
+impl AuthHandler for UserPass {
+    fn authenticate<'a>(&'a self, ctx: AuthContext<'a>) -> AuthFuture<'a> {
+        Box::pin(user_pass(ctx))
+    }
+}
+
+async fn user_pass(ctx: AuthContext<'_>) -> Result<Option<String>> {
+    let AuthContext {
+        conn,
+        source,
+        method,
+        config,
+    } = ctx;
+    let mut header = [0u8; 2];
+    conn.read_exact(&mut header).await?;
+    if header[0] != SOCKS_AUTH_USERPASS_VER
+        && !config
+            .strictness
+            .tolerate("subnegotiation version", &header[..1])
+    {
+        config.tarpit(FailureClass::Protocol).await;
+        conn.write_all(&[SOCKS_AUTH_USERPASS_VER, SocksError::FAIL as u8])
+            .await?;
+        conn.flush().await?;
+        return Err(Socks5ServerError::UnknowProtocol);
+    }
+
+    // Both lengths are single bytes, so neither field can be longer than
+    // 255 bytes.
+    let mut name = vec![0u8; header[1] as usize];
+    conn.read_exact(&mut name).await?;
+    let mut pass_len = [0u8; 1];
+    conn.read_exact(&mut pass_len).await?;
+    let mut pass = vec![0u8; pass_len[0] as usize];
+    conn.read_exact(&mut pass).await?;
+
+    let throttle = config.login_throttle.as_ref();
+    if let Some(delay) =
+        throttle.and_then(|throttle| config.stats.logins.delay(source.ip(), throttle))
+    {
+        debug!("delaying login from {} by {:?}", source, delay);
+        tokio::time::sleep(delay).await;
+    }
+    let accepted = match (&config.authenticator, method) {
+        (Some(authenticator), _) => {
+            let (name, pass) = (
+                String::from_utf8_lossy(&name),
+                String::from_utf8_lossy(&pass),
+            );
+            match authenticator.verify(&name, &pass, source).await {
+                AuthDecision::Accept(identity) => Some(identity),
+                AuthDecision::Reject => None,
+            }
+        }
+        (None, AuthMethod::UserPass(Some((user, pwd))))
+            if constant_time_eq(user.as_bytes(), &name)
+                & constant_time_eq(pwd.as_bytes(), &pass) =>
+        {
+            Some(user.clone())
+        }
+        (None, _) => None,
+    };
+    if let Some(identity) = accepted {
+        debug!("{} authenticated as {:?}", source, identity);
+        if throttle.is_some() {
+            config.stats.logins.succeeded(source.ip());
+        }
+        conn.write_all(&[SOCKS_AUTH_USERPASS_VER, SocksError::SUCCESS as u8])
+            .await?;
+        conn.flush().await?;
+        Ok(Some(identity))
+    } else {
+        if let Some(throttle) = throttle {
+            config.stats.logins.failed(source.ip(), throttle);
+        }
+        config.tarpit(FailureClass::Auth).await;
+        conn.write_all(&[SOCKS_AUTH_USERPASS_VER, SocksError::FAIL as u8])
+            .await?;
+        conn.flush().await?;
+        Err(Socks5ServerError::AuthFailed(
+            String::from_utf8_lossy(&name).into_owned(),
+        ))
+    }
+}
+
+/// Compares `a` and `b` in time depending only on their lengths, so how
+/// long a login takes doesn't tell how much of a guess was right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::constant_time_eq;
+
+    #[test]
+    fn compares_bytes() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(constant_time_eq(b"", b""));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret!"));
+        assert!(!constant_time_eq(b"", b"x"));
+    }
+}
@@ -0,0 +1,28 @@
+use std::{future::Future, net::SocketAddr, pin::Pin};
+
+/// The future returned by [`Authenticator::verify`].
+pub type VerifyFuture<'a> = Pin<Box<dyn Future<Output = AuthDecision> + Send + 'a>>;
+
+/// Verifies the usernames and passwords clients log in with, e.g. against
+/// a database.
+///
+/// Called on the connection's task during the RFC 1929 subnegotiation,
+/// with the address the client connects from. Names and passwords that
+/// aren't UTF-8 arrive lossily converted.
+pub trait Authenticator: Send + Sync {
+    fn verify<'a>(
+        &'a self,
+        username: &'a str,
+        password: &'a str,
+        source: SocketAddr,
+    ) -> VerifyFuture<'a>;
+}
+
+/// What an [`Authenticator`] makes of a login.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuthDecision {
+    /// Lets the client in as `identity`, the user that logs, audit
+    /// entries and accounting name from then on.
+    Accept(String),
+    Reject,
+}
@@ -0,0 +1,228 @@
+use crate::utils::canonical_ip;
+use log::{info, warn};
+use std::{
+    collections::{HashMap, VecDeque},
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+use tokio::time::Instant;
+
+/// Sources tracked for protocol violations before old ones are pruned.
+const MAX_TRACKED: usize = 4096;
+
+/// Bans sources that keep sending something other than SOCKS5: `threshold`
+/// protocol violations from one address within `window` ban it for
+/// `duration`. Failed authentication does not count.
+#[derive(Debug, Clone)]
+pub struct AutoBan {
+    pub(crate) threshold: u32,
+    pub(crate) window: Duration,
+    pub(crate) duration: Duration,
+    pub(crate) allow: Vec<IpAddr>,
+}
+
+impl AutoBan {
+    pub fn new(threshold: u32, window: Duration, duration: Duration) -> Self {
+        AutoBan {
+            threshold: threshold.max(1),
+            window,
+            duration,
+            allow: Vec::new(),
+        }
+    }
+
+    /// Never bans `sources`, however much garbage they send.
+    pub fn allow(mut self, sources: Vec<IpAddr>) -> Self {
+        self.allow = sources.into_iter().map(canonical_ip).collect();
+        self
+    }
+}
+
+/// Sources refused at accept until their ban expires.
+#[derive(Debug, Default)]
+pub(crate) struct Bans {
+    banned: Mutex<HashMap<IpAddr, Instant>>,
+    violations: Mutex<HashMap<IpAddr, VecDeque<Instant>>>,
+    imposed: AtomicU64,
+    refused: AtomicU64,
+}
+
+impl Bans {
+    pub fn ban(&self, ip: IpAddr, duration: Duration) {
+        self.banned
+            .lock()
+            .unwrap()
+            .insert(ip, Instant::now() + duration);
+        self.imposed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn unban(&self, ip: IpAddr) -> bool {
+        self.banned.lock().unwrap().remove(&ip).is_some()
+    }
+
+    /// The banned sources with the time left on each ban.
+    pub fn list(&self) -> Vec<(IpAddr, Duration)> {
+        let now = Instant::now();
+        let mut banned = self.banned.lock().unwrap();
+        expire(&mut banned, now);
+        banned
+            .iter()
+            .map(|(ip, until)| (*ip, until.duration_since(now)))
+            .collect()
+    }
+
+    /// Whether connections from `ip` are to be refused, counting them if so.
+    pub fn check(&self, ip: IpAddr) -> bool {
+        let mut banned = self.banned.lock().unwrap();
+        let until = match banned.get(&ip) {
+            Some(until) => *until,
+            None => return false,
+        };
+        if until <= Instant::now() {
+            banned.remove(&ip);
+            info!("ban on {} expired", ip);
+            return false;
+        }
+        self.refused.fetch_add(1, Ordering::Relaxed);
+        true
+    }
+
+    /// Counts a protocol violation from `ip`, banning it once `policy`'s
+    /// threshold is reached.
+    pub fn violation(&self, ip: IpAddr, policy: &AutoBan) {
+        if policy.allow.contains(&ip) {
+            return;
+        }
+        let now = Instant::now();
+        let mut violations = self.violations.lock().unwrap();
+        if violations.len() >= MAX_TRACKED && !violations.contains_key(&ip) {
+            violations.retain(|_, seen| {
+                seen.back()
+                    .is_some_and(|last| now.duration_since(*last) < policy.window)
+            });
+            if violations.len() >= MAX_TRACKED {
+                let stalest = violations
+                    .iter()
+                    .min_by_key(|(_, seen)| seen.back().copied())
+                    .map(|(ip, _)| *ip);
+                if let Some(stalest) = stalest {
+                    violations.remove(&stalest);
+                }
+            }
+        }
+        let seen = violations.entry(ip).or_default();
+        while seen
+            .front()
+            .is_some_and(|first| now.duration_since(*first) >= policy.window)
+        {
+            seen.pop_front();
+        }
+        seen.push_back(now);
+        if seen.len() < policy.threshold as usize {
+            return;
+        }
+        violations.remove(&ip);
+        drop(violations);
+
+        warn!(
+            "banning {} for {:?} after {} protocol violations",
+            ip, policy.duration, policy.threshold
+        );
+        self.ban(ip, policy.duration);
+    }
+
+    pub fn imposed(&self) -> u64 {
+        self.imposed.load(Ordering::Relaxed)
+    }
+
+    pub fn refused(&self) -> u64 {
+        self.refused.load(Ordering::Relaxed)
+    }
+}
+
+fn expire(banned: &mut HashMap<IpAddr, Instant>, now: Instant) {
+    banned.retain(|ip, until| {
+        let live = *until > now;
+        if !live {
+            info!("ban on {} expired", ip);
+        }
+        live
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    const SECOND: Duration = Duration::from_secs(1);
+
+    fn ip(last: u8) -> IpAddr {
+        Ipv4Addr::new(192, 0, 2, last).into()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn bans_at_the_threshold_within_the_window() {
+        let (bans, policy) = (Bans::default(), AutoBan::new(3, 10 * SECOND, 60 * SECOND));
+        bans.violation(ip(1), &policy);
+        bans.violation(ip(1), &policy);
+        assert!(!bans.check(ip(1)));
+        bans.violation(ip(1), &policy);
+        assert!(bans.check(ip(1)));
+        assert!(!bans.check(ip(2)));
+        assert_eq!((bans.imposed(), bans.refused()), (1, 1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn lifts_bans_once_they_expire() {
+        let (bans, policy) = (Bans::default(), AutoBan::new(1, 10 * SECOND, 60 * SECOND));
+        bans.violation(ip(1), &policy);
+        tokio::time::advance(59 * SECOND).await;
+        assert!(bans.check(ip(1)));
+        assert_eq!(bans.list(), [(ip(1), SECOND)]);
+        tokio::time::advance(SECOND).await;
+        assert!(!bans.check(ip(1)));
+        assert!(bans.list().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn never_bans_allowed_sources() {
+        let policy = AutoBan::new(1, 10 * SECOND, 60 * SECOND).allow(vec![ip(1)]);
+        let bans = Bans::default();
+        for _ in 0..10 {
+            bans.violation(ip(1), &policy);
+        }
+        assert!(!bans.check(ip(1)));
+        assert_eq!(bans.imposed(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn forgets_violations_outside_the_window() {
+        let (bans, policy) = (Bans::default(), AutoBan::new(2, 10 * SECOND, 60 * SECOND));
+        bans.violation(ip(1), &policy);
+        tokio::time::advance(10 * SECOND).await;
+        bans.violation(ip(1), &policy);
+        assert!(!bans.check(ip(1)));
+        tokio::time::advance(9 * SECOND).await;
+        bans.violation(ip(1), &policy);
+        assert!(bans.check(ip(1)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn tracks_at_most_max_tracked_sources() {
+        let (bans, policy) = (Bans::default(), AutoBan::new(2, 10 * SECOND, 60 * SECOND));
+        bans.violation(ip(1), &policy);
+        for n in 0..MAX_TRACKED as u32 {
+            tokio::time::advance(Duration::from_millis(1)).await;
+            bans.violation(Ipv4Addr::from(0x0A00_0000 + n).into(), &policy);
+        }
+        assert_eq!(bans.violations.lock().unwrap().len(), MAX_TRACKED);
+        // The stalest source went to make room, so starts over.
+        bans.violation(ip(1), &policy);
+        assert!(!bans.check(ip(1)));
+    }
+}
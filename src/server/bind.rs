@@ -0,0 +1,199 @@
+use super::{
+    intercept::encode_reply, refuse, replied, tunnel, Config, Dialect, Negotiating, PendingCommand,
+    Result, Socks5ServerError, Target, TunnelSummary,
+};
+use crate::socket;
+use crate::utils::*;
+use log::info;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::{self, AsyncBufReadExt, AsyncWriteExt};
+
+/// Serves a BIND request: listens for the one connection `target` is to
+/// make, replies once with the listening address and once with the peer
+/// that connected, then relays between the client and that peer.
+///
+/// An unspecified IP in the request lets any peer connect, a zero port any
+/// port of the peer.
+pub(super) async fn serve(
+    mut conn: PendingCommand,
+    target: Target,
+    user: Option<String>,
+    config: &Config,
+    negotiating: Negotiating<'_>,
+) -> Result<TunnelSummary> {
+    let expected = match target.resolve(config).await {
+        Ok(addrs) => addrs,
+        Err(e) => return Err(refuse(conn, Dialect::Socks5, config, e).await),
+    };
+    let mut dest = super::admitted(target, expected, None, user, config);
+    if let (Some(tos), Some(client)) = (dest.client_tos, conn.get_ref().as_tcp()) {
+        let v6 = client.local_addr()?.is_ipv6();
+        socket::mark(socket2::SockRef::from(client), v6, tos);
+    }
+
+    let family = dest.addrs[0];
+    let ip = config.egress.bind_for(&family).unwrap_or(match family {
+        SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    });
+    let listener =
+        match socket::bind(SocketAddr::new(ip, 0), &dest.outbound).and_then(|s| s.listen(1)) {
+            Ok(listener) => listener,
+            Err(e) => {
+                reply(&mut conn, config, SocksError::FAIL, None).await?;
+                return Err(e.into());
+            }
+        };
+    let bound = listener.local_addr()?;
+    let (local, client) = (conn.get_ref().local_addr()?, conn.get_ref().peer_addr()?);
+    let advertised = config.advertised.address(bound, local, client);
+    reply(&mut conn, config, SocksError::SUCCESS, Some(advertised)).await?;
+    drop(negotiating);
+    info!(
+        "listening on {} for {}",
+        bound,
+        config.privacy.show(&dest.target.to_string())
+    );
+
+    let waited = tokio::time::timeout(config.bind_timeout, async {
+        tokio::select! {
+            accepted = listener.accept() => Some(accepted),
+            () = closed(&mut conn) => None,
+        }
+    })
+    .await;
+    let (peer, from) = match waited {
+        Ok(Some(Ok(accepted))) => accepted,
+        Ok(Some(Err(e))) => {
+            reply(&mut conn, config, SocksError::FAIL, None).await?;
+            return Err(e.into());
+        }
+        Ok(None) => {
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "client closed before the BIND peer connected",
+            )
+            .into())
+        }
+        Err(_) => {
+            reply(&mut conn, config, SocksError::TTL, None).await?;
+            return Err(Socks5ServerError::BindTimeout(config.bind_timeout));
+        }
+    };
+    drop(listener);
+    let from = canonical_addr(from);
+    if !dest.addrs.iter().any(|expected| matches(*expected, from)) {
+        drop(peer);
+        reply(&mut conn, config, SocksError::DENY, None).await?;
+        return Err(Socks5ServerError::UnexpectedPeer(from.to_string()));
+    }
+
+    reply(&mut conn, config, SocksError::SUCCESS, Some(from)).await?;
+    dest.connected = Some(from);
+    tunnel(conn.0, &dest, peer, 1, config).await
+}
+
+/// Whether a peer connecting from `from` is the one `expected`.
+fn matches(expected: SocketAddr, from: SocketAddr) -> bool {
+    let expected = canonical_addr(expected);
+    (expected.ip().is_unspecified() || expected.ip() == from.ip())
+        && (expected.port() == 0 || expected.port() == from.port())
+}
+
+async fn reply(
+    conn: &mut PendingCommand,
+    config: &Config,
+    rep: SocksError,
+    bound: Option<SocketAddr>,
+) -> Result<()> {
+    replied(config, rep);
+    conn.write_all(&encode_reply(rep, bound)).await?;
+    conn.flush().await?;
+    Ok(())
+}
+
+/// Completes once the client closes its connection or it fails. Data the
+/// client sends ahead stays buffered for the relay.
+async fn closed(conn: &mut PendingCommand) {
+    match conn.fill_buf().await {
+        Ok(buf) if !buf.is_empty() => std::future::pending().await,
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{convert::TryInto, net::IpAddr};
+    use tokio::{io::AsyncReadExt, net::TcpStream};
+
+    /// Starts a server serving BIND, returning its address.
+    async fn proxy() -> SocketAddr {
+        let server = crate::server::new("127.0.0.1:0".parse().unwrap(), None)
+            .unwrap()
+            .bind_and_udp(true);
+        let proxy = server.local_addr().unwrap();
+        tokio::spawn(server.run());
+        tokio::task::yield_now().await;
+        proxy
+    }
+
+    /// Reads a reply to a request for an IPv4 address.
+    async fn read_reply(conn: &mut TcpStream) -> (u8, SocketAddr) {
+        let mut reply = [0u8; 10];
+        conn.read_exact(&mut reply).await.unwrap();
+        let ip: [u8; 4] = reply[4..8].try_into().unwrap();
+        let port = u16::from_be_bytes([reply[8], reply[9]]);
+        (reply[1], SocketAddr::new(ip.into(), port))
+    }
+
+    /// Asks `proxy` to listen for a peer from `expected`, returning the
+    /// connection and the address listened on.
+    async fn bind(proxy: SocketAddr, expected: IpAddr) -> (TcpStream, SocketAddr) {
+        let mut conn = TcpStream::connect(proxy).await.unwrap();
+        conn.write_all(&[SOCKS_VER, 1, 0]).await.unwrap();
+        let mut selected = [0u8; 2];
+        conn.read_exact(&mut selected).await.unwrap();
+        let ip = match expected {
+            IpAddr::V4(ip) => ip.octets(),
+            IpAddr::V6(_) => unreachable!("IPv4 only"),
+        };
+        let mut request = vec![SOCKS_VER, SOCKS_COMMAND_BIND, SOCKS_RSV, SOCKS_ADDR_IPV4];
+        request.extend_from_slice(&ip);
+        request.extend_from_slice(&[0, 0]);
+        conn.write_all(&request).await.unwrap();
+        let (rep, bound) = read_reply(&mut conn).await;
+        assert_eq!(rep, SocksError::SUCCESS as u8);
+        (conn, bound)
+    }
+
+    #[tokio::test]
+    async fn relays_to_the_peer_that_connects() {
+        let proxy = proxy().await;
+        let (mut conn, bound) = bind(proxy, Ipv4Addr::LOCALHOST.into()).await;
+        assert_eq!(bound.ip(), Ipv4Addr::LOCALHOST);
+
+        // The peer connects through the same proxy, with the crate's client.
+        let mut peer = crate::client::new(proxy, bound, None).await.unwrap();
+        let (rep, from) = read_reply(&mut conn).await;
+        assert_eq!(rep, SocksError::SUCCESS as u8);
+        assert_eq!(from.ip(), Ipv4Addr::LOCALHOST);
+
+        peer.write_all(b"hello").await.unwrap();
+        let mut buf = [0u8; 5];
+        conn.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+        conn.write_all(b"world").await.unwrap();
+        peer.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"world");
+    }
+
+    #[tokio::test]
+    async fn refuses_an_unexpected_peer() {
+        let proxy = proxy().await;
+        let (mut conn, bound) = bind(proxy, "127.0.0.2".parse().unwrap()).await;
+        let _peer = crate::client::new(proxy, bound, None).await.unwrap();
+        let (rep, _) = read_reply(&mut conn).await;
+        assert_eq!(rep, SocksError::DENY as u8);
+    }
+}
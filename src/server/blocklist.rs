@@ -0,0 +1,251 @@
+use crate::utils::Hostname;
+use log::{info, warn};
+use std::{
+    collections::HashSet,
+    fs::File,
+    io::{self, BufRead, BufReader},
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, SystemTime},
+};
+
+/// Domains clients may not CONNECT to, read from a file with one domain
+/// per line. A domain blocks itself and every name under it.
+///
+/// Blank lines and lines starting with `#` are skipped, as is a leading
+/// `*.` or `.`. Lines in hosts file form, `0.0.0.0 ads.example`, block the
+/// name after the address. Lines that are not a valid domain are skipped
+/// with a warning. Only requests naming a blocked domain are refused, not
+/// those for an address one resolves to.
+///
+/// Checking a name costs one hash lookup per label, however long the list.
+#[derive(Debug)]
+pub struct Blocklist {
+    path: PathBuf,
+    domains: RwLock<Arc<HashSet<String>>>,
+    /// The modification time of the file as last read.
+    modified: Mutex<Option<SystemTime>>,
+}
+
+impl Blocklist {
+    /// Reads the domains in the file at `path`.
+    pub fn open(path: impl Into<PathBuf>) -> io::Result<Arc<Blocklist>> {
+        let path = path.into();
+        let modified = modified(&path);
+        let domains = read(&path)?;
+        info!("blocking {} domains from {}", domains.len(), path.display());
+        Ok(Arc::new(Blocklist {
+            path,
+            domains: RwLock::new(Arc::new(domains)),
+            modified: Mutex::new(modified),
+        }))
+    }
+
+    /// Reads the domains in the file at `path` like [`open`](Self::open),
+    /// and reads them again whenever the file's modification time has
+    /// changed, looking every `interval`. Must be called within a Tokio
+    /// runtime; watching stops once the blocklist is dropped.
+    pub fn watch(path: impl Into<PathBuf>, interval: Duration) -> io::Result<Arc<Blocklist>> {
+        let blocklist = Blocklist::open(path)?;
+        let weak = Arc::downgrade(&blocklist);
+        tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(interval);
+            ticks.tick().await;
+            loop {
+                ticks.tick().await;
+                let blocklist = match weak.upgrade() {
+                    Some(blocklist) => blocklist,
+                    None => break,
+                };
+                let changed = tokio::task::spawn_blocking(move || {
+                    if modified(&blocklist.path) == *blocklist.modified.lock().unwrap() {
+                        return;
+                    }
+                    if let Err(e) = blocklist.reload() {
+                        warn!("reloading blocklist {}: {}", blocklist.path.display(), e);
+                    }
+                });
+                let _ = changed.await;
+            }
+        });
+        Ok(blocklist)
+    }
+
+    /// Reads the file again, replacing the domains with what it holds now.
+    /// Returns how many that is. If the file can't be read, the domains
+    /// stay as they were.
+    pub fn reload(&self) -> io::Result<usize> {
+        let modified = modified(&self.path);
+        let domains = read(&self.path)?;
+        let len = domains.len();
+        *self.domains.write().unwrap() = Arc::new(domains);
+        *self.modified.lock().unwrap() = modified;
+        info!("blocking {} domains from {}", len, self.path.display());
+        Ok(len)
+    }
+
+    /// The number of domains blocked.
+    pub fn len(&self) -> usize {
+        self.domains.read().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Whether `host` is a blocked domain or lies under one.
+    pub fn blocks(&self, host: &Hostname) -> bool {
+        let domains = self.domains.read().unwrap().clone();
+        let mut name = host.as_str();
+        loop {
+            if domains.contains(name) {
+                return true;
+            }
+            match name.split_once('.') {
+                Some((_, parent)) => name = parent,
+                None => return false,
+            }
+        }
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|meta| meta.modified())
+        .ok()
+}
+
+fn read(path: &Path) -> io::Result<HashSet<String>> {
+    let mut domains = HashSet::new();
+    for (index, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut fields = line.split_whitespace();
+        let mut domain = fields.next().unwrap_or_default();
+        if domain.parse::<IpAddr>().is_ok() {
+            domain = fields.next().unwrap_or_default();
+        }
+        let domain = domain
+            .strip_prefix("*.")
+            .or_else(|| domain.strip_prefix('.'))
+            .unwrap_or(domain);
+        match Hostname::new(domain) {
+            Ok(host) if fields.next().is_none() => {
+                domains.insert(host.as_str().to_owned());
+            }
+            _ => warn!(
+                "{}:{}: skipping malformed line {:?}",
+                path.display(),
+                index + 1,
+                line
+            ),
+        }
+    }
+    Ok(domains)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    /// A file of its own for `test` in the temporary directory, holding
+    /// `content`.
+    fn list(test: &str, content: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("{}-{}.txt", test, std::process::id()));
+        std::fs::write(&path, content).unwrap();
+        path
+    }
+
+    fn host(name: &str) -> Hostname {
+        Hostname::new(name).unwrap()
+    }
+
+    #[test]
+    fn blocks_domains_and_names_under_them() {
+        let path = list(
+            "blocklist-match",
+            "# ads\n\nads.example\n*.tracker.example\n.Malware.Example\n\
+             0.0.0.0 hosts.example\nnot a domain\nbad..example\n",
+        );
+        let blocklist = Blocklist::open(&path).unwrap();
+        assert_eq!(blocklist.len(), 4);
+        for blocked in [
+            "ads.example",
+            "x.ads.example",
+            "ADS.example.",
+            "tracker.example",
+            "a.b.tracker.example",
+            "malware.example",
+            "hosts.example",
+        ] {
+            assert!(blocklist.blocks(&host(blocked)), "{}", blocked);
+        }
+        for allowed in ["example", "myads.example", "ads.example.org", "0.0.0.0"] {
+            assert!(!blocklist.blocks(&host(allowed)), "{}", allowed);
+        }
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn reloads_on_demand() {
+        let path = list("blocklist-reload", "ads.example\n");
+        let blocklist = Blocklist::open(&path).unwrap();
+        std::fs::write(&path, "tracker.example\nmalware.example\n").unwrap();
+        assert!(blocklist.blocks(&host("ads.example")));
+        assert_eq!(blocklist.reload().unwrap(), 2);
+        assert!(!blocklist.blocks(&host("ads.example")));
+        assert!(blocklist.blocks(&host("tracker.example")));
+
+        std::fs::remove_file(&path).unwrap();
+        assert!(blocklist.reload().is_err());
+        assert_eq!(blocklist.len(), 2, "kept when the file is gone");
+    }
+
+    #[tokio::test]
+    async fn reloads_when_the_file_changes() {
+        let path = list("blocklist-watch", "ads.example\n");
+        let blocklist = Blocklist::watch(&path, Duration::from_millis(10)).unwrap();
+        std::fs::write(&path, "tracker.example\n").unwrap();
+        let later = SystemTime::now() + Duration::from_secs(10);
+        File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(later)
+            .unwrap();
+        for _ in 0..100 {
+            if blocklist.blocks(&host("tracker.example")) {
+                assert!(!blocklist.blocks(&host("ads.example")));
+                std::fs::remove_file(path).unwrap();
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("not reloaded");
+    }
+
+    #[test]
+    fn checks_a_large_list_quickly() {
+        let content: String = (0..200_000)
+            .map(|i| format!("host{}.blocked{}.example\n", i, i % 97))
+            .collect();
+        let path = list("blocklist-large", &content);
+        let blocklist = Blocklist::open(&path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(blocklist.len(), 200_000);
+
+        let names: Vec<Hostname> = (0..10_000)
+            .map(|i| host(&format!("a.b.c.host{}.blocked{}.example", i * 7, i % 5)))
+            .collect();
+        let start = Instant::now();
+        let blocked = names.iter().filter(|name| blocklist.blocks(name)).count();
+        let elapsed = start.elapsed();
+        assert!(blocked > 0 && blocked < names.len());
+        assert!(elapsed < Duration::from_millis(500), "took {:?}", elapsed);
+    }
+}
@@ -0,0 +1,140 @@
+use std::{
+    any::Any,
+    fmt,
+    net::SocketAddr,
+    pin::Pin,
+    sync::Mutex,
+    task::{Context, Poll},
+};
+use tokio::{
+    io::{self, AsyncRead, AsyncWrite, ReadBuf},
+    net::TcpStream,
+};
+
+/// Any stream a client may be served over.
+trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> Stream for S {}
+
+/// A client connection being served: one accepted by the server, or a
+/// stream of any transport handed to
+/// [`Socks5Server::serve_connection`](super::Socks5Server::serve_connection).
+pub struct ClientConn {
+    transport: Transport,
+    peer: SocketAddr,
+}
+
+enum Transport {
+    Tcp(TcpStream),
+    /// Only ever reached through `&mut`; the mutex just makes the
+    /// connection `Sync` without asking it of the stream.
+    Other(Mutex<Box<dyn Stream>>),
+}
+
+impl ClientConn {
+    /// `conn`, accepted from `peer`.
+    pub(super) fn tcp(conn: TcpStream, peer: SocketAddr) -> ClientConn {
+        ClientConn {
+            transport: Transport::Tcp(conn),
+            peer,
+        }
+    }
+
+    /// `conn` of any transport, its client at `peer`. A [`TcpStream`] is
+    /// served as TCP.
+    pub(super) fn new<S>(conn: S, peer: SocketAddr) -> ClientConn
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let conn: Box<dyn Any> = Box::new(conn);
+        let transport = match conn.downcast::<TcpStream>() {
+            Ok(conn) => Transport::Tcp(*conn),
+            Err(conn) => {
+                let conn = conn.downcast::<S>().expect("the type just boxed");
+                Transport::Other(Mutex::new(conn))
+            }
+        };
+        ClientConn { transport, peer }
+    }
+
+    /// The client's address.
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.peer)
+    }
+
+    /// The address the client connected to. Only TCP connections have one.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        match &self.transport {
+            Transport::Tcp(conn) => conn.local_addr(),
+            Transport::Other(_) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "not a TCP connection",
+            )),
+        }
+    }
+
+    /// The TCP stream, if the client connected over TCP.
+    pub fn as_tcp(&self) -> Option<&TcpStream> {
+        match &self.transport {
+            Transport::Tcp(conn) => Some(conn),
+            Transport::Other(_) => None,
+        }
+    }
+
+    /// The TCP stream, or the connection back if it is of another
+    /// transport.
+    pub fn into_tcp(self) -> Result<TcpStream, ClientConn> {
+        match self.transport {
+            Transport::Tcp(conn) => Ok(conn),
+            transport => Err(ClientConn { transport, ..self }),
+        }
+    }
+
+    fn stream(&mut self) -> Pin<&mut dyn Stream> {
+        match &mut self.transport {
+            Transport::Tcp(conn) => Pin::new(conn),
+            Transport::Other(conn) => Pin::new(&mut **conn.get_mut().unwrap()),
+        }
+    }
+}
+
+impl AsyncRead for ClientConn {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        self.get_mut().stream().poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for ClientConn {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.get_mut().stream().poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().stream().poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().stream().poll_shutdown(cx)
+    }
+}
+
+impl fmt::Debug for ClientConn {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let transport = match self.transport {
+            Transport::Tcp(_) => "tcp",
+            Transport::Other(_) => "other",
+        };
+        f.debug_struct("ClientConn")
+            .field("transport", &transport)
+            .field("peer", &self.peer)
+            .finish()
+    }
+}
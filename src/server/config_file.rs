@@ -0,0 +1,994 @@
+use super::{
+    egress, handle, rewrite, AtCapacity, AtConnectionLimit, Authenticator, Config, ConnectRetry,
+    DestinationKey, DnsCache, EgressFamily, FailureClass, IpNet, Listener, LogPrivacy,
+    ProtocolStrictness, Result, Rewrite, Rule, RuleAction, RuleSet, ShedMode, Socks5Server,
+    Socks5ServerError, UnboundFamily,
+};
+use crate::utils::{AuthMethod, SocksError};
+#[cfg(unix)]
+use log::{error, info};
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
+
+/// A declarative server configuration, usually read from TOML.
+///
+/// ```toml
+/// listen = ["127.0.0.1:1080", "[::1]:1080"]
+/// strictness = "lenient"
+///
+/// [auth]
+/// method = "userpass"
+/// username = "alice"
+/// password = "secret"
+/// # Or, with the file-auth feature, logins checked against hashes:
+/// # users_file = "/etc/socks5/users"
+///
+/// [acl]
+/// default = "deny"
+///
+/// [[acl.rule]]
+/// action = "deny"
+/// networks = ["10.0.0.0/8", "fd00::/8"]
+///
+/// [[acl.rule]]
+/// action = "allow"
+/// hosts = ["*.example.com"]
+/// ports = [80, 443]
+///
+/// [timeouts]
+/// negotiation_ms = 10000
+/// connect_ms = 15000
+/// idle_ms = 300000
+/// session_ms = 86400000
+///
+/// [limits]
+/// max_connections = 20000
+/// at_limit = "pause"
+/// per_source = 200
+/// ipv6_prefix = 64
+/// max_bytes_down = 104857600
+///
+/// [logging]
+/// privacy = "hashed_destinations"
+///
+/// [[tarpit]]
+/// class = "auth"
+/// delay_ms = 2000
+/// jitter_ms = 500
+///
+/// [shedding]
+/// high_water = 10000
+/// low_water = 8000
+/// mode = "close"
+/// accept_rate = { high = 500.0, low = 300.0 }
+///
+/// [dns]
+/// concurrency = 64
+/// timeout_ms = 3000
+/// cache_entries = 4096
+/// cache_ttl_ms = 60000
+/// negative_ttl_ms = 5000
+/// hosts = { "internal.service" = "10.0.0.5", "*.corp" = "10.0.0.6" }
+///
+/// [destination_limit]
+/// cap = 100
+/// key = "requested"
+/// wait_ms = 500
+/// overrides = { "example.com:443" = 1000 }
+///
+/// [egress]
+/// bind_v4 = "192.0.2.10"
+/// unbound_family = "connect"
+/// family = "v4_only"
+///
+/// [connect_retry]
+/// attempts = 3
+/// delay_ms = 200
+/// jitter_ms = 100
+/// budget_ms = 5000
+///
+/// [[rewrite]]
+/// host = "*.old-cdn.com"
+/// ports = [80]
+/// to_host = "new-cdn.internal"
+/// to_port = 8080
+///
+/// [[rewrite]]
+/// network = "10.0.0.0/8"
+/// ports = [80]
+/// to_port = 8080
+/// ```
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ServerConfig {
+    pub listen: Vec<SocketAddr>,
+    #[serde(default)]
+    pub strictness: ProtocolStrictness,
+    #[serde(default)]
+    pub auth: AuthConfig,
+    #[serde(default)]
+    pub tarpit: Vec<TarpitConfig>,
+    pub shedding: Option<SheddingConfig>,
+    #[serde(default)]
+    pub dns: DnsConfig,
+    pub destination_limit: Option<DestinationLimitConfig>,
+    #[serde(default)]
+    pub egress: EgressConfig,
+    pub connect_retry: Option<ConnectRetryConfig>,
+    #[serde(default)]
+    pub rewrite: Vec<RewriteConfig>,
+    pub acl: Option<AclConfig>,
+    #[serde(default)]
+    pub timeouts: TimeoutsConfig,
+    #[serde(default)]
+    pub limits: LimitsConfig,
+    #[serde(default)]
+    pub logging: LoggingConfig,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthMethodConfig {
+    #[default]
+    None,
+    UserPass,
+}
+
+#[derive(Debug, Clone, PartialEq, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AuthConfig {
+    #[serde(default)]
+    pub method: AuthMethodConfig,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Check userpass logins against this file of hashes, as
+    /// [`FileAuthenticator`](super::FileAuthenticator) reads it, in place
+    /// of `username` and `password`. Needs the `file-auth` feature.
+    pub users_file: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TarpitConfig {
+    pub class: FailureClass,
+    pub delay_ms: u64,
+    #[serde(default)]
+    pub jitter_ms: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SheddingConfig {
+    pub high_water: Option<usize>,
+    pub low_water: Option<usize>,
+    pub mode: Option<ShedMode>,
+    pub accept_rate: Option<AcceptRateConfig>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AcceptRateConfig {
+    pub high: f64,
+    pub low: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DnsConfig {
+    pub concurrency: Option<usize>,
+    pub timeout_ms: Option<u64>,
+    /// Cache this many hostnames; with `cache_ttl_ms`, enables the cache.
+    pub cache_entries: Option<usize>,
+    pub cache_ttl_ms: Option<u64>,
+    /// Also cache failed lookups, this long.
+    pub negative_ttl_ms: Option<u64>,
+    /// Fixed addresses for hostnames, as [`Socks5Server::override_host`]
+    /// takes them.
+    #[serde(default)]
+    pub hosts: HashMap<String, IpAddr>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DestinationLimitConfig {
+    pub cap: usize,
+    pub key: DestinationKey,
+    /// Wait this long for a slot, then reply REP 0x01.
+    pub wait_ms: Option<u64>,
+    /// Reply this REP code right away instead of waiting.
+    pub reject: Option<u8>,
+    #[serde(default)]
+    pub overrides: HashMap<String, usize>,
+}
+
+#[derive(Debug, Clone, PartialEq, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EgressConfig {
+    pub bind_v4: Option<Ipv4Addr>,
+    pub bind_v6: Option<Ipv6Addr>,
+    #[serde(default)]
+    pub unbound_family: UnboundFamily,
+    #[serde(default)]
+    pub family: EgressFamily,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConnectRetryConfig {
+    pub attempts: u32,
+    pub delay_ms: u64,
+    #[serde(default)]
+    pub jitter_ms: u64,
+    pub budget_ms: Option<u64>,
+}
+
+/// A rewrite, as [`Rewrite`] has it: matching by `host` pattern or
+/// `network`, and by any of `ports`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RewriteConfig {
+    pub host: Option<String>,
+    pub network: Option<String>,
+    #[serde(default)]
+    pub ports: Vec<u16>,
+    pub to_host: Option<String>,
+    pub to_port: Option<u16>,
+}
+
+/// Where clients may connect, as [`RuleSet`] has it.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AclConfig {
+    pub default: RuleAction,
+    #[serde(default)]
+    pub rule: Vec<RuleConfig>,
+}
+
+/// A rule, as [`Rule`] has it: matching any of `hosts` or `networks`, and
+/// any of `ports`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RuleConfig {
+    pub action: RuleAction,
+    #[serde(default)]
+    pub hosts: Vec<String>,
+    #[serde(default)]
+    pub networks: Vec<String>,
+    #[serde(default)]
+    pub ports: Vec<u16>,
+}
+
+/// How long each phase may take; those left out keep their defaults.
+#[derive(Debug, Clone, PartialEq, Eq, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TimeoutsConfig {
+    pub negotiation_ms: Option<u64>,
+    pub connect_ms: Option<u64>,
+    pub bind_ms: Option<u64>,
+    pub stall_ms: Option<u64>,
+    pub idle_ms: Option<u64>,
+    pub session_ms: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LimitsConfig {
+    pub max_connections: Option<usize>,
+    /// What happens past `max_connections`, pausing accepts by default.
+    pub at_limit: Option<AtConnectionLimit>,
+    pub per_source: Option<usize>,
+    /// Count IPv6 sources sharing this prefix as one for `per_source`.
+    pub ipv6_prefix: Option<u8>,
+    pub max_handshaking: Option<usize>,
+    pub max_bytes_up: Option<u64>,
+    pub max_bytes_down: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LoggingConfig {
+    #[serde(default)]
+    pub privacy: LogPrivacy,
+}
+
+/// What a configuration refers to outside of itself, loaded before it is
+/// put in force.
+#[derive(Clone, Default)]
+pub(super) struct Resources {
+    users: Option<Arc<dyn Authenticator>>,
+}
+
+/// What a [`reload`](super::Handle::reload) did with each section of the
+/// configuration.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReloadReport {
+    /// Sections that changed and are in force for new connections.
+    pub applied: Vec<&'static str>,
+    /// Sections that are the same as before.
+    pub ignored: Vec<&'static str>,
+    /// Sections that changed but only take effect when the server is
+    /// started again.
+    pub restart_required: Vec<&'static str>,
+}
+
+impl ServerConfig {
+    /// Parses a TOML document.
+    pub fn from_toml(toml: &str) -> Result<ServerConfig> {
+        ::toml::from_str(toml).map_err(|e| Socks5ServerError::Config(vec![e.to_string()]))
+    }
+
+    /// Every inconsistency in the configuration.
+    pub(super) fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if self.listen.is_empty() {
+            problems.push("listen: no address to listen on".to_string());
+        }
+        let auth = &self.auth;
+        let credentials = auth.username.is_some() || auth.password.is_some();
+        match auth.method {
+            AuthMethodConfig::None => {
+                if credentials || auth.users_file.is_some() {
+                    problems.push("auth: credentials given, but method is none".to_string());
+                }
+            }
+            AuthMethodConfig::UserPass if auth.users_file.is_some() => {
+                if credentials {
+                    problems.push("auth: both credentials and users_file given".to_string());
+                }
+            }
+            AuthMethodConfig::UserPass => {
+                if auth.username.is_none() || auth.password.is_none() {
+                    problems.push(
+                        "auth: userpass needs a username and a password, or a users_file"
+                            .to_string(),
+                    );
+                }
+            }
+        }
+        if cfg!(not(feature = "file-auth")) && auth.users_file.is_some() {
+            problems.push("auth: users_file needs the file-auth feature".to_string());
+        }
+        for (name, value) in [("username", &auth.username), ("password", &auth.password)] {
+            if value.as_ref().is_some_and(|value| value.len() > 255) {
+                problems.push(format!("auth: {} longer than 255 bytes", name));
+            }
+        }
+        if let Some(shedding) = &self.shedding {
+            if shedding.high_water.is_none() && shedding.accept_rate.is_none() {
+                problems.push("shedding: needs high_water or accept_rate".to_string());
+            }
+            if let (Some(high), Some(low)) = (shedding.high_water, shedding.low_water) {
+                if low > high {
+                    problems.push("shedding: low_water above high_water".to_string());
+                }
+            }
+            if let Some(rate) = &shedding.accept_rate {
+                if rate.low > rate.high {
+                    problems.push("shedding: accept_rate low above high".to_string());
+                }
+            }
+        }
+        if self.dns.concurrency == Some(0) {
+            problems.push("dns: concurrency must be at least 1".to_string());
+        }
+        match (self.dns.cache_entries, self.dns.cache_ttl_ms) {
+            (Some(_), None) | (None, Some(_)) => problems
+                .push("dns: cache_entries and cache_ttl_ms must be given together".to_string()),
+            (None, None) if self.dns.negative_ttl_ms.is_some() => {
+                problems.push("dns: negative_ttl_ms needs the cache enabled".to_string())
+            }
+            _ => {}
+        }
+        if let Some(limit) = &self.destination_limit {
+            if limit.cap == 0 {
+                problems.push("destination_limit: cap must be at least 1".to_string());
+            }
+            match (limit.wait_ms, limit.reject) {
+                (Some(_), Some(_)) => {
+                    problems.push("destination_limit: wait_ms and reject both given".to_string())
+                }
+                (None, None) => {
+                    problems.push("destination_limit: needs wait_ms or reject".to_string())
+                }
+                (None, Some(rep)) if !(0x01..=0x08).contains(&rep) => problems.push(format!(
+                    "destination_limit: reject {:#04X} is not a failure REP code",
+                    rep
+                )),
+                _ => {}
+            }
+        }
+        if let Some(retry) = &self.connect_retry {
+            if retry.attempts == 0 {
+                problems.push("connect_retry: attempts must be at least 1".to_string());
+            }
+        }
+        for (index, rewrite) in self.rewrite.iter().enumerate() {
+            if rewrite.to_host.is_none() && rewrite.to_port.is_none() {
+                problems.push(format!("rewrite {}: needs to_host or to_port", index));
+            }
+            if let Some(network) = &rewrite.network {
+                if let Err(e) = network.parse::<IpNet>() {
+                    problems.push(format!("rewrite {}: {}", index, e));
+                }
+            }
+            if let Some(host) = &rewrite.to_host {
+                if rewrite::target_at(host, 0).is_none() {
+                    problems.push(format!("rewrite {}: invalid to_host {:?}", index, host));
+                }
+            }
+        }
+        for (index, rule) in self.acl.iter().flat_map(|acl| &acl.rule).enumerate() {
+            for network in &rule.networks {
+                if let Err(e) = network.parse::<IpNet>() {
+                    problems.push(format!("acl rule {}: {}", index, e));
+                }
+            }
+        }
+        let timeouts = &self.timeouts;
+        for (name, timeout) in [
+            ("negotiation_ms", timeouts.negotiation_ms),
+            ("connect_ms", timeouts.connect_ms),
+            ("bind_ms", timeouts.bind_ms),
+            ("stall_ms", timeouts.stall_ms),
+            ("idle_ms", timeouts.idle_ms),
+            ("session_ms", timeouts.session_ms),
+        ] {
+            if timeout == Some(0) {
+                problems.push(format!("timeouts: {} must be at least 1", name));
+            }
+        }
+        let limits = &self.limits;
+        for (name, cap) in [
+            ("max_connections", limits.max_connections),
+            ("per_source", limits.per_source),
+            ("max_handshaking", limits.max_handshaking),
+        ] {
+            if cap == Some(0) {
+                problems.push(format!("limits: {} must be at least 1", name));
+            }
+        }
+        if limits.at_limit.is_some() && limits.max_connections.is_none() {
+            problems.push("limits: at_limit needs max_connections".to_string());
+        }
+        match limits.ipv6_prefix {
+            Some(prefix) if prefix > 128 => {
+                problems.push("limits: ipv6_prefix above 128".to_string())
+            }
+            Some(_) if limits.per_source.is_none() => {
+                problems.push("limits: ipv6_prefix needs per_source".to_string())
+            }
+            _ => {}
+        }
+        problems
+    }
+
+    /// Every problem in the configuration, like
+    /// [`problems`](Self::problems), and with the files it names read
+    /// if there is none.
+    pub(super) fn load(&self) -> std::result::Result<Resources, Vec<String>> {
+        let mut problems = self.problems();
+        let resources = Resources {
+            users: self.load_users(&mut problems),
+        };
+        match problems.is_empty() {
+            true => Ok(resources),
+            false => Err(problems),
+        }
+    }
+
+    /// The logins in the users file, if one is named and can be read.
+    #[cfg(feature = "file-auth")]
+    fn load_users(&self, problems: &mut Vec<String>) -> Option<Arc<dyn Authenticator>> {
+        let path = self.auth.users_file.as_ref()?;
+        match super::FileAuthenticator::open(path) {
+            Ok(users) => Some(Arc::new(users)),
+            Err(e) => {
+                problems.push(format!("auth: users_file {}: {}", path.display(), e));
+                None
+            }
+        }
+    }
+
+    #[cfg(not(feature = "file-auth"))]
+    fn load_users(&self, _: &mut Vec<String>) -> Option<Arc<dyn Authenticator>> {
+        None
+    }
+
+    /// Sorts the sections of this configuration by what reloading it over
+    /// `old` does to them.
+    pub(super) fn report(&self, old: Option<&ServerConfig>) -> ReloadReport {
+        macro_rules! changed {
+            ($section:ident) => {
+                (
+                    stringify!($section),
+                    old.is_none_or(|old| old.$section != self.$section),
+                )
+            };
+        }
+        let mut report = ReloadReport::default();
+        // The users file is read again whether or not its path changed.
+        let reread = self.auth.users_file.is_some();
+        let (section, changed) = changed!(listen);
+        match changed {
+            true => report.restart_required.push(section),
+            false => report.ignored.push(section),
+        }
+        for (section, changed) in [
+            changed!(strictness),
+            changed!(auth),
+            changed!(tarpit),
+            changed!(shedding),
+            changed!(dns),
+            changed!(destination_limit),
+            changed!(egress),
+            changed!(connect_retry),
+            changed!(rewrite),
+            changed!(acl),
+            changed!(timeouts),
+            changed!(limits),
+            changed!(logging),
+        ] {
+            match changed || (section == "auth" && reread) {
+                true => report.applied.push(section),
+                false => report.ignored.push(section),
+            }
+        }
+        report
+    }
+}
+
+impl Socks5Server {
+    /// Builds a server from `config`, reporting every problem in it at
+    /// once.
+    pub fn from_config(config: &ServerConfig) -> Result<Socks5Server> {
+        let resources = config.load().map_err(Socks5ServerError::Config)?;
+        let mut server = super::new(config.listen[0], None)?;
+        for addr in &config.listen[1..] {
+            server = server.listener(Listener::bind(*addr)?);
+        }
+        server.handle.loaded(config);
+        Ok(configure(server, config, &resources))
+    }
+
+    /// Runs the server like [`run`](Self::run), re-reading the
+    /// configuration at `path` and [reloading](super::Handle::reload) it
+    /// whenever the process gets SIGHUP. A file that can't be read or has
+    /// problems is logged, and the configuration in force stays so.
+    #[cfg(unix)]
+    pub async fn run_with_signals(self, path: impl Into<std::path::PathBuf>) -> Result<()> {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let path = path.into();
+        let handle = self.handle();
+        let mut hangup = signal(SignalKind::hangup())?;
+        let reloads = tokio::spawn(async move {
+            while hangup.recv().await.is_some() {
+                let reloaded = match tokio::fs::read_to_string(&path).await {
+                    Ok(toml) => ServerConfig::from_toml(&toml).and_then(|c| handle.reload(&c)),
+                    Err(e) => Err(e.into()),
+                };
+                match reloaded {
+                    Ok(report) => info!("reloaded {}: {:?}", path.display(), report),
+                    Err(e) => error!("reloading {}: {:?}", path.display(), e),
+                }
+            }
+        });
+        let served = self.run().await;
+        reloads.abort();
+        served
+    }
+}
+
+/// Applies everything in `config` but the listen addresses to `server`.
+fn configure(mut server: Socks5Server, config: &ServerConfig, loaded: &Resources) -> Socks5Server {
+    server.config_mut().auth = vec![match (config.auth.method, &loaded.users) {
+        (AuthMethodConfig::None, _) => AuthMethod::NoAuth,
+        (AuthMethodConfig::UserPass, Some(users)) => {
+            server = server.authenticator(users.clone());
+            AuthMethod::UserPass(None)
+        }
+        (AuthMethodConfig::UserPass, None) => AuthMethod::UserPass(Some((
+            config.auth.username.clone().unwrap_or_default(),
+            config.auth.password.clone().unwrap_or_default(),
+        ))),
+    }];
+    server = server.protocol_strictness(config.strictness);
+    for tarpit in &config.tarpit {
+        server = server.tarpit(
+            tarpit.class,
+            Duration::from_millis(tarpit.delay_ms),
+            Duration::from_millis(tarpit.jitter_ms),
+        );
+    }
+    if let Some(shedding) = &config.shedding {
+        if let Some(high) = shedding.high_water {
+            let low = shedding.low_water.unwrap_or(high);
+            server = server.load_shedding(high, low, shedding.mode.unwrap_or(ShedMode::Reply));
+        }
+        if let Some(rate) = &shedding.accept_rate {
+            server = server.shed_on_accept_rate(rate.high, rate.low);
+        }
+    }
+    if let Some(permits) = config.dns.concurrency {
+        server = server.dns_concurrency(permits);
+    }
+    if let Some(timeout) = config.dns.timeout_ms {
+        server = server.dns_timeout(Duration::from_millis(timeout));
+    }
+    for (host, ip) in &config.dns.hosts {
+        server = server.override_host(host, *ip);
+    }
+    if let (Some(entries), Some(ttl)) = (config.dns.cache_entries, config.dns.cache_ttl_ms) {
+        let mut cache = DnsCache::new(entries, Duration::from_millis(ttl));
+        if let Some(ttl) = config.dns.negative_ttl_ms {
+            cache = cache.negative_ttl(Duration::from_millis(ttl));
+        }
+        server = server.dns_cache(cache);
+    }
+    if let Some(limit) = &config.destination_limit {
+        let at_capacity = match (limit.wait_ms, limit.reject) {
+            (_, Some(rep)) => AtCapacity::Reject(SocksError::from(rep)),
+            (wait, None) => AtCapacity::Wait(Duration::from_millis(wait.unwrap_or_default())),
+        };
+        server = server.destination_limit(limit.cap, limit.key, at_capacity);
+        for (dest, cap) in &limit.overrides {
+            server = server.destination_limit_override(dest, *cap);
+        }
+    }
+    if let Some(ip) = config.egress.bind_v4 {
+        server = server.outbound_bind_v4(ip);
+    }
+    if let Some(ip) = config.egress.bind_v6 {
+        server = server.outbound_bind_v6(ip);
+    }
+    server = server.unbound_family(config.egress.unbound_family);
+    server = server.egress_family(config.egress.family);
+    if let Some(retry) = &config.connect_retry {
+        let mut policy = ConnectRetry::new(retry.attempts, Duration::from_millis(retry.delay_ms))
+            .jitter(Duration::from_millis(retry.jitter_ms));
+        if let Some(budget) = retry.budget_ms {
+            policy = policy.budget(Duration::from_millis(budget));
+        }
+        server = server.connect_retry(policy);
+    }
+    for config in &config.rewrite {
+        let mut rewrite = Rewrite::new();
+        if let Some(host) = &config.host {
+            rewrite = rewrite.host(host);
+        }
+        if let Some(network) = config.network.as_ref().and_then(|net| net.parse().ok()) {
+            rewrite = rewrite.network(network);
+        }
+        for port in &config.ports {
+            rewrite = rewrite.ports(*port..=*port);
+        }
+        if let Some(host) = &config.to_host {
+            rewrite = rewrite.to_host(host);
+        }
+        if let Some(port) = config.to_port {
+            rewrite = rewrite.to_port(port);
+        }
+        server = server.rewrite(rewrite);
+    }
+    if let Some(acl) = &config.acl {
+        let mut rules = RuleSet::new(acl.default);
+        for config in &acl.rule {
+            let mut rule = Rule::new(config.action);
+            for host in &config.hosts {
+                rule = rule.host(host);
+            }
+            for network in config.networks.iter().filter_map(|net| net.parse().ok()) {
+                rule = rule.network(network);
+            }
+            for port in &config.ports {
+                rule = rule.ports(*port..=*port);
+            }
+            rules = rules.rule(rule);
+        }
+        server = server.acl(rules);
+    }
+    let timeouts = &config.timeouts;
+    let ms = Duration::from_millis;
+    if let Some(timeout) = timeouts.negotiation_ms {
+        server = server.negotiation_timeout(ms(timeout));
+    }
+    if let Some(timeout) = timeouts.connect_ms {
+        server = server.connect_timeout(ms(timeout));
+    }
+    if let Some(timeout) = timeouts.bind_ms {
+        server = server.bind_timeout(ms(timeout));
+    }
+    if let Some(timeout) = timeouts.stall_ms {
+        server = server.stall_timeout(ms(timeout));
+    }
+    if let Some(timeout) = timeouts.idle_ms {
+        server = server.idle_timeout(ms(timeout));
+    }
+    if let Some(duration) = timeouts.session_ms {
+        server = server.max_session_duration(ms(duration));
+    }
+    let limits = &config.limits;
+    if let Some(limit) = limits.max_connections {
+        let mode = limits.at_limit.unwrap_or(AtConnectionLimit::Pause);
+        server = server.max_connections(limit, mode);
+    }
+    if let Some(cap) = limits.per_source {
+        server = server.max_connections_per_source(cap);
+    }
+    if let Some(prefix) = limits.ipv6_prefix {
+        server = server.source_limit_ipv6_prefix(prefix);
+    }
+    if let Some(cap) = limits.max_handshaking {
+        server = server.max_handshaking(cap);
+    }
+    if let Some(limit) = limits.max_bytes_up {
+        server = server.max_bytes_up(limit);
+    }
+    if let Some(limit) = limits.max_bytes_down {
+        server = server.max_bytes_down(limit);
+    }
+    server.log_privacy(config.logging.privacy)
+}
+
+/// The server settings `base` with everything `config` covers replaced by
+/// what it says. Limits are started afresh: connections admitted before
+/// keep their slot in the old ones until they close.
+pub(super) fn reconfigure(base: &Config, config: &ServerConfig, loaded: &Resources) -> Config {
+    let mut fresh = base.clone();
+    fresh.authenticator = None;
+    fresh.strictness = ProtocolStrictness::Strict;
+    fresh.tarpit.clear();
+    fresh.shedding = None;
+    fresh.dns_permits = None;
+    fresh.dns_timeout = super::DNS_TIMEOUT;
+    fresh.dns_cache = None;
+    fresh.hosts = Default::default();
+    fresh.dest_limit = None;
+    fresh.egress = egress::Egress::default();
+    fresh.connect_retry = None;
+    fresh.rewrites.clear();
+    fresh.acl = None;
+    fresh.negotiation_timeout = super::NEGOTIATION_TIMEOUT;
+    fresh.connect_timeout = super::CONNECT_TIMEOUT;
+    fresh.bind_timeout = super::BIND_TIMEOUT;
+    fresh.stall_timeout = None;
+    fresh.idle_timeout = None;
+    fresh.max_session = None;
+    fresh.connection_limit = None;
+    fresh.source_limit = None;
+    fresh.handshake_budget = None;
+    fresh.max_bytes_up = None;
+    fresh.max_bytes_down = None;
+
+    let (handle, control) = handle::channel(fresh.stats.clone(), fresh.privacy.key.clone());
+    let scratch = Socks5Server {
+        conns: Vec::new(),
+        config: Arc::new(fresh),
+        handle,
+        control,
+    };
+    let server = configure(scratch, config, loaded);
+    Config::clone(&server.config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The example in the documentation of [`ServerConfig`], listening on
+    /// ports picked at random.
+    fn documented() -> String {
+        let source = include_str!("config_file.rs");
+        let start = source.find("/// ```toml\n").unwrap() + "/// ```toml\n".len();
+        let end = start + source[start..].find("/// ```\n").unwrap();
+        source[start..end]
+            .lines()
+            .map(|line| line.trim_start_matches("///").trim_start())
+            .collect::<Vec<_>>()
+            .join("\n")
+            .replace(":1080", ":0")
+    }
+
+    #[tokio::test]
+    async fn builds_a_server_from_the_documented_example() {
+        let config = ServerConfig::from_toml(&documented()).unwrap();
+        assert_eq!(config.listen.len(), 2);
+        let acl = config.acl.as_ref().unwrap();
+        assert_eq!(acl.default, RuleAction::Deny);
+        assert_eq!(acl.rule[1].ports, [80, 443]);
+        assert_eq!(config.timeouts.connect_ms, Some(15000));
+        assert_eq!(config.limits.at_limit, Some(AtConnectionLimit::Pause));
+        assert_eq!(config.logging.privacy, LogPrivacy::HashedDestinations);
+        assert_eq!(config.problems(), Vec::<String>::new());
+
+        let server = Socks5Server::from_config(&config).unwrap();
+        assert_eq!(server.config.connect_timeout, Duration::from_secs(15));
+        assert!(server.config.acl.is_some());
+        assert!(server.config.connection_limit.is_some());
+        assert_eq!(server.config.privacy.mode, LogPrivacy::HashedDestinations);
+    }
+
+    #[test]
+    fn reports_every_problem_at_once() {
+        let config = ServerConfig::from_toml(
+            r#"
+            listen = []
+
+            [auth]
+            method = "userpass"
+
+            [acl]
+            default = "allow"
+
+            [[acl.rule]]
+            action = "deny"
+            networks = ["10.0.0.0/33"]
+
+            [timeouts]
+            idle_ms = 0
+
+            [limits]
+            at_limit = "close"
+            ipv6_prefix = 64
+            "#,
+        )
+        .unwrap();
+        let problems = config.problems();
+        for expected in [
+            "listen:",
+            "auth: userpass needs",
+            "acl rule 0:",
+            "timeouts: idle_ms",
+            "limits: at_limit",
+            "limits: ipv6_prefix needs",
+        ] {
+            assert!(
+                problems.iter().any(|problem| problem.starts_with(expected)),
+                "{:?} missing from {:?}",
+                expected,
+                problems
+            );
+        }
+        assert!(matches!(
+            Socks5Server::from_config(&config),
+            Err(Socks5ServerError::Config(all)) if all == problems
+        ));
+    }
+
+    #[test]
+    fn refuses_a_users_file_alongside_credentials() {
+        let config = ServerConfig::from_toml(
+            r#"
+            listen = ["127.0.0.1:0"]
+
+            [auth]
+            method = "userpass"
+            username = "alice"
+            password = "secret"
+            users_file = "users"
+            "#,
+        )
+        .unwrap();
+        let problems = config.problems();
+        assert!(problems.contains(&"auth: both credentials and users_file given".to_string()));
+    }
+
+    /// The REP code a server at `proxy` answers a CONNECT to `dest` with.
+    async fn reply(proxy: SocketAddr, dest: SocketAddr) -> u8 {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let mut client = tokio::net::TcpStream::connect(proxy).await.unwrap();
+        let mut request = vec![5, 1, 0, 5, 1, 0, 1];
+        match dest.ip() {
+            IpAddr::V4(ip) => request.extend_from_slice(&ip.octets()),
+            IpAddr::V6(_) => unreachable!(),
+        }
+        request.extend_from_slice(&dest.port().to_be_bytes());
+        client.write_all(&request).await.unwrap();
+        let mut replies = [0u8; 4];
+        client.read_exact(&mut replies).await.unwrap();
+        replies[3]
+    }
+
+    #[tokio::test]
+    async fn keeps_the_old_configuration_when_a_reload_has_problems() {
+        let allowing = r#"
+            listen = ["127.0.0.1:0"]
+
+            [acl]
+            default = "allow"
+        "#;
+        let config = ServerConfig::from_toml(allowing).unwrap();
+        let server = Socks5Server::from_config(&config).unwrap();
+        let handle = server.handle();
+        tokio::spawn(server.run());
+        tokio::task::yield_now().await;
+        let proxy = handle.local_addr().unwrap();
+        let dest = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let dest = dest.local_addr().unwrap();
+        assert_eq!(reply(proxy, dest).await, SocksError::SUCCESS as u8);
+
+        let broken = ServerConfig::from_toml(
+            r#"
+            listen = ["127.0.0.1:0"]
+
+            [acl]
+            default = "deny"
+
+            [timeouts]
+            connect_ms = 0
+            "#,
+        )
+        .unwrap();
+        assert!(matches!(
+            handle.reload(&broken),
+            Err(Socks5ServerError::Config(_))
+        ));
+        assert_eq!(reply(proxy, dest).await, SocksError::SUCCESS as u8);
+
+        let denying = ServerConfig::from_toml(
+            r#"
+            listen = ["127.0.0.1:0"]
+
+            [acl]
+            default = "deny"
+
+            [limits]
+            max_connections = 10
+            "#,
+        )
+        .unwrap();
+        let report = handle.reload(&denying).unwrap();
+        assert!(report.applied.contains(&"acl"));
+        assert!(report.applied.contains(&"limits"));
+        assert!(report.ignored.contains(&"timeouts"));
+        let mut denied = false;
+        for _ in 0..100 {
+            if reply(proxy, dest).await == SocksError::DENY as u8 {
+                denied = true;
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        assert!(denied, "the new rules in force");
+
+        let bare = ServerConfig::from_toml(r#"listen = ["127.0.0.1:0"]"#).unwrap();
+        let report = handle.reload(&bare).unwrap();
+        assert!(report.applied.contains(&"acl") && report.applied.contains(&"limits"));
+        let mut allowed = false;
+        for _ in 0..100 {
+            if reply(proxy, dest).await == SocksError::SUCCESS as u8 {
+                allowed = true;
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        assert!(allowed, "the rules removed");
+    }
+
+    #[cfg(feature = "file-auth")]
+    #[test]
+    fn refuses_to_reload_a_users_file_that_cannot_be_read() {
+        let config = ServerConfig::from_toml(
+            r#"
+            listen = ["127.0.0.1:0"]
+
+            [auth]
+            method = "userpass"
+            users_file = "/nonexistent/users"
+            "#,
+        )
+        .unwrap();
+        let problems = match config.load() {
+            Err(problems) => problems,
+            Ok(_) => panic!("loaded a missing file"),
+        };
+        assert!(problems[0].starts_with("auth: users_file /nonexistent/users:"));
+    }
+}
@@ -0,0 +1,168 @@
+use super::{Result, Socks5ServerError, Target};
+use crate::utils::{canonical_addr, rule_host, SocksError};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// What identifies a destination for the per-destination cap.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "config",
+    derive(serde::Deserialize),
+    serde(rename_all = "lowercase")
+)]
+pub enum DestinationKey {
+    /// The resolved `ip:port` actually connected to. IPv4-mapped IPv6
+    /// addresses count as the IPv4 address they map.
+    Resolved,
+    /// The `host:port` the client asked for; literal addresses are used
+    /// as they are.
+    Requested,
+}
+
+/// What happens to a request for a destination already at its cap.
+#[derive(Debug, Clone, Copy)]
+pub enum AtCapacity {
+    /// Wait up to the given duration for a tunnel to close, then reply
+    /// REP 0x01.
+    Wait(Duration),
+    /// Reply with the given code right away.
+    Reject(SocksError),
+}
+
+#[derive(Clone)]
+pub(crate) struct DestinationLimit {
+    pub cap: usize,
+    pub key: DestinationKey,
+    pub at_capacity: AtCapacity,
+    pub overrides: HashMap<String, usize>,
+    slots: Arc<Mutex<HashMap<String, Arc<Semaphore>>>>,
+}
+
+/// A tunnel slot for one destination, given back when dropped.
+pub(crate) struct DestinationPermit {
+    permit: Option<OwnedSemaphorePermit>,
+    key: String,
+    slots: Arc<Mutex<HashMap<String, Arc<Semaphore>>>>,
+}
+
+impl DestinationLimit {
+    pub fn new(cap: usize, key: DestinationKey, at_capacity: AtCapacity) -> Self {
+        DestinationLimit {
+            cap,
+            key,
+            at_capacity,
+            overrides: HashMap::new(),
+            slots: Default::default(),
+        }
+    }
+
+    pub async fn acquire(&self, target: &Target, addr: SocketAddr) -> Result<DestinationPermit> {
+        let (mut guard, semaphore) = self.slot(target, addr);
+        let permit = match self.at_capacity {
+            AtCapacity::Wait(timeout) => tokio::time::timeout(timeout, semaphore.acquire_owned())
+                .await
+                .map(|permit| permit.expect("semaphore never closed"))
+                .map_err(|_| SocksError::FAIL),
+            AtCapacity::Reject(rep) => semaphore.try_acquire_owned().map_err(|_| rep),
+        };
+        match permit {
+            Ok(permit) => {
+                guard.permit = Some(permit);
+                Ok(guard)
+            }
+            Err(rep) => Err(Socks5ServerError::DestinationFull(guard.key.clone(), rep)),
+        }
+    }
+
+    /// A slot for the destination if one is free right away, whatever
+    /// happens at capacity otherwise.
+    pub fn try_acquire(&self, target: &Target, addr: SocketAddr) -> Option<DestinationPermit> {
+        let (mut guard, semaphore) = self.slot(target, addr);
+        guard.permit = Some(semaphore.try_acquire_owned().ok()?);
+        Some(guard)
+    }
+
+    /// The slots of the destination, and a guard to hold one of them in.
+    fn slot(&self, target: &Target, addr: SocketAddr) -> (DestinationPermit, Arc<Semaphore>) {
+        let key = match self.key {
+            DestinationKey::Resolved => canonical_addr(addr).to_string(),
+            DestinationKey::Requested => target.to_string(),
+        };
+        let cap = self.overrides.get(&key).copied().unwrap_or(self.cap);
+        let semaphore = self
+            .slots
+            .lock()
+            .unwrap()
+            .entry(key.clone())
+            .or_insert_with(|| Arc::new(Semaphore::new(cap)))
+            .clone();
+        let guard = DestinationPermit {
+            permit: None,
+            key,
+            slots: self.slots.clone(),
+        };
+        (guard, semaphore)
+    }
+}
+
+impl Drop for DestinationPermit {
+    fn drop(&mut self) {
+        drop(self.permit.take());
+        // Entries are only cloned under the lock, so a count of one means
+        // nobody holds or waits for a slot of this destination any more.
+        let mut slots = self.slots.lock().unwrap();
+        if let Some(semaphore) = slots.get(&self.key) {
+            if Arc::strong_count(semaphore) == 1 {
+                slots.remove(&self.key);
+            }
+        }
+    }
+}
+
+/// `dest`, as written for an override, in the form destinations are keyed
+/// by.
+pub(crate) fn key(dest: &str) -> String {
+    if let Ok(addr) = dest.parse() {
+        return canonical_addr(addr).to_string();
+    }
+    match dest.rsplit_once(':') {
+        Some((host, port)) => format!("{}:{}", rule_host(host), port),
+        None => rule_host(dest),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn resolved_key_is_the_address_given() {
+        let limit = DestinationLimit::new(
+            1,
+            DestinationKey::Resolved,
+            AtCapacity::Reject(SocksError::FAIL),
+        );
+        let target = Target::Ip("10.0.0.1:443".parse().unwrap());
+        let first = limit
+            .acquire(&target, "10.0.0.1:443".parse().unwrap())
+            .await;
+        assert!(first.is_ok());
+        // The same target connected at another address takes its own slot.
+        let other = limit
+            .acquire(&target, "10.0.0.2:443".parse().unwrap())
+            .await;
+        assert!(other.is_ok());
+        let mapped = "[::ffff:10.0.0.1]:443".parse().unwrap();
+        assert!(matches!(
+            limit.acquire(&target, mapped).await,
+            Err(Socks5ServerError::DestinationFull(key, _)) if key == "10.0.0.1:443"
+        ));
+        drop(first);
+        assert!(limit.acquire(&target, mapped).await.is_ok());
+    }
+}
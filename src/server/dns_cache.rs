@@ -0,0 +1,154 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::Mutex,
+    time::Duration,
+};
+use tokio::time::Instant;
+
+/// Remembers the answers of hostname lookups for a while, so repeated
+/// requests to the same host skip the resolver.
+///
+/// Entries are keyed by the lowercased hostname and hold the addresses
+/// regardless of port. When the cache is full, expired entries make room
+/// first, then those closest to expiry.
+#[derive(Debug)]
+pub struct DnsCache {
+    capacity: usize,
+    ttl: Duration,
+    negative_ttl: Option<Duration>,
+    entries: Mutex<HashMap<String, Entry>>,
+}
+
+#[derive(Debug)]
+struct Entry {
+    /// The addresses, or `None` for a failed lookup.
+    ips: Option<Vec<IpAddr>>,
+    expires: Instant,
+}
+
+/// A cached answer.
+pub(crate) enum Cached {
+    Addrs(Vec<SocketAddr>),
+    Failed,
+}
+
+impl DnsCache {
+    /// Holds up to `capacity` hostnames, each for `ttl`. Failed lookups are
+    /// not cached.
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        DnsCache {
+            capacity,
+            ttl,
+            negative_ttl: None,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Also caches failed and empty lookups, for `ttl`.
+    pub fn negative_ttl(mut self, ttl: Duration) -> Self {
+        self.negative_ttl = Some(ttl);
+        self
+    }
+
+    pub(crate) fn get(&self, host: &str, port: u16) -> Option<Cached> {
+        let key = host.to_ascii_lowercase();
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.get(&key)?;
+        if entry.expires <= Instant::now() {
+            entries.remove(&key);
+            return None;
+        }
+        Some(match &entry.ips {
+            Some(ips) => Cached::Addrs(ips.iter().map(|ip| SocketAddr::new(*ip, port)).collect()),
+            None => Cached::Failed,
+        })
+    }
+
+    pub(crate) fn insert(&self, host: &str, addrs: &[SocketAddr]) {
+        let ips = addrs.iter().map(SocketAddr::ip).collect();
+        self.store(host, Some(ips), self.ttl);
+    }
+
+    pub(crate) fn insert_failed(&self, host: &str) {
+        if let Some(ttl) = self.negative_ttl {
+            self.store(host, None, ttl);
+        }
+    }
+
+    fn store(&self, host: &str, ips: Option<Vec<IpAddr>>, ttl: Duration) {
+        if self.capacity == 0 {
+            return;
+        }
+        let key = host.to_ascii_lowercase();
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= self.capacity && !entries.contains_key(&key) {
+            entries.retain(|_, entry| entry.expires > now);
+            if entries.len() >= self.capacity {
+                let soonest = entries
+                    .iter()
+                    .min_by_key(|(_, entry)| entry.expires)
+                    .map(|(host, _)| host.clone());
+                if let Some(soonest) = soonest {
+                    entries.remove(&soonest);
+                }
+            }
+        }
+        let expires = now + ttl;
+        entries.insert(key, Entry { ips, expires });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addrs(cached: Option<Cached>) -> Option<Vec<SocketAddr>> {
+        match cached? {
+            Cached::Addrs(addrs) => Some(addrs),
+            Cached::Failed => None,
+        }
+    }
+
+    #[test]
+    fn answers_for_any_port_and_case() {
+        let cache = DnsCache::new(8, Duration::from_secs(60));
+        cache.insert("Example.com", &["192.0.2.1:80".parse().unwrap()]);
+        let hit = addrs(cache.get("EXAMPLE.COM", 443));
+        assert_eq!(hit, Some(vec!["192.0.2.1:443".parse().unwrap()]));
+        assert!(cache.get("example.org", 443).is_none());
+    }
+
+    #[tokio::test]
+    async fn forgets_entries_once_expired() {
+        let cache = DnsCache::new(8, Duration::from_millis(50));
+        cache.insert("example.com", &["192.0.2.1:80".parse().unwrap()]);
+        assert!(cache.get("example.com", 80).is_some());
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert!(cache.get("example.com", 80).is_none());
+    }
+
+    #[test]
+    fn caches_failures_only_with_a_negative_ttl() {
+        let cache = DnsCache::new(8, Duration::from_secs(60));
+        cache.insert_failed("example.com");
+        assert!(cache.get("example.com", 80).is_none());
+
+        let cache = cache.negative_ttl(Duration::from_secs(5));
+        cache.insert_failed("example.com");
+        assert!(matches!(cache.get("example.com", 80), Some(Cached::Failed)));
+    }
+
+    #[test]
+    fn evicts_the_entry_closest_to_expiry_when_full() {
+        let cache = DnsCache::new(2, Duration::from_secs(60));
+        let addr = ["192.0.2.1:80".parse().unwrap()];
+        cache.insert("a.example", &addr);
+        cache.insert("b.example", &addr);
+        cache.insert("c.example", &addr);
+        assert!(cache.get("a.example", 80).is_none());
+        assert!(cache.get("b.example", 80).is_some());
+        assert!(cache.get("c.example", 80).is_some());
+    }
+}
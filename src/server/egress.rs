@@ -0,0 +1,76 @@
+use crate::socket::{self, SocketOptions};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::{io, net::TcpStream};
+
+/// What happens to destination addresses of a family that has no egress
+/// address bound, while the other family has one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(
+    feature = "config",
+    derive(serde::Deserialize),
+    serde(rename_all = "lowercase")
+)]
+pub enum UnboundFamily {
+    /// Leave them out; only addresses of a bound family are connected to.
+    #[default]
+    Skip,
+    /// Connect to them from an address the system picks.
+    Connect,
+}
+
+/// The address families outbound connections may use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(
+    feature = "config",
+    derive(serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum EgressFamily {
+    #[default]
+    Any,
+    V4Only,
+    V6Only,
+}
+
+/// The local addresses outbound connections are made from.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct Egress {
+    pub v4: Option<Ipv4Addr>,
+    pub v6: Option<Ipv6Addr>,
+    pub unbound: UnboundFamily,
+    pub family: EgressFamily,
+}
+
+impl Egress {
+    /// The egress address for destinations like `dest`, if one is bound.
+    pub fn bind_for(&self, dest: &SocketAddr) -> Option<IpAddr> {
+        match dest {
+            SocketAddr::V4(_) => self.v4.map(IpAddr::V4),
+            SocketAddr::V6(_) => self.v6.map(IpAddr::V6),
+        }
+    }
+
+    /// Whether `dest` may be connected to at all.
+    pub fn allows(&self, dest: &SocketAddr) -> bool {
+        let family_allowed = match self.family {
+            EgressFamily::Any => true,
+            EgressFamily::V4Only => dest.is_ipv4(),
+            EgressFamily::V6Only => dest.is_ipv6(),
+        };
+        if !family_allowed {
+            return false;
+        }
+        let nothing_bound = self.v4.is_none() && self.v6.is_none();
+        nothing_bound || self.unbound == UnboundFamily::Connect || self.bind_for(dest).is_some()
+    }
+
+    /// Connects to `dest` from the egress address of its family, if any,
+    /// with `options` set from the first packet on.
+    pub async fn connect(
+        &self,
+        dest: SocketAddr,
+        options: &SocketOptions,
+    ) -> io::Result<TcpStream> {
+        socket::connect(dest, self.bind_for(&dest), options).await
+    }
+}
@@ -0,0 +1,184 @@
+use super::Target;
+use crate::utils::SocksError;
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    io::{self, AsyncRead, AsyncWrite, ReadBuf},
+    time::Sleep,
+};
+
+/// Decides whether a fault applies to a tunnel.
+#[derive(Clone)]
+pub enum Trigger {
+    /// Every tunnel.
+    Always,
+    /// Each tunnel with this probability, drawn from the seeded generator.
+    Probability(f64),
+    /// Tunnels whose destination, written as `host:port`, is accepted.
+    Destination(Arc<dyn Fn(&str) -> bool + Send + Sync>),
+}
+
+/// Faults injected into tunnels, for testing how applications cope with a
+/// misbehaving proxy. For each kind, the first entry whose trigger fires
+/// applies.
+#[derive(Clone)]
+pub struct Faults {
+    rng: Arc<Mutex<u64>>,
+    latency: Vec<(Trigger, Duration)>,
+    reply: Vec<(Trigger, SocksError)>,
+    stall: Vec<(Trigger, u64, Duration)>,
+    reset: Vec<(Trigger, u64)>,
+}
+
+/// The faults picked for one tunnel.
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct Plan {
+    pub latency: Option<Duration>,
+    pub reply: Option<SocksError>,
+    pub stall: Option<(u64, Duration)>,
+    pub reset: Option<u64>,
+}
+
+impl Faults {
+    /// No faults yet; probabilities are drawn from a generator seeded with
+    /// `seed`, so runs are reproducible.
+    pub fn new(seed: u64) -> Self {
+        Faults {
+            rng: Arc::new(Mutex::new(seed)),
+            latency: Vec::new(),
+            reply: Vec::new(),
+            stall: Vec::new(),
+            reset: Vec::new(),
+        }
+    }
+
+    /// Delays the CONNECT reply by `delay`.
+    pub fn reply_latency(mut self, when: Trigger, delay: Duration) -> Self {
+        self.latency.push((when, delay));
+        self
+    }
+
+    /// Replies `rep` instead of connecting.
+    pub fn force_reply(mut self, when: Trigger, rep: SocksError) -> Self {
+        self.reply.push((when, rep));
+        self
+    }
+
+    /// Freezes the tunnel for `duration` once `after` bytes have passed
+    /// through it, in either direction.
+    pub fn stall(mut self, when: Trigger, after: u64, duration: Duration) -> Self {
+        self.stall.push((when, after, duration));
+        self
+    }
+
+    /// Resets both connections once `after` bytes have passed through the
+    /// tunnel, in either direction.
+    pub fn reset(mut self, when: Trigger, after: u64) -> Self {
+        self.reset.push((when, after));
+        self
+    }
+
+    pub(crate) fn plan(&self, target: &Target) -> Plan {
+        let dest = target.to_string();
+        let fires = |when: &Trigger| match when {
+            Trigger::Always => true,
+            Trigger::Probability(p) => self.sample() < *p,
+            Trigger::Destination(accept) => accept(&dest),
+        };
+        Plan {
+            latency: self.latency.iter().find(|f| fires(&f.0)).map(|f| f.1),
+            reply: self.reply.iter().find(|f| fires(&f.0)).map(|f| f.1),
+            stall: self.stall.iter().find(|f| fires(&f.0)).map(|f| (f.1, f.2)),
+            reset: self.reset.iter().find(|f| fires(&f.0)).map(|f| f.1),
+        }
+    }
+
+    /// A uniform sample in `[0, 1)` (splitmix64).
+    fn sample(&self) -> f64 {
+        let mut state = self.rng.lock().unwrap();
+        *state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = *state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^= z >> 31;
+        (z >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// The destination side of a tunnel with its stall and reset faults.
+pub(crate) struct Faulty<D> {
+    inner: D,
+    bytes: u64,
+    stall: Option<(u64, Duration)>,
+    sleep: Option<Pin<Box<Sleep>>>,
+    reset: Option<u64>,
+}
+
+impl<D> Faulty<D> {
+    pub fn new(inner: D, plan: &Plan) -> Self {
+        Faulty {
+            inner,
+            bytes: 0,
+            stall: plan.stall,
+            sleep: None,
+            reset: plan.reset,
+        }
+    }
+
+    fn poll_fault(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if self.reset.is_some_and(|after| self.bytes >= after) {
+            return Poll::Ready(Err(io::ErrorKind::ConnectionReset.into()));
+        }
+        if let Some((after, duration)) = self.stall {
+            if self.bytes >= after {
+                let sleep = self
+                    .sleep
+                    .get_or_insert_with(|| Box::pin(tokio::time::sleep(duration)));
+                ready!(sleep.as_mut().poll(cx));
+                self.stall = None;
+                self.sleep = None;
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<D: AsyncRead + Unpin> AsyncRead for Faulty<D> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        ready!(self.poll_fault(cx))?;
+        let before = buf.filled().len();
+        ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+        self.bytes += (buf.filled().len() - before) as u64;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<D: AsyncWrite + Unpin> AsyncWrite for Faulty<D> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        ready!(self.poll_fault(cx))?;
+        let n = ready!(Pin::new(&mut self.inner).poll_write(cx, buf))?;
+        self.bytes += n as u64;
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
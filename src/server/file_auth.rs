@@ -0,0 +1,208 @@
+use super::{AuthDecision, Authenticator, VerifyFuture};
+use argon2::{
+    password_hash::{PasswordHash, PasswordVerifier},
+    Argon2,
+};
+use log::warn;
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, BufRead, BufReader},
+    net::SocketAddr,
+    path::Path,
+    sync::Arc,
+};
+use thiserror::Error;
+
+/// Checked for unknown users, so they take as long to turn away as a
+/// wrong password.
+const DUMMY_HASH: &str = "$2b$12$3/1MIzwX4VZjXfFSHxThheZeeZYKkP.MXiXRoyAHg0Fb6NSVH8Hzm";
+
+/// Verifies logins against `username:hash` lines, hashes being bcrypt
+/// (`$2b$...`) or Argon2 (`$argon2id$...`) strings as produced by
+/// `htpasswd -B` or the `argon2` tool.
+///
+/// Blank lines and lines starting with `#` are skipped. Usernames are
+/// matched exactly, and may not contain `:`. The file is read once; hashes
+/// are checked on Tokio's blocking pool, being slow on purpose.
+#[derive(Debug, Clone)]
+pub struct FileAuthenticator {
+    users: Arc<HashMap<String, Hash>>,
+}
+
+#[derive(Debug)]
+enum Hash {
+    Bcrypt(String),
+    Argon2(String),
+}
+
+/// Why a credentials file couldn't be loaded.
+#[derive(Debug, Error)]
+pub enum CredentialsError {
+    #[error("failed to read credentials: {0}")]
+    Io(#[from] io::Error),
+    #[error("line {line}: expected username:hash")]
+    Malformed { line: usize },
+    #[error("line {line}: unrecognized password hash for user {user:?}")]
+    UnknownHash { line: usize, user: String },
+}
+
+impl FileAuthenticator {
+    /// Loads the credentials in the file at `path`.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, CredentialsError> {
+        Self::from_reader(BufReader::new(File::open(path)?))
+    }
+
+    /// Loads the credentials `reader` yields.
+    pub fn from_reader(reader: impl BufRead) -> Result<Self, CredentialsError> {
+        let mut users = HashMap::new();
+        for (at, line) in reader.lines().enumerate() {
+            let line = line?;
+            let number = at + 1;
+            let line = line.trim_end_matches('\r');
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (user, hash) = match line.split_once(':') {
+                Some((user, hash)) if !user.is_empty() && !hash.is_empty() => (user, hash),
+                _ => return Err(CredentialsError::Malformed { line: number }),
+            };
+            let hash = Hash::parse(hash).ok_or_else(|| CredentialsError::UnknownHash {
+                line: number,
+                user: user.to_owned(),
+            })?;
+            if users.insert(user.to_owned(), hash).is_some() {
+                warn!("credentials line {}: user {:?} listed again", number, user);
+            }
+        }
+        Ok(FileAuthenticator {
+            users: Arc::new(users),
+        })
+    }
+
+    /// The number of users loaded.
+    pub fn len(&self) -> usize {
+        self.users.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.users.is_empty()
+    }
+}
+
+impl Hash {
+    fn parse(hash: &str) -> Option<Hash> {
+        let bcrypt = ["$2a$", "$2b$", "$2x$", "$2y$"];
+        if bcrypt.iter().any(|prefix| hash.starts_with(prefix)) {
+            hash.parse::<bcrypt::HashParts>().ok()?;
+            Some(Hash::Bcrypt(hash.to_owned()))
+        } else if hash.starts_with("$argon2") {
+            PasswordHash::new(hash).ok()?;
+            Some(Hash::Argon2(hash.to_owned()))
+        } else {
+            None
+        }
+    }
+
+    fn matches(&self, password: &str) -> bool {
+        match self {
+            Hash::Bcrypt(hash) => bcrypt::verify(password, hash).unwrap_or(false),
+            Hash::Argon2(hash) => PasswordHash::new(hash)
+                .map(|hash| {
+                    Argon2::default()
+                        .verify_password(password.as_bytes(), &hash)
+                        .is_ok()
+                })
+                .unwrap_or(false),
+        }
+    }
+}
+
+impl Authenticator for FileAuthenticator {
+    fn verify<'a>(
+        &'a self,
+        username: &'a str,
+        password: &'a str,
+        _source: SocketAddr,
+    ) -> VerifyFuture<'a> {
+        let users = self.users.clone();
+        let username = username.to_owned();
+        let password = password.to_owned();
+        Box::pin(async move {
+            let check = tokio::task::spawn_blocking(move || match users.get(&username) {
+                Some(hash) => hash.matches(&password).then_some(username),
+                None => {
+                    let _ = bcrypt::verify(&password, DUMMY_HASH);
+                    None
+                }
+            });
+            match check.await {
+                Ok(Some(username)) => AuthDecision::Accept(username),
+                _ => AuthDecision::Reject,
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use argon2::password_hash::{PasswordHasher, SaltString};
+
+    fn credentials() -> FileAuthenticator {
+        let bcrypt = bcrypt::hash("open sesame", 4).unwrap();
+        let salt = SaltString::encode_b64(b"sixteen salt b.").unwrap();
+        // Cheap parameters, read back from the hash when verifying.
+        let params = argon2::Params::new(8, 1, 1, None).unwrap();
+        let argon2 = Argon2::new(argon2::Algorithm::Argon2id, argon2::Version::V0x13, params)
+            .hash_password(b"hunter2", &salt)
+            .unwrap()
+            .to_string();
+        let file = format!("# users\nalice:{}\n\nbob:{}\n", bcrypt, argon2);
+        FileAuthenticator::from_reader(file.as_bytes()).unwrap()
+    }
+
+    async fn login(auth: &FileAuthenticator, user: &str, pass: &str) -> Option<String> {
+        let source = "192.0.2.1:5000".parse().unwrap();
+        match auth.verify(user, pass, source).await {
+            AuthDecision::Accept(identity) => Some(identity),
+            AuthDecision::Reject => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn verifies_passwords_against_their_hashes() {
+        let auth = credentials();
+        assert_eq!(auth.len(), 2);
+        assert_eq!(
+            login(&auth, "alice", "open sesame").await.as_deref(),
+            Some("alice")
+        );
+        assert_eq!(login(&auth, "bob", "hunter2").await.as_deref(), Some("bob"));
+        assert_eq!(login(&auth, "alice", "hunter2").await, None);
+        assert_eq!(login(&auth, "bob", "open sesame").await, None);
+        assert_eq!(login(&auth, "carol", "open sesame").await, None);
+        assert_eq!(login(&auth, "Alice", "open sesame").await, None);
+    }
+
+    #[test]
+    fn tells_where_a_file_is_broken() {
+        let load = |file: &str| FileAuthenticator::from_reader(file.as_bytes()).err();
+        assert!(matches!(
+            load("# users\nalice\n"),
+            Some(CredentialsError::Malformed { line: 2 })
+        ));
+        assert!(matches!(
+            load(":$2b$04$abc\n"),
+            Some(CredentialsError::Malformed { line: 1 })
+        ));
+        assert!(matches!(
+            load("alice:plaintext\n"),
+            Some(CredentialsError::UnknownHash { line: 1, user }) if user == "alice"
+        ));
+        assert!(matches!(
+            load("alice:$2b$04$not-a-hash\n"),
+            Some(CredentialsError::UnknownHash { line: 1, .. })
+        ));
+    }
+}
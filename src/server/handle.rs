@@ -0,0 +1,277 @@
+use super::{
+    check_auth, privacy::PrivacyKey, Config, Result, RuleSet, Socks5ServerError, Stats, TopMetric,
+};
+#[cfg(feature = "config")]
+use super::{config_file::Resources, ReloadReport, ServerConfig};
+use crate::{socket, AuthMethod, SocketOptions};
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    io,
+    net::TcpListener,
+    sync::{
+        mpsc::{self, UnboundedReceiver, UnboundedSender},
+        oneshot,
+    },
+};
+
+/// Controls a server from outside its accept loop.
+#[derive(Clone)]
+pub struct Handle {
+    control: UnboundedSender<Control>,
+    stats: Arc<Stats>,
+    privacy_key: PrivacyKey,
+    /// The addresses listened on, with the options their sockets were
+    /// bound with; none once stopped.
+    listeners: Arc<Mutex<Vec<(SocketAddr, SocketOptions)>>>,
+    /// The configuration last loaded, if the server was built from one.
+    #[cfg(feature = "config")]
+    loaded: Arc<Mutex<Option<ServerConfig>>>,
+}
+
+/// What the accept loop is asked to do by a [`Handle`].
+pub(super) enum Control {
+    Rebind(Vec<TcpListener>),
+    #[cfg(feature = "config")]
+    Reload(Box<ServerConfig>, Resources),
+    Shutdown,
+    Drain(Duration, oneshot::Sender<Drained>),
+    /// Answered with whether the update was applied.
+    Update(Update, oneshot::Sender<io::Result<()>>),
+}
+
+/// A setting changed for connections accepted from then on.
+pub(super) enum Update {
+    Auth(Vec<AuthMethod>),
+    Acl(RuleSet),
+    Limits(TunnelLimits),
+}
+
+impl Update {
+    pub(super) fn apply(self, config: &mut Config) -> io::Result<()> {
+        match self {
+            Update::Auth(methods) => {
+                check_auth(&methods, config)?;
+                config.auth = methods;
+            }
+            Update::Acl(acl) => config.acl = Some(acl),
+            Update::Limits(limits) => {
+                config.max_session = limits.max_session;
+                config.idle_timeout = limits.idle_timeout;
+                config.max_bytes_up = limits.max_bytes_up;
+                config.max_bytes_down = limits.max_bytes_down;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Bounds on each tunnel, as
+/// [`max_session_duration`](super::Socks5Server::max_session_duration),
+/// [`idle_timeout`](super::Socks5Server::idle_timeout) and the byte caps
+/// set them. `None` leaves a bound off.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TunnelLimits {
+    pub max_session: Option<Duration>,
+    pub idle_timeout: Option<Duration>,
+    pub max_bytes_up: Option<u64>,
+    pub max_bytes_down: Option<u64>,
+}
+
+/// How the connections open when a server was drained ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Drained {
+    /// Connections that closed by themselves within the deadline.
+    pub drained: usize,
+    /// Connections still open at the deadline, cut then.
+    pub cut: usize,
+}
+
+pub(crate) fn channel(
+    stats: Arc<Stats>,
+    privacy_key: PrivacyKey,
+) -> (Handle, UnboundedReceiver<Control>) {
+    let (control, rx) = mpsc::unbounded_channel();
+    let handle = Handle {
+        control,
+        stats,
+        privacy_key,
+        listeners: Arc::default(),
+        #[cfg(feature = "config")]
+        loaded: Arc::default(),
+    };
+    (handle, rx)
+}
+
+impl Handle {
+    /// Moves the server to `addrs`: binds and listens on each of them, then
+    /// stops accepting on the old listeners. Established tunnels are left
+    /// alone. Each new listener keeps the name, auth, rules and socket
+    /// options of the old one in the same place, if any, a name that was
+    /// the old address becoming the new one; its backlog is 1024. If any
+    /// address fails to bind, the old listeners stay in use.
+    pub fn rebind(&self, addrs: Vec<SocketAddr>) -> Result<()> {
+        if addrs.is_empty() {
+            return Err(
+                io::Error::new(io::ErrorKind::InvalidInput, "no address to listen on").into(),
+            );
+        }
+        let options: Vec<_> = {
+            let listeners = self.listeners.lock().unwrap();
+            listeners
+                .iter()
+                .map(|(_, options)| options.clone())
+                .collect()
+        };
+        let listeners = addrs
+            .into_iter()
+            .enumerate()
+            .map(|(i, addr)| {
+                let options = options.get(i).cloned().unwrap_or_default();
+                socket::bind(addr, &options)?.listen(1024)
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        self.control
+            .send(Control::Rebind(listeners))
+            .map_err(|_| Socks5ServerError::Stopped)
+    }
+
+    /// Stops the server: its listeners close, its connections are cut, and
+    /// [`run`](super::Socks5Server::run) returns once they are gone.
+    pub fn shutdown(&self) -> Result<()> {
+        self.control
+            .send(Control::Shutdown)
+            .map_err(|_| Socks5ServerError::Stopped)
+    }
+
+    /// Stops the server gently: its listeners close right away, while its
+    /// connections are left to finish for up to `deadline` and cut after.
+    /// Resolves once they are all gone, telling how many finished.
+    pub async fn drain(&self, deadline: Duration) -> Result<Drained> {
+        let (report, drained) = oneshot::channel();
+        self.control
+            .send(Control::Drain(deadline, report))
+            .map_err(|_| Socks5ServerError::Stopped)?;
+        drained.await.map_err(|_| Socks5ServerError::Stopped)
+    }
+
+    /// The address the server listens on, the first if it listens on
+    /// several; with port 0 bound, the port picked.
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        let listeners = self.listeners.lock().unwrap();
+        let first = listeners.first().map(|(addr, _)| *addr);
+        first.ok_or(Socks5ServerError::Stopped)
+    }
+
+    /// Records `listeners` as the addresses listened on, and the options
+    /// bound with.
+    pub(super) fn listening(&self, listeners: Vec<(SocketAddr, SocketOptions)>) {
+        *self.listeners.lock().unwrap() = listeners;
+    }
+
+    /// Applies `config` to connections accepted from now on, as far as it
+    /// can be applied to a running server. Listen addresses only change
+    /// with a restart (or [`rebind`](Self::rebind)); listeners with their
+    /// own auth keep it. If `config` has problems, nothing changes and they
+    /// are all reported.
+    #[cfg(feature = "config")]
+    pub fn reload(&self, config: &ServerConfig) -> Result<ReloadReport> {
+        let resources = config.load().map_err(Socks5ServerError::Config)?;
+        let mut loaded = self.loaded.lock().unwrap();
+        let report = config.report(loaded.as_ref());
+        let mut config = config.clone();
+        if let Some(loaded) = loaded.as_ref() {
+            // Still listening there until restarted.
+            config.listen = loaded.listen.clone();
+        }
+        self.control
+            .send(Control::Reload(Box::new(config.clone()), resources))
+            .map_err(|_| Socks5ServerError::Stopped)?;
+        *loaded = Some(config);
+        Ok(report)
+    }
+
+    /// Lets clients accepted from now on authenticate with `methods`, as
+    /// [`auth_methods`](super::Socks5Server::auth_methods) does. Clients
+    /// already authenticated stay connected, and listeners with their own
+    /// auth keep it. Resolves once the running server took them; methods it
+    /// can't serve fail with the error [`run`](super::Socks5Server::run)
+    /// would have, the methods before staying.
+    pub async fn set_auth(&self, methods: Vec<AuthMethod>) -> Result<()> {
+        let applied = self.update(Update::Auth(methods))?;
+        let applied = applied.await.map_err(|_| Socks5ServerError::Stopped)?;
+        Ok(applied?)
+    }
+
+    /// Checks the requests of clients accepted from now on against `acl`,
+    /// as [`acl`](super::Socks5Server::acl) does. Established tunnels are
+    /// left alone, and listeners with their own rules keep them.
+    pub fn set_acl(&self, acl: RuleSet) -> Result<()> {
+        self.update(Update::Acl(acl)).map(drop)
+    }
+
+    /// Bounds the tunnels of clients accepted from now on by `limits`.
+    /// Established tunnels keep the bounds they started with.
+    pub fn set_limits(&self, limits: TunnelLimits) -> Result<()> {
+        self.update(Update::Limits(limits)).map(drop)
+    }
+
+    /// Sends `update`, returning where it is answered.
+    fn update(&self, update: Update) -> Result<oneshot::Receiver<io::Result<()>>> {
+        let (applied, answer) = oneshot::channel();
+        self.control
+            .send(Control::Update(update, applied))
+            .map_err(|_| Socks5ServerError::Stopped)?;
+        Ok(answer)
+    }
+
+    /// Remembers `config` as the one the server was built from.
+    #[cfg(feature = "config")]
+    pub(super) fn loaded(&self, config: &ServerConfig) {
+        *self.loaded.lock().unwrap() = Some(config.clone());
+    }
+
+    /// Refuses connections from `ip` for `duration`, replacing any ban it
+    /// is already under. Established tunnels are left alone.
+    pub fn ban(&self, ip: IpAddr, duration: Duration) {
+        self.stats
+            .bans
+            .ban(crate::utils::canonical_ip(ip), duration);
+    }
+
+    /// Lifts the ban on `ip`. Returns whether it was banned.
+    pub fn unban(&self, ip: IpAddr) -> bool {
+        self.stats.bans.unban(crate::utils::canonical_ip(ip))
+    }
+
+    /// The sources currently banned, with the time left on each ban.
+    pub fn banned(&self) -> Vec<(IpAddr, Duration)> {
+        self.stats.bans.list()
+    }
+
+    /// Hashes destinations with `key` from now on, under
+    /// [`LogPrivacy::HashedDestinations`](super::LogPrivacy::HashedDestinations).
+    /// Hashes from before no longer correlate with those after.
+    pub fn rotate_privacy_key(&self, key: [u8; 16]) {
+        let key = u128::from_le_bytes(key);
+        *self.privacy_key.write().unwrap() = (key as u64, (key >> 64) as u64);
+    }
+
+    /// The `k` destinations with the most connections or bytes over the
+    /// last `window`, highest first. Destinations that didn't fit in the
+    /// aggregator are reported together as [`OTHER_DESTINATIONS`](super::OTHER_DESTINATIONS).
+    /// Empty unless
+    /// [`top_destinations`](super::Socks5Server::top_destinations) is
+    /// configured.
+    pub fn top_destinations(
+        &self,
+        window: Duration,
+        metric: TopMetric,
+        k: usize,
+    ) -> Vec<(String, u64)> {
+        self.stats.top.top(window, metric, k)
+    }
+}
@@ -0,0 +1,172 @@
+use std::{future::Future, net::SocketAddr, time::Duration};
+use tokio::{io, task::JoinSet};
+
+/// The default delay between connection attempts, as RFC 8305 recommends.
+const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// An IP address family.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AddressFamily {
+    V4,
+    #[default]
+    V6,
+}
+
+/// How connects race over the addresses of a destination (RFC 8305).
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct HappyEyeballs {
+    pub prefer: AddressFamily,
+    pub delay: Duration,
+}
+
+impl Default for HappyEyeballs {
+    fn default() -> Self {
+        HappyEyeballs {
+            prefer: AddressFamily::default(),
+            delay: ATTEMPT_DELAY,
+        }
+    }
+}
+
+impl HappyEyeballs {
+    /// Connects to one of `addrs`, starting with the preferred family and
+    /// alternating between families from there. Each further attempt
+    /// starts `delay` after the one before, or as soon as that one fails.
+    /// The first connection wins and the attempts still running are
+    /// aborted; once all have failed, the error of the last one is
+    /// returned.
+    ///
+    /// Returns the connection, the address it is to and the number of
+    /// attempts started.
+    pub async fn connect<F, Fut, C>(
+        &self,
+        addrs: &[SocketAddr],
+        connect: F,
+    ) -> io::Result<(C, SocketAddr, u32)>
+    where
+        F: Fn(SocketAddr) -> Fut,
+        Fut: Future<Output = io::Result<C>> + Send + 'static,
+        C: Send + 'static,
+    {
+        let mut pending = self.order(addrs).into_iter().peekable();
+        let mut attempts = JoinSet::new();
+        let (mut started, mut failed) = (0, None);
+        loop {
+            if let Some(addr) = pending.next() {
+                let attempt = connect(addr);
+                attempts.spawn(async move { (addr, attempt.await) });
+                started += 1;
+            }
+            let joined = match pending.peek() {
+                Some(_) => tokio::select! {
+                    joined = attempts.join_next() => joined,
+                    () = tokio::time::sleep(self.delay) => continue,
+                },
+                None => attempts.join_next().await,
+            };
+            match joined {
+                Some(Ok((addr, Ok(conn)))) => return Ok((conn, addr, started)),
+                Some(Ok((_, Err(e)))) => failed = Some(e),
+                Some(Err(e)) if e.is_panic() => std::panic::resume_unwind(e.into_panic()),
+                Some(Err(e)) => failed = Some(e.into()),
+                None => {}
+            }
+            if pending.peek().is_none() && attempts.is_empty() {
+                return Err(failed.unwrap_or_else(|| io::ErrorKind::NotFound.into()));
+            }
+        }
+    }
+
+    /// `addrs` in the order they are tried: alternating families, the
+    /// preferred one first, each family in the order resolved.
+    fn order(&self, addrs: &[SocketAddr]) -> Vec<SocketAddr> {
+        let preferred = |addr: &&SocketAddr| match self.prefer {
+            AddressFamily::V4 => addr.is_ipv4(),
+            AddressFamily::V6 => addr.is_ipv6(),
+        };
+        let (mut first, mut second) = (
+            addrs.iter().filter(preferred),
+            addrs.iter().filter(|addr| !preferred(addr)),
+        );
+        let mut ordered = Vec::with_capacity(addrs.len());
+        loop {
+            match (first.next(), second.next()) {
+                (None, None) => return ordered,
+                (a, b) => ordered.extend(a.into_iter().chain(b).copied()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+    use tokio::net::{TcpListener, TcpStream};
+
+    fn eyeballs(prefer: AddressFamily, delay: Duration) -> HappyEyeballs {
+        HappyEyeballs { prefer, delay }
+    }
+
+    #[test]
+    fn alternates_families_from_the_preferred_one() {
+        let addrs: Vec<SocketAddr> = ["10.0.0.1:1", "10.0.0.2:1", "[fd00::1]:1", "[fd00::2]:1"]
+            .iter()
+            .map(|addr| addr.parse().unwrap())
+            .collect();
+        let ordered = eyeballs(AddressFamily::V6, ATTEMPT_DELAY).order(&addrs);
+        assert_eq!(ordered, [addrs[2], addrs[0], addrs[3], addrs[1]]);
+        let ordered = eyeballs(AddressFamily::V4, ATTEMPT_DELAY).order(&addrs);
+        assert_eq!(ordered, [addrs[0], addrs[2], addrs[1], addrs[3]]);
+    }
+
+    #[tokio::test]
+    async fn connects_to_the_preferred_family() {
+        let v4 = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let v6 = TcpListener::bind("[::1]:0").await.unwrap();
+        let addrs = [v4.local_addr().unwrap(), v6.local_addr().unwrap()];
+        for (prefer, expected) in [(AddressFamily::V4, addrs[0]), (AddressFamily::V6, addrs[1])] {
+            let (_, addr, started) = eyeballs(prefer, ATTEMPT_DELAY)
+                .connect(&addrs, TcpStream::connect)
+                .await
+                .unwrap();
+            assert_eq!((addr, started), (expected, 1));
+        }
+    }
+
+    #[tokio::test]
+    async fn falls_back_once_the_delay_passes() {
+        let v4 = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let v6 = TcpListener::bind("[::1]:0").await.unwrap();
+        let addrs = [v4.local_addr().unwrap(), v6.local_addr().unwrap()];
+        // IPv6 blackholed: its connects never complete.
+        let connect = |addr: SocketAddr| async move {
+            match addr.is_ipv6() {
+                true => std::future::pending().await,
+                false => TcpStream::connect(addr).await,
+            }
+        };
+        let delay = Duration::from_millis(50);
+        let start = Instant::now();
+        let (_, addr, started) = eyeballs(AddressFamily::V6, delay)
+            .connect(&addrs, connect)
+            .await
+            .unwrap();
+        assert_eq!((addr, started), (addrs[0], 2));
+        assert!(start.elapsed() >= delay);
+    }
+
+    #[tokio::test]
+    async fn fails_once_every_attempt_has() {
+        let closed = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addrs = [closed.local_addr().unwrap()];
+        drop(closed);
+        let failed = eyeballs(AddressFamily::V6, ATTEMPT_DELAY)
+            .connect(&addrs, TcpStream::connect)
+            .await;
+        assert_eq!(
+            failed.err().map(|e| e.kind()),
+            Some(io::ErrorKind::ConnectionRefused)
+        );
+    }
+}
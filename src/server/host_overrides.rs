@@ -0,0 +1,40 @@
+use std::{collections::HashMap, net::IpAddr};
+
+/// Hostnames that resolve to fixed addresses, without asking the
+/// resolver, like entries of a hosts file.
+///
+/// A name matches regardless of case. A `*.` entry matches every name
+/// under it, but not the name itself; the longest matching one wins, and
+/// an exact entry beats them all.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct HostOverrides {
+    exact: HashMap<String, IpAddr>,
+    /// Keyed by the suffix, with its leading dot.
+    suffixes: HashMap<String, IpAddr>,
+}
+
+impl HostOverrides {
+    pub fn insert(&mut self, host: &str, ip: IpAddr) {
+        let host = normalize(host);
+        match host.strip_prefix('*') {
+            Some(suffix) if suffix.starts_with('.') => self.suffixes.insert(suffix.to_owned(), ip),
+            _ => self.exact.insert(host, ip),
+        };
+    }
+
+    pub fn get(&self, host: &str) -> Option<IpAddr> {
+        if self.exact.is_empty() && self.suffixes.is_empty() {
+            return None;
+        }
+        let host = normalize(host);
+        if let Some(ip) = self.exact.get(&host) {
+            return Some(*ip);
+        }
+        host.match_indices('.')
+            .find_map(|(at, _)| self.suffixes.get(&host[at..]).copied())
+    }
+}
+
+fn normalize(host: &str) -> String {
+    host.trim_end_matches('.').to_ascii_lowercase()
+}
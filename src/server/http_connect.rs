@@ -0,0 +1,107 @@
+use super::{Command, Config, PendingCommand, Result, Socks5ServerError, Target};
+use crate::utils::*;
+use std::net::SocketAddr;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt};
+
+/// The most a request line and its headers may take together.
+const MAX_HEAD: u64 = 8192;
+
+/// Whether a connection starting with `first` speaks HTTP: methods are
+/// uppercase tokens, unlike the version byte SOCKS starts with.
+pub(super) fn sniff(first: u8) -> bool {
+    first.is_ascii_uppercase()
+}
+
+/// Reads an HTTP `CONNECT host:port` request and its headers, which are
+/// ignored. Like SOCKS4, HTTP is only served while clients may go without
+/// authentication. Anything the client sends past the headers is relayed.
+pub(super) async fn read_request(
+    conn: &mut PendingCommand,
+    config: &Config,
+) -> Result<(Command, Target)> {
+    let mut head = (&mut conn.0).take(MAX_HEAD);
+    let mut line = String::new();
+    read_line(&mut head, &mut line).await?;
+    let mut parts = line.split(' ');
+    let (method, authority, version) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(authority), Some(version)) if parts.next().is_none() => {
+            (method.to_owned(), authority.to_owned(), version)
+        }
+        _ => return Err(Socks5ServerError::BadHttpRequest(line)),
+    };
+    if !version.starts_with("HTTP/1.") {
+        return Err(Socks5ServerError::BadHttpRequest(line));
+    }
+    loop {
+        line.clear();
+        read_line(&mut head, &mut line).await?;
+        if line.is_empty() {
+            break;
+        }
+    }
+
+    let source = canonical_ip(conn.get_ref().peer_addr()?.ip());
+    if !config
+        .auth_for(source)
+        .iter()
+        .any(|method| matches!(method, AuthMethod::NoAuth))
+    {
+        return Err(Socks5ServerError::UnsupportAuth);
+    }
+    if method != "CONNECT" {
+        return Err(Socks5ServerError::HttpMethod(method));
+    }
+    let target = parse_authority(&authority)?;
+    match target.port() {
+        0 => Err(Socks5ServerError::ZeroPort(target.host())),
+        _ => Ok((Command::Connect, target)),
+    }
+}
+
+/// Reads a line into `line`, without its line ending.
+async fn read_line<R>(head: &mut R, line: &mut String) -> Result<()>
+where
+    R: AsyncBufReadExt + Unpin,
+{
+    let mut raw = Vec::new();
+    head.read_until(b'\n', &mut raw).await?;
+    match raw.strip_suffix(b"\n") {
+        Some(raw) => {
+            let raw = raw.strip_suffix(b"\r").unwrap_or(raw);
+            line.push_str(&String::from_utf8_lossy(raw));
+            Ok(())
+        }
+        None if raw.is_empty() => Err(Socks5ServerError::EarlyEof),
+        None => Err(Socks5ServerError::BadHttpRequest(
+            "request head unterminated or too long".to_string(),
+        )),
+    }
+}
+
+/// Parses `host:port`, with IPv6 addresses in brackets.
+fn parse_authority(authority: &str) -> Result<Target> {
+    if let Ok(addr) = authority.parse::<SocketAddr>() {
+        return Ok(Target::Ip(addr));
+    }
+    let bad = || Socks5ServerError::BadHttpRequest(format!("invalid authority {:?}", authority));
+    let (host, port) = authority.rsplit_once(':').ok_or_else(bad)?;
+    let port = port.parse().map_err(|_| bad())?;
+    Ok(Target::Domain(Hostname::new(host)?, port))
+}
+
+/// Encodes the response standing for `rep`.
+pub(super) fn encode_reply(rep: SocksError) -> Vec<u8> {
+    let status = match rep {
+        SocksError::SUCCESS => "200 Connection Established",
+        SocksError::DENY => "403 Forbidden",
+        SocksError::COMMAND => "405 Method Not Allowed\r\nAllow: CONNECT",
+        SocksError::ADDRESS => "400 Bad Request",
+        SocksError::TTL => "504 Gateway Timeout",
+        _ => "502 Bad Gateway",
+    };
+    let length = match rep {
+        SocksError::SUCCESS => "",
+        _ => "\r\nContent-Length: 0\r\nConnection: close",
+    };
+    format!("HTTP/1.1 {}{}\r\n\r\n", status, length).into_bytes()
+}
@@ -0,0 +1,157 @@
+use super::{ClientConn, PendingCommand, Result};
+use crate::utils::*;
+use std::{
+    fmt,
+    future::Future,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    sync::Arc,
+};
+use tokio::io::{self, BufReader};
+
+pub(super) type Handler =
+    Arc<dyn Fn(IncomingRequest) -> Pin<Box<dyn Future<Output = ()> + Send>> + Send + Sync>;
+
+/// A negotiated request handed to the handler of
+/// [`run_with_handler`](super::Socks5Server::run_with_handler), waiting for
+/// its reply.
+pub struct IncomingRequest {
+    conn: PendingCommand,
+    dest: Addr,
+    source: SocketAddr,
+    user: Option<String>,
+}
+
+impl IncomingRequest {
+    pub(super) fn new(
+        conn: PendingCommand,
+        dest: Addr,
+        source: SocketAddr,
+        user: Option<String>,
+    ) -> IncomingRequest {
+        IncomingRequest {
+            conn,
+            dest,
+            source,
+            user,
+        }
+    }
+
+    /// The destination the client asked for.
+    pub fn dest(&self) -> &Addr {
+        &self.dest
+    }
+
+    /// The client's address.
+    pub fn source(&self) -> SocketAddr {
+        self.source
+    }
+
+    /// The user the client authenticated as, if any.
+    pub fn user(&self) -> Option<&str> {
+        self.user.as_deref()
+    }
+
+    /// Sends the success reply carrying `bound` and returns the client
+    /// connection to carry the tunnel on. It stays buffered: clients may
+    /// pipeline data right behind the request, and those bytes are read
+    /// from the buffer first.
+    pub async fn reply_success(self, bound: SocketAddr) -> Result<BufReader<ClientConn>> {
+        let rep = encode_reply(SocksError::SUCCESS, Some(bound));
+        self.conn.reply(&rep).await
+    }
+
+    /// Like [`reply_success`](Self::reply_success), but returns the client
+    /// connection as a std socket, still in nonblocking mode, together with
+    /// whatever the client already sent past the request.
+    pub async fn reply_success_std(
+        self,
+        bound: SocketAddr,
+    ) -> Result<(std::net::TcpStream, Vec<u8>)> {
+        let conn = self.reply_success(bound).await?;
+        let pending = conn.buffer().to_vec();
+        let conn = conn
+            .into_inner()
+            .into_tcp()
+            .map_err(|_| io::Error::new(io::ErrorKind::Unsupported, "not a TCP connection"))?;
+        Ok((conn.into_std()?, pending))
+    }
+
+    /// Refuses the request with `rep` and closes the connection.
+    pub async fn reply_error(self, rep: SocksError) -> Result<()> {
+        self.conn.reply(&encode_reply(rep, None)).await?;
+        Ok(())
+    }
+}
+
+impl fmt::Debug for IncomingRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("IncomingRequest")
+            .field("dest", &self.dest)
+            .field("source", &self.source)
+            .field("user", &self.user)
+            .finish()
+    }
+}
+
+/// Encodes a reply with code `rep` and BND.ADDR `bound`, all zeros if none.
+pub(super) fn encode_reply(rep: SocksError, bound: Option<SocketAddr>) -> Vec<u8> {
+    let mut reply = vec![SOCKS_VER, rep as u8, SOCKS_RSV];
+    match bound.map(|bound| (bound.ip(), bound.port())) {
+        Some((IpAddr::V6(ip), port)) => {
+            reply.push(SOCKS_ADDR_IPV6);
+            reply.extend_from_slice(&ip.octets());
+            reply.extend_from_slice(&port.to_be_bytes());
+        }
+        Some((IpAddr::V4(ip), port)) => {
+            reply.push(SOCKS_ADDR_IPV4);
+            reply.extend_from_slice(&ip.octets());
+            reply.extend_from_slice(&port.to_be_bytes());
+        }
+        None => reply.extend_from_slice(&[SOCKS_ADDR_IPV4, 0, 0, 0, 0, 0, 0]),
+    }
+    reply
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+    };
+
+    #[tokio::test]
+    async fn hands_over_std_sockets_with_the_bytes_pipelined() {
+        let server = crate::server::new("127.0.0.1:0".parse().unwrap(), None).unwrap();
+        let proxy = server.local_addr().unwrap();
+        let bound: SocketAddr = "192.0.2.1:4000".parse().unwrap();
+        tokio::spawn(server.run_with_handler(move |request| async move {
+            let (mut conn, pending) = request.reply_success_std(bound).await.unwrap();
+            conn.set_nonblocking(false).unwrap();
+            tokio::task::spawn_blocking(move || {
+                conn.write_all(&pending).unwrap();
+                conn.write_all(b"pong").unwrap();
+            });
+        }));
+        tokio::task::yield_now().await;
+
+        let mut client = TcpStream::connect(proxy).await.unwrap();
+        client.set_nodelay(true).unwrap();
+        let mut segment = vec![SOCKS_VER, 1, 0, SOCKS_VER, SOCKS_COMMAND_CONNECT, SOCKS_RSV];
+        segment.extend_from_slice(&[SOCKS_ADDR_IPV4, 10, 0, 0, 1, 0, 80]);
+        segment.extend_from_slice(b"ping");
+        client.write_all(&segment).await.unwrap();
+
+        let mut replies = [0u8; 2 + 10];
+        client.read_exact(&mut replies).await.unwrap();
+        assert_eq!(
+            &replies[2..],
+            &encode_reply(SocksError::SUCCESS, Some(bound))[..]
+        );
+        let mut echoed = [0u8; 8];
+        client.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(&echoed, b"pingpong");
+    }
+}
@@ -0,0 +1,310 @@
+use super::{check_auth, Config, Result, RuleSet};
+use crate::{socket, utils::AuthMethod, SocketOptions};
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+use tokio::{
+    io,
+    net::{TcpListener, TcpSocket},
+};
+
+/// An additional address for a server to accept on, with settings of its
+/// own. Whatever a listener doesn't set is taken from the server; limits
+/// and stats are shared by all listeners.
+pub struct Listener {
+    conn: Socket,
+    name: String,
+    auth: Option<Vec<AuthMethod>>,
+    rules: Option<RuleSet>,
+    /// What the socket was bound with, to bind the same way again.
+    options: SocketOptions,
+}
+
+enum Socket {
+    Bound(TcpSocket),
+    /// Handed over listening already, its backlog set by the caller.
+    Listening(TcpListener),
+}
+
+impl Listener {
+    /// Binds `addr`, naming the listener after the address bound, with the
+    /// port picked if `addr` has port 0.
+    pub fn bind(addr: SocketAddr) -> Result<Listener> {
+        Listener::bind_with(addr, &SocketOptions::default())
+    }
+
+    /// Binds `addr` with `options` set on the listening socket. Which of
+    /// them accepted connections inherit depends on the platform.
+    pub fn bind_with(addr: SocketAddr, options: &SocketOptions) -> Result<Listener> {
+        let conn = socket::bind(addr, options)?;
+        Ok(Listener {
+            name: conn.local_addr()?.to_string(),
+            conn: Socket::Bound(conn),
+            auth: None,
+            rules: None,
+            options: options.clone(),
+        })
+    }
+
+    /// Accepts on `listener`, bound and listening already with whatever
+    /// options and backlog the caller chose, naming the listener after its
+    /// address.
+    pub fn from_listener(listener: TcpListener) -> Result<Listener> {
+        Ok(Listener {
+            name: listener.local_addr()?.to_string(),
+            conn: Socket::Listening(listener),
+            auth: None,
+            rules: None,
+            options: SocketOptions::default(),
+        })
+    }
+
+    /// Names the listener in logs and stats.
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.name = name.into();
+        self
+    }
+
+    /// Authenticates clients of this listener with `auth` instead of the
+    /// server's methods.
+    pub fn auth(self, auth: AuthMethod) -> Self {
+        self.auth_methods(vec![auth])
+    }
+
+    /// Lets clients of this listener authenticate with any of `methods`
+    /// instead of the server's, as [`Socks5Server::auth_methods`] does.
+    ///
+    /// [`Socks5Server::auth_methods`]: super::Socks5Server::auth_methods
+    pub fn auth_methods(mut self, methods: Vec<AuthMethod>) -> Self {
+        self.auth = Some(methods);
+        self
+    }
+
+    /// Checks the requests of clients of this listener against `rules`
+    /// instead of the server's [`acl`](super::Socks5Server::acl).
+    pub fn rules(mut self, rules: RuleSet) -> Self {
+        self.rules = Some(rules);
+        self
+    }
+
+    /// The address bound, with the port picked if bound to port 0.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        match &self.conn {
+            Socket::Bound(conn) => conn.local_addr(),
+            Socket::Listening(listener) => listener.local_addr(),
+        }
+    }
+
+    /// The options the socket was bound with, none if handed over bound.
+    pub(super) fn options(&self) -> &SocketOptions {
+        &self.options
+    }
+
+    /// Starts listening, with the server settings `base` adjusted for this
+    /// listener.
+    pub(super) fn listen(self, base: &Arc<Config>) -> io::Result<Bound> {
+        if let Some(auth) = &self.auth {
+            check_auth(auth, base)?;
+        }
+        let listener = match self.conn {
+            Socket::Bound(conn) => conn.listen(1024)?,
+            Socket::Listening(listener) => listener,
+        };
+        Ok(Bound::new(
+            listener,
+            self.name.into(),
+            self.auth,
+            self.rules,
+            self.options,
+            base,
+        ))
+    }
+}
+
+/// A listening socket and the settings its connections are served with.
+pub(super) struct Bound {
+    pub(super) listener: TcpListener,
+    pub(super) config: Arc<Config>,
+    /// What the settings are derived with, kept for reloads.
+    name: Arc<str>,
+    auth: Option<Vec<AuthMethod>>,
+    rules: Option<RuleSet>,
+    pub(super) options: SocketOptions,
+}
+
+impl Bound {
+    pub(super) fn new(
+        listener: TcpListener,
+        name: Arc<str>,
+        auth: Option<Vec<AuthMethod>>,
+        rules: Option<RuleSet>,
+        options: SocketOptions,
+        base: &Config,
+    ) -> Bound {
+        Bound {
+            listener,
+            config: derive(&name, auth.as_ref(), rules.as_ref(), base),
+            name,
+            auth,
+            rules,
+            options,
+        }
+    }
+
+    /// Serves `listener` with the settings of this listener, as where it
+    /// moved to. A name that only told the old address tells the new one.
+    pub(super) fn moved(&self, listener: TcpListener, base: &Config) -> io::Result<Bound> {
+        let addr = listener.local_addr()?.to_string();
+        let name = match self.listener.local_addr() {
+            Ok(old) if *self.name == old.to_string() => addr.into(),
+            _ => self.name.clone(),
+        };
+        Ok(Bound::new(
+            listener,
+            name,
+            self.auth.clone(),
+            self.rules.clone(),
+            self.options.clone(),
+            base,
+        ))
+    }
+
+    /// Derives the settings of this listener from the server settings
+    /// `base` anew.
+    pub(super) fn configure(&mut self, base: &Config) {
+        self.config = derive(&self.name, self.auth.as_ref(), self.rules.as_ref(), base);
+    }
+}
+
+/// The listeners `old` moved to `rebound`, each taking the settings of the
+/// old one in its place, if any. Fails, with `old` left as it was, if any
+/// can't be moved.
+pub(super) fn move_all(
+    old: &[Bound],
+    rebound: Vec<TcpListener>,
+    base: &Config,
+) -> io::Result<Vec<Bound>> {
+    let mut moved = Vec::with_capacity(rebound.len());
+    for (i, listener) in rebound.into_iter().enumerate() {
+        moved.push(match old.get(i) {
+            Some(old) => old.moved(listener, base)?,
+            None => {
+                let name = listener.local_addr()?.to_string().into();
+                Bound::new(listener, name, None, None, SocketOptions::default(), base)
+            }
+        });
+    }
+    Ok(moved)
+}
+
+/// Whether an error accepting a connection only concerns that connection,
+/// such as one aborted before it was accepted, leaving the listener fit
+/// to accept the next.
+pub(super) fn is_transient(e: &io::Error) -> bool {
+    // The codes Linux passes on from connections failing in the backlog,
+    // which no error kind stands for.
+    #[cfg(target_os = "linux")]
+    const PENDING_ERRORS: &[i32] = &[64, 71, 92, 95, 112];
+    #[cfg(not(target_os = "linux"))]
+    const PENDING_ERRORS: &[i32] = &[];
+    match e.kind() {
+        io::ErrorKind::ConnectionAborted
+        | io::ErrorKind::ConnectionReset
+        | io::ErrorKind::Interrupted
+        | io::ErrorKind::WouldBlock
+        | io::ErrorKind::TimedOut
+        | io::ErrorKind::HostUnreachable
+        | io::ErrorKind::NetworkUnreachable
+        | io::ErrorKind::NetworkDown => true,
+        _ => e
+            .raw_os_error()
+            .is_some_and(|code| PENDING_ERRORS.contains(&code)),
+    }
+}
+
+/// Whether accepting failed because the process is out of descriptors or
+/// memory, which connections closing may free up again.
+pub(super) fn is_exhaustion(e: &io::Error) -> bool {
+    // EMFILE, ENFILE and ENOMEM.
+    #[cfg(unix)]
+    const EXHAUSTION_ERRORS: &[i32] = &[24, 23, 12];
+    #[cfg(not(unix))]
+    const EXHAUSTION_ERRORS: &[i32] = &[];
+    e.kind() == io::ErrorKind::OutOfMemory
+        || e.raw_os_error()
+            .is_some_and(|code| EXHAUSTION_ERRORS.contains(&code))
+}
+
+/// How long to wait before accepting again after `failures` exhaustion
+/// errors in a row: 10ms, doubling up to a second.
+pub(super) fn exhaustion_backoff(failures: u32) -> Duration {
+    Duration::from_millis(10 << failures.min(7)).min(Duration::from_secs(1))
+}
+
+fn derive(
+    name: &Arc<str>,
+    auth: Option<&Vec<AuthMethod>>,
+    rules: Option<&RuleSet>,
+    base: &Config,
+) -> Arc<Config> {
+    let mut config = base.clone();
+    config.listener = Some(name.clone());
+    if let Some(auth) = auth {
+        config.auth = auth.clone();
+    }
+    if let Some(rules) = rules {
+        config.acl = Some(rules.clone());
+    }
+    Arc::new(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tells_transient_accept_errors_from_fatal_ones() {
+        for kind in [
+            io::ErrorKind::ConnectionAborted,
+            io::ErrorKind::ConnectionReset,
+            io::ErrorKind::Interrupted,
+            io::ErrorKind::WouldBlock,
+        ] {
+            assert!(is_transient(&io::Error::from(kind)), "{:?}", kind);
+        }
+        #[cfg(target_os = "linux")]
+        for code in [71, 112] {
+            assert!(
+                is_transient(&io::Error::from_raw_os_error(code)),
+                "{}",
+                code
+            );
+        }
+        // EBADF and EINVAL: the listener itself is gone.
+        for code in [9, 22] {
+            assert!(
+                !is_transient(&io::Error::from_raw_os_error(code)),
+                "{}",
+                code
+            );
+        }
+        assert!(!is_transient(&io::Error::other("listener closed")));
+    }
+
+    #[test]
+    fn tells_exhaustion_apart() {
+        #[cfg(unix)]
+        for code in [24, 23, 12] {
+            let e = io::Error::from_raw_os_error(code);
+            assert!(is_exhaustion(&e) && !is_transient(&e), "{}", code);
+        }
+        assert!(is_exhaustion(&io::Error::from(io::ErrorKind::OutOfMemory)));
+        let aborted = io::Error::from(io::ErrorKind::ConnectionAborted);
+        assert!(!is_exhaustion(&aborted));
+    }
+
+    #[test]
+    fn backs_off_doubling_up_to_a_second() {
+        let schedule: Vec<_> = (0..9).map(|n| exhaustion_backoff(n).as_millis()).collect();
+        assert_eq!(schedule, [10, 20, 40, 80, 160, 320, 640, 1000, 1000]);
+        assert_eq!(exhaustion_backoff(u32::MAX), Duration::from_secs(1));
+    }
+}
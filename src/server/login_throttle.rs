@@ -0,0 +1,270 @@
+use crate::utils::canonical_ip;
+use log::warn;
+use std::{
+    collections::{HashMap, VecDeque},
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+use tokio::time::Instant;
+
+/// Sources tracked for failed logins before old ones are pruned.
+const MAX_TRACKED: usize = 4096;
+
+/// Slows down sources that keep failing username/password logins:
+/// `threshold` failures from one address within `window` throttle it.
+///
+/// A throttled source waits before hearing the outcome of each further
+/// login, `delay` at first and twice as long with every failure after,
+/// up to `max_delay`. With a lockout, it is also refused the
+/// username/password method altogether for that long. A successful login
+/// clears its failures.
+#[derive(Debug, Clone)]
+pub struct LoginThrottle {
+    pub(crate) threshold: u32,
+    pub(crate) window: Duration,
+    pub(crate) delay: Duration,
+    pub(crate) max_delay: Duration,
+    pub(crate) lockout: Option<Duration>,
+    pub(crate) allow: Vec<IpAddr>,
+}
+
+impl LoginThrottle {
+    /// Throttles with a delay of one second, doubling up to 30 seconds, and
+    /// no lockout.
+    pub fn new(threshold: u32, window: Duration) -> Self {
+        LoginThrottle {
+            threshold: threshold.max(1),
+            window,
+            delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(30),
+            lockout: None,
+            allow: Vec::new(),
+        }
+    }
+
+    /// Delays throttled logins by `delay`, doubling up to `max_delay`. A
+    /// zero delay leaves them undelayed.
+    pub fn delay(mut self, delay: Duration, max_delay: Duration) -> Self {
+        self.delay = delay;
+        self.max_delay = max_delay.max(delay);
+        self
+    }
+
+    /// Refuses throttled sources the username/password method for
+    /// `duration`, answering their method selection with 0xFF if nothing
+    /// else they offer is acceptable.
+    pub fn lockout(mut self, duration: Duration) -> Self {
+        self.lockout = Some(duration);
+        self
+    }
+
+    /// Never throttles `sources`.
+    pub fn allow(mut self, sources: Vec<IpAddr>) -> Self {
+        self.allow = sources.into_iter().map(canonical_ip).collect();
+        self
+    }
+}
+
+#[derive(Debug, Default)]
+struct Failures {
+    seen: VecDeque<Instant>,
+    locked_until: Option<Instant>,
+}
+
+/// Failed logins by source.
+#[derive(Debug, Default)]
+pub(crate) struct Logins {
+    failures: Mutex<HashMap<IpAddr, Failures>>,
+    throttled: AtomicU64,
+}
+
+impl Logins {
+    /// Whether `ip` is locked out of username/password logins.
+    pub fn locked(&self, ip: IpAddr) -> bool {
+        let mut failures = self.failures.lock().unwrap();
+        let until = match failures.get(&ip).and_then(|failures| failures.locked_until) {
+            Some(until) => until,
+            None => return false,
+        };
+        if until <= Instant::now() {
+            failures.remove(&ip);
+            return false;
+        }
+        true
+    }
+
+    /// How long a login from `ip` is to wait for its outcome.
+    pub fn delay(&self, ip: IpAddr, policy: &LoginThrottle) -> Option<Duration> {
+        let now = Instant::now();
+        let mut failures = self.failures.lock().unwrap();
+        let seen = &mut failures.get_mut(&ip)?.seen;
+        prune(seen, now, policy.window);
+        let excess = (seen.len() as u32).checked_sub(policy.threshold)?;
+        let delay = policy
+            .delay
+            .checked_mul(1 << excess.min(16))
+            .unwrap_or(policy.max_delay);
+        Some(delay.min(policy.max_delay)).filter(|delay| !delay.is_zero())
+    }
+
+    /// Counts a failed login from `ip`, throttling it once `policy`'s
+    /// threshold is reached.
+    pub fn failed(&self, ip: IpAddr, policy: &LoginThrottle) {
+        if policy.allow.contains(&ip) {
+            return;
+        }
+        let now = Instant::now();
+        let mut failures = self.failures.lock().unwrap();
+        if failures.len() >= MAX_TRACKED && !failures.contains_key(&ip) {
+            failures.retain(|_, failures| {
+                failures.locked_until.is_some_and(|until| until > now)
+                    || failures
+                        .seen
+                        .back()
+                        .is_some_and(|last| now.duration_since(*last) < policy.window)
+            });
+            if failures.len() >= MAX_TRACKED {
+                let stalest = failures
+                    .iter()
+                    .min_by_key(|(_, failures)| failures.seen.back().copied())
+                    .map(|(ip, _)| *ip);
+                if let Some(stalest) = stalest {
+                    failures.remove(&stalest);
+                }
+            }
+        }
+        let entry = failures.entry(ip).or_default();
+        prune(&mut entry.seen, now, policy.window);
+        entry.seen.push_back(now);
+        if entry.seen.len() != policy.threshold as usize {
+            return;
+        }
+        self.throttled.fetch_add(1, Ordering::Relaxed);
+        match policy.lockout {
+            Some(lockout) => {
+                entry.locked_until = Some(now + lockout);
+                warn!(
+                    "throttling logins from {} after {} failures, locked out for {:?}",
+                    ip, policy.threshold, lockout
+                );
+            }
+            None => warn!(
+                "throttling logins from {} after {} failures",
+                ip, policy.threshold
+            ),
+        }
+    }
+
+    /// Clears the failures of `ip`, which logged in.
+    pub fn succeeded(&self, ip: IpAddr) {
+        self.failures.lock().unwrap().remove(&ip);
+    }
+
+    pub fn throttled(&self) -> u64 {
+        self.throttled.load(Ordering::Relaxed)
+    }
+}
+
+fn prune(seen: &mut VecDeque<Instant>, now: Instant, window: Duration) {
+    while seen
+        .front()
+        .is_some_and(|first| now.duration_since(*first) >= window)
+    {
+        seen.pop_front();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{server::tests::log_in, utils::*};
+    use std::net::Ipv4Addr;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+    };
+
+    const SECOND: Duration = Duration::from_secs(1);
+
+    fn ip(last: u8) -> IpAddr {
+        Ipv4Addr::new(192, 0, 2, last).into()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn doubles_the_delay_up_to_the_most_allowed() {
+        let policy = LoginThrottle::new(2, 60 * SECOND).delay(SECOND, 4 * SECOND);
+        let logins = Logins::default();
+        logins.failed(ip(1), &policy);
+        assert_eq!(logins.delay(ip(1), &policy), None);
+        let mut delays = Vec::new();
+        for _ in 0..4 {
+            logins.failed(ip(1), &policy);
+            delays.push(logins.delay(ip(1), &policy).unwrap());
+        }
+        assert_eq!(delays, [SECOND, 2 * SECOND, 4 * SECOND, 4 * SECOND]);
+        assert_eq!(logins.throttled(), 1);
+
+        tokio::time::advance(60 * SECOND).await;
+        assert_eq!(logins.delay(ip(1), &policy), None, "out of the window");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn clears_failures_on_success() {
+        let policy = LoginThrottle::new(2, 60 * SECOND).lockout(60 * SECOND);
+        let logins = Logins::default();
+        logins.failed(ip(1), &policy);
+        logins.failed(ip(1), &policy);
+        assert!(logins.locked(ip(1)));
+        assert!(logins.delay(ip(1), &policy).is_some());
+
+        logins.succeeded(ip(1));
+        assert!(!logins.locked(ip(1)));
+        logins.failed(ip(1), &policy);
+        assert_eq!(logins.delay(ip(1), &policy), None, "counting from zero");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn tracks_at_most_max_tracked_sources() {
+        let policy = LoginThrottle::new(2, 60 * SECOND);
+        let logins = Logins::default();
+        logins.failed(ip(1), &policy);
+        for n in 0..MAX_TRACKED as u32 {
+            tokio::time::advance(Duration::from_millis(1)).await;
+            logins.failed(Ipv4Addr::from(0x0A00_0000 + n).into(), &policy);
+        }
+        assert_eq!(logins.failures.lock().unwrap().len(), MAX_TRACKED);
+        // The stalest source went to make room, so starts over.
+        logins.failed(ip(1), &policy);
+        assert_eq!(logins.delay(ip(1), &policy), None);
+    }
+
+    #[tokio::test]
+    async fn refuses_the_method_to_locked_out_sources() {
+        let throttle = LoginThrottle::new(1, 60 * SECOND)
+            .delay(Duration::ZERO, Duration::ZERO)
+            .lockout(60 * SECOND);
+        let server = crate::server::new(
+            "127.0.0.1:0".parse().unwrap(),
+            Some(("user", "pass").into()),
+        )
+        .unwrap()
+        .login_throttle(throttle);
+        let proxy = server.local_addr().unwrap();
+        tokio::spawn(server.run());
+        tokio::task::yield_now().await;
+
+        let mut client = TcpStream::connect(proxy).await.unwrap();
+        let status = log_in(&mut client, SOCKS_AUTH_USERPASS_VER, ("user", "guess")).await;
+        assert_eq!(status, Some(SocksError::FAIL as u8));
+
+        let mut client = TcpStream::connect(proxy).await.unwrap();
+        client.write_all(&[SOCKS_VER, 1, 2]).await.unwrap();
+        let mut selected = [0u8; 2];
+        client.read_exact(&mut selected).await.unwrap();
+        assert_eq!(selected, [SOCKS_VER, 0xFF]);
+    }
+}
@@ -0,0 +1,27 @@
+use super::Target;
+use std::sync::Arc;
+
+/// Picks the TOS of a tunnel by destination host.
+pub(crate) type TosRule = Arc<dyn Fn(&str) -> Option<u8> + Send + Sync>;
+
+/// The IP TOS (IPv6 traffic class) tunnels are marked with.
+#[derive(Clone, Default)]
+pub(crate) struct Marking {
+    pub outbound: Option<u8>,
+    pub client: Option<u8>,
+    pub by_destination: Option<TosRule>,
+}
+
+impl Marking {
+    /// The outbound and the client marking of a tunnel to `target`.
+    pub fn for_destination(&self, target: &Target) -> (Option<u8>, Option<u8>) {
+        match self
+            .by_destination
+            .as_ref()
+            .and_then(|rule| rule(&target.host()))
+        {
+            Some(tos) => (Some(tos), Some(tos)),
+            None => (self.outbound, self.client),
+        }
+    }
+}
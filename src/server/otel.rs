@@ -0,0 +1,141 @@
+use super::{Stats, TunnelSummary};
+use crate::utils::SocksError;
+use opentelemetry::{
+    metrics::{Counter, Histogram, Meter, ObservableCounter, ObservableGauge},
+    KeyValue,
+};
+use std::{sync::Arc, time::Duration};
+
+/// The server's instruments, made from a meter the embedder supplies.
+pub(crate) struct Metrics {
+    negotiation: Histogram<f64>,
+    connect: Histogram<f64>,
+    tunnel: Histogram<f64>,
+    bytes: Counter<u64>,
+    replies: Counter<u64>,
+    _gauges: Vec<ObservableGauge<u64>>,
+    _counters: Vec<ObservableCounter<u64>>,
+}
+
+impl Metrics {
+    pub fn new(meter: &Meter, stats: &Arc<Stats>) -> Metrics {
+        let gauge = |name: &'static str, description: &'static str, read: fn(&Stats) -> u64| {
+            let stats = stats.clone();
+            meter
+                .u64_observable_gauge(name)
+                .with_description(description)
+                .with_callback(move |observer| observer.observe(read(&stats), &[]))
+                .build()
+        };
+        let gauges = vec![
+            gauge(
+                "socks.connections.active",
+                "Connections being served",
+                |stats| stats.active() as u64,
+            ),
+            gauge(
+                "socks.connections.negotiating",
+                "Connections still negotiating",
+                |stats| stats.handshaking() as u64,
+            ),
+            gauge(
+                "socks.connections.relaying",
+                "Connections relaying data",
+                |stats| stats.relaying() as u64,
+            ),
+            gauge("socks.dns.in_flight", "DNS lookups in progress", |stats| {
+                stats.dns_in_flight() as u64
+            }),
+            gauge(
+                "socks.shedding",
+                "1 while load shedding is in effect",
+                |stats| stats.shedding() as u64,
+            ),
+        ];
+
+        let counter = |name: &'static str, description: &'static str, read: fn(&Stats) -> u64| {
+            let stats = stats.clone();
+            meter
+                .u64_observable_counter(name)
+                .with_description(description)
+                .with_callback(move |observer| observer.observe(read(&stats), &[]))
+                .build()
+        };
+        let accepted = {
+            let stats = stats.clone();
+            meter
+                .u64_observable_counter("socks.connections.accepted")
+                .with_description("Connections accepted, by listener")
+                .with_callback(move |observer| {
+                    for (listener, accepted) in stats.accepted_by_listener() {
+                        observer.observe(accepted, &[KeyValue::new("server.address", listener)]);
+                    }
+                })
+                .build()
+        };
+        let counters = vec![
+            counter(
+                "socks.connections.tarpitted",
+                "Failures delayed by the tarpit",
+                Stats::tarpitted,
+            ),
+            counter(
+                "socks.connections.shed",
+                "Connections turned away by load shedding",
+                Stats::shed,
+            ),
+            accepted,
+        ];
+
+        Metrics {
+            negotiation: meter
+                .f64_histogram("socks.negotiation.duration")
+                .with_description("Time from accept to the end of the negotiation")
+                .with_unit("s")
+                .build(),
+            connect: meter
+                .f64_histogram("socks.connect.duration")
+                .with_description("Time to connect to destinations")
+                .with_unit("s")
+                .build(),
+            tunnel: meter
+                .f64_histogram("socks.tunnel.duration")
+                .with_description("Lifetime of tunnels")
+                .with_unit("s")
+                .build(),
+            bytes: meter
+                .u64_counter("socks.tunnel.bytes")
+                .with_description("Bytes relayed, by direction")
+                .with_unit("By")
+                .build(),
+            replies: meter
+                .u64_counter("socks.replies")
+                .with_description("Replies to requests, by REP code")
+                .build(),
+            _gauges: gauges,
+            _counters: counters,
+        }
+    }
+
+    pub fn negotiated(&self, took: Duration) {
+        self.negotiation.record(took.as_secs_f64(), &[]);
+    }
+
+    pub fn connected(&self, took: Duration, ok: bool) {
+        let outcome = KeyValue::new("outcome", if ok { "ok" } else { "failed" });
+        self.connect.record(took.as_secs_f64(), &[outcome]);
+    }
+
+    pub fn replied(&self, rep: SocksError) {
+        self.replies
+            .add(1, &[KeyValue::new("socks.rep", rep as u8 as i64)]);
+    }
+
+    pub fn closed(&self, summary: &TunnelSummary) {
+        self.tunnel.record(summary.duration.as_secs_f64(), &[]);
+        self.bytes
+            .add(summary.bytes_up, &[KeyValue::new("direction", "up")]);
+        self.bytes
+            .add(summary.bytes_down, &[KeyValue::new("direction", "down")]);
+    }
+}
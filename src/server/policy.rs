@@ -0,0 +1,81 @@
+use super::{Config, Result, Socks5ServerError, Target};
+use crate::utils::*;
+use log::info;
+use std::{future::Future, net::SocketAddr, pin::Pin};
+
+/// The future returned by [`Policy::evaluate`].
+pub type PolicyFuture<'a> = Pin<Box<dyn Future<Output = Verdict> + Send + 'a>>;
+
+/// Decides on every CONNECT request, e.g. by asking a database.
+///
+/// Called on the connection's task once the request is read, before the
+/// destination is resolved or any rule is checked.
+pub trait Policy: Send + Sync {
+    fn evaluate<'a>(&'a self, ctx: &'a ConnectContext) -> PolicyFuture<'a>;
+}
+
+/// A CONNECT request for a [`Policy`] to decide on.
+#[derive(Debug, Clone)]
+pub struct ConnectContext {
+    pub source: SocketAddr,
+    /// The destination requested.
+    pub dest: Addr,
+    /// The user the client authenticated as, if any.
+    pub user: Option<String>,
+}
+
+/// What a [`Policy`] makes of a request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    Allow,
+    /// Refuses the request with this reply.
+    Deny(SocksError),
+    /// Connects to this destination instead, resolving it if a hostname.
+    Rewrite(Addr),
+}
+
+/// Asks `policy` about a request for `target`, returning where to connect.
+pub(super) async fn evaluate(
+    policy: &dyn Policy,
+    target: Target,
+    source: SocketAddr,
+    user: Option<&str>,
+    config: &Config,
+) -> Result<Target> {
+    let ctx = ConnectContext {
+        source,
+        dest: Addr::from(&target),
+        user: user.map(str::to_owned),
+    };
+    match policy.evaluate(&ctx).await {
+        Verdict::Allow => Ok(target),
+        Verdict::Deny(rep) => Err(Socks5ServerError::PolicyDenied(target.to_string(), rep)),
+        Verdict::Rewrite(dest) => {
+            let rewritten = target_of(dest)?;
+            info!(
+                "policy rewrote {} to {}",
+                config.privacy.show(&target.to_string()),
+                config.privacy.show(&rewritten.to_string())
+            );
+            Ok(rewritten)
+        }
+    }
+}
+
+/// The target standing for `dest`: a socket address, or `host:port`.
+pub(super) fn target_of(dest: Addr) -> Result<Target> {
+    let host_port = match dest {
+        Addr::SocketAddr(addr) => return Ok(Target::Ip(addr)),
+        Addr::HostnamePort(host_port) => host_port,
+    };
+    if let Ok(addr) = host_port.parse() {
+        return Ok(Target::Ip(addr));
+    }
+    let split = host_port
+        .rsplit_once(':')
+        .and_then(|(host, port)| Some((host, port.parse().ok()?)));
+    match split {
+        Some((host, port)) => Ok(Target::Domain(Hostname::new(host)?, port)),
+        None => Err(Socks5ServerError::InvalidRewrite(host_port)),
+    }
+}
@@ -0,0 +1,206 @@
+use super::Socks5ServerError;
+use crate::utils::random_u64;
+use std::{
+    hash::Hasher,
+    sync::{Arc, RwLock},
+};
+
+/// What logs, errors, audit entries and top destinations reveal of the
+/// destinations clients visit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(
+    feature = "config",
+    derive(serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum LogPrivacy {
+    #[default]
+    Full,
+    /// Hosts are replaced with a keyed hash: connections to the same host
+    /// correlate while the key stays the same, but the host can't be told
+    /// from its hash.
+    HashedDestinations,
+    /// Destinations are left out.
+    NoDestinations,
+}
+
+/// Stands in for destinations under [`LogPrivacy::NoDestinations`].
+const REDACTED: &str = "<redacted>";
+
+/// The key hosts are hashed with, shared with the server's handle.
+pub(crate) type PrivacyKey = Arc<RwLock<(u64, u64)>>;
+
+pub(crate) fn random_key() -> PrivacyKey {
+    Arc::new(RwLock::new((random_u64(), random_u64())))
+}
+
+#[derive(Clone)]
+pub(crate) struct Privacy {
+    pub mode: LogPrivacy,
+    pub key: PrivacyKey,
+}
+
+impl Privacy {
+    /// Whether anything is revealed of destinations.
+    pub fn reveals(&self) -> bool {
+        self.mode != LogPrivacy::NoDestinations
+    }
+
+    /// `dest`, a host or a `host:port`, as far as it may be shown. Ports
+    /// are kept when hashing.
+    pub fn show(&self, dest: &str) -> String {
+        match self.mode {
+            LogPrivacy::Full => dest.to_owned(),
+            LogPrivacy::HashedDestinations => {
+                let (host, port) = match dest.rsplit_once(':') {
+                    Some((host, port)) if port.parse::<u16>().is_ok() => (host, Some(port)),
+                    _ => (dest, None),
+                };
+                let (k0, k1) = *self.key.read().unwrap();
+                #[allow(deprecated)]
+                let mut hasher = std::hash::SipHasher::new_with_keys(k0, k1);
+                hasher.write(host.as_bytes());
+                match port {
+                    Some(port) => format!("h{:016x}:{}", hasher.finish(), port),
+                    None => format!("h{:016x}", hasher.finish()),
+                }
+            }
+            LogPrivacy::NoDestinations => REDACTED.to_owned(),
+        }
+    }
+
+    /// `e` with the destinations it names shown as far as they may be.
+    pub fn scrub(&self, e: Socks5ServerError) -> Socks5ServerError {
+        use Socks5ServerError::*;
+        if self.mode == LogPrivacy::Full {
+            return e;
+        }
+        match e {
+            DNSError(host) => DNSError(self.show(&host)),
+            DNSTimeout(host) => DNSTimeout(self.show(&host)),
+            DestinationFull(key, rep) => DestinationFull(self.show(&key), rep),
+            NoEgress(dest) => NoEgress(self.show(&dest)),
+            ConnectTimeout(dest, timeout) => ConnectTimeout(self.show(&dest), timeout),
+            ZeroPort(host) => ZeroPort(self.show(&host)),
+            DomainRefused(host) => DomainRefused(self.show(&host)),
+            PolicyDenied(dest, rep) => PolicyDenied(self.show(&dest), rep),
+            InvalidRewrite(dest) => InvalidRewrite(self.show(&dest)),
+            PrivateDestination(dest) => PrivateDestination(self.show(&dest)),
+            Blocked(dest) => Blocked(self.show(&dest)),
+            Denied(dest, rule) => Denied(self.show(&dest), rule),
+            UserDenied(user, dest) => UserDenied(user, self.show(&dest)),
+            UnexpectedPeer(peer) => UnexpectedPeer(self.show(&peer)),
+            // The request line names the destination.
+            BadHttpRequest(_) => BadHttpRequest(REDACTED.to_owned()),
+            // TLS errors may quote the certificate's names.
+            #[cfg(feature = "tls")]
+            EgressTls(dest, e) => EgressTls(
+                self.show(&dest),
+                std::io::Error::new(e.kind(), "TLS handshake failed"),
+            ),
+            e => e,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::tests::{connect_request, domain_request, serve, Failing, CAPTURED};
+    use crate::utils::*;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    const HIDING: [LogPrivacy; 2] = [LogPrivacy::HashedDestinations, LogPrivacy::NoDestinations];
+
+    /// Whether anything logged so far contains `needle`.
+    fn logged(needle: &str) -> bool {
+        let captured = CAPTURED.0.lock().unwrap();
+        captured.iter().any(|(_, message)| message.contains(needle))
+    }
+
+    #[test]
+    fn scrubs_destinations_from_errors() {
+        for mode in HIDING {
+            let privacy = Privacy {
+                mode,
+                key: random_key(),
+            };
+            let errors = [
+                Socks5ServerError::DNSError("secret.example".into()),
+                Socks5ServerError::UnexpectedPeer("192.0.2.7:4000".into()),
+            ];
+            for e in errors {
+                let shown = privacy.scrub(e).to_string();
+                assert!(!shown.contains("secret.example"), "{:?}: {}", mode, shown);
+                assert!(!shown.contains("192.0.2.7"), "{:?}: {}", mode, shown);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn keeps_destinations_out_of_logs_and_summaries() {
+        let _ = log::set_logger(&CAPTURED);
+        log::set_max_level(log::LevelFilter::Trace);
+        for mode in HIDING {
+            let dest = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = dest.local_addr().unwrap();
+            let server = crate::server::new("127.0.0.1:0".parse().unwrap(), None)
+                .unwrap()
+                .log_privacy(mode);
+            let (mut client, served) = serve(server).await;
+            client.write_all(&[SOCKS_VER, 1, 0]).await.unwrap();
+            client.write_all(&connect_request(addr)).await.unwrap();
+            let mut replies = [0u8; 12];
+            client.read_exact(&mut replies).await.unwrap();
+            assert_eq!(replies[3], SocksError::SUCCESS as u8);
+            let (upstream, _) = dest.accept().await.unwrap();
+            drop((client, upstream));
+
+            let summary = served.await.unwrap().unwrap();
+            let hidden = addr.to_string();
+            for shown in [&summary.destination, &summary.connected] {
+                match mode {
+                    LogPrivacy::HashedDestinations => {
+                        let shown = shown.as_deref().unwrap();
+                        assert!(shown.starts_with('h') && !shown.contains(&hidden));
+                    }
+                    _ => assert_eq!(*shown, None),
+                }
+            }
+            let connecting = match mode {
+                LogPrivacy::HashedDestinations => "connecting to h".to_owned(),
+                _ => format!("connecting to {}", REDACTED),
+            };
+            assert!(logged(&connecting), "{:?}", mode);
+            assert!(!logged(&hidden), "{:?}", mode);
+        }
+    }
+
+    #[tokio::test]
+    async fn keeps_hosts_that_fail_to_resolve_out_of_logs_and_errors() {
+        let _ = log::set_logger(&CAPTURED);
+        log::set_max_level(log::LevelFilter::Trace);
+        for mode in HIDING {
+            let host = format!("unresolvable-{:?}.example", mode).to_lowercase();
+            let server = crate::server::new("127.0.0.1:0".parse().unwrap(), None)
+                .unwrap()
+                .resolver(Arc::new(Failing::default()))
+                .log_privacy(mode);
+            let (mut client, served) = serve(server).await;
+            client.write_all(&[SOCKS_VER, 1, 0]).await.unwrap();
+            client.write_all(&domain_request(&host, 80)).await.unwrap();
+            let mut replies = [0u8; 4];
+            client.read_exact(&mut replies).await.unwrap();
+            assert_eq!(replies[3], SocksError::HOST as u8);
+
+            let e = served.await.unwrap().unwrap_err();
+            assert!(matches!(e, Socks5ServerError::DNSError(_)));
+            assert!(!e.to_string().contains(&host), "{:?}: {}", mode, e);
+            assert!(logged("looking up"), "{:?}", mode);
+            assert!(!logged(&host), "{:?}", mode);
+        }
+    }
+}
@@ -0,0 +1,26 @@
+use crate::utils::canonical_ip;
+use std::net::IpAddr;
+
+/// Whether `ip` belongs to this host or a private network: unspecified,
+/// loopback, RFC 1918, shared (100.64.0.0/10), link-local, broadcast or
+/// unique-local, also in IPv4-mapped form.
+pub(crate) fn is_private(ip: IpAddr) -> bool {
+    match canonical_ip(ip) {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            a == 0
+                || ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_broadcast()
+                || a == 100 && b & 0xC0 == 64
+        }
+        IpAddr::V6(ip) => {
+            let first = ip.segments()[0];
+            ip.is_unspecified()
+                || ip.is_loopback()
+                || first & 0xFE00 == 0xFC00
+                || first & 0xFFC0 == 0xFE80
+        }
+    }
+}
@@ -0,0 +1,269 @@
+use super::relay::{CloseReason, Progress, TunnelSummary};
+use log::info;
+use std::{
+    collections::HashMap,
+    future::Future,
+    io,
+    sync::{atomic::Ordering, Mutex},
+    time::Duration,
+};
+use tokio::time::Instant;
+
+/// How often open tunnels add what they relayed to their user's usage.
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What happens to users that used up their quota.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum QuotaAction {
+    /// Their CONNECT requests are refused with REP 0x02, while their open
+    /// tunnels run on.
+    #[default]
+    Refuse,
+    /// Their open tunnels are cut as well.
+    Terminate,
+}
+
+/// Caps the bytes each authenticated user may relay, both directions
+/// counted, until their usage is reset.
+///
+/// Usage is kept in memory only. Periods are up to the owner: read the
+/// counters at the end of one, e.g. with [`take`](Self::take), to start
+/// the next. Open tunnels add their traffic every second and when they
+/// close, so a user may overshoot by as much as their tunnels relay in a
+/// second. Unauthenticated clients have no quota.
+#[derive(Debug)]
+pub struct Quotas {
+    limit: u64,
+    limits: HashMap<String, u64>,
+    action: QuotaAction,
+    used: Mutex<HashMap<String, u64>>,
+}
+
+impl Quotas {
+    /// Lets every user relay `limit` bytes.
+    pub fn new(limit: u64) -> Self {
+        Quotas {
+            limit,
+            limits: HashMap::new(),
+            action: QuotaAction::default(),
+            used: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Lets `user` relay `limit` bytes in place of the common limit.
+    pub fn limit_for(mut self, user: &str, limit: u64) -> Self {
+        self.limits.insert(user.to_owned(), limit);
+        self
+    }
+
+    /// Sets what happens to users over their quota. Refusing their
+    /// requests by default.
+    pub fn on_exceeded(mut self, action: QuotaAction) -> Self {
+        self.action = action;
+        self
+    }
+
+    /// The bytes `user` relayed since their usage was last reset.
+    pub fn usage(&self, user: &str) -> u64 {
+        let used = self.used.lock().unwrap();
+        used.get(user).copied().unwrap_or_default()
+    }
+
+    /// The usage of every user that relayed anything since the last reset.
+    pub fn usages(&self) -> HashMap<String, u64> {
+        self.used.lock().unwrap().clone()
+    }
+
+    /// Resets the usage of `user`.
+    pub fn reset(&self, user: &str) {
+        self.used.lock().unwrap().remove(user);
+    }
+
+    /// Resets the usage of every user.
+    pub fn reset_all(&self) {
+        self.used.lock().unwrap().clear();
+    }
+
+    /// Resets the usage of every user, returning it. No bytes go
+    /// uncounted between the reading and the reset.
+    pub fn take(&self) -> HashMap<String, u64> {
+        std::mem::take(&mut *self.used.lock().unwrap())
+    }
+
+    fn limit(&self, user: &str) -> u64 {
+        self.limits.get(user).copied().unwrap_or(self.limit)
+    }
+
+    /// Whether `user` has used up their quota.
+    pub(crate) fn exceeded(&self, user: &str) -> bool {
+        self.usage(user) >= self.limit(user)
+    }
+
+    /// Adds what `progress` counted since `counted` to the usage of `user`.
+    /// Returns whether they are over their quota.
+    fn count(&self, user: &str, progress: &Progress, counted: &mut u64) -> bool {
+        let total = progress.up.load(Ordering::Relaxed) + progress.down.load(Ordering::Relaxed);
+        let delta = total - *counted;
+        *counted = total;
+        let mut used = self.used.lock().unwrap();
+        let used = used.entry(user.to_owned()).or_default();
+        *used += delta;
+        *used >= self.limit(user)
+    }
+
+    /// Drives the tunnel of `user` to completion, adding what `progress`
+    /// counts to their usage, and cuts it once they are over their quota
+    /// if so configured.
+    pub(crate) async fn enforce<F>(
+        &self,
+        user: &str,
+        tunnel: F,
+        progress: &Progress,
+    ) -> io::Result<TunnelSummary>
+    where
+        F: Future<Output = io::Result<TunnelSummary>>,
+    {
+        let start = Instant::now();
+        let mut counted = 0;
+        tokio::pin!(tunnel);
+        let mut ticks = tokio::time::interval_at(start + CHECK_INTERVAL, CHECK_INTERVAL);
+        let output = loop {
+            tokio::select! {
+                output = &mut tunnel => break output,
+                _ = ticks.tick() => {
+                    let over = self.count(user, progress, &mut counted);
+                    if over && self.action == QuotaAction::Terminate {
+                        info!("cutting tunnel of user {:?}, over quota", user);
+                        return Ok(TunnelSummary {
+                            bytes_up: progress.up.load(Ordering::Relaxed),
+                            bytes_down: progress.down.load(Ordering::Relaxed),
+                            duration: start.elapsed(),
+                            close_reason: CloseReason::QuotaExceeded,
+                            connect_attempts: 1,
+                            destination: None,
+                            connected: None,
+                        });
+                    }
+                }
+            }
+        };
+        self.count(user, progress, &mut counted);
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        server::{
+            tests::{connect_request, log_in, serve},
+            Socks5Server,
+        },
+        utils::*,
+    };
+    use std::{net::SocketAddr, sync::Arc};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    /// Counts `bytes` relayed by a tunnel of `user`.
+    fn relayed(quotas: &Quotas, user: &str, bytes: u64) -> bool {
+        let progress = Progress::default();
+        progress.up.store(bytes, Ordering::Relaxed);
+        quotas.count(user, &progress, &mut 0)
+    }
+
+    /// A server letting alice in, under `quotas`.
+    fn server(quotas: &Arc<Quotas>) -> Socks5Server {
+        let alice = AuthMethod::from(("alice", "secret"));
+        crate::server::new("127.0.0.1:0".parse().unwrap(), Some(alice))
+            .unwrap()
+            .quotas(quotas.clone())
+    }
+
+    /// Logs `client` in as alice and requests a tunnel to `dest`,
+    /// returning the REP code.
+    async fn connect(client: &mut TcpStream, dest: SocketAddr) -> u8 {
+        let status = log_in(client, SOCKS_AUTH_USERPASS_VER, ("alice", "secret")).await;
+        assert_eq!(status, Some(0));
+        client.write_all(&connect_request(dest)).await.unwrap();
+        let mut reply = [0u8; 10];
+        client.read_exact(&mut reply).await.unwrap();
+        reply[1]
+    }
+
+    #[tokio::test]
+    async fn refuses_users_that_used_up_their_quota() {
+        let quotas = Arc::new(Quotas::new(4));
+        let dest = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let dest_addr = dest.local_addr().unwrap();
+
+        let (mut client, served) = serve(server(&quotas)).await;
+        assert_eq!(
+            connect(&mut client, dest_addr).await,
+            SocksError::SUCCESS as u8
+        );
+        let (mut upstream, _) = dest.accept().await.unwrap();
+        client.write_all(b"12345678").await.unwrap();
+        upstream.read_exact(&mut [0u8; 8]).await.unwrap();
+        drop((client, upstream));
+        served.await.unwrap().unwrap();
+        assert_eq!(quotas.usage("alice"), 8, "the tunnel ran on past the quota");
+
+        let (mut client, served) = serve(server(&quotas)).await;
+        assert_eq!(
+            connect(&mut client, dest_addr).await,
+            SocksError::DENY as u8
+        );
+        assert!(served.await.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn cuts_open_tunnels_when_terminating() {
+        let quotas = Arc::new(Quotas::new(4).on_exceeded(QuotaAction::Terminate));
+        let dest = TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+        let (mut client, served) = serve(server(&quotas)).await;
+        let rep = connect(&mut client, dest.local_addr().unwrap()).await;
+        assert_eq!(rep, SocksError::SUCCESS as u8);
+        let (mut upstream, _) = dest.accept().await.unwrap();
+        client.write_all(b"12345678").await.unwrap();
+        upstream.read_exact(&mut [0u8; 8]).await.unwrap();
+
+        let summary = served.await.unwrap().unwrap();
+        assert_eq!(summary.close_reason, CloseReason::QuotaExceeded);
+        assert_eq!(summary.bytes_up, 8);
+        assert_eq!(client.read(&mut [0u8; 1]).await.unwrap(), 0, "closed");
+    }
+    #[test]
+    fn takes_limits_of_single_users_over_the_common_one() {
+        let quotas = Quotas::new(10).limit_for("bob", 100);
+        assert!(relayed(&quotas, "alice", 10));
+        assert!(!relayed(&quotas, "bob", 10));
+        assert!(!relayed(&quotas, "bob", 89));
+        assert!(relayed(&quotas, "bob", 1));
+        assert!(quotas.exceeded("alice") && quotas.exceeded("bob"));
+        assert!(!quotas.exceeded("carol"));
+    }
+
+    #[test]
+    fn starts_over_once_taken_or_reset() {
+        let quotas = Quotas::new(10);
+        relayed(&quotas, "alice", 12);
+        relayed(&quotas, "bob", 3);
+        let taken = quotas.take();
+        assert_eq!(taken.len(), 2);
+        assert_eq!((taken["alice"], taken["bob"]), (12, 3));
+        assert!(quotas.usages().is_empty());
+        assert!(!quotas.exceeded("alice"));
+
+        relayed(&quotas, "alice", 12);
+        relayed(&quotas, "bob", 3);
+        quotas.reset("alice");
+        assert_eq!((quotas.usage("alice"), quotas.usage("bob")), (0, 3));
+        quotas.reset_all();
+        assert_eq!(quotas.usage("bob"), 0);
+    }
+}
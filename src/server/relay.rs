@@ -0,0 +1,364 @@
+use log::info;
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicU64, Ordering},
+    task::{ready, Context, Poll},
+    time::{Duration, Instant},
+};
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+/// Which side ended the tunnel first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "audit",
+    derive(serde::Serialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum CloseReason {
+    /// The client finished sending first.
+    ClientClosed,
+    /// The destination finished sending first.
+    DestinationClosed,
+    /// One side stopped taking data while the other had more to send.
+    Stalled,
+    /// The tunnel was cut, its user over their quota.
+    QuotaExceeded,
+    /// The tunnel was cut, open for as long as sessions may last.
+    MaxDuration,
+    /// The tunnel was cut, one direction having relayed as many bytes as
+    /// it may.
+    ByteLimit,
+    /// The tunnel was cut, nothing having been relayed for the idle
+    /// timeout.
+    Idle,
+}
+
+/// Outcome of a tunnel that ran to completion.
+#[derive(Debug, Clone)]
+pub struct TunnelSummary {
+    /// Bytes relayed from the client to the destination.
+    pub bytes_up: u64,
+    /// Bytes relayed from the destination to the client.
+    pub bytes_down: u64,
+    pub duration: Duration,
+    pub close_reason: CloseReason,
+    /// Outbound connects it took to reach the destination.
+    pub connect_attempts: u32,
+    /// The destination requested, as far as the
+    /// [log privacy](super::LogPrivacy) lets it be shown.
+    pub destination: Option<String>,
+    /// The address connected to, after rewrites and resolution, as far as
+    /// the log privacy lets it be shown.
+    pub connected: Option<String>,
+}
+
+/// What a tunnel may do before it is ended.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct Limits {
+    /// How long a write may make no progress.
+    pub stall: Option<Duration>,
+    /// How long the tunnel may go without relaying anything.
+    pub idle: Option<Duration>,
+    /// The bytes that may be relayed from the client.
+    pub up: Option<u64>,
+    /// The bytes that may be relayed to the client.
+    pub down: Option<u64>,
+}
+
+/// Bytes relayed so far, readable while the tunnel is still open.
+#[derive(Debug, Default)]
+pub(crate) struct Progress {
+    pub up: AtomicU64,
+    pub down: AtomicU64,
+    /// When anything was last read from either side, in milliseconds
+    /// since the tunnel opened.
+    last_read: AtomicU64,
+}
+
+/// Relays data in both directions until both sides have closed, or until
+/// either direction fails. `pending` holds client bytes already read during
+/// the negotiation; they are written to the destination before anything
+/// else. With a stall timeout, the tunnel is ended as soon as a write to
+/// either side makes no progress for that long; with an idle timeout, as
+/// soon as neither side has sent anything for that long; with a byte limit,
+/// as soon as its direction has relayed that much, the side written to
+/// being shut down first.
+pub(crate) async fn relay<C, D>(
+    client: C,
+    dest: D,
+    pending: &[u8],
+    progress: &Progress,
+    limits: Limits,
+) -> io::Result<TunnelSummary>
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+    D: AsyncRead + AsyncWrite + Unpin,
+{
+    let start = Instant::now();
+    let (client_r, client_w) = io::split(client);
+    let (dest_r, mut dest_w) = io::split(dest);
+
+    let stall = limits.stall;
+    let up = async {
+        let pending = match limits.up {
+            Some(limit) => &pending[..pending.len().min(limit as usize)],
+            None => pending,
+        };
+        if !write_all(&mut dest_w, pending, stall).await? {
+            return Ok(Copied::Stalled);
+        }
+        progress
+            .up
+            .fetch_add(pending.len() as u64, Ordering::Relaxed);
+        let client_r = Counted::new(client_r, &progress.up, progress, start);
+        let limit = limits.up.map(|limit| limit - pending.len() as u64);
+        Ok::<_, io::Error>(match copy(client_r, dest_w, stall, limit).await? {
+            Copied::Closed(n) => Copied::Closed(pending.len() as u64 + n),
+            copied => copied,
+        })
+    };
+    let dest_r = Counted::new(dest_r, &progress.down, progress, start);
+    let down = copy(dest_r, client_w, stall, limits.down);
+    let idle = async {
+        let idle = match limits.idle {
+            Some(idle) => idle,
+            None => return std::future::pending().await,
+        };
+        loop {
+            let last_read = Duration::from_millis(progress.last_read.load(Ordering::Relaxed));
+            match (last_read + idle).checked_sub(start.elapsed()) {
+                Some(left) if !left.is_zero() => tokio::time::sleep(left).await,
+                _ => return,
+            }
+        }
+    };
+    tokio::pin!(up, down, idle);
+
+    let summary = |bytes_up, bytes_down, close_reason| TunnelSummary {
+        bytes_up,
+        bytes_down,
+        duration: start.elapsed(),
+        close_reason,
+        connect_attempts: 1,
+        destination: None,
+        connected: None,
+    };
+    let cut = |close_reason| {
+        summary(
+            progress.up.load(Ordering::Relaxed),
+            progress.down.load(Ordering::Relaxed),
+            close_reason,
+        )
+    };
+
+    let summary = tokio::select! {
+        r = &mut up => match r? {
+            // Half-closed, the tunnel still ends when idle for too long.
+            Copied::Closed(up) => tokio::select! {
+                r = &mut down => match r? {
+                    Copied::Closed(down) => summary(up, down, CloseReason::ClientClosed),
+                    copied => cut(copied.close_reason()),
+                },
+                () = &mut idle => cut(CloseReason::Idle),
+            },
+            copied => cut(copied.close_reason()),
+        },
+        r = &mut down => match r? {
+            Copied::Closed(down) => tokio::select! {
+                r = &mut up => match r? {
+                    Copied::Closed(up) => summary(up, down, CloseReason::DestinationClosed),
+                    copied => cut(copied.close_reason()),
+                },
+                () = &mut idle => cut(CloseReason::Idle),
+            },
+            copied => cut(copied.close_reason()),
+        },
+        () = &mut idle => cut(CloseReason::Idle),
+    };
+    Ok(summary)
+}
+
+/// Drives `tunnel` to completion unless it takes longer than `limit`, in
+/// which case it is dropped, closing both sides.
+pub(crate) async fn limit<F>(
+    tunnel: F,
+    limit: Duration,
+    progress: &Progress,
+) -> io::Result<TunnelSummary>
+where
+    F: Future<Output = io::Result<TunnelSummary>>,
+{
+    match tokio::time::timeout(limit, tunnel).await {
+        Ok(output) => output,
+        Err(_) => {
+            info!(
+                "cutting tunnel after {:?}, the longest a session may last",
+                limit
+            );
+            Ok(TunnelSummary {
+                bytes_up: progress.up.load(Ordering::Relaxed),
+                bytes_down: progress.down.load(Ordering::Relaxed),
+                duration: limit,
+                close_reason: CloseReason::MaxDuration,
+                connect_attempts: 1,
+                destination: None,
+                connected: None,
+            })
+        }
+    }
+}
+
+/// How one direction of a tunnel ended.
+enum Copied {
+    /// The reading side closed after this many bytes.
+    Closed(u64),
+    /// The writing side stopped taking data.
+    Stalled,
+    /// As much as may be was relayed, and the writing side shut down.
+    Limited,
+}
+
+impl Copied {
+    /// Why the tunnel ends when a direction ends so before the other.
+    fn close_reason(&self) -> CloseReason {
+        match self {
+            Copied::Limited => CloseReason::ByteLimit,
+            _ => CloseReason::Stalled,
+        }
+    }
+}
+
+/// Copies from `r` to `w` until `r` closes, or until `limit` bytes are
+/// copied, reading no more than that.
+async fn copy(
+    mut r: impl AsyncRead + Unpin,
+    mut w: impl AsyncWrite + Unpin,
+    stall: Option<Duration>,
+    limit: Option<u64>,
+) -> io::Result<Copied> {
+    let mut buf = vec![0u8; 8 * 1024];
+    let mut n = 0;
+    loop {
+        let want = match limit {
+            Some(limit) if n >= limit => {
+                w.shutdown().await.unwrap_or(());
+                return Ok(Copied::Limited);
+            }
+            Some(limit) => buf.len().min((limit - n) as usize),
+            None => buf.len(),
+        };
+        let read = r.read(&mut buf[..want]).await?;
+        if read == 0 {
+            break;
+        }
+        if !write_all(&mut w, &buf[..read], stall).await? {
+            return Ok(Copied::Stalled);
+        }
+        n += read as u64;
+    }
+
+    w.shutdown().await.unwrap_or(());
+    Ok(Copied::Closed(n))
+}
+
+/// Writes and flushes all of `buf`. Returns false if a single write made no
+/// progress for `stall`.
+async fn write_all(
+    w: &mut (impl AsyncWrite + Unpin),
+    mut buf: &[u8],
+    stall: Option<Duration>,
+) -> io::Result<bool> {
+    macro_rules! progress {
+        ($op:expr) => {
+            match stall {
+                Some(stall) => match tokio::time::timeout(stall, $op).await {
+                    Ok(result) => result?,
+                    Err(_) => return Ok(false),
+                },
+                None => $op.await?,
+            }
+        };
+    }
+    while !buf.is_empty() {
+        let n = progress!(w.write(buf));
+        if n == 0 {
+            return Err(io::ErrorKind::WriteZero.into());
+        }
+        buf = &buf[n..];
+    }
+    progress!(w.flush());
+    Ok(true)
+}
+
+/// Adds whatever is read through it to a counter, and notes when that was
+/// in the tunnel's progress.
+struct Counted<'a, R> {
+    inner: R,
+    counter: &'a AtomicU64,
+    progress: &'a Progress,
+    start: Instant,
+}
+
+impl<'a, R> Counted<'a, R> {
+    fn new(inner: R, counter: &'a AtomicU64, progress: &'a Progress, start: Instant) -> Self {
+        Counted {
+            inner,
+            counter,
+            progress,
+            start,
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for Counted<'_, R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+        let n = buf.filled().len() - before;
+        if n > 0 {
+            self.counter.fetch_add(n as u64, Ordering::Relaxed);
+            let now = self.start.elapsed().as_millis() as u64;
+            self.progress.last_read.store(now, Ordering::Relaxed);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn idle_after_half_close() {
+        let (client, mut peer) = io::duplex(64);
+        let (dest, _dest_peer) = io::duplex(64);
+        peer.shutdown().await.unwrap();
+        let limits = Limits {
+            idle: Some(Duration::from_millis(100)),
+            ..Limits::default()
+        };
+        let progress = Progress::default();
+        let summary = relay(client, dest, b"", &progress, limits).await.unwrap();
+        assert_eq!(summary.close_reason, CloseReason::Idle);
+    }
+
+    #[tokio::test]
+    async fn stalls_when_a_side_stops_reading() {
+        let (client, _reader) = io::duplex(64);
+        let (dest, mut dest_peer) = io::duplex(64);
+        tokio::spawn(async move { dest_peer.write_all(&[0u8; 1024]).await });
+        let limits = Limits {
+            stall: Some(Duration::from_millis(100)),
+            ..Limits::default()
+        };
+        let progress = Progress::default();
+        let summary = relay(client, dest, b"", &progress, limits).await.unwrap();
+        assert_eq!(summary.close_reason, CloseReason::Stalled);
+        assert!(summary.bytes_down < 1024);
+    }
+}
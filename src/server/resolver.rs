@@ -0,0 +1,23 @@
+use std::{future::Future, io, net::SocketAddr, pin::Pin};
+
+/// The future returned by [`Resolver::resolve`].
+pub type ResolveFuture<'a> = Pin<Box<dyn Future<Output = io::Result<Vec<SocketAddr>>> + Send + 'a>>;
+
+/// Resolves the hostnames clients ask to connect to.
+///
+/// The lookup runs on a task of its own, so the concurrency limit and
+/// timeout of the server apply to it as they do to the system resolver.
+/// A failed or empty answer gets the client REP 0x04.
+pub trait Resolver: Send + Sync {
+    fn resolve<'a>(&'a self, host: &'a str, port: u16) -> ResolveFuture<'a>;
+}
+
+/// Resolves through the system, with `getaddrinfo` on a blocking thread.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemResolver;
+
+impl Resolver for SystemResolver {
+    fn resolve<'a>(&'a self, host: &'a str, port: u16) -> ResolveFuture<'a> {
+        Box::pin(async move { Ok(tokio::net::lookup_host((host, port)).await?.collect()) })
+    }
+}
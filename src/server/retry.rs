@@ -0,0 +1,49 @@
+use super::tarpit::Delay;
+use std::time::Duration;
+use tokio::io;
+
+/// When and how often a failed outbound connect is tried again before the
+/// client gets a failure reply.
+#[derive(Debug, Clone)]
+pub struct ConnectRetry {
+    pub(crate) attempts: u32,
+    pub(crate) delay: Delay,
+    pub(crate) retry_on: Vec<io::ErrorKind>,
+    pub(crate) budget: Option<Duration>,
+}
+
+impl ConnectRetry {
+    /// Up to `attempts` connects in all, `delay` apart, retrying refused
+    /// and timed out connects.
+    pub fn new(attempts: u32, delay: Duration) -> Self {
+        ConnectRetry {
+            attempts: attempts.max(1),
+            delay: Delay {
+                delay,
+                jitter: Duration::ZERO,
+            },
+            retry_on: vec![io::ErrorKind::ConnectionRefused, io::ErrorKind::TimedOut],
+            budget: None,
+        }
+    }
+
+    /// Adds a random share of `jitter` to every delay.
+    pub fn jitter(mut self, jitter: Duration) -> Self {
+        self.delay.jitter = jitter;
+        self
+    }
+
+    /// Replaces the error kinds that are worth another attempt.
+    pub fn retry_on(mut self, kinds: Vec<io::ErrorKind>) -> Self {
+        self.retry_on = kinds;
+        self
+    }
+
+    /// Bounds all attempts and the delays between them to `budget` in
+    /// total. An attempt still running when it runs out fails as timed
+    /// out.
+    pub fn budget(mut self, budget: Duration) -> Self {
+        self.budget = Some(budget);
+        self
+    }
+}
@@ -0,0 +1,196 @@
+use super::{rules, IpNet, Result, Socks5ServerError, Target};
+use crate::utils::Hostname;
+use std::{
+    net::{IpAddr, SocketAddr},
+    ops::RangeInclusive,
+};
+
+/// Sends requests for some destinations elsewhere: to another host, to
+/// another port, or both.
+///
+/// A request matches if it names a hostname matching one of the host
+/// patterns or an address within one of the networks, and a port within
+/// one of the port ranges. A rewrite without hosts and networks matches
+/// every destination, one without ports every port. Networks only match
+/// requested addresses: rewrites are applied before anything is resolved.
+#[derive(Debug, Clone, Default)]
+pub struct Rewrite {
+    hosts: Vec<String>,
+    networks: Vec<IpNet>,
+    ports: Vec<RangeInclusive<u16>>,
+    to_host: Option<String>,
+    to_port: Option<u16>,
+}
+
+impl Rewrite {
+    /// A rewrite matching every destination and changing nothing.
+    pub fn new() -> Self {
+        Rewrite::default()
+    }
+
+    /// Matches the hostname `pattern`, as [`Rule::host`](super::Rule::host)
+    /// does.
+    pub fn host(mut self, pattern: &str) -> Self {
+        self.hosts.push(rules::normalize(pattern));
+        self
+    }
+
+    /// Matches requested addresses within `network`.
+    pub fn network(mut self, network: IpNet) -> Self {
+        self.networks.push(network);
+        self
+    }
+
+    /// Only matches ports within `ports`.
+    pub fn ports(mut self, ports: RangeInclusive<u16>) -> Self {
+        self.ports.push(ports);
+        self
+    }
+
+    /// Connects to `host`, a hostname or an address, in place of the host
+    /// requested.
+    pub fn to_host(mut self, host: &str) -> Self {
+        self.to_host = Some(host.to_owned());
+        self
+    }
+
+    /// Connects to `port` in place of the port requested.
+    pub fn to_port(mut self, port: u16) -> Self {
+        self.to_port = Some(port);
+        self
+    }
+
+    fn matches(&self, target: &Target) -> bool {
+        if !self.ports.is_empty()
+            && !self
+                .ports
+                .iter()
+                .any(|ports| ports.contains(&target.port()))
+        {
+            return false;
+        }
+        if self.hosts.is_empty() && self.networks.is_empty() {
+            return true;
+        }
+        match target {
+            Target::Ip(addr) => self.networks.iter().any(|net| net.contains(addr.ip())),
+            Target::Domain(host, _) => {
+                let host = rules::normalize(host.as_str());
+                self.hosts
+                    .iter()
+                    .any(|pattern| rules::matches_pattern(pattern, &host))
+            }
+        }
+    }
+
+    /// Where a request for `target` goes instead.
+    fn rewritten(&self, target: &Target) -> Result<Target> {
+        let port = self.to_port.unwrap_or_else(|| target.port());
+        let host = match &self.to_host {
+            Some(host) => host,
+            None => {
+                return Ok(match target {
+                    Target::Ip(addr) => Target::Ip(SocketAddr::new(addr.ip(), port)),
+                    Target::Domain(host, _) => Target::Domain(host.clone(), port),
+                })
+            }
+        };
+        target_at(host, port).ok_or_else(|| Socks5ServerError::InvalidRewrite(host.clone()))
+    }
+}
+
+/// The target at `host`, an address or a hostname, on `port`.
+pub(super) fn target_at(host: &str, port: u16) -> Option<Target> {
+    let bare = host
+        .strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+        .unwrap_or(host);
+    match bare.parse::<IpAddr>() {
+        Ok(ip) => Some(Target::Ip(SocketAddr::new(ip, port))),
+        Err(_) => Some(Target::Domain(Hostname::new(host).ok()?, port)),
+    }
+}
+
+/// Applies the first of `rewrites` matching `target`; the others are not
+/// looked at, whether or not they match what it makes of it. Returns
+/// where to connect, and the target requested if that is elsewhere.
+pub(super) fn apply(rewrites: &[Rewrite], target: Target) -> Result<(Target, Option<Target>)> {
+    match rewrites.iter().find(|rewrite| rewrite.matches(&target)) {
+        Some(rewrite) => Ok((rewrite.rewritten(&target)?, Some(target))),
+        None => Ok((target, None)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn target(host: &str, port: u16) -> Target {
+        target_at(host, port).unwrap()
+    }
+
+    fn applied(rewrites: &[Rewrite], host: &str, port: u16) -> String {
+        let (effective, _) = apply(rewrites, target(host, port)).unwrap();
+        effective.to_string()
+    }
+
+    #[test]
+    fn replaces_host_port_or_both() {
+        let rewrites = [
+            Rewrite::new()
+                .host("*.old-cdn.com")
+                .ports(80..=80)
+                .to_host("new-cdn.internal")
+                .to_port(8080),
+            Rewrite::new()
+                .network("10.0.0.0/8".parse().unwrap())
+                .ports(80..=80)
+                .to_port(8080),
+            Rewrite::new().host("legacy.example").to_host("[fd00::1]"),
+        ];
+        let cases = [
+            ("img.old-cdn.com", 80, "new-cdn.internal:8080"),
+            ("img.old-cdn.com", 443, "img.old-cdn.com:443"),
+            ("10.1.2.3", 80, "10.1.2.3:8080"),
+            ("192.0.2.1", 80, "192.0.2.1:80"),
+            ("Legacy.Example", 443, "[fd00::1]:443"),
+        ];
+        for (host, port, expected) in cases {
+            assert_eq!(
+                applied(&rewrites, host, port),
+                expected,
+                "{}:{}",
+                host,
+                port
+            );
+        }
+    }
+
+    #[test]
+    fn applies_only_the_first_match() {
+        let rewrites = [
+            Rewrite::new().host("a.example").to_host("b.example"),
+            Rewrite::new().host("b.example").to_host("c.example"),
+        ];
+        let (effective, requested) = apply(&rewrites, target("a.example", 80)).unwrap();
+        assert_eq!(effective.to_string(), "b.example:80");
+        assert_eq!(
+            requested.map(|t| t.to_string()),
+            Some("a.example:80".into())
+        );
+
+        let (effective, requested) = apply(&rewrites, target("d.example", 80)).unwrap();
+        assert_eq!(effective.to_string(), "d.example:80");
+        assert!(requested.is_none());
+    }
+
+    #[test]
+    fn fails_on_a_host_it_cannot_rewrite_to() {
+        let rewrites = [Rewrite::new().to_host("not a host")];
+        let rewritten = apply(&rewrites, target("example.com", 80));
+        assert!(matches!(
+            rewritten,
+            Err(Socks5ServerError::InvalidRewrite(_))
+        ));
+    }
+}
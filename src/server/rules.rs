@@ -0,0 +1,391 @@
+use super::Target;
+use crate::utils::canonical_ip;
+use std::{
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    ops::RangeInclusive,
+    str::FromStr,
+};
+use thiserror::Error;
+
+/// What a [`Rule`] does with the destinations it matches.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "config",
+    derive(serde::Deserialize),
+    serde(rename_all = "lowercase")
+)]
+pub enum RuleAction {
+    Allow,
+    Deny,
+}
+
+/// An IP network, such as `10.0.0.0/8` or `fd00::/8`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct IpNet {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+/// A string that is not an address with an optional `/prefix`.
+#[derive(Debug, Error)]
+#[error("invalid network {0:?}")]
+pub struct InvalidNet(String);
+
+impl IpNet {
+    /// The network of `addr` with a `prefix`-bit mask, or `None` if the
+    /// prefix is longer than the address.
+    pub fn new(addr: IpAddr, prefix: u8) -> Option<IpNet> {
+        let bits = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        if prefix > bits {
+            return None;
+        }
+        Some(IpNet { addr, prefix })
+    }
+
+    /// Whether `ip` lies within the network. IPv4 networks also hold the
+    /// IPv4-mapped forms of their addresses.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, canonical_ip(ip)) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpNet {
+    type Err = InvalidNet;
+
+    /// Parses `addr/prefix`, or a bare address standing for itself alone.
+    fn from_str(s: &str) -> Result<IpNet, InvalidNet> {
+        let invalid = || InvalidNet(s.to_owned());
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| invalid())?;
+        let prefix = match (prefix, addr) {
+            (Some(prefix), _) => prefix.parse().map_err(|_| invalid())?,
+            (None, IpAddr::V4(_)) => 32,
+            (None, IpAddr::V6(_)) => 128,
+        };
+        IpNet::new(addr, prefix).ok_or_else(invalid)
+    }
+}
+
+impl fmt::Display for IpNet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+impl From<Ipv4Addr> for IpNet {
+    fn from(addr: Ipv4Addr) -> IpNet {
+        IpNet {
+            addr: addr.into(),
+            prefix: 32,
+        }
+    }
+}
+
+impl From<Ipv6Addr> for IpNet {
+    fn from(addr: Ipv6Addr) -> IpNet {
+        IpNet {
+            addr: addr.into(),
+            prefix: 128,
+        }
+    }
+}
+
+/// A rule matching destinations by hostname or address, and by port.
+///
+/// A destination matches if its hostname matches one of the host
+/// patterns or one of its addresses lies within one of the networks, and
+/// its port within one of the port ranges. A rule without hosts and
+/// networks matches every destination, one without ports every port.
+#[derive(Debug, Clone)]
+pub struct Rule {
+    action: RuleAction,
+    hosts: Vec<String>,
+    networks: Vec<IpNet>,
+    ports: Vec<RangeInclusive<u16>>,
+}
+
+impl Rule {
+    pub fn new(action: RuleAction) -> Self {
+        Rule {
+            action,
+            hosts: Vec::new(),
+            networks: Vec::new(),
+            ports: Vec::new(),
+        }
+    }
+
+    pub fn allow() -> Self {
+        Rule::new(RuleAction::Allow)
+    }
+
+    pub fn deny() -> Self {
+        Rule::new(RuleAction::Deny)
+    }
+
+    /// Matches the hostname `pattern`, regardless of case. `*.example.com`
+    /// matches every name under `example.com` but not the name itself, and
+    /// `*` every hostname. Hostnames only match requests naming them, not
+    /// those for an address the hostname resolves to.
+    pub fn host(mut self, pattern: &str) -> Self {
+        self.hosts.push(normalize(pattern));
+        self
+    }
+
+    /// Matches addresses within `network`: those requested, and those that
+    /// requested hostnames resolve to.
+    pub fn network(mut self, network: IpNet) -> Self {
+        self.networks.push(network);
+        self
+    }
+
+    /// Only matches ports within `ports`.
+    pub fn ports(mut self, ports: RangeInclusive<u16>) -> Self {
+        self.ports.push(ports);
+        self
+    }
+
+    fn matches_port(&self, port: u16) -> bool {
+        self.ports.is_empty() || self.ports.iter().any(|ports| ports.contains(&port))
+    }
+
+    fn matches_host(&self, host: &str) -> bool {
+        self.hosts
+            .iter()
+            .any(|pattern| matches_pattern(pattern, host))
+    }
+}
+
+/// Ordered rules deciding where clients may connect. The first matching
+/// rule decides; destinations no rule matches get the default action.
+#[derive(Debug, Clone)]
+pub struct RuleSet {
+    rules: Vec<Rule>,
+    default: RuleAction,
+}
+
+/// What a [`RuleSet`] decided, and by which rule.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Decision {
+    pub action: RuleAction,
+    /// The index of the deciding rule, `None` for the default action.
+    pub rule: Option<usize>,
+}
+
+impl RuleSet {
+    /// An empty rule set, applying `default` to every destination.
+    pub fn new(default: RuleAction) -> Self {
+        RuleSet {
+            rules: Vec::new(),
+            default,
+        }
+    }
+
+    /// Appends `rule`, checked after those before it.
+    pub fn rule(mut self, rule: Rule) -> Self {
+        self.rules.push(rule);
+        self
+    }
+
+    /// Decides on `target` as requested. `None` leaves the decision to the
+    /// addresses the hostname resolves to.
+    pub(crate) fn check_target(&self, target: &Target) -> Option<Decision> {
+        match target {
+            Target::Ip(addr) => self.check(None, Some(addr.ip()), addr.port()),
+            Target::Domain(host, port) => self.check(Some(host.as_str()), None, *port),
+        }
+    }
+
+    /// Leaves out the addresses of `target` the rules deny. Fails with the
+    /// decision on the last one if none is left.
+    pub(crate) fn filter(
+        &self,
+        target: &Target,
+        addrs: &mut Vec<SocketAddr>,
+    ) -> Result<(), Decision> {
+        let host = match target {
+            Target::Domain(host, _) => Some(host.as_str()),
+            Target::Ip(_) => None,
+        };
+        let mut denied = None;
+        addrs.retain(
+            |addr| match self.check(host, Some(addr.ip()), addr.port()) {
+                Some(decision) if decision.action == RuleAction::Allow => true,
+                decision => {
+                    denied = decision;
+                    false
+                }
+            },
+        );
+        match (addrs.is_empty(), denied) {
+            (true, Some(decision)) => Err(decision),
+            _ => Ok(()),
+        }
+    }
+
+    /// Decides on a destination named by `host` or `ip`, on `port`. A
+    /// hostname not resolved yet leaves the decision open, as `None`, once
+    /// a rule matching by network might match its addresses.
+    fn check(&self, host: Option<&str>, ip: Option<IpAddr>, port: u16) -> Option<Decision> {
+        let host = host.map(normalize);
+        for (index, rule) in self.rules.iter().enumerate() {
+            if !rule.matches_port(port) {
+                continue;
+            }
+            let matched = match (&host, ip) {
+                _ if rule.hosts.is_empty() && rule.networks.is_empty() => true,
+                (Some(host), _) if rule.matches_host(host) => true,
+                (_, Some(ip)) => rule.networks.iter().any(|net| net.contains(ip)),
+                (_, None) if !rule.networks.is_empty() => return None,
+                (_, None) => false,
+            };
+            if matched {
+                return Some(Decision {
+                    action: rule.action,
+                    rule: Some(index),
+                });
+            }
+        }
+        Some(Decision {
+            action: self.default,
+            rule: None,
+        })
+    }
+}
+
+/// Whether the normalized `host` matches the normalized `pattern`.
+pub(super) fn matches_pattern(pattern: &str, host: &str) -> bool {
+    match pattern.strip_prefix('*') {
+        Some("") => true,
+        Some(suffix) if suffix.starts_with('.') => host.ends_with(suffix),
+        _ => host == pattern,
+    }
+}
+
+pub(super) fn normalize(host: &str) -> String {
+    host.trim_end_matches('.').to_ascii_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::Hostname;
+
+    #[test]
+    fn matches_hostnames_however_written() {
+        let rules = RuleSet::new(RuleAction::Allow).rule(Rule::deny().host("example.com"));
+        for name in ["example.com", "Example.COM.", "EXAMPLE.com"] {
+            let target = Target::Domain(Hostname::new(name).unwrap(), 443);
+            let decision = rules.check_target(&target).unwrap();
+            assert_eq!(decision.action, RuleAction::Deny, "{}", name);
+        }
+        let target = Target::Domain(Hostname::new("www.example.com").unwrap(), 443);
+        assert_eq!(
+            rules.check_target(&target).unwrap().action,
+            RuleAction::Allow
+        );
+    }
+
+    fn ip(addr: &str) -> Target {
+        Target::Ip(addr.parse().unwrap())
+    }
+
+    fn domain(host: &str, port: u16) -> Target {
+        Target::Domain(Hostname::new(host).unwrap(), port)
+    }
+
+    fn decide(rules: &RuleSet, target: &Target) -> Option<(RuleAction, Option<usize>)> {
+        rules
+            .check_target(target)
+            .map(|decision| (decision.action, decision.rule))
+    }
+
+    #[test]
+    fn parses_networks() {
+        let net: IpNet = "10.1.2.3/8".parse().unwrap();
+        assert!(net.contains("10.200.0.1".parse().unwrap()));
+        assert!(!net.contains("11.0.0.1".parse().unwrap()));
+        let host: IpNet = "fd00::1".parse().unwrap();
+        assert!(host.contains("fd00::1".parse().unwrap()));
+        assert!(!host.contains("fd00::2".parse().unwrap()));
+        let all: IpNet = "0.0.0.0/0".parse().unwrap();
+        assert!(all.contains("203.0.113.7".parse().unwrap()));
+        assert!(!all.contains("2001:db8::1".parse().unwrap()));
+        for invalid in ["10.0.0.0/33", "fd00::/129", "example.com", "10.0.0.0/x"] {
+            assert!(invalid.parse::<IpNet>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn first_matching_rule_decides() {
+        use RuleAction::*;
+        let rules = RuleSet::new(Allow)
+            .rule(Rule::allow().host("ok.internal"))
+            .rule(Rule::deny().host("*.internal"))
+            .rule(Rule::deny().network("10.0.0.0/8".parse().unwrap()))
+            .rule(Rule::allow().ports(443..=443))
+            .rule(Rule::deny());
+        let cases = [
+            (domain("ok.internal", 80), Some((Allow, Some(0)))),
+            (domain("db.internal", 80), Some((Deny, Some(1)))),
+            (domain("a.b.internal", 80), Some((Deny, Some(1)))),
+            // The pattern covers names under it, not the name itself; the
+            // network rule still has to see the addresses.
+            (domain("internal", 80), None),
+            (ip("10.1.2.3:443"), Some((Deny, Some(2)))),
+            (ip("192.0.2.1:443"), Some((Allow, Some(3)))),
+            (ip("192.0.2.1:80"), Some((Deny, Some(4)))),
+        ];
+        for (target, expected) in cases {
+            assert_eq!(decide(&rules, &target), expected, "{}", target);
+        }
+    }
+
+    #[test]
+    fn applies_the_default_when_nothing_matches() {
+        let rules = RuleSet::new(RuleAction::Deny).rule(Rule::allow().ports(80..=89));
+        assert_eq!(
+            decide(&rules, &ip("192.0.2.1:85")),
+            Some((RuleAction::Allow, Some(0)))
+        );
+        assert_eq!(
+            decide(&rules, &domain("example.com", 443)),
+            Some((RuleAction::Deny, None))
+        );
+    }
+
+    #[test]
+    fn filters_what_hostnames_resolve_to() {
+        let rules = RuleSet::new(RuleAction::Allow)
+            .rule(Rule::deny().network("10.0.0.0/8".parse().unwrap()));
+        let target = domain("example.com", 80);
+        assert_eq!(rules.check_target(&target), None);
+
+        let mut addrs = vec![
+            "10.0.0.1:80".parse().unwrap(),
+            "192.0.2.1:80".parse().unwrap(),
+        ];
+        rules.filter(&target, &mut addrs).unwrap();
+        assert_eq!(addrs, ["192.0.2.1:80".parse().unwrap()]);
+
+        let mut addrs = vec!["10.0.0.1:80".parse().unwrap()];
+        let denied = rules.filter(&target, &mut addrs).unwrap_err();
+        assert_eq!((denied.action, denied.rule), (RuleAction::Deny, Some(0)));
+    }
+}
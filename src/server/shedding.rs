@@ -0,0 +1,90 @@
+use super::Stats;
+use log::warn;
+
+/// How connections are turned away while the server is shedding load.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "config",
+    derive(serde::Deserialize),
+    serde(rename_all = "lowercase")
+)]
+pub enum ShedMode {
+    /// Complete the negotiation and answer the request with REP 0x01.
+    Reply,
+    /// Close the connection before reading anything.
+    Close,
+}
+
+/// What happens to new connections while the most allowed by
+/// [`Socks5Server::max_connections`](super::Socks5Server::max_connections)
+/// are open.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "config",
+    derive(serde::Deserialize),
+    serde(rename_all = "lowercase")
+)]
+pub enum AtConnectionLimit {
+    /// Stop accepting until one closes, leaving new connections in the
+    /// listen backlog.
+    Pause,
+    /// Accept them and close them right away.
+    Close,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Shedding {
+    pub high_water: usize,
+    pub low_water: usize,
+    /// High and low water marks on the accept rate, per second.
+    pub accept_rate: Option<(f64, f64)>,
+    pub mode: ShedMode,
+}
+
+impl Default for Shedding {
+    fn default() -> Self {
+        Shedding {
+            high_water: usize::MAX,
+            low_water: usize::MAX,
+            accept_rate: None,
+            mode: ShedMode::Reply,
+        }
+    }
+}
+
+impl Shedding {
+    /// Decides whether a new connection must be shed. Shedding starts once
+    /// a high water mark is reached and only stops when everything is back
+    /// below the low water marks, so the state doesn't flap.
+    pub fn check(&self, stats: &Stats) -> Option<ShedMode> {
+        let active = stats.active();
+        let rate = stats.accept_rate();
+        let (rate_high, rate_low) = self.accept_rate.unwrap_or((f64::INFINITY, f64::INFINITY));
+
+        let was_shedding = stats.shedding();
+        let shedding = if active >= self.high_water || rate >= rate_high {
+            true
+        } else if active <= self.low_water && rate <= rate_low {
+            false
+        } else {
+            was_shedding
+        };
+
+        if shedding != was_shedding {
+            stats.set_shedding(shedding);
+            if shedding {
+                warn!(
+                    "shedding load, {} active connections, {:.1} accepts/s",
+                    active, rate
+                );
+            } else {
+                warn!("stopped shedding load, {} active connections", active);
+            }
+        }
+        if shedding {
+            Some(self.mode)
+        } else {
+            None
+        }
+    }
+}
@@ -0,0 +1,84 @@
+use super::{Command, Config, PendingCommand, Result, Socks5ServerError, Target};
+use crate::utils::*;
+use log::debug;
+use std::net::{Ipv4Addr, SocketAddr};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt};
+
+pub(super) const SOCKS4_VER: u8 = 0x04;
+const REPLY_VER: u8 = 0x00;
+const GRANTED: u8 = 0x5A;
+const REJECTED: u8 = 0x5B;
+
+/// The longest user ID or hostname read from a request.
+const MAX_FIELD: usize = 255;
+
+/// Reads a SOCKS4 or SOCKS4a request. Only CONNECT is served, and only
+/// where clients may connect without authenticating, SOCKS4 having no
+/// authentication of its own.
+pub(super) async fn read_request(
+    conn: &mut PendingCommand,
+    config: &Config,
+) -> Result<(Command, Target)> {
+    let mut header = [0u8; 8];
+    conn.read_exact(&mut header).await?;
+    let port = u16::from_be_bytes([header[2], header[3]]);
+    let ip = Ipv4Addr::new(header[4], header[5], header[6], header[7]);
+    let ident = read_field(conn).await?;
+    // SOCKS4a: 0.0.0.x, x nonzero, announces a hostname after the user ID.
+    let target = match ip.octets() {
+        [0, 0, 0, x] if x != 0 => {
+            let host = read_field(conn).await?;
+            let host = std::str::from_utf8(&host).map_err(HostnameError::from)?;
+            Target::Domain(Hostname::new(host)?, port)
+        }
+        _ => Target::Ip(SocketAddr::from((ip, port))),
+    };
+    debug!(
+        "SOCKS4 request from user ID {:?}",
+        String::from_utf8_lossy(&ident)
+    );
+
+    let source = canonical_ip(conn.get_ref().peer_addr()?.ip());
+    if !config
+        .auth_for(source)
+        .iter()
+        .any(|method| matches!(method, AuthMethod::NoAuth))
+    {
+        return Err(Socks5ServerError::UnsupportAuth);
+    }
+    match header[1] {
+        SOCKS_COMMAND_CONNECT if port != 0 => Ok((Command::Connect, target)),
+        SOCKS_COMMAND_CONNECT => Err(Socks5ServerError::ZeroPort(target.host())),
+        command => Err(Socks5ServerError::UnsupportCommand(command)),
+    }
+}
+
+/// Reads a NUL-terminated field.
+async fn read_field(conn: &mut PendingCommand) -> Result<Vec<u8>> {
+    let mut field = Vec::new();
+    (&mut conn.0)
+        .take(MAX_FIELD as u64 + 1)
+        .read_until(0, &mut field)
+        .await?;
+    match field.pop() {
+        Some(0) => Ok(field),
+        _ => Err(Socks5ServerError::UnknowProtocol),
+    }
+}
+
+/// Encodes a reply: granted for success, rejected for any failure.
+pub(super) fn encode_reply(rep: SocksError, bound: Option<SocketAddr>) -> Vec<u8> {
+    let code = match rep {
+        SocksError::SUCCESS => GRANTED,
+        _ => REJECTED,
+    };
+    let mut reply = vec![REPLY_VER, code];
+    match bound {
+        Some(SocketAddr::V4(bound)) => {
+            reply.extend_from_slice(&bound.port().to_be_bytes());
+            reply.extend_from_slice(&bound.ip().octets());
+        }
+        _ => reply.extend_from_slice(&[0; 6]),
+    }
+    reply
+}
@@ -0,0 +1,116 @@
+use crate::utils::canonical_ip;
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv6Addr},
+    sync::{Arc, Mutex},
+};
+
+#[derive(Clone)]
+pub(crate) struct SourceLimit {
+    pub cap: usize,
+    /// The leading bits IPv6 sources are told apart by.
+    pub v6_prefix: u8,
+    open: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+/// A connection slot for one source, given back when dropped.
+pub(crate) struct SourceSlot {
+    key: IpAddr,
+    open: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+impl SourceLimit {
+    pub fn new(cap: usize) -> Self {
+        SourceLimit {
+            cap,
+            v6_prefix: 128,
+            open: Default::default(),
+        }
+    }
+
+    /// A slot for a connection from `ip`, or `None` if its source is at
+    /// the cap.
+    pub fn acquire(&self, ip: IpAddr) -> Option<SourceSlot> {
+        let key = match canonical_ip(ip) {
+            IpAddr::V6(ip) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - self.v6_prefix as u32)
+                    .unwrap_or(0);
+                IpAddr::V6(Ipv6Addr::from(u128::from(ip) & mask))
+            }
+            ip => ip,
+        };
+        let mut open = self.open.lock().unwrap();
+        let count = open.entry(key).or_default();
+        if *count >= self.cap {
+            if *count == 0 {
+                open.remove(&key);
+            }
+            return None;
+        }
+        *count += 1;
+        Some(SourceSlot {
+            key,
+            open: self.open.clone(),
+        })
+    }
+}
+
+impl Drop for SourceSlot {
+    fn drop(&mut self) {
+        let mut open = self.open.lock().unwrap();
+        if let Some(count) = open.get_mut(&self.key) {
+            *count -= 1;
+            if *count == 0 {
+                open.remove(&self.key);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_mapped_and_plain_sources_together() {
+        let limit = SourceLimit::new(1);
+        let plain: IpAddr = "203.0.113.7".parse().unwrap();
+        let slot = limit.acquire(plain).unwrap();
+        assert!(limit
+            .acquire("::ffff:203.0.113.7".parse().unwrap())
+            .is_none());
+        drop(slot);
+        assert!(limit
+            .acquire("::ffff:203.0.113.7".parse().unwrap())
+            .is_some());
+    }
+
+    #[test]
+    fn caps_each_source_alone_and_forgets_closed_ones() {
+        let limit = SourceLimit::new(2);
+        let busy: IpAddr = "198.51.100.1".parse().unwrap();
+        let slots: Vec<_> = (0..2).map(|_| limit.acquire(busy).unwrap()).collect();
+        assert!(limit.acquire(busy).is_none());
+        let other = limit.acquire("198.51.100.2".parse().unwrap());
+        assert!(other.is_some(), "other sources unaffected");
+
+        drop((slots, other));
+        assert!(limit.open.lock().unwrap().is_empty());
+        assert!(limit.acquire(busy).is_some());
+    }
+
+    #[test]
+    fn buckets_ipv6_sources_by_prefix() {
+        let mut limit = SourceLimit::new(1);
+        let first: IpAddr = "2001:db8:1:2::1".parse().unwrap();
+        let neighbour: IpAddr = "2001:db8:1:2:ffff::9".parse().unwrap();
+        let _slot = limit.acquire(first).unwrap();
+        assert!(limit.acquire(neighbour).is_some(), "told apart by /128");
+
+        limit.v6_prefix = 64;
+        let _slot = limit.acquire(first).unwrap();
+        assert!(limit.acquire(neighbour).is_none(), "one /64");
+        assert!(limit.acquire("2001:db8:1:3::1".parse().unwrap()).is_some());
+    }
+}
@@ -0,0 +1,247 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use super::{bans::Bans, login_throttle::Logins, top::TopDestinations};
+
+/// Live counters of a running server, shared with
+/// [`Socks5Server::stats`](super::Socks5Server::stats).
+#[derive(Debug, Default)]
+pub struct Stats {
+    tarpitted: AtomicU64,
+    active: AtomicUsize,
+    shed: AtomicU64,
+    shedding: AtomicBool,
+    accept_rate: Mutex<AcceptRate>,
+    dns_in_flight: AtomicUsize,
+    handshaking: AtomicUsize,
+    relaying: AtomicUsize,
+    accepted_by_listener: Mutex<HashMap<Arc<str>, u64>>,
+    source_refused: AtomicU64,
+    open: AtomicUsize,
+    over_limit: AtomicU64,
+    source_limited: AtomicU64,
+    accepts_delayed: AtomicU64,
+    panics: AtomicU64,
+    handshake_refused: AtomicU64,
+    pub(crate) top: TopDestinations,
+    pub(crate) bans: Bans,
+    pub(crate) logins: Logins,
+}
+
+impl Stats {
+    /// Connections whose failure was delayed by the tarpit.
+    pub fn tarpitted(&self) -> u64 {
+        self.tarpitted.load(Ordering::Relaxed)
+    }
+
+    /// Connections currently being served.
+    pub fn active(&self) -> usize {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    /// Connections turned away by load shedding.
+    pub fn shed(&self) -> u64 {
+        self.shed.load(Ordering::Relaxed)
+    }
+
+    /// Whether load shedding is currently in effect.
+    pub fn shedding(&self) -> bool {
+        self.shedding.load(Ordering::Relaxed)
+    }
+
+    /// Active connections still negotiating.
+    pub fn handshaking(&self) -> usize {
+        self.handshaking.load(Ordering::Relaxed)
+    }
+
+    /// Active connections relaying data.
+    pub fn relaying(&self) -> usize {
+        self.relaying.load(Ordering::Relaxed)
+    }
+
+    /// Exponentially weighted moving average of new connections per second.
+    pub fn accept_rate(&self) -> f64 {
+        self.accept_rate.lock().unwrap().current(Instant::now())
+    }
+
+    /// DNS lookups currently in progress.
+    pub fn dns_in_flight(&self) -> usize {
+        self.dns_in_flight.load(Ordering::Relaxed)
+    }
+
+    /// Connections accepted so far, by the name of the listener.
+    pub fn accepted_by_listener(&self) -> HashMap<String, u64> {
+        let accepted = self.accepted_by_listener.lock().unwrap();
+        accepted
+            .iter()
+            .map(|(name, count)| (name.to_string(), *count))
+            .collect()
+    }
+
+    /// Connections closed at accept because their source is not allowed.
+    pub fn source_refused(&self) -> u64 {
+        self.source_refused.load(Ordering::Relaxed)
+    }
+
+    /// Connections open, from accept until closed.
+    pub fn open(&self) -> usize {
+        self.open.load(Ordering::Relaxed)
+    }
+
+    /// Connections closed at accept because the connection limit was
+    /// reached.
+    pub fn over_limit(&self) -> u64 {
+        self.over_limit.load(Ordering::Relaxed)
+    }
+
+    /// Connections closed at accept because their source had the most
+    /// connections open it may.
+    pub fn source_limited(&self) -> u64 {
+        self.source_limited.load(Ordering::Relaxed)
+    }
+
+    /// Accepts held back by the accept rate limit.
+    pub fn accepts_delayed(&self) -> u64 {
+        self.accepts_delayed.load(Ordering::Relaxed)
+    }
+
+    /// Connections closed at accept because the most allowed were still
+    /// negotiating.
+    pub fn handshake_refused(&self) -> u64 {
+        self.handshake_refused.load(Ordering::Relaxed)
+    }
+
+    /// Connections whose serving panicked.
+    pub fn panics(&self) -> u64 {
+        self.panics.load(Ordering::Relaxed)
+    }
+
+    /// Bans imposed so far, automatically or through
+    /// [`Handle::ban`](super::Handle::ban).
+    pub fn bans(&self) -> u64 {
+        self.bans.imposed()
+    }
+
+    /// Connections refused because their source was banned.
+    pub fn banned_refused(&self) -> u64 {
+        self.bans.refused()
+    }
+
+    /// Sources throttled so far for failing to log in.
+    pub fn throttled_logins(&self) -> u64 {
+        self.logins.throttled()
+    }
+
+    pub(crate) fn record_tarpitted(&self) {
+        self.tarpitted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_source_refused(&self) {
+        self.source_refused.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_over_limit(&self) {
+        self.over_limit.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_source_limited(&self) {
+        self.source_limited.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_accept_delayed(&self) {
+        self.accepts_delayed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_handshake_refused(&self) {
+        self.handshake_refused.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_panic(&self) {
+        self.panics.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_shed(&self) {
+        self.shed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn set_shedding(&self, shedding: bool) {
+        self.shedding.store(shedding, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_accept(&self, listener: Option<&Arc<str>>) {
+        self.accept_rate.lock().unwrap().record(Instant::now());
+        if let Some(listener) = listener {
+            let mut accepted = self.accepted_by_listener.lock().unwrap();
+            *accepted.entry(listener.clone()).or_default() += 1;
+        }
+    }
+
+    /// Counts a connection as active until the returned guard is dropped.
+    pub(crate) fn track_active(&self) -> GaugeGuard<'_> {
+        GaugeGuard::new(&self.active)
+    }
+
+    /// Counts a connection as open until the returned guard is dropped.
+    pub(crate) fn track_open(&self) -> GaugeGuard<'_> {
+        GaugeGuard::new(&self.open)
+    }
+
+    /// Counts a connection as negotiating until the returned guard is
+    /// dropped.
+    pub(crate) fn track_handshaking(&self) -> GaugeGuard<'_> {
+        GaugeGuard::new(&self.handshaking)
+    }
+
+    /// Counts a connection as relaying until the returned guard is dropped.
+    pub(crate) fn track_relaying(&self) -> GaugeGuard<'_> {
+        GaugeGuard::new(&self.relaying)
+    }
+
+    /// Counts a DNS lookup as in flight until the returned guard is dropped.
+    pub(crate) fn track_dns(&self) -> GaugeGuard<'_> {
+        GaugeGuard::new(&self.dns_in_flight)
+    }
+}
+
+pub(crate) struct GaugeGuard<'a>(&'a AtomicUsize);
+
+impl<'a> GaugeGuard<'a> {
+    fn new(gauge: &'a AtomicUsize) -> Self {
+        gauge.fetch_add(1, Ordering::Relaxed);
+        GaugeGuard(gauge)
+    }
+}
+
+impl Drop for GaugeGuard<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Time constant of the accept rate average, in seconds.
+const ACCEPT_RATE_TAU: f64 = 1.0;
+
+#[derive(Debug, Default)]
+struct AcceptRate {
+    last: Option<Instant>,
+    per_sec: f64,
+}
+
+impl AcceptRate {
+    fn current(&self, now: Instant) -> f64 {
+        match self.last {
+            Some(last) => {
+                let dt = now.duration_since(last).as_secs_f64();
+                self.per_sec * (-dt / ACCEPT_RATE_TAU).exp()
+            }
+            None => 0.0,
+        }
+    }
+
+    fn record(&mut self, now: Instant) {
+        self.per_sec = self.current(now) + 1.0 / ACCEPT_RATE_TAU;
+        self.last = Some(now);
+    }
+}
@@ -0,0 +1,35 @@
+use log::debug;
+
+/// How the server treats requests that deviate from RFC 1928 and 1929.
+///
+/// Lenient tolerates:
+/// - a non-zero RSV byte in the request;
+/// - a subnegotiation version other than 0x01 in username/password
+///   authentication.
+///
+/// Bytes sent right after the request are relayed as early data in both
+/// modes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(
+    feature = "config",
+    derive(serde::Deserialize),
+    serde(rename_all = "lowercase")
+)]
+pub enum ProtocolStrictness {
+    /// Reject every deviation.
+    #[default]
+    Strict,
+    /// Accept the documented deviations, logging each at debug level.
+    Lenient,
+}
+
+impl ProtocolStrictness {
+    /// Whether the deviation `what`, seen in `bytes`, is let through.
+    pub(crate) fn tolerate(self, what: &str, bytes: &[u8]) -> bool {
+        let tolerated = self == ProtocolStrictness::Lenient;
+        if tolerated {
+            debug!("tolerating {}: {:02X?}", what, bytes);
+        }
+        tolerated
+    }
+}
@@ -0,0 +1,36 @@
+use crate::utils::random_u64;
+use std::time::Duration;
+
+/// Kinds of failure a tarpit delay can be configured for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(
+    feature = "config",
+    derive(serde::Deserialize),
+    serde(rename_all = "lowercase")
+)]
+pub enum FailureClass {
+    /// The client sent something that is not valid SOCKS5.
+    Protocol,
+    /// No acceptable authenticate method, or wrong credentials.
+    Auth,
+    /// Refused by the ACL, a blocklist or a policy, or from a banned
+    /// source.
+    Denied,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Delay {
+    pub delay: Duration,
+    pub jitter: Duration,
+}
+
+impl Delay {
+    /// The configured delay plus a random share of the jitter.
+    pub fn sample(&self) -> Duration {
+        let jitter = self.jitter.as_nanos() as u64;
+        if jitter == 0 {
+            return self.delay;
+        }
+        self.delay + Duration::from_nanos(random_u64() % jitter)
+    }
+}
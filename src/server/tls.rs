@@ -0,0 +1,75 @@
+use super::Target;
+use crate::utils::{canonical_ip, rule_host, SocksError};
+use std::{convert::TryFrom, sync::Arc};
+use tokio::{io, net::TcpStream};
+use tokio_rustls::{
+    client::TlsStream,
+    rustls::{pki_types::ServerName, ClientConfig},
+    TlsConnector,
+};
+
+/// Upgrades tunnels to a destination to TLS on the server side.
+///
+/// The destination is matched by the requested hostname, compared as a
+/// [`Hostname`](crate::Hostname), or IP literal, and optionally by port. SNI and certificate
+/// verification use the requested hostname unless
+/// [`server_name`](Self::server_name) overrides it.
+#[derive(Clone)]
+pub struct EgressTls {
+    host: String,
+    port: Option<u16>,
+    connector: TlsConnector,
+    server_name: Option<String>,
+}
+
+impl EgressTls {
+    /// `config` carries the trusted roots and any client certificate.
+    pub fn new(host: &str, port: Option<u16>, config: Arc<ClientConfig>) -> Self {
+        EgressTls {
+            host: rule_host(host),
+            port,
+            connector: TlsConnector::from(config),
+            server_name: None,
+        }
+    }
+
+    /// Name to send as SNI and to verify the certificate against, instead
+    /// of the requested hostname.
+    pub fn server_name(mut self, name: &str) -> Self {
+        self.server_name = Some(name.into());
+        self
+    }
+
+    pub(crate) fn matches(&self, target: &Target) -> bool {
+        let (host, port) = match target {
+            Target::Ip(addr) => (canonical_ip(addr.ip()).to_string(), addr.port()),
+            Target::Domain(host, port) => (host.to_string(), *port),
+        };
+        host == self.host && self.port.is_none_or(|p| p == port)
+    }
+
+    pub(crate) async fn connect(
+        &self,
+        target: &Target,
+        conn: TcpStream,
+    ) -> io::Result<TlsStream<TcpStream>> {
+        let name = match (&self.server_name, target) {
+            (Some(name), _) => ServerName::try_from(name.clone()),
+            (None, Target::Domain(host, _)) => ServerName::try_from(host.to_string()),
+            (None, Target::Ip(addr)) => Ok(ServerName::from(addr.ip())),
+        }
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        self.connector.connect(name, conn).await
+    }
+}
+
+/// A destination that hangs up on the handshake refuses the connection;
+/// anything else (bad certificate, protocol mismatch) is our failure.
+pub(crate) fn reply_code(e: &io::Error) -> SocksError {
+    match e.kind() {
+        io::ErrorKind::ConnectionReset
+        | io::ErrorKind::ConnectionAborted
+        | io::ErrorKind::UnexpectedEof => SocksError::CONNECTION,
+        _ => SocksError::FAIL,
+    }
+}
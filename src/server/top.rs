@@ -0,0 +1,141 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// What destinations are ranked by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TopMetric {
+    /// Tunnels closed.
+    Connections,
+    /// Bytes relayed in both directions.
+    Bytes,
+}
+
+/// The name [`top_destinations`](super::Handle::top_destinations) reports
+/// destinations under once a bucket has no room left for new ones.
+pub const OTHER_DESTINATIONS: &str = "other";
+
+/// Per-destination tallies over a sliding window, made of a ring of time
+/// buckets. Each bucket tracks a bounded number of destinations and lumps
+/// any further ones together, so memory stays bounded. Disabled until
+/// configured.
+#[derive(Debug, Default)]
+pub(crate) struct TopDestinations {
+    enabled: AtomicBool,
+    ring: Mutex<Option<Ring>>,
+}
+
+#[derive(Debug)]
+struct Ring {
+    origin: Instant,
+    bucket: Duration,
+    max_keys: usize,
+    buckets: Vec<Bucket>,
+}
+
+#[derive(Debug, Default)]
+struct Bucket {
+    epoch: u64,
+    tallies: HashMap<String, Tally>,
+    other: Tally,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct Tally {
+    connections: u64,
+    bytes: u64,
+}
+
+impl Tally {
+    fn get(&self, metric: TopMetric) -> u64 {
+        match metric {
+            TopMetric::Connections => self.connections,
+            TopMetric::Bytes => self.bytes,
+        }
+    }
+}
+
+impl Ring {
+    fn epoch(&self, now: Instant) -> u64 {
+        (now.duration_since(self.origin).as_nanos() / self.bucket.as_nanos().max(1)) as u64
+    }
+}
+
+impl TopDestinations {
+    pub fn configure(&self, bucket: Duration, buckets: usize, max_keys: usize) {
+        let ring = Ring {
+            origin: Instant::now(),
+            bucket,
+            max_keys,
+            buckets: (0..buckets.max(1)).map(|_| Bucket::default()).collect(),
+        };
+        *self.ring.lock().unwrap() = Some(ring);
+        self.enabled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn record(&self, dest: &str, bytes: u64) {
+        if !self.enabled.load(Ordering::Relaxed) {
+            return;
+        }
+        let mut ring = self.ring.lock().unwrap();
+        let ring = match ring.as_mut() {
+            Some(ring) => ring,
+            None => return,
+        };
+        let epoch = ring.epoch(Instant::now());
+        let slots = ring.buckets.len() as u64;
+        let max_keys = ring.max_keys;
+        let bucket = &mut ring.buckets[(epoch % slots) as usize];
+        if bucket.epoch != epoch {
+            *bucket = Bucket {
+                epoch,
+                ..Default::default()
+            };
+        }
+        let tally = if bucket.tallies.contains_key(dest) || bucket.tallies.len() < max_keys {
+            bucket.tallies.entry(dest.to_owned()).or_default()
+        } else {
+            &mut bucket.other
+        };
+        tally.connections += 1;
+        tally.bytes += bytes;
+    }
+
+    /// The `k` destinations ranking highest by `metric` over the buckets
+    /// covering the last `window`.
+    pub fn top(&self, window: Duration, metric: TopMetric, k: usize) -> Vec<(String, u64)> {
+        let ring = self.ring.lock().unwrap();
+        let ring = match ring.as_ref() {
+            Some(ring) => ring,
+            None => return Vec::new(),
+        };
+        let now = ring.epoch(Instant::now());
+        let span = (window.as_nanos() / ring.bucket.as_nanos().max(1)) as u64 + 1;
+        let oldest = now.saturating_sub(span.min(ring.buckets.len() as u64) - 1);
+
+        let mut totals: HashMap<&str, u64> = HashMap::new();
+        for bucket in &ring.buckets {
+            if bucket.epoch < oldest || bucket.epoch > now {
+                continue;
+            }
+            for (dest, tally) in &bucket.tallies {
+                *totals.entry(dest).or_default() += tally.get(metric);
+            }
+            if bucket.other.connections > 0 {
+                *totals.entry(OTHER_DESTINATIONS).or_default() += bucket.other.get(metric);
+            }
+        }
+        let mut top: Vec<(String, u64)> = totals
+            .into_iter()
+            .map(|(dest, total)| (dest.to_owned(), total))
+            .collect();
+        top.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        top.truncate(k);
+        top
+    }
+}
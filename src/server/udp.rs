@@ -0,0 +1,762 @@
+use super::{
+    dest_limit::DestinationPermit, intercept::encode_reply, replied, screen, vet, Config,
+    Negotiating, PendingCommand, Result, Socks5ServerError, Target, TunnelSummary,
+};
+use crate::socket::{self, SocketOptions};
+use crate::utils::*;
+use log::{debug, info};
+use std::{
+    collections::{HashMap, HashSet},
+    convert::TryInto,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    ops::RangeInclusive,
+    sync::Arc,
+    time::Instant,
+};
+use tokio::{
+    io::{self, AsyncBufReadExt, AsyncWriteExt},
+    net::UdpSocket,
+    task::{JoinError, JoinSet},
+};
+
+const MAX_DATAGRAM: usize = 65535;
+
+/// Destinations an association remembers, both those decided on and
+/// addresses whose datagrams are relayed back to the client.
+const MAX_PEERS: usize = 1024;
+
+/// Targets an association decides on at once, lookups included.
+const MAX_DECIDING: usize = 64;
+
+/// Datagrams held for each target being decided on.
+const MAX_HELD: usize = 16;
+
+/// How the relay sockets of UDP associations are set up.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct UdpRelay {
+    pub bind: Option<IpAddr>,
+    pub ports: Option<RangeInclusive<u16>>,
+    pub options: SocketOptions,
+}
+
+impl UdpRelay {
+    /// Binds a relay socket for a client reaching the server at `local`:
+    /// to the configured address, or to the unspecified address of
+    /// `local`'s family, on a port from the configured range, if any.
+    fn bind(&self, local: SocketAddr) -> io::Result<UdpSocket> {
+        let ip = self.bind.unwrap_or(match local {
+            SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+            SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+        });
+        let ports = match &self.ports {
+            Some(ports) => ports,
+            None => return socket::bind_udp(SocketAddr::new(ip, 0), &self.options),
+        };
+        let (first, count) = (*ports.start(), ports.len() as u64);
+        let offset = match count {
+            0 => 0,
+            count => random_u64() % count,
+        };
+        for i in 0..count {
+            let port = first + ((offset + i) % count) as u16;
+            match socket::bind_udp(SocketAddr::new(ip, port), &self.options) {
+                Ok(socket) => return Ok(socket),
+                Err(e) if e.kind() == io::ErrorKind::AddrInUse => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            "no free port in the UDP relay port range",
+        ))
+    }
+}
+
+/// Serves a UDP ASSOCIATE request: replies with the address of a fresh
+/// relay socket and relays datagrams between the client and the
+/// destinations it names until the client closes its TCP connection.
+///
+/// The request names the endpoint the client sends from. Where its IP is
+/// unspecified, the first datagram from the IP of the TCP connection
+/// settles it, and where its port is zero, the first datagram from that
+/// IP; datagrams from anywhere else are dropped until then. After that,
+/// only datagrams from destinations the client has sent to are relayed
+/// back to it.
+pub(super) async fn serve(
+    mut conn: PendingCommand,
+    target: Target,
+    user: Option<String>,
+    config: &Config,
+    negotiating: Negotiating<'_>,
+) -> Result<TunnelSummary> {
+    let expected = match target {
+        Target::Ip(addr) => canonical_addr(addr),
+        // No client names itself by hostname; take it as the wildcard.
+        Target::Domain(..) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+    };
+    let control = conn.get_ref();
+    let (local, peer) = (control.local_addr()?, canonical_addr(control.peer_addr()?));
+    let relay = match config.udp.bind(local) {
+        Ok(relay) => relay,
+        Err(e) => {
+            replied(config, SocksError::FAIL);
+            conn.reply(&encode_reply(SocksError::FAIL, None)).await?;
+            return Err(e.into());
+        }
+    };
+    let bound = relay.local_addr()?;
+    let advertised = config.advertised.address(bound, local, peer);
+    replied(config, SocksError::SUCCESS);
+    conn.write_all(&encode_reply(SocksError::SUCCESS, Some(advertised)))
+        .await?;
+    conn.flush().await?;
+    drop(negotiating);
+    info!("relaying UDP on {} for {}", bound, peer);
+
+    let _relaying = config.stats.track_relaying();
+    let start = Instant::now();
+    let mut association = Association {
+        expected: match expected.ip().is_unspecified() {
+            true => SocketAddr::new(peer.ip(), expected.port()),
+            false => expected,
+        },
+        v6: bound.is_ipv6(),
+        source: peer,
+        user,
+        client: None,
+        peers: HashSet::new(),
+        decided: HashMap::new(),
+        permits: Vec::new(),
+        deciding: JoinSet::new(),
+        held: HashMap::new(),
+        up: 0,
+        down: 0,
+    };
+    // Decisions are taken in tasks of their own, which outlive the borrow.
+    let shared = Arc::new(config.clone());
+    let mut buf = vec![0u8; MAX_DATAGRAM];
+    let expired = async {
+        match config.max_session {
+            Some(limit) => tokio::time::sleep(limit).await,
+            None => std::future::pending().await,
+        }
+    };
+    tokio::pin!(expired);
+    let mut close_reason = super::CloseReason::ClientClosed;
+    loop {
+        let (len, from) = tokio::select! {
+            received = relay.recv_from(&mut buf) => received?,
+            Some(decision) = association.deciding.join_next() => {
+                association.decided(&relay, decision, config).await;
+                continue;
+            }
+            () = closed(&mut conn) => break,
+            () = &mut expired => {
+                info!(
+                    "cutting UDP association on {} after {:?}, the longest a session may last",
+                    bound,
+                    start.elapsed()
+                );
+                close_reason = super::CloseReason::MaxDuration;
+                break;
+            }
+        };
+        let from = canonical_addr(from);
+        association.relay(&relay, &buf[..len], from, &shared).await;
+    }
+
+    Ok(TunnelSummary {
+        bytes_up: association.up,
+        bytes_down: association.down,
+        duration: start.elapsed(),
+        close_reason,
+        connect_attempts: 0,
+        destination: None,
+        connected: None,
+    })
+}
+
+struct Association {
+    /// Where the client is expected to send from; a zero port matches any.
+    expected: SocketAddr,
+    /// Whether the relay socket is IPv6, IPv4 destinations then being
+    /// reached at their mapped address.
+    v6: bool,
+    /// The peer of the TCP connection, and the user it authenticated as.
+    source: SocketAddr,
+    user: Option<String>,
+    /// Where the client does send from, once its first datagram arrived.
+    client: Option<SocketAddr>,
+    /// Destinations the client has sent to.
+    peers: HashSet<SocketAddr>,
+    /// Where datagrams to each target the client named go, if anywhere.
+    decided: HashMap<String, Option<SocketAddr>>,
+    /// The slots under the per-destination cap of those destinations.
+    permits: Vec<DestinationPermit>,
+    /// The decisions being taken, off the receive loop.
+    deciding: JoinSet<Decision>,
+    /// Datagrams to the targets being decided on, sent once they are.
+    held: HashMap<String, Vec<Vec<u8>>>,
+    up: u64,
+    down: u64,
+}
+
+impl Association {
+    async fn relay(
+        &mut self,
+        relay: &UdpSocket,
+        datagram: &[u8],
+        from: SocketAddr,
+        config: &Arc<Config>,
+    ) {
+        let client = match self.client {
+            Some(client) => client,
+            None if self.expects(from) => {
+                debug!("UDP association pinned to client {}", from);
+                *self.client.insert(from)
+            }
+            None => {
+                debug!("dropping datagram from {} before the client's first", from);
+                return;
+            }
+        };
+
+        if from == client {
+            let (target, payload) = match decode(datagram) {
+                Some(decoded) => decoded,
+                None => return debug!("dropping malformed or fragmented datagram from client"),
+            };
+            if let Some(dest) = self.admit(target, payload, config) {
+                self.send(relay, payload, dest, config).await;
+            }
+        } else if self.peers.contains(&from) {
+            let mut reply = encode_header(from);
+            reply.extend_from_slice(datagram);
+            match relay.send_to(&reply, self.to(client)).await {
+                Ok(_) => self.down += datagram.len() as u64,
+                Err(e) => debug!("relaying datagram to client {}: {}", client, e),
+            }
+        } else {
+            debug!(
+                "dropping datagram from unsolicited {}",
+                config.privacy.show(&from.to_string())
+            );
+        }
+    }
+
+    /// Sends `payload` from the client on to `dest`.
+    async fn send(&mut self, relay: &UdpSocket, payload: &[u8], dest: SocketAddr, config: &Config) {
+        if self.peers.len() >= MAX_PEERS {
+            self.peers.clear();
+        }
+        self.peers.insert(dest);
+        match relay.send_to(payload, self.to(dest)).await {
+            Ok(_) => self.up += payload.len() as u64,
+            Err(e) => debug!(
+                "relaying datagram to {}: {}",
+                config.privacy.show(&dest.to_string()),
+                e
+            ),
+        }
+    }
+
+    /// `addr` as the relay socket sends to it.
+    fn to(&self, addr: SocketAddr) -> SocketAddr {
+        match (self.v6, addr) {
+            (true, SocketAddr::V4(v4)) => (v4.ip().to_ipv6_mapped(), v4.port()).into(),
+            _ => addr,
+        }
+    }
+
+    fn expects(&self, from: SocketAddr) -> bool {
+        self.expected.ip() == from.ip()
+            && (self.expected.port() == 0 || self.expected.port() == from.port())
+    }
+
+    /// Where a datagram to `target` goes, as far as the checks of CONNECT
+    /// requests let it go anywhere, if that is decided already. Otherwise
+    /// `payload` is held until it is. Decisions stand for the association,
+    /// except for the quotas and failures that may pass, such as lookups
+    /// timing out.
+    fn admit(
+        &mut self,
+        target: Target,
+        payload: &[u8],
+        config: &Arc<Config>,
+    ) -> Option<SocketAddr> {
+        if let (Some(user), Some(quotas)) = (&self.user, &config.quotas) {
+            if quotas.exceeded(user) {
+                debug!("dropping datagram of user {:?}, over quota", user);
+                return None;
+            }
+        }
+        if let Target::Domain(host, _) = &target {
+            if !config.resolve_domains {
+                debug!(
+                    "dropping datagram to hostname {}",
+                    config.privacy.show(host.as_str())
+                );
+                return None;
+            }
+        }
+        if self.decided.len() >= MAX_PEERS {
+            self.decided.clear();
+            self.permits.clear();
+        }
+        let key = target.to_string();
+        if let Some(decided) = self.decided.get(&key) {
+            return *decided;
+        }
+        if let Some(held) = self.held.get_mut(&key) {
+            if held.len() < MAX_HELD {
+                held.push(payload.to_vec());
+            }
+            return None;
+        }
+        if self.deciding.len() >= MAX_DECIDING {
+            debug!("dropping datagram, too many destinations being decided on");
+            return None;
+        }
+        self.held.insert(key.clone(), vec![payload.to_vec()]);
+        let (source, user, v6) = (self.source, self.user.clone(), self.v6);
+        let config = config.clone();
+        self.deciding.spawn(async move {
+            let decided = decide(target, source, user.as_deref(), v6, &config).await;
+            Decision { key, decided }
+        });
+        None
+    }
+
+    /// Takes the `decision` on a target, sending on the datagrams held for
+    /// it.
+    async fn decided(
+        &mut self,
+        relay: &UdpSocket,
+        decision: std::result::Result<Decision, JoinError>,
+        config: &Config,
+    ) {
+        let Decision { key, decided } = match decision {
+            Ok(decision) => decision,
+            Err(e) => {
+                // Which target it was is lost with the task; let them all
+                // be decided on again.
+                debug!("deciding on a UDP destination failed: {}", e);
+                self.held.clear();
+                return;
+            }
+        };
+        let held = self.held.remove(&key).unwrap_or_default();
+        let dest = match decided {
+            Ok(Some((dest, permit))) => {
+                self.permits.extend(permit);
+                Some(dest)
+            }
+            Ok(None) => None,
+            Err(e) => {
+                let transient = matches!(
+                    e,
+                    Socks5ServerError::DNSTimeout(_) | Socks5ServerError::IOError(_)
+                );
+                debug!("dropping datagram: {}", config.privacy.scrub(e));
+                if transient {
+                    return;
+                }
+                None
+            }
+        };
+        self.decided.insert(key, dest);
+        if let Some(dest) = dest {
+            for payload in held {
+                self.send(relay, &payload, dest, config).await;
+            }
+        }
+    }
+}
+
+/// Where datagrams to a target go, keyed as the association keeps it.
+struct Decision {
+    key: String,
+    decided: Result<Option<(SocketAddr, Option<DestinationPermit>)>>,
+}
+
+/// Where datagrams from a client at `source` to `target` go, if
+/// anywhere, with the slot taken under the per-destination cap.
+async fn decide(
+    target: Target,
+    source: SocketAddr,
+    user: Option<&str>,
+    v6: bool,
+    config: &Config,
+) -> Result<Option<(SocketAddr, Option<DestinationPermit>)>> {
+    let (target, _) = screen(target, source, user, config).await?;
+    let addrs = vet(&target, user, config).await?;
+    let dest = match addrs.into_iter().find(|addr| v6 || addr.is_ipv4()) {
+        Some(dest) => dest,
+        None => return Ok(None),
+    };
+    let permit = match &config.dest_limit {
+        Some(limit) => match limit.try_acquire(&target, dest) {
+            Some(permit) => Some(permit),
+            None => {
+                let shown = config.privacy.show(&target.to_string());
+                debug!("dropping datagram to {}, at capacity", shown);
+                return Ok(None);
+            }
+        },
+        None => None,
+    };
+    Ok(Some((dest, permit)))
+}
+
+/// Splits a datagram from the client into its destination and payload.
+/// Fragments are not supported and are treated as malformed.
+fn decode(datagram: &[u8]) -> Option<(Target, &[u8])> {
+    let header = datagram.get(..4)?;
+    let rest = &datagram[4..];
+    if header[..3] != [SOCKS_RSV, SOCKS_RSV, 0x00] {
+        return None;
+    }
+    let (target, len) = match header[3] {
+        SOCKS_ADDR_IPV4 => {
+            let ip: [u8; 4] = rest.get(..4)?.try_into().ok()?;
+            (Target::Ip(SocketAddr::new(ip.into(), port_at(rest, 4)?)), 4)
+        }
+        SOCKS_ADDR_IPV6 => {
+            let ip: [u8; 16] = rest.get(..16)?.try_into().ok()?;
+            (
+                Target::Ip(SocketAddr::new(ip.into(), port_at(rest, 16)?)),
+                16,
+            )
+        }
+        SOCKS_ADDR_DOMAINNAME => {
+            let len = *rest.first()? as usize;
+            let host = std::str::from_utf8(rest.get(1..1 + len)?).ok()?;
+            let host = Hostname::new(host).ok()?;
+            (Target::Domain(host, port_at(rest, 1 + len)?), 1 + len)
+        }
+        _ => return None,
+    };
+    Some((target, &rest[len + 2..]))
+}
+
+#[inline]
+fn port_at(buf: &[u8], at: usize) -> Option<u16> {
+    let port = buf.get(at..at + 2)?;
+    Some(u16::from_be_bytes([port[0], port[1]]))
+}
+
+/// The header of a datagram relayed to the client from `from`.
+fn encode_header(from: SocketAddr) -> Vec<u8> {
+    let mut header = vec![SOCKS_RSV, SOCKS_RSV, 0x00];
+    match from {
+        SocketAddr::V4(v4) => {
+            header.push(SOCKS_ADDR_IPV4);
+            header.extend_from_slice(&v4.ip().octets());
+        }
+        SocketAddr::V6(v6) => {
+            header.push(SOCKS_ADDR_IPV6);
+            header.extend_from_slice(&v6.ip().octets());
+        }
+    }
+    header.extend_from_slice(&from.port().to_be_bytes());
+    header
+}
+
+/// Completes once the client closes the TCP connection or it fails.
+/// Anything the client sends on it is discarded.
+async fn closed(conn: &mut PendingCommand) {
+    loop {
+        let len = match conn.fill_buf().await {
+            Ok(buf) if !buf.is_empty() => buf.len(),
+            _ => return,
+        };
+        conn.consume(len);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::{
+        tests::serve, IpNet, ResolveFuture, Resolver, Rule, RuleAction, RuleSet, Socks5Server,
+    };
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
+    use tokio::{io::AsyncReadExt, net::TcpStream};
+
+    #[test]
+    fn decodes_headers() {
+        let datagram = [0, 0, 0, SOCKS_ADDR_IPV4, 10, 0, 0, 1, 0, 53, b'q'];
+        let (target, payload) = decode(&datagram).unwrap();
+        assert_eq!(target.to_string(), "10.0.0.1:53");
+        assert_eq!(payload, b"q");
+
+        let mut datagram = vec![0, 0, 0, SOCKS_ADDR_DOMAINNAME, 11];
+        datagram.extend_from_slice(b"example.com");
+        datagram.extend_from_slice(&[0x01, 0xBB]);
+        datagram.extend_from_slice(b"payload");
+        let (target, payload) = decode(&datagram).unwrap();
+        assert_eq!(target.to_string(), "example.com:443");
+        assert_eq!(payload, b"payload");
+
+        let from: SocketAddr = "[2001:db8::1]:5353".parse().unwrap();
+        let mut reply = encode_header(from);
+        reply.push(b'r');
+        let (target, payload) = decode(&reply).unwrap();
+        assert_eq!(target.to_string(), from.to_string());
+        assert_eq!(payload, b"r");
+    }
+
+    #[test]
+    fn drops_fragments_and_broken_headers() {
+        let fragment = [0, 0, 1, SOCKS_ADDR_IPV4, 10, 0, 0, 1, 0, 53];
+        assert!(decode(&fragment).is_none());
+        assert!(decode(&[0, 0, 0, SOCKS_ADDR_IPV4, 10, 0]).is_none());
+        assert!(decode(&[0, 0, 0, SOCKS_ADDR_DOMAINNAME, 20, b'a']).is_none());
+        assert!(decode(&[0, 0, 0, 0x05, 0, 0]).is_none());
+    }
+
+    /// Requests an association for datagrams from anywhere, returning the
+    /// REP code and the relay address.
+    async fn associate(control: &mut TcpStream) -> (u8, SocketAddr) {
+        associate_from(control, "0.0.0.0:0".parse().unwrap()).await
+    }
+
+    /// Requests an association for datagrams from `from`.
+    async fn associate_from(control: &mut TcpStream, from: SocketAddr) -> (u8, SocketAddr) {
+        control.write_all(&[SOCKS_VER, 1, 0]).await.unwrap();
+        let mut selected = [0u8; 2];
+        control.read_exact(&mut selected).await.unwrap();
+        let mut request = vec![SOCKS_VER, 3];
+        request.extend_from_slice(&encode_header(from)[2..]);
+        control.write_all(&request).await.unwrap();
+        let mut reply = [0u8; 10];
+        control.read_exact(&mut reply).await.unwrap();
+        let ip: [u8; 4] = reply[4..8].try_into().unwrap();
+        (
+            reply[1],
+            SocketAddr::new(ip.into(), port_at(&reply, 8).unwrap()),
+        )
+    }
+
+    /// A UDP socket sending back whatever it receives.
+    async fn echo() -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            while let Ok((len, from)) = socket.recv_from(&mut buf).await {
+                let _ = socket.send_to(&buf[..len], from).await;
+            }
+        });
+        addr
+    }
+
+    async fn exchange(client: &UdpSocket, relay: SocketAddr, dest: SocketAddr) -> Option<Vec<u8>> {
+        exchange_with(client, relay, encode_header(dest)).await
+    }
+
+    /// Sends a datagram with `header` and waits a while for the reply.
+    async fn exchange_with(
+        client: &UdpSocket,
+        relay: SocketAddr,
+        header: Vec<u8>,
+    ) -> Option<Vec<u8>> {
+        let mut datagram = header;
+        datagram.extend_from_slice(b"ping");
+        client.send_to(&datagram, relay).await.unwrap();
+        let mut buf = [0u8; 512];
+        let received = tokio::time::timeout(Duration::from_millis(200), client.recv(&mut buf));
+        let len = received.await.ok()?.unwrap();
+        Some(buf[..len].to_vec())
+    }
+
+    fn server() -> Socks5Server {
+        crate::server::new("127.0.0.1:0".parse().unwrap(), None)
+            .unwrap()
+            .bind_and_udp(true)
+    }
+
+    #[tokio::test]
+    async fn relays_while_the_control_connection_is_open() {
+        let dest = echo().await;
+        let (mut control, served) = serve(server()).await;
+        let (rep, relay) = associate(&mut control).await;
+        assert_eq!(rep, SocksError::SUCCESS as u8);
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let reply = exchange(&client, relay, dest).await.unwrap();
+        let mut expected = encode_header(dest);
+        expected.extend_from_slice(b"ping");
+        assert_eq!(reply, expected);
+
+        drop(control);
+        let summary = served.await.unwrap().unwrap();
+        assert_eq!((summary.bytes_up, summary.bytes_down), (4, 4));
+    }
+
+    #[tokio::test]
+    async fn pins_a_wildcard_client_to_its_first_datagram() {
+        let dest = echo().await;
+        let (mut control, _served) = serve(server()).await;
+        let (_, relay) = associate(&mut control).await;
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        assert!(exchange(&client, relay, dest).await.is_some());
+        let other = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        assert!(exchange(&other, relay, dest).await.is_none());
+        assert!(exchange(&client, relay, dest).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn relays_only_for_the_client_named() {
+        let dest = echo().await;
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let (mut control, _served) = serve(server()).await;
+        let (_, relay) = associate_from(&mut control, client.local_addr().unwrap()).await;
+
+        let other = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        assert!(exchange(&other, relay, dest).await.is_none());
+        assert!(exchange(&client, relay, dest).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn ignores_a_first_datagram_from_another_ip() {
+        let dest = echo().await;
+        let (mut control, _served) = serve(server()).await;
+        let (_, relay) = associate(&mut control).await;
+
+        let spoofed = UdpSocket::bind("127.0.0.2:0").await.unwrap();
+        assert!(exchange(&spoofed, relay, dest).await.is_none());
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        assert!(exchange(&client, relay, dest).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn vets_each_destination() {
+        let (allowed, denied) = (echo().await, echo().await);
+        let rule = Rule::deny()
+            .network(IpNet::from(Ipv4Addr::LOCALHOST))
+            .ports(denied.port()..=denied.port());
+        let acl = RuleSet::new(RuleAction::Allow).rule(rule);
+        let (mut control, _served) = serve(server().acl(acl)).await;
+        let (_, relay) = associate(&mut control).await;
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        assert!(exchange(&client, relay, denied).await.is_none());
+        assert!(exchange(&client, relay, allowed).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn refused_unless_enabled() {
+        let server = crate::server::new("127.0.0.1:0".parse().unwrap(), None).unwrap();
+        let (mut control, served) = serve(server).await;
+        let (rep, _) = associate(&mut control).await;
+        assert_eq!(rep, SocksError::COMMAND as u8);
+        assert!(served.await.unwrap().is_err());
+    }
+
+    /// The header of a datagram to `host`.
+    fn domain_header(host: &str, port: u16) -> Vec<u8> {
+        let mut header = vec![
+            SOCKS_RSV,
+            SOCKS_RSV,
+            0x00,
+            SOCKS_ADDR_DOMAINNAME,
+            host.len() as u8,
+        ];
+        header.extend_from_slice(host.as_bytes());
+        header.extend_from_slice(&port.to_be_bytes());
+        header
+    }
+
+    /// Takes `delay` to answer with the loopback address for each of the
+    /// first `slow` lookups of hosts starting with "slow", and for no
+    /// other lookup.
+    struct Slow {
+        delay: Duration,
+        slow: usize,
+        calls: AtomicUsize,
+    }
+
+    impl Resolver for Slow {
+        fn resolve<'a>(&'a self, host: &'a str, port: u16) -> ResolveFuture<'a> {
+            let slow =
+                host.starts_with("slow") && self.calls.fetch_add(1, Ordering::SeqCst) < self.slow;
+            Box::pin(async move {
+                if slow {
+                    tokio::time::sleep(self.delay).await;
+                }
+                Ok(vec![SocketAddr::from(([127, 0, 0, 1], port))])
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn relays_to_others_while_looking_up_a_host() {
+        let resolver = Slow {
+            delay: Duration::from_secs(10),
+            slow: usize::MAX,
+            calls: AtomicUsize::new(0),
+        };
+        let dest = echo().await;
+        let (mut control, _served) = serve(server().resolver(Arc::new(resolver))).await;
+        let (_, relay) = associate(&mut control).await;
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let stuck = domain_header("slow.example", dest.port());
+        assert!(exchange_with(&client, relay, stuck).await.is_none());
+        let quick = domain_header("quick.example", dest.port());
+        assert!(exchange_with(&client, relay, quick).await.is_some());
+        assert!(exchange(&client, relay, dest).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn looks_up_again_after_a_lookup_timed_out() {
+        let resolver = Slow {
+            delay: Duration::from_secs(10),
+            slow: 1,
+            calls: AtomicUsize::new(0),
+        };
+        let server = server()
+            .resolver(Arc::new(resolver))
+            .dns_timeout(Duration::from_millis(50));
+        let dest = echo().await;
+        let (mut control, _served) = serve(server).await;
+        let (_, relay) = associate(&mut control).await;
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let header = domain_header("slow.example", dest.port());
+        assert!(exchange_with(&client, relay, header.clone())
+            .await
+            .is_none());
+        assert!(exchange_with(&client, relay, header).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn sends_on_datagrams_held_for_a_lookup() {
+        let resolver = Slow {
+            delay: Duration::from_millis(100),
+            slow: usize::MAX,
+            calls: AtomicUsize::new(0),
+        };
+        let dest = echo().await;
+        let (mut control, _served) = serve(server().resolver(Arc::new(resolver))).await;
+        let (_, relay) = associate(&mut control).await;
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut datagram = domain_header("slow.example", dest.port());
+        datagram.extend_from_slice(b"ping");
+        for _ in 0..3 {
+            client.send_to(&datagram, relay).await.unwrap();
+        }
+        let mut buf = [0u8; 512];
+        for _ in 0..3 {
+            let received = tokio::time::timeout(Duration::from_secs(1), client.recv(&mut buf));
+            let len = received.await.unwrap().unwrap();
+            assert!(buf[..len].ends_with(b"ping"));
+        }
+    }
+}
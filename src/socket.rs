@@ -0,0 +1,310 @@
+use log::debug;
+use socket2::{Domain, SockRef, Socket, TcpKeepalive, Type};
+use std::{
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+use tokio::{
+    io,
+    net::{TcpSocket, TcpStream, UdpSocket},
+};
+
+/// Options set on a socket the crate creates, before it binds or
+/// connects. Nothing is set unless asked for, leaving the system defaults.
+#[derive(Debug, Clone, Default)]
+pub struct SocketOptions {
+    nodelay: Option<bool>,
+    keepalive: Option<Duration>,
+    linger: Option<Duration>,
+    recv_buffer_size: Option<usize>,
+    send_buffer_size: Option<usize>,
+    reuse_address: Option<bool>,
+    #[cfg(all(
+        unix,
+        not(any(target_os = "solaris", target_os = "illumos", target_os = "cygwin"))
+    ))]
+    reuse_port: Option<bool>,
+    #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+    bind_device: Option<Vec<u8>>,
+    tos: Option<u8>,
+}
+
+impl SocketOptions {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Sets `TCP_NODELAY`.
+    pub fn nodelay(mut self, nodelay: bool) -> Self {
+        self.nodelay = Some(nodelay);
+        self
+    }
+
+    /// Enables keepalive probes once the connection has been idle for
+    /// `idle`.
+    pub fn keepalive(mut self, idle: Duration) -> Self {
+        self.keepalive = Some(idle);
+        self
+    }
+
+    /// Sets `SO_LINGER`; a zero duration makes closing send an RST.
+    pub fn linger(mut self, linger: Duration) -> Self {
+        self.linger = Some(linger);
+        self
+    }
+
+    pub fn recv_buffer_size(mut self, size: usize) -> Self {
+        self.recv_buffer_size = Some(size);
+        self
+    }
+
+    pub fn send_buffer_size(mut self, size: usize) -> Self {
+        self.send_buffer_size = Some(size);
+        self
+    }
+
+    /// Sets `SO_REUSEADDR`.
+    pub fn reuse_address(mut self, reuse: bool) -> Self {
+        self.reuse_address = Some(reuse);
+        self
+    }
+
+    /// Sets `SO_REUSEPORT`, letting several listeners share an address.
+    #[cfg(all(
+        unix,
+        not(any(target_os = "solaris", target_os = "illumos", target_os = "cygwin"))
+    ))]
+    pub fn reuse_port(mut self, reuse: bool) -> Self {
+        self.reuse_port = Some(reuse);
+        self
+    }
+
+    /// Binds the socket to the network interface `device`
+    /// (`SO_BINDTODEVICE`).
+    #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+    pub fn bind_device(mut self, device: &str) -> Self {
+        self.bind_device = Some(device.as_bytes().to_vec());
+        self
+    }
+
+    /// Marks the socket with the IP TOS (IPv6 traffic class) `tos`. Where
+    /// the platform can't, the socket goes on unmarked.
+    pub fn tos(mut self, tos: u8) -> Self {
+        self.tos = Some(tos);
+        self
+    }
+
+    fn apply(&self, socket: SockRef<'_>, v6: bool) -> io::Result<()> {
+        if let Some(nodelay) = self.nodelay {
+            socket.set_tcp_nodelay(nodelay)?;
+        }
+        if let Some(idle) = self.keepalive {
+            socket.set_tcp_keepalive(&TcpKeepalive::new().with_time(idle))?;
+        }
+        if let Some(linger) = self.linger {
+            socket.set_linger(Some(linger))?;
+        }
+        if let Some(size) = self.recv_buffer_size {
+            socket.set_recv_buffer_size(size)?;
+        }
+        if let Some(size) = self.send_buffer_size {
+            socket.set_send_buffer_size(size)?;
+        }
+        if let Some(reuse) = self.reuse_address {
+            socket.set_reuse_address(reuse)?;
+        }
+        #[cfg(all(
+            unix,
+            not(any(target_os = "solaris", target_os = "illumos", target_os = "cygwin"))
+        ))]
+        if let Some(reuse) = self.reuse_port {
+            socket.set_reuse_port(reuse)?;
+        }
+        #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+        if let Some(device) = &self.bind_device {
+            socket.bind_device(Some(device))?;
+        }
+        if let Some(tos) = self.tos {
+            mark(socket, v6, tos);
+        }
+        Ok(())
+    }
+}
+
+/// A fresh nonblocking socket of type `ty` for `addr`'s family, with
+/// `options` applied.
+fn new(addr: &SocketAddr, ty: Type, options: &SocketOptions) -> io::Result<Socket> {
+    let socket = Socket::new(Domain::for_address(*addr), ty, None)?;
+    socket.set_nonblocking(true)?;
+    options.apply(SockRef::from(&socket), addr.is_ipv6())?;
+    Ok(socket)
+}
+
+fn socket(addr: &SocketAddr, options: &SocketOptions) -> io::Result<TcpSocket> {
+    let socket = new(addr, Type::STREAM, options)?;
+    Ok(TcpSocket::from_std_stream(socket.into()))
+}
+
+/// A socket bound to `addr`, ready to listen.
+pub(crate) fn bind(addr: SocketAddr, options: &SocketOptions) -> io::Result<TcpSocket> {
+    let conn = socket(&addr, options)?;
+    conn.bind(addr)?;
+    Ok(conn)
+}
+
+/// A UDP socket bound to `addr`. Only options that apply to UDP, such as
+/// buffer sizes, may be set in `options`.
+pub(crate) fn bind_udp(addr: SocketAddr, options: &SocketOptions) -> io::Result<UdpSocket> {
+    let socket = new(&addr, Type::DGRAM, options)?;
+    socket.bind(&addr.into())?;
+    UdpSocket::from_std(socket.into())
+}
+
+/// Connects to `dest`, from `from` if given.
+pub(crate) async fn connect(
+    dest: SocketAddr,
+    from: Option<IpAddr>,
+    options: &SocketOptions,
+) -> io::Result<TcpStream> {
+    let conn = socket(&dest, options)?;
+    if let Some(ip) = from {
+        conn.bind(SocketAddr::new(ip, 0))?;
+    }
+    conn.connect(dest).await
+}
+
+/// Sets the TOS of `socket`. A platform without the option only gets a
+/// debug log; the connection goes on unmarked.
+pub(crate) fn mark(socket: SockRef<'_>, v6: bool, tos: u8) {
+    let marked = match v6 {
+        false => set_tos_v4(&socket, tos),
+        true => set_tclass_v6(&socket, tos),
+    };
+    if let Err(e) = marked {
+        debug!("marking connection with TOS {:#04X}: {}", tos, e);
+    }
+}
+
+#[cfg(not(any(
+    target_os = "fuchsia",
+    target_os = "redox",
+    target_os = "solaris",
+    target_os = "haiku",
+    target_os = "wasi",
+)))]
+fn set_tos_v4(socket: &SockRef<'_>, tos: u8) -> io::Result<()> {
+    socket.set_tos_v4(tos.into())
+}
+
+#[cfg(any(
+    target_os = "fuchsia",
+    target_os = "redox",
+    target_os = "solaris",
+    target_os = "haiku",
+    target_os = "wasi",
+))]
+fn set_tos_v4(_: &SockRef<'_>, _: u8) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "IP_TOS not available",
+    ))
+}
+
+#[cfg(any(
+    target_os = "android",
+    target_os = "dragonfly",
+    target_os = "freebsd",
+    target_os = "fuchsia",
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "netbsd",
+    target_os = "openbsd",
+    target_os = "cygwin",
+    target_os = "illumos",
+))]
+fn set_tclass_v6(socket: &SockRef<'_>, tos: u8) -> io::Result<()> {
+    socket.set_tclass_v6(tos.into())
+}
+
+#[cfg(not(any(
+    target_os = "android",
+    target_os = "dragonfly",
+    target_os = "freebsd",
+    target_os = "fuchsia",
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "netbsd",
+    target_os = "openbsd",
+    target_os = "cygwin",
+    target_os = "illumos",
+)))]
+fn set_tclass_v6(_: &SockRef<'_>, _: u8) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "IPV6_TCLASS not available",
+    ))
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn applies_each_option() {
+        let options = SocketOptions::new()
+            .nodelay(true)
+            .keepalive(Duration::from_secs(30))
+            .linger(Duration::from_secs(1))
+            .recv_buffer_size(64 * 1024)
+            .send_buffer_size(64 * 1024)
+            .reuse_address(true)
+            .reuse_port(true)
+            .tos(0x10);
+        let conn = bind("127.0.0.1:0".parse().unwrap(), &options).unwrap();
+        let socket = SockRef::from(&conn);
+        assert!(socket.tcp_nodelay().unwrap());
+        assert!(socket.keepalive().unwrap());
+        assert_eq!(
+            socket.tcp_keepalive_time().unwrap(),
+            Duration::from_secs(30)
+        );
+        assert_eq!(socket.linger().unwrap(), Some(Duration::from_secs(1)));
+        // The kernel doubles the sizes asked for, to make room for its own
+        // bookkeeping.
+        assert!(socket.recv_buffer_size().unwrap() >= 64 * 1024);
+        assert!(socket.send_buffer_size().unwrap() >= 64 * 1024);
+        assert!(socket.reuse_address().unwrap());
+        assert!(socket.reuse_port().unwrap());
+        assert_eq!(socket.tos_v4().unwrap(), 0x10);
+    }
+
+    #[tokio::test]
+    async fn leaves_the_defaults_alone() {
+        let conn = bind("127.0.0.1:0".parse().unwrap(), &SocketOptions::new()).unwrap();
+        let socket = SockRef::from(&conn);
+        assert!(!socket.tcp_nodelay().unwrap());
+        assert!(!socket.keepalive().unwrap());
+        assert_eq!(socket.linger().unwrap(), None);
+        assert_eq!(socket.tos_v4().unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn applies_options_to_udp_sockets() {
+        let options = SocketOptions::new().recv_buffer_size(64 * 1024).tos(0x10);
+        let socket = bind_udp("127.0.0.1:0".parse().unwrap(), &options).unwrap();
+        let socket = SockRef::from(&socket);
+        assert!(socket.recv_buffer_size().unwrap() >= 64 * 1024);
+        assert_eq!(socket.tos_v4().unwrap(), 0x10);
+    }
+
+    #[tokio::test]
+    async fn connects_from_the_address_given() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let dest = listener.local_addr().unwrap();
+        let from = "127.0.0.2".parse().unwrap();
+        let options = SocketOptions::new().nodelay(true);
+        let conn = connect(dest, Some(from), &options).await.unwrap();
+        assert_eq!(conn.local_addr().unwrap().ip(), from);
+        assert!(conn.nodelay().unwrap());
+    }
+}
@@ -1,23 +1,73 @@
+use std::fmt;
 use std::io::{self, Result};
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use thiserror::Error;
 
 pub const SOCKS_VER: u8 = 0x05;
 pub const SOCKS_RSV: u8 = 0x00;
+#[cfg(feature = "net")]
+pub const SOCKS_AUTH_USERPASS_VER: u8 = 0x01;
 pub const SOCKS_COMMAND_CONNECT: u8 = 0x01;
+#[cfg(feature = "net")]
+pub const SOCKS_COMMAND_BIND: u8 = 0x02;
+#[cfg(feature = "net")]
+pub const SOCKS_COMMAND_UDP_ASSOCIATE: u8 = 0x03;
 pub const SOCKS_ADDR_IPV4: u8 = 0x01;
 pub const SOCKS_ADDR_IPV6: u8 = 0x04;
 pub const SOCKS_ADDR_DOMAINNAME: u8 = 0x03;
 
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Addr {
     SocketAddr(SocketAddr),
     HostnamePort(String),
 }
-#[derive(Debug)]
+impl From<SocketAddr> for Addr {
+    fn from(addr: SocketAddr) -> Addr {
+        Addr::SocketAddr(addr)
+    }
+}
+impl From<(IpAddr, u16)> for Addr {
+    fn from(addr: (IpAddr, u16)) -> Addr {
+        Addr::SocketAddr(addr.into())
+    }
+}
+/// A hostname that is an IP address gives a socket address.
+impl From<(&str, u16)> for Addr {
+    fn from((hostname, port): (&str, u16)) -> Addr {
+        match hostname.parse::<IpAddr>() {
+            Ok(ip) => Addr::SocketAddr(SocketAddr::new(ip, port)),
+            Err(_) => Addr::HostnamePort(format!("{}:{}", hostname, port)),
+        }
+    }
+}
+impl From<&Addr> for Addr {
+    fn from(addr: &Addr) -> Addr {
+        addr.clone()
+    }
+}
+impl fmt::Display for Addr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Addr::SocketAddr(addr) => addr.fmt(f),
+            Addr::HostnamePort(hostname_port) => f.write_str(hostname_port),
+        }
+    }
+}
+#[derive(Debug, Clone)]
 pub enum AuthMethod {
     NoAuth,
     UserPass(Option<(String, String)>),
     NoAvailable,
+    /// A method known by its code alone, such as GSSAPI (0x01) or one of
+    /// the private methods (0x80 to 0xFE). Servers serve it through a
+    /// handler registered for the code.
+    Other(u8),
+}
+/// Username and password authentication.
+impl From<(&str, &str)> for AuthMethod {
+    fn from((username, password): (&str, &str)) -> AuthMethod {
+        AuthMethod::UserPass(Some((username.to_owned(), password.to_owned())))
+    }
 }
 impl AuthMethod {
     pub fn to_code(&self) -> u8 {
@@ -26,6 +76,7 @@ impl AuthMethod {
             NoAuth => 0x00,
             UserPass(_) => 0x02,
             NoAvailable => 0xFF,
+            Other(code) => *code,
         }
     }
     pub fn from_code(code: u8) -> Result<AuthMethod> {
@@ -34,14 +85,12 @@ impl AuthMethod {
             0x00 => Ok(NoAuth),
             0x02 => Ok(UserPass(None)),
             0xFF => Ok(NoAvailable),
-            _ => Err(io::Error::new(
-                io::ErrorKind::ConnectionRefused,
-                format!("unsupported authenticate method {:#04X?}", code),
-            )),
+            code => Ok(Other(code)),
         }
     }
 }
-#[derive(Error, Debug)]
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
 pub enum SocksError {
     #[error("succeeded")]
     SUCCESS = 0x00,
@@ -65,9 +114,22 @@ pub enum SocksError {
     OTHOR,
 }
 
-impl Into<io::Error> for SocksError {
-    fn into(self) -> io::Error {
-        io::Error::new(io::ErrorKind::ConnectionAborted, self)
+impl From<SocksError> for io::Error {
+    fn from(e: SocksError) -> io::Error {
+        io::Error::new(io::ErrorKind::ConnectionAborted, e)
+    }
+}
+
+/// The reply for a connection to the destination that failed with `e`.
+impl From<&io::Error> for SocksError {
+    fn from(e: &io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::ConnectionRefused => SocksError::CONNECTION,
+            io::ErrorKind::HostUnreachable => SocksError::HOST,
+            io::ErrorKind::NetworkUnreachable | io::ErrorKind::NetworkDown => SocksError::NETWORK,
+            io::ErrorKind::TimedOut => SocksError::TTL,
+            _ => SocksError::FAIL,
+        }
     }
 }
 
@@ -88,13 +150,122 @@ impl From<u8> for SocksError {
     }
 }
 
+/// A hostname in the one form it is compared, matched and looked up in:
+/// lowercase, IDNA-encoded to ASCII, without a trailing dot. Two names for
+/// the same host, such as `Example.COM.` and `example.com`, are equal.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Hostname(String);
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum HostnameError {
+    #[error("empty hostname")]
+    Empty,
+    #[error("hostname longer than 253 bytes")]
+    TooLong,
+    #[error("empty label in hostname")]
+    EmptyLabel,
+    #[error("hostname label longer than 63 bytes")]
+    LabelTooLong,
+    #[error("hostname contains NUL, whitespace or control characters")]
+    ForbiddenChar,
+    #[error("hostname can't be IDNA-encoded")]
+    Idna,
+    #[error("hostname is not UTF-8")]
+    NotUtf8(#[from] std::str::Utf8Error),
+}
+
+impl Hostname {
+    /// Normalizes `name`, stripping a single trailing dot.
+    pub fn new(name: &str) -> std::result::Result<Hostname, HostnameError> {
+        let name = name.strip_suffix('.').unwrap_or(name);
+        if name.is_empty() {
+            return Err(HostnameError::Empty);
+        }
+        if name.chars().any(|c| c.is_whitespace() || c.is_control()) {
+            return Err(HostnameError::ForbiddenChar);
+        }
+        let name = match name.is_ascii() {
+            true => name.to_ascii_lowercase(),
+            false => idna::domain_to_ascii(name).map_err(|_| HostnameError::Idna)?,
+        };
+        if name.len() > 253 {
+            return Err(HostnameError::TooLong);
+        }
+        for label in name.split('.') {
+            match label.len() {
+                0 => return Err(HostnameError::EmptyLabel),
+                1..=63 => {}
+                _ => return Err(HostnameError::LabelTooLong),
+            }
+        }
+        Ok(Hostname(name))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for Hostname {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::str::FromStr for Hostname {
+    type Err = HostnameError;
+
+    fn from_str(name: &str) -> std::result::Result<Hostname, HostnameError> {
+        Hostname::new(name)
+    }
+}
+
+/// The form a host written in a rule is matched in: IP literals as
+/// [`canonical_ip`] prints them, anything else as a [`Hostname`], or just
+/// lowercased if it is no valid hostname.
+#[cfg(feature = "net")]
+pub(crate) fn rule_host(host: &str) -> String {
+    if let Ok(ip) = host.parse() {
+        return canonical_ip(ip).to_string();
+    }
+    match Hostname::new(host) {
+        Ok(host) => host.0,
+        Err(_) => host.to_lowercase(),
+    }
+}
+
+/// Folds IPv4-mapped IPv6 addresses (`::ffff:a.b.c.d`) into plain IPv4, so
+/// the same host compares equal however it connected or was written.
+#[cfg(feature = "net")]
+pub(crate) fn canonical_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        IpAddr::V4(_) => ip,
+    }
+}
+
+#[cfg(feature = "net")]
+pub(crate) fn canonical_addr(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(canonical_ip(addr.ip()), addr.port())
+}
+
+/// A cheap, non-cryptographic random number, good enough for jitter.
+#[cfg(feature = "net")]
+pub(crate) fn random_u64() -> u64 {
+    use std::{
+        collections::hash_map::RandomState,
+        hash::{BuildHasher, Hasher},
+    };
+    RandomState::new().build_hasher().finish()
+}
+
 pub struct Buffer<'a> {
     buffer: &'a mut [u8],
     pos: usize,
 }
 impl<'a> Buffer<'a> {
     #[inline]
-    pub fn from(buffer: &mut [u8]) -> Buffer {
+    pub fn from(buffer: &mut [u8]) -> Buffer<'_> {
         Buffer { buffer, pos: 0 }
     }
     #[inline]
@@ -115,6 +286,20 @@ impl<'a> Buffer<'a> {
 }
 
 macro_rules! impl_deref {
+    ($x:ident<$s:ident>, $y:ty) => {
+        struct $x<$s>($y);
+        impl<$s> Deref for $x<$s> {
+            type Target = $y;
+            fn deref(&self) -> &Self::Target {
+                &self.0
+            }
+        }
+        impl<$s> DerefMut for $x<$s> {
+            fn deref_mut(&mut self) -> &mut Self::Target {
+                &mut self.0
+            }
+        }
+    };
     ($x:tt,$y:ty) => {
         struct $x($y);
         impl Deref for $x {
@@ -130,3 +315,94 @@ macro_rules! impl_deref {
         }
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalizes_hostnames() {
+        use HostnameError::*;
+        let long_label = "a".repeat(64);
+        let long_name = vec!["a".repeat(63); 4].join(".");
+        let cases: Vec<(&str, std::result::Result<&str, HostnameError>)> = vec![
+            ("example.com", Ok("example.com")),
+            ("Example.COM.", Ok("example.com")),
+            ("EXAMPLE.com", Ok("example.com")),
+            ("Bücher.example", Ok("xn--bcher-kva.example")),
+            ("xn--bcher-kva.example.", Ok("xn--bcher-kva.example")),
+            ("", Err(Empty)),
+            (".", Err(Empty)),
+            ("example.com..", Err(EmptyLabel)),
+            ("a..b", Err(EmptyLabel)),
+            (".example.com", Err(EmptyLabel)),
+            ("exa mple.com", Err(ForbiddenChar)),
+            ("exa\0mple.com", Err(ForbiddenChar)),
+            (&long_label, Err(LabelTooLong)),
+            (&long_name, Err(TooLong)),
+        ];
+        for (name, expected) in cases {
+            let normalized = Hostname::new(name);
+            let normalized = normalized.as_ref().map(Hostname::as_str);
+            assert_eq!(normalized, expected.as_ref().copied(), "{:?}", name);
+        }
+        assert_eq!(
+            Hostname::new("Example.COM.").unwrap(),
+            Hostname::new("example.com").unwrap()
+        );
+    }
+
+    #[test]
+    fn converts_destinations() {
+        let v4: SocketAddr = "192.0.2.1:80".parse().unwrap();
+        let v6: SocketAddr = "[2001:db8::1]:443".parse().unwrap();
+        let cases = [
+            (Addr::from(v4), Addr::SocketAddr(v4)),
+            (Addr::from((v6.ip(), 443)), Addr::SocketAddr(v6)),
+            (Addr::from(("192.0.2.1", 80)), Addr::SocketAddr(v4)),
+            (Addr::from(("2001:db8::1", 443)), Addr::SocketAddr(v6)),
+            (
+                Addr::from(("example.com", 80)),
+                Addr::HostnamePort("example.com:80".to_owned()),
+            ),
+        ];
+        for (converted, expected) in cases {
+            assert_eq!(converted, expected);
+        }
+    }
+
+    #[test]
+    fn takes_credentials_as_user_pass() {
+        match AuthMethod::from(("user", "pass")) {
+            AuthMethod::UserPass(Some((user, pass))) => {
+                assert_eq!((&*user, &*pass), ("user", "pass"))
+            }
+            other => panic!("{:?}", other),
+        }
+        assert_eq!(AuthMethod::from(("", "")).to_code(), 0x02);
+    }
+
+    #[test]
+    fn maps_connect_errors_to_replies() {
+        let cases = [
+            (io::ErrorKind::ConnectionRefused, SocksError::CONNECTION),
+            (io::ErrorKind::HostUnreachable, SocksError::HOST),
+            (io::ErrorKind::NetworkUnreachable, SocksError::NETWORK),
+            (io::ErrorKind::NetworkDown, SocksError::NETWORK),
+            (io::ErrorKind::TimedOut, SocksError::TTL),
+            (io::ErrorKind::PermissionDenied, SocksError::FAIL),
+            (io::ErrorKind::Other, SocksError::FAIL),
+        ];
+        for (kind, reply) in cases {
+            let e = io::Error::from(kind);
+            assert_eq!(SocksError::from(&e) as u8, reply as u8, "{:?}", kind);
+        }
+        // ECONNREFUSED, EHOSTUNREACH, ENETUNREACH and ETIMEDOUT, as the OS
+        // reports them.
+        #[cfg(target_os = "linux")]
+        for (errno, reply) in [(111, 0x05), (113, 0x04), (101, 0x03), (110, 0x06)] {
+            let e = io::Error::from_raw_os_error(errno);
+            assert_eq!(SocksError::from(&e) as u8, reply, "errno {}", errno);
+        }
+    }
+}